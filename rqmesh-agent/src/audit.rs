@@ -0,0 +1,313 @@
+//! Append-only log of the requests an agent handles.
+//!
+//! Every request dispatched to a handler, and every request rejected before
+//! it got that far, is recorded in `audit_log` with who made it, how it
+//! ended and how long it took. Records are never changed once written; the
+//! only way out of the log is for a record to age past the retention period
+//! or be pushed out by newer records once the log is full.
+
+use crate::Agent;
+use log::{debug, error, warn};
+use rqmesh_core::{
+    AuditOutcome, AuditRecord, QueryAuditLogRequest, RequestContext, RqMeshError, StorageErrorKind,
+};
+use rusqlite::params;
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+type Result<T> = std::result::Result<T, RqMeshError>;
+
+/// Time between successive prunings of the audit log.
+const PRUNE_PERIOD: Duration = Duration::from_secs(60);
+
+/// Records returned by a query that does not set a limit.
+const DEFAULT_QUERY_LIMIT: u32 = 1000;
+
+/// Most records returned by a single query, so that the response fits in a
+/// frame.
+const MAX_QUERY_LIMIT: u32 = 10_000;
+
+const SHUTDOWN_POLL: Duration = Duration::from_millis(100);
+
+impl Agent {
+    /// Records that the request for `action` claiming to come from
+    /// `requestor` was handled with `result` in `duration`. Only a caller
+    /// established from `context` is recorded as the requestor; the claim is
+    /// kept alongside it. Failing to record it is logged rather than
+    /// returned, since the request has already been handled by then.
+    pub fn record_audit(
+        &self,
+        context: &RequestContext,
+        requestor: &str,
+        action: &str,
+        result: std::result::Result<(), &RqMeshError>,
+        duration: Duration,
+    ) {
+        let (outcome, error) = match result {
+            Ok(()) => (AuditOutcome::Ok, None),
+            Err(e @ RqMeshError::Unauthorized(_)) => (AuditOutcome::Denied, Some(format!("{}", e))),
+            Err(e) => (AuditOutcome::Error, Some(format!("{}", e))),
+        };
+        let recorded = self.connection().execute(
+            "INSERT INTO audit_log (recorded_at, requestor, claimed_requestor, action, outcome, duration_us, error, peer_addr) VALUES (datetime('now'), ?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                context.authenticated_caller(),
+                requestor,
+                action,
+                outcome.to_string(),
+                i64::try_from(duration.as_micros()).unwrap_or(i64::MAX),
+                error,
+                context.peer_addr()
+            ],
+        );
+        if let Err(e) = recorded {
+            error!(
+                "Error recording {} from {} in the audit log: {}",
+                action,
+                context.caller(requestor),
+                audit_query_err("record_audit", e)
+            );
+        }
+    }
+
+    /// Fetches the audit records matching `request`, oldest first.
+    pub fn query_audit_log(&self, request: &QueryAuditLogRequest) -> Result<Vec<AuditRecord>> {
+        let limit = request
+            .limit()
+            .unwrap_or(DEFAULT_QUERY_LIMIT)
+            .min(MAX_QUERY_LIMIT);
+        let conn = self.connection();
+        let mut statement = conn
+            .prepare(
+                "SELECT id, recorded_at, requestor, claimed_requestor, action, outcome, duration_us, error, peer_addr FROM audit_log
+                 WHERE (?1 IS NULL OR recorded_at >= ?1) AND (?2 IS NULL OR recorded_at <= ?2) AND (?3 IS NULL OR requestor = ?3)
+                 ORDER BY id LIMIT ?4",
+            )
+            .map_err(|e| audit_query_err("query_audit_log", e))?;
+        let rows = statement
+            .query_map(
+                params![request.since(), request.until(), request.requestor(), limit],
+                |row| {
+                    let id: i64 = row.get(0)?;
+                    let recorded_at: String = row.get(1)?;
+                    let requestor: Option<String> = row.get(2)?;
+                    let claimed_requestor: String = row.get(3)?;
+                    let action: String = row.get(4)?;
+                    let outcome: String = row.get(5)?;
+                    let duration_us: i64 = row.get(6)?;
+                    let error: Option<String> = row.get(7)?;
+                    let peer_addr: Option<String> = row.get(8)?;
+                    Ok((
+                        (id, recorded_at),
+                        (requestor, claimed_requestor, action),
+                        (outcome, duration_us, error, peer_addr),
+                    ))
+                },
+            )
+            .map_err(|e| audit_query_err("query_audit_log", e))?;
+
+        let mut records = Vec::new();
+        for row in rows {
+            let ((id, recorded_at), (requestor, claimed_requestor, action), outcome) =
+                row.map_err(|e| audit_query_err("query_audit_log", e))?;
+            let (outcome, duration_us, error, peer_addr) = outcome;
+            records.push(
+                AuditRecord::new(
+                    id as u64,
+                    recorded_at,
+                    requestor,
+                    claimed_requestor,
+                    action,
+                    outcome.parse()?,
+                    Duration::from_micros(duration_us as u64),
+                )
+                .with_error(error)
+                .with_peer_addr(peer_addr),
+            );
+        }
+        Ok(records)
+    }
+
+    /// Forgets the audit records older than the retention period, then the
+    /// oldest records beyond the most the log may hold, returning how many
+    /// were forgotten.
+    pub fn prune_audit_log(&self) -> Result<usize> {
+        let conn = self.connection();
+        let mut pruned = 0;
+        if let Some(retention) = self.context.audit_retention() {
+            pruned += conn
+                .execute(
+                    "DELETE FROM audit_log WHERE recorded_at < datetime('now', ?1)",
+                    params![format!("-{} seconds", retention.as_secs())],
+                )
+                .map_err(|e| audit_query_err("prune_audit_log", e))?;
+        }
+        if let Some(max_rows) = self.context.audit_max_rows() {
+            pruned += conn
+                .execute(
+                    "DELETE FROM audit_log WHERE id <= (SELECT id FROM audit_log ORDER BY id DESC LIMIT 1 OFFSET ?1)",
+                    params![i64::try_from(max_rows).unwrap_or(i64::MAX)],
+                )
+                .map_err(|e| audit_query_err("prune_audit_log", e))?;
+        }
+        if pruned > 0 {
+            debug!("Pruned {} records from the audit log", pruned);
+        }
+        Ok(pruned)
+    }
+}
+
+fn audit_query_err(operation: &str, e: rusqlite::Error) -> RqMeshError {
+    RqMeshError::from(StorageErrorKind::new_query_err(operation, format!("{}", e)))
+}
+
+/// Prunes the audit log on a background thread until shutdown.
+pub fn spawn_audit_pruner(
+    agent: Arc<Agent>,
+    shutdown: Arc<AtomicBool>,
+) -> std::io::Result<JoinHandle<()>> {
+    thread::Builder::new()
+        .name("rqmesh-audit".to_string())
+        .spawn(move || {
+            let mut next_prune = Instant::now();
+            while !shutdown.load(Ordering::SeqCst) {
+                if Instant::now() < next_prune {
+                    thread::sleep(SHUTDOWN_POLL);
+                    continue;
+                }
+                next_prune = Instant::now() + PRUNE_PERIOD;
+                if let Err(e) = agent.prune_audit_log() {
+                    warn!("Error pruning audit log: {}", e);
+                }
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::initialization::in_memory_agent;
+    use rqmesh_core::{AgentInitializationContext, UnauthorizedErrorKind};
+
+    fn agent(max_rows: Option<u64>) -> Agent {
+        in_memory_agent(
+            AgentInitializationContext::new(":memory:", "", "").with_audit_max_rows(max_rows),
+        )
+    }
+
+    fn record(agent: &Agent, context: &RequestContext, claimed: &str) {
+        agent.record_audit(
+            context,
+            claimed,
+            "describe",
+            Ok(()),
+            Duration::from_millis(3),
+        );
+    }
+
+    fn all_records(agent: &Agent) -> Vec<AuditRecord> {
+        agent
+            .query_audit_log(&QueryAuditLogRequest::default())
+            .expect("queries audit log")
+    }
+
+    #[test]
+    fn only_authenticated_callers_are_recorded_as_requestors() {
+        let agent = agent(None);
+        let peer = Some("127.0.0.1:5000".to_string());
+        record(&agent, &RequestContext::new(peer.clone(), None), "root");
+        record(
+            &agent,
+            &RequestContext::new(peer.clone(), None).with_key_id("ops"),
+            "root",
+        );
+        record(
+            &agent,
+            &RequestContext::new(peer, Some("node-1".to_string())).with_key_id("ops"),
+            "node-1",
+        );
+
+        let records = all_records(&agent);
+        let requestors: Vec<_> = records.iter().map(|r| r.requestor()).collect();
+        assert_eq!(requestors, vec![None, Some("ops"), Some("node-1")]);
+        assert!(records
+            .iter()
+            .all(|r| r.peer_addr() == Some("127.0.0.1:5000")));
+        assert_eq!(records[0].claimed_requestor(), "root");
+        assert_eq!(records[1].claimed_requestor(), "root");
+
+        // A claim alone never matches a query for a requestor.
+        let root = agent
+            .query_audit_log(&QueryAuditLogRequest::default().with_requestor("root"))
+            .expect("queries audit log");
+        assert!(root.is_empty());
+        let ops = agent
+            .query_audit_log(&QueryAuditLogRequest::default().with_requestor("ops"))
+            .expect("queries audit log");
+        assert_eq!(ops.len(), 1);
+    }
+
+    #[test]
+    fn denials_and_errors_are_recorded_with_their_error() {
+        let agent = agent(None);
+        let denied = RqMeshError::from(UnauthorizedErrorKind::new_action_denied("ops", "run"));
+        let failed = RqMeshError::from(StorageErrorKind::new_not_found("job", "1"));
+        let context = RequestContext::default().with_key_id("ops");
+        agent.record_audit(&context, "ops", "run", Err(&denied), Duration::ZERO);
+        agent.record_audit(&context, "ops", "job", Err(&failed), Duration::ZERO);
+
+        let records = all_records(&agent);
+        assert_eq!(records[0].outcome(), AuditOutcome::Denied);
+        assert_eq!(records[0].error(), Some(denied.to_string().as_str()));
+        assert_eq!(records[1].outcome(), AuditOutcome::Error);
+        assert_eq!(records[1].error(), Some(failed.to_string().as_str()));
+    }
+
+    #[test]
+    fn records_cannot_be_changed() {
+        let agent = agent(None);
+        record(&agent, &RequestContext::default(), "alice");
+        let updated = agent.connection().execute(
+            "UPDATE audit_log SET requestor = 'mallory', outcome = 'ok'",
+            [],
+        );
+        assert!(updated.is_err());
+        assert_eq!(all_records(&agent)[0].requestor(), None);
+    }
+
+    #[test]
+    fn pruning_keeps_the_newest_records_within_the_limit() {
+        let agent = agent(Some(2));
+        for claimed in ["a", "b", "c", "d", "e"] {
+            record(&agent, &RequestContext::default(), claimed);
+        }
+        assert_eq!(agent.prune_audit_log().expect("prunes"), 3);
+        let claimed: Vec<_> = all_records(&agent)
+            .iter()
+            .map(|r| r.claimed_requestor().to_string())
+            .collect();
+        assert_eq!(claimed, vec!["d", "e"]);
+        assert_eq!(agent.prune_audit_log().expect("prunes"), 0);
+    }
+
+    #[test]
+    fn pruning_forgets_records_past_the_retention_period() {
+        let agent = agent(None);
+        agent
+            .connection()
+            .execute(
+                "INSERT INTO audit_log (recorded_at, requestor, claimed_requestor, action, outcome, duration_us) VALUES (datetime('now', '-400 days'), NULL, 'old', 'describe', 'ok', 0)",
+                [],
+            )
+            .expect("inserts old record");
+        record(&agent, &RequestContext::default(), "new");
+
+        assert_eq!(agent.prune_audit_log().expect("prunes"), 1);
+        let records = all_records(&agent);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].claimed_requestor(), "new");
+    }
+}
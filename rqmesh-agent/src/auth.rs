@@ -0,0 +1,244 @@
+//! Verification of signed requests against the keys in the agent store.
+//!
+//! Keys live in `auth_keys`, and the nonce of every signed request accepted
+//! is kept in `auth_nonces` for as long as its signature would still be
+//! fresh, so a request cannot be replayed even across restarts.
+
+use crate::Agent;
+use log::{debug, trace};
+use rqmesh_core::{
+    unix_time, RequestContext, RqMeshEnvelope, RqMeshError, SigningKey, StorageErrorKind,
+    UnauthorizedErrorKind,
+};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::time::{Duration, SystemTime};
+
+type Result<T> = std::result::Result<T, RqMeshError>;
+
+/// How far a request's signing time may be from the agent's clock.
+const REPLAY_WINDOW: Duration = Duration::from_secs(5 * 60);
+
+/// Action a policy allows callers whose word is taken for who sent the
+/// unsigned requests they relay.
+pub const RELAY_ACTION: &str = "relay";
+
+impl Agent {
+    /// Checks the signature on `envelope`, returning it with the key it was
+    /// signed with recorded in its context.
+    ///
+    /// Unsigned requests are let through unless the agent requires
+    /// authentication and the sender did not authenticate with a TLS
+    /// certificate instead.
+    pub fn authenticate(&self, envelope: RqMeshEnvelope) -> Result<RqMeshEnvelope> {
+        let signature = match envelope.signature() {
+            Some(signature) => signature,
+            None if self.context.require_auth()
+                && envelope.context().authenticated_caller().is_none() =>
+            {
+                return Err(RqMeshError::from(
+                    UnauthorizedErrorKind::new_missing_signature(),
+                ))
+            }
+            None => return Ok(envelope),
+        };
+
+        let now = SystemTime::now();
+        let conn = self.connection();
+        let key = load_auth_key(&conn, signature.key_id())?;
+        key.verify(&envelope, now, REPLAY_WINDOW)?;
+
+        let oldest = unix_time(now).saturating_sub(REPLAY_WINDOW.as_secs());
+        let pruned = conn
+            .execute(
+                "DELETE FROM auth_nonces WHERE signed_at < ?1",
+                params![oldest as i64],
+            )
+            .map_err(|e| auth_query_err("prune_nonces", e))?;
+        if pruned > 0 {
+            trace!("Forgot {} nonces outside the replay window", pruned);
+        }
+        let inserted = conn
+            .execute(
+                "INSERT OR IGNORE INTO auth_nonces (key_id, nonce, signed_at) VALUES (?1, ?2, ?3)",
+                params![
+                    signature.key_id(),
+                    signature.nonce(),
+                    signature.timestamp() as i64
+                ],
+            )
+            .map_err(|e| auth_query_err("record_nonce", e))?;
+        if inserted == 0 {
+            return Err(RqMeshError::from(
+                UnauthorizedErrorKind::new_replayed_nonce(signature.key_id()),
+            ));
+        }
+        drop(conn);
+
+        debug!(
+            "{} signed {} with key {}",
+            envelope.requestor(),
+            envelope.action(),
+            signature.key_id()
+        );
+        let context = envelope.context().clone().with_key_id(signature.key_id());
+        Ok(envelope.with_context(context))
+    }
+}
+
+impl Agent {
+    /// Establishes who sent `envelope`, relayed to us by the sender of a
+    /// request received with `relay` on behalf of the caller it established,
+    /// `on_behalf_of`.
+    ///
+    /// A signed envelope is verified as if its signer had sent it to us. The
+    /// relay's word is only taken for an unsigned one if the relay is itself
+    /// authenticated and allowed to [`RELAY_ACTION`]; otherwise the envelope
+    /// has no authenticated caller, and is rejected if the agent requires
+    /// authentication.
+    pub fn authenticate_relayed(
+        &self,
+        relay: &RequestContext,
+        envelope: &RqMeshEnvelope,
+        on_behalf_of: Option<&str>,
+    ) -> Result<RequestContext> {
+        let mut context = RequestContext::new(relay.peer_addr().map(|a| a.to_string()), None);
+        if let Some(relay) = relay.authenticated_caller() {
+            let vouched = match on_behalf_of {
+                Some(caller) if envelope.signature().is_none() => {
+                    match self.authorize(Some(relay), envelope.requestor(), RELAY_ACTION, &[]) {
+                        Ok(()) => Some(caller.to_string()),
+                        Err(e) => {
+                            debug!("Not taking {}'s word for {}: {}", relay, caller, e);
+                            None
+                        }
+                    }
+                }
+                _ => None,
+            };
+            context = context.with_relay(relay, vouched);
+        }
+        let envelope = self.authenticate(envelope.clone().with_context(context))?;
+        trace!(
+            "{} relayed {} for {}",
+            envelope.context().relay().unwrap_or("-"),
+            envelope.action(),
+            envelope.context().caller(envelope.requestor())
+        );
+        Ok(envelope.context().clone())
+    }
+}
+
+/// Stores `key`, replacing any key with the same ID.
+pub fn store_auth_key(conn: &Connection, key: &SigningKey) -> Result<()> {
+    conn.execute(
+        "INSERT INTO auth_keys (key_id, secret, created_at) VALUES (?1, ?2, datetime('now')) ON CONFLICT (key_id) DO UPDATE SET secret = excluded.secret, created_at = excluded.created_at",
+        params![key.key_id(), key.secret()],
+    )
+    .map_err(|e| auth_query_err("store_auth_key", e))?;
+    Ok(())
+}
+
+/// Loads the key stored as `key_id`.
+pub fn load_auth_key(conn: &Connection, key_id: &str) -> Result<SigningKey> {
+    let secret: Option<Vec<u8>> = conn
+        .query_row(
+            "SELECT secret FROM auth_keys WHERE key_id = ?1",
+            params![key_id],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(|e| auth_query_err("load_auth_key", e))?;
+    secret
+        .map(|secret| SigningKey::new(key_id, secret))
+        .ok_or_else(|| RqMeshError::from(UnauthorizedErrorKind::new_unknown_key(key_id)))
+}
+
+fn auth_query_err(operation: &str, e: rusqlite::Error) -> RqMeshError {
+    RqMeshError::from(StorageErrorKind::new_query_err(operation, format!("{}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::initialization::in_memory_agent;
+    use rqmesh_core::{AgentInitializationContext, DescribeAgentRequest, RqMeshFrame};
+
+    fn agent(context: AgentInitializationContext) -> Agent {
+        let agent = in_memory_agent(context);
+        store_auth_key(&agent.connection(), &SigningKey::generate("ops")).expect("stores key");
+        agent
+    }
+
+    fn envelope() -> RqMeshEnvelope {
+        RqMeshFrame::new(DescribeAgentRequest::default(), "alice")
+            .into_envelope()
+            .expect("encodes request")
+    }
+
+    fn signed(agent: &Agent) -> RqMeshEnvelope {
+        let key = load_auth_key(&agent.connection(), "ops").expect("loads key");
+        key.sign(envelope()).expect("signs request")
+    }
+
+    /// A relay that connected with the certificate of `identity`.
+    fn relay(identity: Option<&str>) -> RequestContext {
+        RequestContext::new(
+            Some("127.0.0.1:5000".to_string()),
+            identity.map(|i| i.to_string()),
+        )
+    }
+
+    #[test]
+    fn relayed_requests_keep_the_signer_as_their_caller() {
+        let agent = agent(AgentInitializationContext::new(":memory:", "", ""));
+        let context = agent
+            .authenticate_relayed(&relay(Some("node-a")), &signed(&agent), Some("mallory"))
+            .expect("authenticates");
+        assert_eq!(context.authenticated_caller(), Some("ops"));
+        assert_eq!(context.relay(), Some("node-a"));
+        assert_eq!(context.peer_identity(), None);
+        assert_eq!(context.peer_addr(), Some("127.0.0.1:5000"));
+    }
+
+    #[test]
+    fn relays_are_only_believed_when_authenticated_and_allowed_to_relay() {
+        let mut agent = agent(AgentInitializationContext::new(":memory:", "", ""));
+        let caller = |agent: &Agent, identity| {
+            agent
+                .authenticate_relayed(&relay(identity), &envelope(), Some("alice"))
+                .expect("authenticates")
+                .authenticated_caller()
+                .map(|c| c.to_string())
+        };
+        assert_eq!(caller(&agent, Some("node-a")), Some("alice".to_string()));
+        assert_eq!(caller(&agent, None), None);
+
+        agent.policy = Some(
+            toml::from_str(
+                r#"
+                default = "deny"
+
+                [[rule]]
+                requestors = ["node-*"]
+                actions = ["relay"]
+                "#,
+            )
+            .expect("parses policy"),
+        );
+        assert_eq!(caller(&agent, Some("node-a")), Some("alice".to_string()));
+        assert_eq!(caller(&agent, Some("intruder")), None);
+    }
+
+    #[test]
+    fn unvouched_relayed_requests_need_a_signature_when_auth_is_required() {
+        let agent =
+            agent(AgentInitializationContext::new(":memory:", "", "").with_require_auth(true));
+        match agent.authenticate_relayed(&relay(None), &envelope(), Some("alice")) {
+            Err(RqMeshError::Unauthorized(UnauthorizedErrorKind::MissingSignature)) => {}
+            other => panic!("expected MissingSignature, got {:?}", other),
+        }
+        assert!(agent
+            .authenticate_relayed(&relay(None), &signed(&agent), None)
+            .is_ok());
+    }
+}
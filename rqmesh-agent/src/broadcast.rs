@@ -0,0 +1,271 @@
+//! Fan-out of a single request to every agent matching a capability
+//! selector.
+//!
+//! The receiving agent contacts each member not known to be dead, skips
+//! those that do not advertise the selected capabilities, and relays the
+//! broadcast envelope to the rest as a route request that may not be
+//! forwarded any further. Each of them then establishes who sent the
+//! envelope just as for a routed request: a signed envelope is handled for
+//! its signer, and an unsigned one for the caller the receiving agent
+//! vouches for if it is allowed to relay, never for the receiving agent
+//! itself.
+//! Broadcasts and routes cannot themselves be broadcast, since every agent
+//! reached would fan them out again.
+
+use crate::dispatch::Dispatcher;
+use crate::routing::satisfies_all;
+use crate::Agent;
+use log::{debug, info, trace};
+use rqmesh_client::Client;
+use rqmesh_core::{
+    BroadcastRequest, BroadcastResponse, ListCapabilitiesRequest, MemberState, PeerRecord,
+    ProtocolErrorKind, RequestContext, RouteRequest, RoutingErrorKind, RqMeshError,
+    RqMeshProtocolAction, RqMeshResponseEnvelope,
+};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+type Result<T> = std::result::Result<T, RqMeshError>;
+
+/// How long each agent has to answer a broadcast that does not set a limit.
+const DEFAULT_NODE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Agents contacted at once for a broadcast that does not set a limit.
+const DEFAULT_MAX_CONCURRENCY: u32 = 8;
+
+/// An agent a broadcast may run on.
+enum Target {
+    Local,
+    Peer(PeerRecord),
+}
+
+impl Agent {
+    /// Runs the envelope of `request` on every matching agent, using `local`
+    /// to dispatch it with `context` when this agent matches.
+    pub fn broadcast(
+        &self,
+        local: &Dispatcher,
+        context: &RequestContext,
+        request: &BroadcastRequest,
+    ) -> Result<BroadcastResponse> {
+        let envelope = request.envelope();
+        local.authorize(self, &envelope.clone().with_context(context.clone()))?;
+        let node_timeout = request.node_timeout().unwrap_or(DEFAULT_NODE_TIMEOUT);
+        let concurrency = request
+            .max_concurrency()
+            .unwrap_or(DEFAULT_MAX_CONCURRENCY)
+            .max(1) as usize;
+
+        let mut targets = Vec::new();
+        if local.actions().any(|a| a == envelope.action())
+            && satisfies_all(&self.list_capabilities(None)?, request.selector())
+        {
+            targets.push(Target::Local);
+        }
+        targets.extend(
+            self.list_peers()?
+                .into_iter()
+                .filter(|p| p.state() != MemberState::Dead)
+                .map(Target::Peer),
+        );
+        info!(
+            "Broadcasting {} from {} to up to {} agents, {} at a time",
+            envelope.action(),
+            envelope.requestor(),
+            targets.len(),
+            concurrency
+        );
+
+        let workers = concurrency.min(targets.len());
+        let pending = Mutex::new(targets.into_iter());
+        let results = Mutex::new(BTreeMap::new());
+        thread::scope(|scope| {
+            for _ in 0..workers {
+                scope.spawn(|| loop {
+                    let next = pending.lock().unwrap_or_else(|e| e.into_inner()).next();
+                    let target = match next {
+                        Some(target) => target,
+                        None => break,
+                    };
+                    if let Some((node, response)) =
+                        self.broadcast_to(local, context, target, request, node_timeout)
+                    {
+                        results
+                            .lock()
+                            .unwrap_or_else(|e| e.into_inner())
+                            .insert(node, response);
+                    }
+                });
+            }
+        });
+
+        let results = results.into_inner().unwrap_or_else(|e| e.into_inner());
+        debug!(
+            "Broadcast of {} ran on {} agents",
+            envelope.action(),
+            results.len()
+        );
+        Ok(BroadcastResponse::new(results))
+    }
+
+    /// Runs the broadcast on `target`, returning the key and response to
+    /// report for it, or `None` if it does not match the selector.
+    fn broadcast_to(
+        &self,
+        local: &Dispatcher,
+        context: &RequestContext,
+        target: Target,
+        request: &BroadcastRequest,
+        node_timeout: Duration,
+    ) -> Option<(String, RqMeshResponseEnvelope)> {
+        let envelope = request.envelope();
+        let peer = match target {
+            Target::Local => {
+                let response = local.dispatch(self, envelope.clone().with_context(context.clone()));
+                return Some((self.node_id().to_string(), response));
+            }
+            Target::Peer(peer) => peer,
+        };
+
+        let started = Instant::now();
+        let mut node = peer
+            .node_id()
+            .unwrap_or_else(|| peer.endpoint())
+            .to_string();
+        let result = self
+            .connect_to(peer.endpoint(), self.node_id(), node_timeout)
+            .and_then(|mut client| {
+                node = client.remote().identity().to_string();
+                self.send_broadcast(&mut client, context, request)
+            });
+        match result {
+            Ok(Some(response)) => {
+                trace!("{} answered broadcast of {}", &node, envelope.action());
+                Some((node, response))
+            }
+            Ok(None) => {
+                trace!("{} does not match broadcast selector", &node);
+                None
+            }
+            Err(e) => {
+                debug!("Broadcast to {} failed: {}", peer.endpoint(), e);
+                let e = if started.elapsed() >= node_timeout {
+                    RqMeshError::from(RoutingErrorKind::new_node_timed_out(
+                        node.as_str(),
+                        node_timeout,
+                    ))
+                } else {
+                    e
+                };
+                Some((node, RqMeshResponseEnvelope::new_err(envelope.action(), e)))
+            }
+        }
+    }
+
+    /// Relays the envelope of `request` for the caller of `context` over
+    /// `client`, if the agent matches the selector of `request`.
+    fn send_broadcast(
+        &self,
+        client: &mut Client,
+        context: &RequestContext,
+        request: &BroadcastRequest,
+    ) -> Result<Option<RqMeshResponseEnvelope>> {
+        if !request.selector().is_empty() {
+            let capabilities = client
+                .call(ListCapabilitiesRequest::default())?
+                .into_capabilities();
+            if !satisfies_all(&capabilities, request.selector()) {
+                return Ok(None);
+            }
+        }
+        let envelope = request.envelope();
+        for action in [envelope.action(), RouteRequest::ACTION] {
+            if !client.remote().supports(action) {
+                return Err(RqMeshError::from(ProtocolErrorKind::new_unknown_action(
+                    action,
+                )));
+            }
+        }
+        let relayed = RouteRequest::new(envelope.clone(), request.selector().to_vec())
+            .with_max_hops(1)
+            .forwarded(context.authenticated_caller());
+        let routed = client.call(relayed)?;
+        Ok(Some(routed.response().clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dispatch::ActionHandler;
+    use crate::initialization::in_memory_agent;
+    use rqmesh_core::{
+        AgentInitializationContext, MembershipUpdate, PingRequest, PingResponse, RqMeshFrame,
+    };
+    use std::net::TcpListener;
+
+    struct PongHandler;
+
+    impl ActionHandler for PongHandler {
+        type Action = PingRequest;
+
+        fn handle(&self, _agent: &Agent, _frame: RqMeshFrame<PingRequest>) -> Result<PingResponse> {
+            Ok(PingResponse::default())
+        }
+    }
+
+    fn member(endpoint: &str, node_id: &str, state: MemberState) -> MembershipUpdate {
+        MembershipUpdate::new(endpoint, "0.1.0", state, 0).with_node_id(Some(node_id.to_string()))
+    }
+
+    #[test]
+    fn unreachable_and_silent_agents_are_reported_alongside_the_local_result() {
+        let agent = in_memory_agent(AgentInitializationContext::new(":memory:", "", ""));
+        let refused = TcpListener::bind("127.0.0.1:0")
+            .expect("binds")
+            .local_addr()
+            .expect("has an address")
+            .to_string();
+        // Accepted by the kernel but never answered, so the handshake stalls.
+        let silent = TcpListener::bind("127.0.0.1:0").expect("binds");
+        let silent_addr = silent.local_addr().expect("has an address").to_string();
+        agent.apply_membership_updates(&[
+            member(&refused, "refused", MemberState::Alive),
+            member(&silent_addr, "silent", MemberState::Alive),
+            member("127.0.0.1:1", "gone", MemberState::Dead),
+        ]);
+        let mut local = Dispatcher::new();
+        local.register(PongHandler);
+        let envelope = RqMeshFrame::new(PingRequest::default(), "alice")
+            .into_envelope()
+            .expect("encodes request");
+        let node_timeout = Duration::from_millis(300);
+        let request = BroadcastRequest::new(envelope, Vec::new()).with_node_timeout(node_timeout);
+
+        let results = agent
+            .broadcast(&local, &RequestContext::new(None, None), &request)
+            .expect("broadcasts")
+            .into_results::<PingRequest>();
+
+        let nodes: Vec<&str> = results.keys().map(String::as_str).collect();
+        let mut expected = vec![agent.node_id(), "refused", "silent"];
+        expected.sort_unstable();
+        assert_eq!(nodes, expected);
+        assert!(results[agent.node_id()].is_ok());
+        assert!(
+            matches!(&results["refused"], Err(RqMeshError::TransportError(_))),
+            "expected a connection error, got {:?}",
+            results["refused"]
+        );
+        match &results["silent"] {
+            Err(RqMeshError::RoutingError(RoutingErrorKind::NodeTimedOut { node, timeout })) => {
+                assert_eq!(node, "silent");
+                assert_eq!(*timeout, node_timeout);
+            }
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+        drop(silent);
+    }
+}
@@ -0,0 +1,130 @@
+use crate::{process, Agent};
+use log::{debug, info, trace, warn};
+use rqmesh_core::{
+    CapabilityBroadcast, CapabilitySource, CommandSpec, InitializationErrorKind, RqMeshError,
+    StorageErrorKind,
+};
+use rusqlite::{params, Connection};
+
+type Result<T> = std::result::Result<T, RqMeshError>;
+
+/// Capabilities probed for on every start, as the capability name and the
+/// command whose success indicates it is present.
+const DETECTED_CAPABILITIES: &[(&str, &str)] = &[
+    ("sqlite", "sqlite3 --version"),
+    ("docker", "docker --version"),
+    ("git", "git --version"),
+];
+
+/// Runs each of the [`DETECTED_CAPABILITIES`] probes, returning those that
+/// succeeded within [`process::PROBE_TIMEOUT`] along with the first
+/// version-looking token of their output.
+pub fn detect_capabilities() -> Vec<CapabilityBroadcast> {
+    DETECTED_CAPABILITIES
+        .iter()
+        .filter_map(|(name, probe)| {
+            let command = match CommandSpec::parse(probe) {
+                Ok(command) => command.with_timeout(process::PROBE_TIMEOUT),
+                Err(e) => {
+                    warn!("Not probing for capability {}: {}", name, e);
+                    return None;
+                }
+            };
+            trace!("Probing for capability {} with {}", name, &command);
+            let output = process::run_spec(&command, &[])
+                .ok()
+                .filter(|o| o.status.success())?;
+            let version = parse_version(&String::from_utf8_lossy(&output.stdout));
+            debug!("Detected capability {} version {:?}", name, &version);
+            Some(CapabilityBroadcast::new(
+                *name,
+                version,
+                CapabilitySource::Detected,
+            ))
+        })
+        .collect()
+}
+
+/// The first version-looking token in the output of a `--version` style
+/// command, such as `2.39.2` in `git version 2.39.2` or in `v2.39.2,`.
+pub fn parse_version(output: &str) -> Option<String> {
+    output
+        .split_whitespace()
+        .map(|t| t.trim_start_matches('v').trim_end_matches(','))
+        .find(|t| t.starts_with(|c: char| c.is_ascii_digit()) && t.contains('.'))
+        .map(|t| t.to_string())
+}
+
+/// Replaces the stored capabilities with `capabilities`, so the table always
+/// reflects what was declared and detected on the most recent start.
+pub fn record_capabilities(conn: &Connection, capabilities: &[CapabilityBroadcast]) -> Result<()> {
+    let init_err = |e: rusqlite::Error| {
+        RqMeshError::from(InitializationErrorKind::new_sqlite_init_err(format!(
+            "Error recording capabilities: {}",
+            e
+        )))
+    };
+
+    conn.execute("DELETE FROM capabilities", [])
+        .map_err(init_err)?;
+    for capability in capabilities {
+        info!(
+            "Advertising {} capability {} (version {})",
+            capability.source(),
+            capability.capability_type(),
+            capability.version().unwrap_or("unknown")
+        );
+        conn.execute(
+            "INSERT OR REPLACE INTO capabilities (capability_type, version, source, updated_at) VALUES (?1, ?2, ?3, datetime('now'))",
+            params![
+                capability.capability_type(),
+                capability.version(),
+                capability.source().to_string()
+            ],
+        )
+        .map_err(init_err)?;
+    }
+    Ok(())
+}
+
+impl Agent {
+    /// Capabilities this agent advertises, optionally only those of
+    /// `capability_type`.
+    pub fn list_capabilities(
+        &self,
+        capability_type: Option<&str>,
+    ) -> Result<Vec<CapabilityBroadcast>> {
+        let query_err = |e: rusqlite::Error| {
+            RqMeshError::from(StorageErrorKind::new_query_err(
+                "list_capabilities",
+                format!("{}", e),
+            ))
+        };
+
+        let conn = self.connection();
+        let mut stmt = conn
+            .prepare(
+                "SELECT capability_type, version, source FROM capabilities WHERE ?1 IS NULL OR capability_type = ?1 ORDER BY capability_type",
+            )
+            .map_err(query_err)?;
+        let rows = stmt
+            .query_map(params![capability_type], |row| {
+                let capability_type: String = row.get(0)?;
+                let version: Option<String> = row.get(1)?;
+                let source: String = row.get(2)?;
+                Ok((capability_type, version, source))
+            })
+            .map_err(query_err)?;
+
+        let mut capabilities = Vec::new();
+        for row in rows {
+            let (capability_type, version, source) = row.map_err(query_err)?;
+            capabilities.push(CapabilityBroadcast::new(
+                capability_type,
+                version,
+                source.parse()?,
+            ));
+        }
+        Ok(capabilities)
+    }
+}
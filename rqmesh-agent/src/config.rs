@@ -0,0 +1,757 @@
+//! Agent settings, layered from defaults, config files, environment
+//! variables and flags.
+//!
+//! A config file is TOML, with every key optional and defaulting to the
+//! value shown:
+//!
+//! ```toml
+//! [store]
+//! location = "./.rqmesh-agent.db"
+//! audit_retention_days = 30      # 0 keeps audit records forever
+//! audit_max_rows = 1000000       # 0 for no limit
+//!
+//! [dependencies]
+//! check_command = "apk list sqlite --installed"
+//! install_command = "apk add sqlite"
+//! shell = false                  # run the commands with /bin/sh -c
+//! # manifest = "/etc/rqmesh/dependencies.toml"
+//! packages = []                  # e.g. ["sqlite>=3.40"]
+//! # package_manager = "apt"      # detected from the host by default
+//! no_install = false
+//! # privilege_helper = "sudo -n"
+//!
+//! [listener]
+//! bind = "127.0.0.1"
+//! port = 0                       # 0 picks a free port
+//! # port_file = "/run/rqmesh/port"
+//! # advertise_host = "node-1.example.com"
+//! workers = 8
+//! job_workers = 2
+//!
+//! [logging]
+//! level = "warn"                 # off, error, warn, info, debug or trace
+//!
+//! [peers]
+//! seeds = []                     # e.g. ["10.0.0.2:4100"]
+//! capabilities = []              # e.g. ["gpu", "docker=24.0"]
+//! protocol_period_ms = 1000      # time between failure detector probes
+//! suspect_timeout_ms = 5000      # time a suspect has to refute
+//!
+//! [tls]
+//! # cert = "/etc/rqmesh/node.pem"
+//! # key = "/etc/rqmesh/node.key"
+//! # ca = "/etc/rqmesh/ca.pem"
+//!
+//! [auth]
+//! # key_id = "node-1"          # ID of a stored signing key
+//! require = false
+//!
+//! [policy]
+//! # file = "/etc/rqmesh/policy.toml"
+//! dry_run = false
+//! ```
+//!
+//! Each setting is taken from the first of these that sets it:
+//!
+//! 1. its flag, such as `--port`;
+//! 2. its environment variable, named `RQMESH_` followed by the key with the
+//!    dot replaced by an underscore, in upper case, such as
+//!    `RQMESH_LISTENER_PORT`, with lists separated by commas;
+//! 3. the config files given with `--config`, or `RQMESH_CONFIG` without
+//!    one, the last file setting it winning;
+//! 4. its default.
+//!
+//! Lists are replaced as a whole rather than added to. `RUST_LOG_LEVEL` is
+//! still read for `logging.level`, below `RQMESH_LOGGING_LEVEL`, and ignored
+//! with a warning when it is not a level.
+
+use crate::dependencies::DependencyManifest;
+use crate::packages;
+use crate::policy::Policy;
+use log::LevelFilter;
+use rqmesh_core::{
+    AgentInitializationContext, CapabilityBroadcast, CommandSpec, InitializationErrorKind,
+    PackageRequirement, RqMeshError, TlsConfig, DEFAULT_PROTOCOL_PERIOD, DEFAULT_SUSPECT_TIMEOUT,
+};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use toml::value::{Table, Value};
+
+type Result<T> = std::result::Result<T, RqMeshError>;
+
+/// Environment variable naming a config file when no `--config` is given.
+pub const CONFIG_ENV: &str = "RQMESH_CONFIG";
+
+/// Environment variable the log level was read from before config files,
+/// still honoured below `RQMESH_LOGGING_LEVEL`.
+const LEGACY_LOG_LEVEL_ENV: &str = "RUST_LOG_LEVEL";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AgentConfig {
+    pub store: StoreConfig,
+    pub dependencies: DependenciesConfig,
+    pub listener: ListenerConfig,
+    pub logging: LoggingConfig,
+    pub peers: PeersConfig,
+    pub tls: TlsSettings,
+    pub auth: AuthConfig,
+    pub policy: PolicyConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StoreConfig {
+    pub location: PathBuf,
+    pub audit_retention_days: u64,
+    pub audit_max_rows: u64,
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        StoreConfig {
+            location: PathBuf::from("./.rqmesh-agent.db"),
+            audit_retention_days: 30,
+            audit_max_rows: 1_000_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DependenciesConfig {
+    pub check_command: String,
+    pub install_command: String,
+    pub shell: bool,
+    pub manifest: Option<PathBuf>,
+    pub packages: Vec<String>,
+    pub package_manager: Option<String>,
+    pub no_install: bool,
+    pub privilege_helper: Option<String>,
+}
+
+impl Default for DependenciesConfig {
+    fn default() -> Self {
+        DependenciesConfig {
+            check_command: "apk list sqlite --installed".to_string(),
+            install_command: "apk add sqlite".to_string(),
+            shell: false,
+            manifest: None,
+            packages: Vec::new(),
+            package_manager: None,
+            no_install: false,
+            privilege_helper: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenerConfig {
+    pub bind: IpAddr,
+    pub port: u16,
+    pub port_file: Option<PathBuf>,
+    pub advertise_host: Option<String>,
+    pub workers: usize,
+    pub job_workers: usize,
+}
+
+impl Default for ListenerConfig {
+    fn default() -> Self {
+        ListenerConfig {
+            bind: IpAddr::from([127, 0, 0, 1]),
+            port: 0,
+            port_file: None,
+            advertise_host: None,
+            workers: 8,
+            job_workers: 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub level: String,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            level: "warn".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PeersConfig {
+    pub seeds: Vec<String>,
+    pub capabilities: Vec<String>,
+    pub protocol_period_ms: u64,
+    pub suspect_timeout_ms: u64,
+}
+
+impl Default for PeersConfig {
+    fn default() -> Self {
+        PeersConfig {
+            seeds: Vec::new(),
+            capabilities: Vec::new(),
+            protocol_period_ms: DEFAULT_PROTOCOL_PERIOD.as_millis() as u64,
+            suspect_timeout_ms: DEFAULT_SUSPECT_TIMEOUT.as_millis() as u64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsSettings {
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    pub ca: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub key_id: Option<String>,
+    pub require: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolicyConfig {
+    pub file: Option<PathBuf>,
+    pub dry_run: bool,
+}
+
+/// How a setting given as a single string, by an environment variable or a
+/// flag, becomes a TOML value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Text,
+    Integer,
+    Flag,
+    List,
+}
+
+/// A setting that can be overridden by an environment variable and a flag,
+/// given by its section and key in a config file, separated by a dot, the
+/// name of the clap argument setting it and its kind.
+struct Setting(&'static str, &'static str, Kind);
+
+/// Every setting, in the order of the config file.
+const SETTINGS: &[Setting] = &[
+    Setting("store.location", "STORE_LOCATION", Kind::Text),
+    Setting(
+        "store.audit_retention_days",
+        "AUDIT_RETENTION_DAYS",
+        Kind::Integer,
+    ),
+    Setting("store.audit_max_rows", "AUDIT_MAX_ROWS", Kind::Integer),
+    Setting("dependencies.check_command", "CHECK_CMD", Kind::Text),
+    Setting("dependencies.install_command", "INSTALL_CMD", Kind::Text),
+    Setting("dependencies.shell", "SHELL_CMDS", Kind::Flag),
+    Setting("dependencies.manifest", "DEPENDENCY_MANIFEST", Kind::Text),
+    Setting("dependencies.packages", "PACKAGE", Kind::List),
+    Setting(
+        "dependencies.package_manager",
+        "PACKAGE_MANAGER",
+        Kind::Text,
+    ),
+    Setting("dependencies.no_install", "NO_INSTALL", Kind::Flag),
+    Setting(
+        "dependencies.privilege_helper",
+        "PRIVILEGE_HELPER",
+        Kind::Text,
+    ),
+    Setting("listener.bind", "BIND", Kind::Text),
+    Setting("listener.port", "PORT", Kind::Integer),
+    Setting("listener.port_file", "PORT_FILE", Kind::Text),
+    Setting("listener.advertise_host", "ADVERTISE_HOST", Kind::Text),
+    Setting("listener.workers", "WORKERS", Kind::Integer),
+    Setting("listener.job_workers", "JOB_WORKERS", Kind::Integer),
+    Setting("logging.level", "LOG_LEVEL", Kind::Text),
+    Setting("peers.seeds", "SEED", Kind::List),
+    Setting("peers.capabilities", "CAPABILITY", Kind::List),
+    Setting(
+        "peers.protocol_period_ms",
+        "PROTOCOL_PERIOD_MS",
+        Kind::Integer,
+    ),
+    Setting(
+        "peers.suspect_timeout_ms",
+        "SUSPECT_TIMEOUT_MS",
+        Kind::Integer,
+    ),
+    Setting("tls.cert", "TLS_CERT", Kind::Text),
+    Setting("tls.key", "TLS_KEY", Kind::Text),
+    Setting("tls.ca", "TLS_CA", Kind::Text),
+    Setting("auth.key_id", "AUTH_KEY", Kind::Text),
+    Setting("auth.require", "REQUIRE_AUTH", Kind::Flag),
+    Setting("policy.file", "POLICY", Kind::Text),
+    Setting("policy.dry_run", "DRY_RUN_POLICY", Kind::Flag),
+];
+
+impl Setting {
+    fn key(&self) -> &'static str {
+        self.0
+    }
+
+    fn arg(&self) -> &'static str {
+        self.1
+    }
+
+    fn kind(&self) -> Kind {
+        self.2
+    }
+
+    fn env_var(&self) -> String {
+        format!(
+            "RQMESH_{}",
+            self.key().replace('.', "_").to_ascii_uppercase()
+        )
+    }
+
+    /// Reads `raw`, given by `source`, as this setting's value.
+    fn parse(&self, source: &str, raw: &str) -> Result<Value> {
+        let invalid = |message: String| {
+            RqMeshError::from(InitializationErrorKind::new_invalid_config(source, message))
+        };
+        match self.kind() {
+            Kind::Text => Ok(Value::String(raw.to_string())),
+            Kind::Integer => raw
+                .trim()
+                .parse::<i64>()
+                .map(Value::Integer)
+                .map_err(|e| invalid(format!("{} must be an integer: {}", self.key(), e))),
+            Kind::Flag => match raw.trim().to_ascii_lowercase().as_str() {
+                "1" | "true" | "yes" | "on" => Ok(Value::Boolean(true)),
+                "0" | "false" | "no" | "off" | "" => Ok(Value::Boolean(false)),
+                _ => Err(invalid(format!("{} must be true or false", self.key()))),
+            },
+            Kind::List => Ok(Value::Array(
+                raw.split(',')
+                    .map(str::trim)
+                    .filter(|item| !item.is_empty())
+                    .map(|item| Value::String(item.to_string()))
+                    .collect(),
+            )),
+        }
+    }
+}
+
+/// Config files to read, from `--config` or else [`CONFIG_ENV`].
+pub fn config_files(matches: &clap::ArgMatches) -> Vec<PathBuf> {
+    match matches.values_of("CONFIG") {
+        Some(paths) => paths.map(PathBuf::from).collect(),
+        None => std::env::var_os(CONFIG_ENV)
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from)
+            .into_iter()
+            .collect(),
+    }
+}
+
+impl AgentConfig {
+    /// The settings in effect given the config `files`, the environment and
+    /// the flags in `matches`.
+    pub fn load(files: &[PathBuf], matches: &clap::ArgMatches) -> Result<AgentConfig> {
+        let mut layered = Table::new();
+        for path in files {
+            merge(&mut layered, read_file(path)?);
+        }
+
+        // Unlike every other setting, a bad legacy level is only warned
+        // about, as it always was. Logging is not set up yet, hence eprintln.
+        if let Ok(level) = std::env::var(LEGACY_LOG_LEVEL_ENV) {
+            if level.parse::<LevelFilter>().is_ok() {
+                set(&mut layered, "logging.level", Value::String(level));
+            } else {
+                eprintln!(
+                    "Ignoring {}={}, which is not one of off, error, warn, info, debug or trace",
+                    LEGACY_LOG_LEVEL_ENV, level
+                );
+            }
+        }
+        for setting in SETTINGS {
+            let var = setting.env_var();
+            match std::env::var(&var) {
+                Ok(raw) => set(&mut layered, setting.key(), setting.parse(&var, &raw)?),
+                Err(std::env::VarError::NotPresent) => {}
+                Err(e) => {
+                    return Err(RqMeshError::from(
+                        InitializationErrorKind::new_invalid_config(var, format!("{}", e)),
+                    ))
+                }
+            }
+        }
+
+        for setting in SETTINGS {
+            if setting.kind() == Kind::Flag {
+                if matches.is_present(setting.arg()) {
+                    set(&mut layered, setting.key(), Value::Boolean(true));
+                }
+            } else if setting.kind() == Kind::List {
+                if let Some(values) = matches.values_of(setting.arg()) {
+                    let values = values.map(|v| Value::String(v.to_string())).collect();
+                    set(&mut layered, setting.key(), Value::Array(values));
+                }
+            } else if let Some(raw) = matches.value_of(setting.arg()) {
+                let source = format!("--{}", setting.arg().replace('_', "-").to_ascii_lowercase());
+                set(&mut layered, setting.key(), setting.parse(&source, raw)?);
+            }
+        }
+
+        let config = from_table(layered, "effective config")?;
+        config.check()?;
+        Ok(config)
+    }
+
+    /// Reads the settings in `path` on top of the defaults alone, checking
+    /// them and the dependency manifest and policy they name.
+    pub fn validate_file(path: &Path) -> Result<AgentConfig> {
+        let config = from_table(read_file(path)?, &path.to_string_lossy())?;
+        config.check()?;
+        config.check_referenced_files()?;
+        Ok(config)
+    }
+
+    /// Level messages are logged at.
+    pub fn log_level(&self) -> LevelFilter {
+        self.logging.level.parse().unwrap_or(LevelFilter::Warn)
+    }
+
+    /// The settings as a config file.
+    pub fn to_toml(&self) -> Result<String> {
+        toml::to_string(self).map_err(|e| {
+            RqMeshError::from(InitializationErrorKind::new_invalid_config(
+                "effective config",
+                format!("{}", e),
+            ))
+        })
+    }
+
+    /// Checks every setting makes sense, short of reading the files named.
+    pub fn check(&self) -> Result<()> {
+        self.to_context().map(|_| ())
+    }
+
+    /// Checks that the dependency manifest and policy named, if any, can be
+    /// loaded.
+    pub fn check_referenced_files(&self) -> Result<()> {
+        if let Some(manifest) = &self.dependencies.manifest {
+            DependencyManifest::load(manifest)?;
+        }
+        if let Some(policy) = &self.policy.file {
+            Policy::load(policy)?;
+        }
+        Ok(())
+    }
+
+    /// The context an agent initializes from with these settings.
+    pub fn to_context(&self) -> Result<AgentInitializationContext> {
+        let invalid = |key: &str, message: String| {
+            RqMeshError::from(InitializationErrorKind::new_invalid_config(key, message))
+        };
+        if self.logging.level.parse::<LevelFilter>().is_err() {
+            return Err(invalid(
+                "logging.level",
+                format!(
+                    "{} is not one of off, error, warn, info, debug or trace",
+                    self.logging.level
+                ),
+            ));
+        }
+        if self.listener.workers == 0 {
+            return Err(invalid(
+                "listener.workers",
+                "must be at least 1".to_string(),
+            ));
+        }
+        if self.listener.job_workers == 0 {
+            return Err(invalid(
+                "listener.job_workers",
+                "must be at least 1".to_string(),
+            ));
+        }
+        if self.peers.protocol_period_ms == 0 {
+            return Err(invalid(
+                "peers.protocol_period_ms",
+                "must be at least 1".to_string(),
+            ));
+        }
+        if !self.dependencies.shell {
+            CommandSpec::parse(&self.dependencies.check_command)
+                .map_err(|e| invalid("dependencies.check_command", format!("{}", e)))?;
+            CommandSpec::parse(&self.dependencies.install_command)
+                .map_err(|e| invalid("dependencies.install_command", format!("{}", e)))?;
+        }
+        if let Some(helper) = &self.dependencies.privilege_helper {
+            CommandSpec::parse(helper)
+                .map_err(|e| invalid("dependencies.privilege_helper", format!("{}", e)))?;
+        }
+        if let Some(package_manager) = &self.dependencies.package_manager {
+            if !packages::PACKAGE_MANAGERS.contains(&package_manager.as_str()) {
+                return Err(invalid(
+                    "dependencies.package_manager",
+                    format!(
+                        "{} is not one of {}",
+                        package_manager,
+                        packages::PACKAGE_MANAGERS.join(", ")
+                    ),
+                ));
+            }
+        }
+        if self.policy.dry_run && self.policy.file.is_none() {
+            return Err(invalid(
+                "policy.dry_run",
+                "needs a policy.file to dry run".to_string(),
+            ));
+        }
+
+        let mut ctx = AgentInitializationContext::new(
+            self.store.location.clone(),
+            &self.dependencies.check_command,
+            &self.dependencies.install_command,
+        )
+        .with_listen_address((self.listener.bind, self.listener.port))
+        .with_audit_retention(
+            Some(self.store.audit_retention_days)
+                .filter(|&days| days > 0)
+                .map(|days| Duration::from_secs(days * 24 * 60 * 60)),
+        )
+        .with_audit_max_rows(Some(self.store.audit_max_rows).filter(|&rows| rows > 0))
+        .with_protocol_period(Duration::from_millis(self.peers.protocol_period_ms))
+        .with_suspect_timeout(Duration::from_millis(self.peers.suspect_timeout_ms))
+        .with_shell_commands(self.dependencies.shell)
+        .with_no_install(self.dependencies.no_install)
+        .with_require_auth(self.auth.require);
+        if let Some(port_file) = &self.listener.port_file {
+            ctx = ctx.with_port_file(port_file);
+        }
+        if let Some(advertise_host) = &self.listener.advertise_host {
+            ctx = ctx.with_advertise_host(advertise_host);
+        }
+        for declaration in &self.peers.capabilities {
+            ctx = ctx.with_capability(CapabilityBroadcast::parse_declared(declaration)?);
+        }
+        for requirement in &self.dependencies.packages {
+            ctx = ctx.with_package(PackageRequirement::parse(requirement)?);
+        }
+        if let Some(manifest) = &self.dependencies.manifest {
+            ctx = ctx.with_dependency_manifest(manifest);
+        }
+        if let Some(package_manager) = &self.dependencies.package_manager {
+            ctx = ctx.with_package_manager(package_manager);
+        }
+        if let Some(helper) = &self.dependencies.privilege_helper {
+            ctx = ctx.with_privilege_helper(helper);
+        }
+        for seed in &self.peers.seeds {
+            ctx = ctx.with_seed(seed);
+        }
+        match (&self.tls.cert, &self.tls.key, &self.tls.ca) {
+            (Some(cert), Some(key), Some(ca)) => {
+                ctx = ctx.with_tls(TlsConfig::new(cert, key, ca));
+            }
+            (None, None, None) => {}
+            _ => {
+                return Err(invalid(
+                    "tls",
+                    "cert, key and ca must be set together".to_string(),
+                ))
+            }
+        }
+        if let Some(key_id) = &self.auth.key_id {
+            ctx = ctx.with_auth_key(key_id);
+        }
+        if let Some(policy) = &self.policy.file {
+            ctx = ctx
+                .with_policy(policy)
+                .with_dry_run_policy(self.policy.dry_run);
+        }
+        Ok(ctx)
+    }
+}
+
+fn read_file(path: &Path) -> Result<Table> {
+    let invalid = |message: String| {
+        RqMeshError::from(InitializationErrorKind::new_invalid_config(
+            path.to_string_lossy(),
+            message,
+        ))
+    };
+    let text = std::fs::read_to_string(path).map_err(|e| invalid(format!("{}", e)))?;
+    toml::from_str(&text).map_err(|e| invalid(format!("{}", e)))
+}
+
+/// Checks the layered settings against the schema, filling in defaults.
+fn from_table(table: Table, source: &str) -> Result<AgentConfig> {
+    Value::Table(table).try_into().map_err(|e| {
+        RqMeshError::from(InitializationErrorKind::new_invalid_config(
+            source,
+            format!("{}", e),
+        ))
+    })
+}
+
+/// Lays `overlay` over `base`, key by key within each section.
+fn merge(base: &mut Table, overlay: Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base)), Value::Table(overlay)) => merge(base, overlay),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Sets the dotted `key` in `table`, replacing whatever it was.
+fn set(table: &mut Table, key: &str, value: Value) {
+    let (section, key) = key.split_once('.').expect("setting keys have a section");
+    let section = table
+        .entry(section.to_string())
+        .or_insert_with(|| Value::Table(Table::new()));
+    // A section that is not a table is left for the schema to reject.
+    if let Value::Table(section) = section {
+        section.insert(key.to_string(), value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Serializes the tests that set environment variables `load` reads.
+    static ENV: Mutex<()> = Mutex::new(());
+
+    /// Matches for the few flags these tests give, named as in `main`.
+    fn matches(args: &[&str]) -> clap::ArgMatches<'static> {
+        clap::App::new("rqmesh-agent")
+            .arg(clap::Arg::with_name("PORT").long("port").takes_value(true))
+            .arg(
+                clap::Arg::with_name("SEED")
+                    .long("seed")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1),
+            )
+            .arg(clap::Arg::with_name("NO_INSTALL").long("no-install"))
+            .get_matches_from(std::iter::once("rqmesh-agent").chain(args.iter().copied()))
+    }
+
+    fn config_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "rqmesh-config-test-{}-{}.toml",
+            std::process::id(),
+            name
+        ));
+        std::fs::write(&path, contents).expect("writes config file");
+        path
+    }
+
+    fn with_env<T>(vars: &[(&str, &str)], f: impl FnOnce() -> T) -> T {
+        let _guard = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        for (name, value) in vars {
+            std::env::set_var(name, value);
+        }
+        let result = f();
+        for (name, _) in vars {
+            std::env::remove_var(name);
+        }
+        result
+    }
+
+    #[test]
+    fn later_files_override_earlier_ones_key_by_key() {
+        let first = config_file(
+            "first",
+            "[listener]\nport = 4100\nworkers = 3\n[peers]\nseeds = [\"a:1\", \"b:1\"]\n",
+        );
+        let second = config_file(
+            "second",
+            "[listener]\nport = 4200\n[peers]\nseeds = [\"c:1\"]\n",
+        );
+        let config =
+            with_env(&[], || AgentConfig::load(&[first, second], &matches(&[]))).expect("loads");
+        assert_eq!(config.listener.port, 4200);
+        assert_eq!(config.listener.workers, 3);
+        assert_eq!(config.listener.job_workers, 2);
+        assert_eq!(config.peers.seeds, ["c:1"]);
+    }
+
+    #[test]
+    fn environment_overrides_files_and_flags_override_environment() {
+        let file = config_file(
+            "layers",
+            "[listener]\nport = 4100\nworkers = 3\njob_workers = 4\n[peers]\nseeds = [\"a:1\"]\n",
+        );
+        let config = with_env(
+            &[
+                ("RQMESH_LISTENER_PORT", "4200"),
+                ("RQMESH_LISTENER_WORKERS", "5"),
+                ("RQMESH_PEERS_SEEDS", "b:1, c:1"),
+                ("RQMESH_DEPENDENCIES_NO_INSTALL", "false"),
+            ],
+            || {
+                AgentConfig::load(
+                    &[file],
+                    &matches(&["--port", "4300", "--seed", "d:1", "--no-install"]),
+                )
+            },
+        )
+        .expect("loads");
+        assert_eq!(config.listener.port, 4300);
+        assert_eq!(config.listener.workers, 5);
+        assert_eq!(config.listener.job_workers, 4);
+        assert_eq!(config.peers.seeds, ["d:1"]);
+        assert!(config.dependencies.no_install);
+    }
+
+    #[test]
+    fn environment_lists_are_split_on_commas() {
+        let config = with_env(&[("RQMESH_PEERS_SEEDS", "b:1, c:1,")], || {
+            AgentConfig::load(&[], &matches(&[]))
+        })
+        .expect("loads");
+        assert_eq!(config.peers.seeds, ["b:1", "c:1"]);
+    }
+
+    #[test]
+    fn malformed_environment_values_name_their_variable() {
+        let result = with_env(&[("RQMESH_LISTENER_PORT", "http")], || {
+            AgentConfig::load(&[], &matches(&[]))
+        });
+        match result {
+            Err(RqMeshError::InitializationError(InitializationErrorKind::InvalidConfig {
+                source,
+                ..
+            })) => assert_eq!(source, "RQMESH_LISTENER_PORT"),
+            other => panic!("expected InvalidConfig, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn legacy_log_level_sits_below_its_replacement_and_is_lenient() {
+        let level = |vars: &[(&str, &str)]| {
+            with_env(vars, || AgentConfig::load(&[], &matches(&[])))
+                .expect("loads")
+                .logging
+                .level
+        };
+        assert_eq!(level(&[(LEGACY_LOG_LEVEL_ENV, "debug")]), "debug");
+        assert_eq!(level(&[(LEGACY_LOG_LEVEL_ENV, "loud")]), "warn");
+        assert_eq!(
+            level(&[
+                (LEGACY_LOG_LEVEL_ENV, "debug"),
+                ("RQMESH_LOGGING_LEVEL", "error")
+            ]),
+            "error"
+        );
+    }
+}
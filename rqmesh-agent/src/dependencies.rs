@@ -0,0 +1,538 @@
+//! Declarative dependencies checked while an agent initializes.
+//!
+//! A dependency manifest is a TOML file, or YAML when its name ends in
+//! `.yaml` or `.yml`, listing what the agent needs on the host:
+//!
+//! ```toml
+//! [[dependency]]
+//! name = "sqlite"
+//! binary = "sqlite3"
+//! min_version = "3.40"
+//! packages = ["sqlite"]
+//!
+//! [[dependency]]
+//! name = "docker"
+//! command = "docker info"
+//! required = false
+//!
+//! [[dependency]]
+//! name = "ca-bundle"
+//! file = "/etc/ssl/certs/ca-certificates.crt"
+//! install = "update-ca-certificates"
+//! ```
+//!
+//! Each dependency is probed in exactly one way: `command` succeeds when the
+//! command exits successfully, `file` when the path exists and `binary` when
+//! the program is on the `PATH`, and, with `min_version`, when the first
+//! version in the output of running it with `version_args` (`--version` by
+//! default) is at least that version.
+//!
+//! Commands are split into arguments following the shell's quoting rules,
+//! but are only run through `/bin/sh`, and so may only use pipes, variables
+//! and the like, when the dependency sets `shell = true`.
+//!
+//! A dependency whose probe fails is installed with its `install` command or
+//! by installing its `packages` through the host's package manager, then
+//! probed again. If it is still unavailable, a required dependency (the
+//! default) stops the agent from starting, while one with `required = false`
+//! only stops the agent from advertising its `capability`, which defaults to
+//! the dependency's name. Every available dependency is advertised as a
+//! detected capability, with the version its probe found if any.
+
+use crate::install::Installer;
+use crate::{capabilities, packages, process};
+use log::{info, trace, warn};
+use rqmesh_core::{
+    compare_versions, CapabilityBroadcast, CapabilitySource, CommandSpec, InitializationErrorKind,
+    PackageRequirement, RqMeshError,
+};
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+type Result<T> = std::result::Result<T, RqMeshError>;
+
+fn default_required() -> bool {
+    true
+}
+
+fn default_version_args() -> Vec<String> {
+    vec!["--version".to_string()]
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Dependency {
+    name: String,
+    #[serde(default = "default_required")]
+    required: bool,
+    capability: Option<String>,
+    command: Option<String>,
+    file: Option<PathBuf>,
+    binary: Option<String>,
+    min_version: Option<String>,
+    #[serde(default = "default_version_args")]
+    version_args: Vec<String>,
+    install: Option<String>,
+    #[serde(default)]
+    packages: Vec<String>,
+    #[serde(default)]
+    shell: bool,
+}
+
+impl Dependency {
+    fn capability(&self) -> &str {
+        self.capability.as_deref().unwrap_or(&self.name)
+    }
+
+    /// The command `line` runs, through the shell if the dependency says so.
+    fn command_spec(&self, line: &str) -> std::result::Result<CommandSpec, String> {
+        if self.shell {
+            Ok(CommandSpec::shell(line))
+        } else {
+            CommandSpec::parse(line)
+                .map_err(|e| format!("dependency {} has an invalid command: {}", self.name, e))
+        }
+    }
+
+    /// Checks that exactly one probe is given, and that everything else
+    /// makes sense alongside it.
+    fn validate(&self) -> std::result::Result<(), String> {
+        if self.name.trim().is_empty() {
+            return Err("dependency name must be non-empty".to_string());
+        }
+        let probes = [
+            self.command.is_some(),
+            self.file.is_some(),
+            self.binary.is_some(),
+        ];
+        if probes.iter().filter(|&&given| given).count() != 1 {
+            return Err(format!(
+                "dependency {} must set exactly one of command, file or binary",
+                self.name
+            ));
+        }
+        if self.min_version.is_some() && self.binary.is_none() {
+            return Err(format!(
+                "dependency {} sets min_version without a binary to check it against",
+                self.name
+            ));
+        }
+        if self.install.is_some() && !self.packages.is_empty() {
+            return Err(format!(
+                "dependency {} must set at most one of install or packages",
+                self.name
+            ));
+        }
+        for command in self.command.iter().chain(self.install.iter()) {
+            self.command_spec(command)?;
+        }
+        for package in &self.packages {
+            PackageRequirement::parse(package).map_err(|e| format!("{}", e))?;
+        }
+        Ok(())
+    }
+
+    /// Runs the probe, returning the version it found, if any, or why the
+    /// dependency is unavailable.
+    fn probe(&self) -> std::result::Result<Option<String>, String> {
+        if let Some(command) = &self.command {
+            trace!("Probing dependency {} with {}", self.name, command);
+            let output = run(&self
+                .command_spec(command)?
+                .with_timeout(process::PROBE_TIMEOUT))?;
+            return Ok(capabilities::parse_version(&String::from_utf8_lossy(
+                &output,
+            )));
+        }
+        if let Some(file) = &self.file {
+            trace!(
+                "Probing dependency {} for file {}",
+                self.name,
+                file.to_string_lossy()
+            );
+            return if file.exists() {
+                Ok(None)
+            } else {
+                Err(format!("{} not found", file.to_string_lossy()))
+            };
+        }
+        let binary = self.binary.as_deref().unwrap_or_default();
+        trace!("Probing dependency {} for binary {}", self.name, binary);
+        let path = packages::find_on_path(binary)
+            .ok_or_else(|| format!("{} not found on PATH", binary))?;
+        let output = match process::run(
+            Command::new(&path).args(&self.version_args),
+            Some(process::PROBE_TIMEOUT),
+            &[],
+        ) {
+            Ok(output) if output.status.success() => output,
+            Ok(_) | Err(_) if self.min_version.is_none() => return Ok(None),
+            Ok(output) => {
+                return Err(format!(
+                    "{} {} exited with {}",
+                    binary,
+                    self.version_args.join(" "),
+                    output.status
+                ))
+            }
+            Err(e) => return Err(format!("{}: {}", binary, e)),
+        };
+        let version = capabilities::parse_version(&String::from_utf8_lossy(&output.stdout))
+            .or_else(|| capabilities::parse_version(&String::from_utf8_lossy(&output.stderr)));
+        match (&self.min_version, version) {
+            (None, version) => Ok(version),
+            (Some(min_version), None) => Err(format!(
+                "no version found in the output of {} {}, need at least {}",
+                binary,
+                self.version_args.join(" "),
+                min_version
+            )),
+            (Some(min_version), Some(version)) => {
+                if compare_versions(&version, min_version).is_ge() {
+                    Ok(Some(version))
+                } else {
+                    Err(format!(
+                        "{} is version {}, need at least {}",
+                        binary, version, min_version
+                    ))
+                }
+            }
+        }
+    }
+
+    /// Runs the install step, if there is one, returning whether it ran.
+    fn install(&self, package_manager: Option<&str>, installer: &Installer) -> Result<bool> {
+        let subject = format!("dependency {}", self.name);
+        if let Some(install) = &self.install {
+            let command = self.command_spec(install).map_err(|message| {
+                RqMeshError::from(InitializationErrorKind::new_invalid_install_deps_cmd(
+                    install, message,
+                ))
+            })?;
+            return installer.install(&subject, command);
+        }
+        if self.packages.is_empty() {
+            return Ok(false);
+        }
+        let package_manager = packages::package_manager(package_manager)?;
+        let requirements = self
+            .packages
+            .iter()
+            .map(|p| PackageRequirement::parse(p))
+            .collect::<Result<Vec<_>>>()?;
+        let names: Vec<&str> = requirements.iter().map(|r| r.name()).collect();
+        installer.install(&subject, package_manager.install_command(&names))
+    }
+}
+
+/// Runs `command` to completion, returning its stdout if it succeeded or
+/// why it did not.
+fn run(command: &CommandSpec) -> std::result::Result<Vec<u8>, String> {
+    let output =
+        process::run_spec(command, &[]).map_err(|e| format!("{}: {}", command.program(), e))?;
+    if output.status.success() {
+        Ok(output.stdout)
+    } else {
+        Err(format!(
+            "{} exited with {}: {}",
+            command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DependencyManifest {
+    #[serde(default, rename = "dependency")]
+    dependencies: Vec<Dependency>,
+}
+
+/// What checking a [`DependencyManifest`] found on the host.
+#[derive(Debug, Default)]
+pub struct DependencyReport {
+    /// Capabilities of the dependencies that are available.
+    pub available: Vec<CapabilityBroadcast>,
+    /// Capabilities of the optional dependencies that are not, which the
+    /// agent must not advertise.
+    pub unavailable: BTreeSet<String>,
+}
+
+impl DependencyManifest {
+    /// Reads and validates the manifest at `path`.
+    pub fn load(path: &Path) -> Result<DependencyManifest> {
+        let invalid = |message: String| {
+            RqMeshError::from(InitializationErrorKind::new_invalid_dependency_manifest(
+                path.to_string_lossy(),
+                message,
+            ))
+        };
+        let text = std::fs::read_to_string(path).map_err(|e| invalid(format!("{}", e)))?;
+        let manifest: DependencyManifest = match path.extension().and_then(|e| e.to_str()) {
+            Some("yaml") | Some("yml") => {
+                serde_yaml::from_str(&text).map_err(|e| invalid(format!("{}", e)))?
+            }
+            _ => toml::from_str(&text).map_err(|e| invalid(format!("{}", e)))?,
+        };
+        let mut names = BTreeSet::new();
+        for dependency in &manifest.dependencies {
+            dependency.validate().map_err(invalid)?;
+            if !names.insert(dependency.name.as_str()) {
+                return Err(invalid(format!(
+                    "dependency {} is listed more than once",
+                    dependency.name
+                )));
+            }
+        }
+        Ok(manifest)
+    }
+
+    /// Probes every dependency, installing and probing again those that are
+    /// unavailable, and fails listing each required dependency still
+    /// unavailable afterwards.
+    pub fn check(
+        &self,
+        package_manager: Option<&str>,
+        installer: &Installer,
+    ) -> Result<DependencyReport> {
+        let mut report = DependencyReport::default();
+        let mut missing = Vec::new();
+        for dependency in &self.dependencies {
+            let probed = match dependency.probe() {
+                Ok(version) => Ok(version),
+                Err(reason) => {
+                    warn!("Dependency {} unavailable: {}", dependency.name, reason);
+                    // A failed install leaves the dependency unavailable like
+                    // any other reason, failing startup only if it is required.
+                    match dependency.install(package_manager, installer) {
+                        Ok(true) => dependency.probe(),
+                        Ok(false) => Err(reason),
+                        Err(e) => Err(format!("{}, and installing it failed: {}", reason, e)),
+                    }
+                }
+            };
+            match probed {
+                Ok(version) => {
+                    info!(
+                        "Dependency {} available (version {})",
+                        dependency.name,
+                        version.as_deref().unwrap_or("unknown")
+                    );
+                    report.available.push(CapabilityBroadcast::new(
+                        dependency.capability(),
+                        version,
+                        CapabilitySource::Detected,
+                    ));
+                }
+                Err(reason) if dependency.required => {
+                    missing.push(format!("{} ({})", dependency.name, reason));
+                }
+                Err(reason) => {
+                    warn!(
+                        "Optional dependency {} unavailable, not advertising capability {}: {}",
+                        dependency.name,
+                        dependency.capability(),
+                        reason
+                    );
+                    report
+                        .unavailable
+                        .insert(dependency.capability().to_string());
+                }
+            }
+        }
+        if !missing.is_empty() {
+            return Err(RqMeshError::from(
+                InitializationErrorKind::new_missing_deps(missing.join(", ")),
+            ));
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrations;
+    use rqmesh_core::AgentInitializationContext;
+    use rusqlite::Connection;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rqmesh-deps-test-{}-{}", std::process::id(), name))
+    }
+
+    /// Writes `text` to a manifest called `name` and loads it.
+    fn load(name: &str, text: &str) -> Result<DependencyManifest> {
+        let path = temp_path(name);
+        std::fs::write(&path, text).expect("writes manifest");
+        let manifest = DependencyManifest::load(&path);
+        let _ = std::fs::remove_file(path);
+        manifest
+    }
+
+    fn invalid(name: &str, text: &str) -> String {
+        match load(name, text) {
+            Err(RqMeshError::InitializationError(
+                InitializationErrorKind::InvalidDependencyManifest { message, .. },
+            )) => message,
+            other => panic!("expected an invalid manifest, got {:?}", other),
+        }
+    }
+
+    fn dry_run() -> Installer<'static> {
+        Installer::dry_run(&AgentInitializationContext::new(":memory:", "", ""))
+            .expect("creates installer")
+    }
+
+    #[test]
+    fn manifests_are_read_as_toml_or_yaml() {
+        let toml = load(
+            "manifest.toml",
+            r#"
+            [[dependency]]
+            name = "sh"
+            binary = "sh"
+
+            [[dependency]]
+            name = "docker"
+            command = "docker info"
+            required = false
+            "#,
+        )
+        .expect("loads toml");
+        let yaml = load(
+            "manifest.yaml",
+            "dependency:\n  - name: sh\n    binary: sh\n  - name: docker\n    command: docker info\n    required: false\n",
+        )
+        .expect("loads yaml");
+        for manifest in [toml, yaml] {
+            let names: Vec<(&str, bool)> = manifest
+                .dependencies
+                .iter()
+                .map(|d| (d.name.as_str(), d.required))
+                .collect();
+            assert_eq!(names, vec![("sh", true), ("docker", false)]);
+        }
+    }
+
+    #[test]
+    fn manifests_with_ambiguous_or_repeated_dependencies_are_rejected() {
+        let message = invalid(
+            "two-probes.toml",
+            "[[dependency]]\nname = \"sh\"\nbinary = \"sh\"\nfile = \"/bin/sh\"\n",
+        );
+        assert!(message.contains("exactly one of"), "{}", message);
+
+        let message = invalid(
+            "no-binary.toml",
+            "[[dependency]]\nname = \"sh\"\nfile = \"/bin/sh\"\nmin_version = \"1.0\"\n",
+        );
+        assert!(message.contains("min_version"), "{}", message);
+
+        let message = invalid(
+            "repeated.toml",
+            "[[dependency]]\nname = \"sh\"\nbinary = \"sh\"\n\n[[dependency]]\nname = \"sh\"\nfile = \"/bin/sh\"\n",
+        );
+        assert!(message.contains("more than once"), "{}", message);
+
+        let message = invalid(
+            "unknown.toml",
+            "[[dependency]]\nname = \"sh\"\nbniary = \"sh\"\n",
+        );
+        assert!(message.contains("bniary"), "{}", message);
+    }
+
+    #[test]
+    fn missing_optional_dependencies_downgrade_capabilities() {
+        let manifest = load(
+            "optional.toml",
+            r#"
+            [[dependency]]
+            name = "shell"
+            command = "true"
+
+            [[dependency]]
+            name = "docker"
+            command = "false"
+            required = false
+
+            [[dependency]]
+            name = "gpu-driver"
+            file = "/nonexistent/rqmesh/gpu"
+            capability = "gpu"
+            required = false
+            "#,
+        )
+        .expect("loads manifest");
+
+        let report = manifest.check(None, &dry_run()).expect("checks manifest");
+
+        let available: Vec<&str> = report
+            .available
+            .iter()
+            .map(|c| c.capability_type())
+            .collect();
+        assert_eq!(available, vec!["shell"]);
+        let unavailable: Vec<&str> = report.unavailable.iter().map(String::as_str).collect();
+        assert_eq!(unavailable, vec!["docker", "gpu"]);
+    }
+
+    #[test]
+    fn missing_required_dependencies_stop_the_agent_starting() {
+        let manifest = load(
+            "required.toml",
+            r#"
+            [[dependency]]
+            name = "docker"
+            command = "false"
+            required = false
+
+            [[dependency]]
+            name = "gpu-driver"
+            file = "/nonexistent/rqmesh/gpu"
+            "#,
+        )
+        .expect("loads manifest");
+
+        match manifest.check(None, &dry_run()) {
+            Err(RqMeshError::InitializationError(
+                InitializationErrorKind::MissingRequiredDependencies { message },
+            )) => {
+                assert!(message.contains("gpu-driver"), "{}", message);
+                assert!(!message.contains("docker"), "{}", message);
+            }
+            other => panic!("expected missing dependencies, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn installed_dependencies_are_probed_again() {
+        let marker = temp_path("installed-marker");
+        let _ = std::fs::remove_file(&marker);
+        let manifest = load(
+            "install.toml",
+            &format!(
+                "[[dependency]]\nname = \"marker\"\nfile = \"{0}\"\ninstall = \"touch {0}\"\n",
+                marker.display()
+            ),
+        )
+        .expect("loads manifest");
+        let mut conn = Connection::open_in_memory().expect("opens in-memory store");
+        migrations::migrate(&mut conn, ":memory:").expect("migrates store");
+        let ctx = AgentInitializationContext::new(":memory:", "", "");
+        let installer = Installer::new(&ctx, &conn).expect("creates installer");
+
+        let report = manifest.check(None, &installer).expect("checks manifest");
+
+        let available: Vec<&str> = report
+            .available
+            .iter()
+            .map(|c| c.capability_type())
+            .collect();
+        assert_eq!(available, vec!["marker"]);
+        assert!(marker.exists());
+        let _ = std::fs::remove_file(marker);
+    }
+}
@@ -0,0 +1,270 @@
+use crate::Agent;
+use log::{trace, warn};
+use rqmesh_core::{
+    ProtocolErrorKind, RqMeshEnvelope, RqMeshError, RqMeshFrame, RqMeshProtocolAction,
+    RqMeshResponseEnvelope,
+};
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+type Result<T> = std::result::Result<T, RqMeshError>;
+
+/// Handles a single [`RqMeshProtocolAction`] on behalf of the agent.
+///
+/// Adding a new action to the agent means implementing this trait and
+/// registering the handler with [`Dispatcher::register`].
+pub trait ActionHandler: Send + Sync {
+    type Action: RqMeshProtocolAction;
+
+    fn handle(
+        &self,
+        agent: &Agent,
+        frame: RqMeshFrame<Self::Action>,
+    ) -> Result<<Self::Action as RqMeshProtocolAction>::ResponseType>;
+
+    /// Arguments of `action` that policy rules can restrict, by name.
+    fn arguments(&self, _action: &Self::Action) -> Vec<(&'static str, String)> {
+        Vec::new()
+    }
+}
+
+/// Object safe view of an [`ActionHandler`] that works on raw envelopes.
+trait EnvelopeHandler: Send + Sync {
+    fn handle_envelope(&self, agent: &Agent, envelope: RqMeshEnvelope) -> RqMeshResponseEnvelope;
+
+    fn authorize_envelope(&self, agent: &Agent, envelope: &RqMeshEnvelope) -> Result<()>;
+}
+
+impl<H> EnvelopeHandler for H
+where
+    H: ActionHandler,
+{
+    fn handle_envelope(&self, agent: &Agent, envelope: RqMeshEnvelope) -> RqMeshResponseEnvelope {
+        let response = RqMeshFrame::<H::Action>::from_envelope(envelope).and_then(|frame| {
+            authorize_frame(self, agent, &frame)?;
+            self.handle(agent, frame)
+        });
+        RqMeshResponseEnvelope::from_response::<H::Action>(response)
+            .unwrap_or_else(|e| RqMeshResponseEnvelope::new_err(H::Action::ACTION, e))
+    }
+
+    fn authorize_envelope(&self, agent: &Agent, envelope: &RqMeshEnvelope) -> Result<()> {
+        let frame = RqMeshFrame::<H::Action>::from_envelope(envelope.clone())?;
+        authorize_frame(self, agent, &frame)
+    }
+}
+
+/// Checks the agent's policy allows the caller of `frame` to have `handler`
+/// handle it.
+fn authorize_frame<H>(handler: &H, agent: &Agent, frame: &RqMeshFrame<H::Action>) -> Result<()>
+where
+    H: ActionHandler,
+{
+    let arguments = handler.arguments(frame.contents());
+    agent.authorize(
+        frame.authenticated_caller(),
+        frame.requestor(),
+        H::Action::ACTION,
+        &arguments,
+    )
+}
+
+/// Routes incoming envelopes to the handler registered for their action.
+#[derive(Default)]
+pub struct Dispatcher {
+    handlers: BTreeMap<&'static str, Box<dyn EnvelopeHandler>>,
+}
+
+impl Dispatcher {
+    pub fn new() -> Dispatcher {
+        Dispatcher::default()
+    }
+
+    /// Registers `handler` for its action, replacing any previous handler.
+    pub fn register<H>(&mut self, handler: H) -> &mut Dispatcher
+    where
+        H: ActionHandler + 'static,
+    {
+        trace!("Registering handler for action {}", H::Action::ACTION);
+        if self
+            .handlers
+            .insert(H::Action::ACTION, Box::new(handler))
+            .is_some()
+        {
+            warn!("Replaced existing handler for action {}", H::Action::ACTION);
+        }
+        self
+    }
+
+    pub fn actions(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.handlers.keys().copied()
+    }
+
+    /// Checks the agent's policy allows the caller of `envelope` to have it
+    /// handled, without handling it. Used before passing a request on to
+    /// other agents, so that a caller is held to the policy of the agent it
+    /// asked as well as to theirs. Actions without a handler here are left
+    /// for the other agent to judge.
+    pub fn authorize(&self, agent: &Agent, envelope: &RqMeshEnvelope) -> Result<()> {
+        match self.handlers.get(envelope.action()) {
+            Some(handler) => handler.authorize_envelope(agent, envelope),
+            None => Ok(()),
+        }
+    }
+
+    /// Handles `envelope`, always producing a response; unknown actions and
+    /// handler failures are reported as errors inside the response. Every
+    /// envelope dispatched is recorded in the agent's audit log.
+    pub fn dispatch(&self, agent: &Agent, envelope: RqMeshEnvelope) -> RqMeshResponseEnvelope {
+        trace!(
+            "Dispatching action {} from {}",
+            envelope.action(),
+            envelope.requestor()
+        );
+        let started = Instant::now();
+        let context = envelope.context().clone();
+        let requestor = envelope.requestor().to_string();
+        let action = envelope.action().to_string();
+        let response = self.handle(agent, envelope);
+        agent.record_audit(
+            &context,
+            &requestor,
+            &action,
+            response.result().as_ref().map(|_| ()),
+            started.elapsed(),
+        );
+        response
+    }
+
+    fn handle(&self, agent: &Agent, envelope: RqMeshEnvelope) -> RqMeshResponseEnvelope {
+        match self.handlers.get(envelope.action()) {
+            Some(handler) => handler.handle_envelope(agent, envelope),
+            None => {
+                warn!(
+                    "Received unknown action {} from {}",
+                    envelope.action(),
+                    envelope.requestor()
+                );
+                let action = envelope.action().to_string();
+                RqMeshResponseEnvelope::new_err(
+                    action.clone(),
+                    RqMeshError::from(ProtocolErrorKind::new_unknown_action(action)),
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::initialization::in_memory_agent;
+    use rqmesh_core::{
+        AgentInitializationContext, AuditOutcome, PingRequest, PingResponse, QueryAuditLogRequest,
+        RequestContext, UnauthorizedErrorKind,
+    };
+    use serde::{Deserialize, Serialize};
+
+    /// An action no agent handles.
+    #[derive(Serialize, Deserialize)]
+    struct UnheardOfRequest {}
+
+    impl RqMeshProtocolAction for UnheardOfRequest {
+        const ACTION: &'static str = "unheard_of";
+        type ResponseType = PingResponse;
+    }
+
+    struct PongHandler;
+
+    impl ActionHandler for PongHandler {
+        type Action = PingRequest;
+
+        fn handle(&self, _agent: &Agent, _frame: RqMeshFrame<PingRequest>) -> Result<PingResponse> {
+            Ok(PingResponse::default())
+        }
+    }
+
+    fn agent() -> Agent {
+        in_memory_agent(AgentInitializationContext::new(":memory:", "", ""))
+    }
+
+    fn envelope<A: RqMeshProtocolAction>(action: A) -> RqMeshEnvelope {
+        RqMeshFrame::new(action, "alice")
+            .into_envelope()
+            .expect("encodes request")
+            .with_context(RequestContext::new(None, None).with_key_id("ops"))
+    }
+
+    fn dispatcher() -> Dispatcher {
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.register(PongHandler);
+        dispatcher
+    }
+
+    fn audit_outcomes(agent: &Agent) -> Vec<(String, AuditOutcome)> {
+        agent
+            .query_audit_log(&QueryAuditLogRequest::default())
+            .expect("queries audit log")
+            .iter()
+            .map(|r| (r.action().to_string(), r.outcome()))
+            .collect()
+    }
+
+    #[test]
+    fn unknown_actions_are_answered_with_an_error_naming_them() {
+        let agent = agent();
+        let response = dispatcher().dispatch(&agent, envelope(UnheardOfRequest {}));
+
+        assert_eq!(response.action(), "unheard_of");
+        match response.result() {
+            Err(RqMeshError::ProtocolError(ProtocolErrorKind::UnknownAction { action })) => {
+                assert_eq!(action, "unheard_of")
+            }
+            other => panic!("expected UnknownAction, got {:?}", other),
+        }
+        assert_eq!(
+            audit_outcomes(&agent),
+            vec![("unheard_of".to_string(), AuditOutcome::Error)]
+        );
+    }
+
+    #[test]
+    fn known_actions_are_handled_by_their_handler() {
+        let agent = agent();
+        let response = dispatcher().dispatch(&agent, envelope(PingRequest::default()));
+
+        assert_eq!(response.action(), PingRequest::ACTION);
+        response
+            .into_response::<PingRequest>()
+            .expect("handled ping");
+        assert_eq!(
+            audit_outcomes(&agent),
+            vec![(PingRequest::ACTION.to_string(), AuditOutcome::Ok)]
+        );
+    }
+
+    #[test]
+    fn actions_the_policy_denies_are_not_handled() {
+        let mut agent = agent();
+        agent.policy = Some(toml::from_str("default = \"deny\"").expect("parses policy"));
+        let dispatcher = dispatcher();
+        let request = envelope(PingRequest::default());
+
+        match dispatcher.authorize(&agent, &request) {
+            Err(RqMeshError::Unauthorized(UnauthorizedErrorKind::ActionDenied {
+                caller, ..
+            })) => assert_eq!(caller, "ops"),
+            other => panic!("expected ActionDenied, got {:?}", other),
+        }
+        let response = dispatcher.dispatch(&agent, request);
+        assert!(response.result().is_err());
+        assert_eq!(
+            audit_outcomes(&agent),
+            vec![(PingRequest::ACTION.to_string(), AuditOutcome::Denied)]
+        );
+        // actions without a handler are left to whoever handles them
+        assert!(dispatcher
+            .authorize(&agent, &envelope(UnheardOfRequest {}))
+            .is_ok());
+    }
+}
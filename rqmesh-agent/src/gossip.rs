@@ -0,0 +1,595 @@
+//! SWIM style failure detection and membership dissemination.
+//!
+//! Every protocol period the agent probes one member, chosen round-robin
+//! from a shuffled list of members not known to be dead. A member that does
+//! not answer is probed indirectly through a few other members, and if none
+//! of them can reach it either it is marked suspect. Suspects that do not
+//! refute the suspicion (by announcing themselves alive with a higher
+//! incarnation) before the suspicion timeout are marked dead.
+//!
+//! State changes are stored in the `peers` table and disseminated by
+//! piggybacking them on the probes and announcements agents exchange, and on
+//! the responses to them.
+
+use crate::Agent;
+use log::{debug, info, trace, warn};
+use rand::seq::SliceRandom;
+use rqmesh_core::{
+    AnnounceAgentRequest, MemberState, MembershipUpdate, PingReqRequest, PingRequest,
+    RqMeshEnvelope, RqMeshError, RqMeshProtocolAction, StorageErrorKind,
+};
+use rusqlite::{params, OptionalExtension};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+type Result<T> = std::result::Result<T, RqMeshError>;
+
+/// How long a direct or indirect probe may take before it counts as failed,
+/// shortened to half the protocol period when that is less.
+const PING_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Number of members asked to probe a target that missed a direct probe.
+const INDIRECT_PROBES: usize = 3;
+
+/// Most updates piggybacked on a single envelope.
+const MAX_PIGGYBACK: usize = 8;
+
+/// Action a policy allows callers whose piggybacked updates are merged.
+pub const GOSSIP_ACTION: &str = "gossip";
+
+/// Actions whose responses piggyback updates. Other actions are mostly sent
+/// by clients outside the mesh, which would use up the updates'
+/// retransmissions without passing them on.
+const MEMBERSHIP_ACTIONS: [&str; 3] = [
+    PingRequest::ACTION,
+    PingReqRequest::ACTION,
+    AnnounceAgentRequest::ACTION,
+];
+
+/// Each update is piggybacked `RETRANSMIT_MULT * log2(members + 1)` times.
+const RETRANSMIT_MULT: u32 = 3;
+
+const SHUTDOWN_POLL: Duration = Duration::from_millis(100);
+
+/// In-memory half of the membership protocol; the member table itself lives
+/// in the store.
+#[derive(Default)]
+pub struct Membership {
+    state: Mutex<MembershipState>,
+}
+
+#[derive(Default)]
+struct MembershipState {
+    local: Option<LocalMember>,
+    broadcasts: Vec<(MembershipUpdate, u32)>,
+    probe_order: Vec<String>,
+    suspects: HashMap<String, Instant>,
+}
+
+struct LocalMember {
+    endpoint: String,
+    version: String,
+    identity: String,
+    incarnation: u64,
+}
+
+impl Membership {
+    pub fn new() -> Membership {
+        Membership::default()
+    }
+
+    fn state(&self) -> MutexGuard<'_, MembershipState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl MembershipState {
+    /// Queues `update` for dissemination, replacing any queued update about
+    /// the same member.
+    fn enqueue(&mut self, update: MembershipUpdate, members: usize) {
+        let transmissions = RETRANSMIT_MULT * (usize::BITS - (members + 1).leading_zeros());
+        self.broadcasts
+            .retain(|(u, _)| u.endpoint() != update.endpoint());
+        self.broadcasts.push((update, transmissions.max(1)));
+    }
+}
+
+impl Agent {
+    /// Sets the identity this agent uses in the membership protocol and
+    /// queues an announcement that it is alive.
+    pub fn join_membership(&self, endpoint: &str, identity: &str) {
+        let version = self.context.version().to_string();
+        let members = self.member_count().unwrap_or(0);
+        let mut state = self.membership.state();
+        let update = MembershipUpdate::new(endpoint, version.as_str(), MemberState::Alive, 0)
+            .with_node_id(Some(self.node_id().to_string()));
+        state.local = Some(LocalMember {
+            endpoint: endpoint.to_string(),
+            version,
+            identity: identity.to_string(),
+            incarnation: 0,
+        });
+        state.enqueue(update, members);
+    }
+
+    /// Merges the updates piggybacked on a request, but only if the policy
+    /// allows its caller to [`GOSSIP_ACTION`], since anyone able to connect
+    /// could otherwise declare members dead or add members of their own.
+    pub fn accept_piggyback(&self, envelope: &RqMeshEnvelope) {
+        if envelope.piggyback().is_empty() {
+            return;
+        }
+        let caller = envelope.context().authenticated_caller();
+        match self.authorize(caller, envelope.requestor(), GOSSIP_ACTION, &[]) {
+            Ok(()) => self.apply_membership_updates(envelope.piggyback()),
+            Err(e) => debug!(
+                "Ignoring membership updates from {}: {}",
+                caller.unwrap_or_else(|| envelope.requestor()),
+                e
+            ),
+        }
+    }
+
+    /// Merges updates received from another member, queueing any that change
+    /// our view for further dissemination.
+    pub fn apply_membership_updates(&self, updates: &[MembershipUpdate]) {
+        if updates.is_empty() {
+            return;
+        }
+        let members = self.member_count().unwrap_or(0);
+        let mut state = self.membership.state();
+        for update in updates {
+            if let Some(local) = state.local.as_mut() {
+                if local.endpoint == update.endpoint() {
+                    if update.state() != MemberState::Alive
+                        && update.incarnation() >= local.incarnation
+                    {
+                        local.incarnation = update.incarnation() + 1;
+                        info!(
+                            "Refuting {} claim about ourselves with incarnation {}",
+                            update.state(),
+                            local.incarnation
+                        );
+                        let refutation = MembershipUpdate::new(
+                            local.endpoint.as_str(),
+                            local.version.as_str(),
+                            MemberState::Alive,
+                            local.incarnation,
+                        )
+                        .with_node_id(Some(self.node_id().to_string()));
+                        state.enqueue(refutation, members);
+                    }
+                    continue;
+                }
+            }
+            if update.node_id() == Some(self.node_id()) {
+                trace!(
+                    "Ignoring update about our old endpoint {}",
+                    update.endpoint()
+                );
+                continue;
+            }
+
+            let current = match self.member_state(update.endpoint()) {
+                Ok(current) => current,
+                Err(e) => {
+                    warn!("Error reading member {}: {}", update.endpoint(), e);
+                    continue;
+                }
+            };
+            let changed = match current {
+                Some((s, i)) => update.supersedes(s, i),
+                None => !self.has_moved(update),
+            };
+            if !changed {
+                continue;
+            }
+
+            if let Err(e) = self.store_member_state(update) {
+                warn!("Error storing member {}: {}", update.endpoint(), e);
+                continue;
+            }
+            debug!(
+                "Member {} is now {} (incarnation {})",
+                update.endpoint(),
+                update.state(),
+                update.incarnation()
+            );
+            match update.state() {
+                MemberState::Suspect => {
+                    state
+                        .suspects
+                        .entry(update.endpoint().to_string())
+                        .or_insert_with(Instant::now);
+                }
+                MemberState::Alive | MemberState::Dead => {
+                    state.suspects.remove(update.endpoint());
+                }
+            }
+            state.enqueue(update.clone(), members);
+        }
+    }
+
+    /// Takes the next batch of updates to piggyback on the response to
+    /// `action`, if it is a membership action.
+    pub fn take_response_piggyback(&self, action: &str) -> Vec<MembershipUpdate> {
+        if MEMBERSHIP_ACTIONS.contains(&action) {
+            self.take_piggyback()
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Takes the next batch of updates to piggyback on an outgoing envelope.
+    pub fn take_piggyback(&self) -> Vec<MembershipUpdate> {
+        let mut state = self.membership.state();
+        let mut piggyback = Vec::new();
+        for (update, remaining) in state.broadcasts.iter_mut().take(MAX_PIGGYBACK) {
+            piggyback.push(update.clone());
+            *remaining -= 1;
+        }
+        state.broadcasts.retain(|(_, remaining)| *remaining > 0);
+        piggyback
+    }
+
+    /// Marks a member that has just (re)announced itself as alive, bumping
+    /// its incarnation if we had suspected it or declared it dead, and
+    /// queues the news for the rest of the mesh.
+    pub fn announce_member(
+        &self,
+        endpoint: &str,
+        version: &str,
+        node_id: Option<&str>,
+    ) -> Result<()> {
+        let update = match self.member_state(endpoint)? {
+            Some((MemberState::Alive, incarnation)) => {
+                MembershipUpdate::new(endpoint, version, MemberState::Alive, incarnation)
+            }
+            Some((_, incarnation)) => {
+                MembershipUpdate::new(endpoint, version, MemberState::Alive, incarnation + 1)
+            }
+            None => MembershipUpdate::new(endpoint, version, MemberState::Alive, 0),
+        }
+        .with_node_id(node_id.map(|n| n.to_string()));
+        self.store_member_state(&update)?;
+        let members = self.member_count()?;
+        let mut state = self.membership.state();
+        state.suspects.remove(endpoint);
+        state.enqueue(update, members);
+        Ok(())
+    }
+
+    /// Probes `target` directly, exchanging piggybacked updates with it.
+    pub fn ping_member(&self, target: &str) -> bool {
+        let identity = match self.membership.state().local.as_ref() {
+            Some(local) => local.identity.clone(),
+            None => return false,
+        };
+        let piggyback = self.take_piggyback();
+        let result = self
+            .connect_to(target, &identity, PING_TIMEOUT)
+            .and_then(|mut c| c.call_with_piggyback(PingRequest::default(), piggyback));
+        match result {
+            Ok((_, received)) => {
+                self.apply_membership_updates(&received);
+                if let Err(e) = self.touch_member(target) {
+                    warn!("Error updating last seen time of {}: {}", target, e);
+                }
+                true
+            }
+            Err(e) => {
+                trace!("Ping to {} failed: {}", target, e);
+                false
+            }
+        }
+    }
+
+    /// Asks up to [`INDIRECT_PROBES`] other members to probe `target`.
+    fn ping_member_indirectly(&self, target: &str) -> Result<bool> {
+        let identity = match self.membership.state().local.as_ref() {
+            Some(local) => local.identity.clone(),
+            None => return Ok(false),
+        };
+        let mut helpers: Vec<String> = self
+            .probe_candidates()?
+            .into_iter()
+            .filter(|e| e != target)
+            .collect();
+        helpers.shuffle(&mut rand::thread_rng());
+        helpers.truncate(INDIRECT_PROBES);
+
+        for helper in helpers {
+            let piggyback = self.take_piggyback();
+            let result = self
+                .connect_to(helper.as_str(), identity.as_str(), self.ping_timeout() * 2)
+                .and_then(|mut c| c.call_with_piggyback(PingReqRequest::new(target), piggyback));
+            match result {
+                Ok((response, received)) => {
+                    self.apply_membership_updates(&received);
+                    if response.acked() {
+                        trace!("{} reached {} on our behalf", helper, target);
+                        return Ok(true);
+                    }
+                }
+                Err(e) => trace!("Indirect probe via {} failed: {}", helper, e),
+            }
+        }
+        Ok(false)
+    }
+
+    fn ping_timeout(&self) -> Duration {
+        PING_TIMEOUT.min(self.context.protocol_period() / 2)
+    }
+
+    /// Picks the next member to probe, reshuffling once every member has
+    /// been probed.
+    fn next_probe_target(&self) -> Result<Option<String>> {
+        if self.membership.state().probe_order.is_empty() {
+            let mut order = self.probe_candidates()?;
+            order.shuffle(&mut rand::thread_rng());
+            self.membership.state().probe_order = order;
+        }
+        Ok(self.membership.state().probe_order.pop())
+    }
+
+    /// Declares dead every suspect whose suspicion has timed out.
+    fn expire_suspects(&self) -> Result<()> {
+        let expired: Vec<String> = self
+            .membership
+            .state()
+            .suspects
+            .iter()
+            .filter(|(_, since)| since.elapsed() >= self.context.suspect_timeout())
+            .map(|(endpoint, _)| endpoint.clone())
+            .collect();
+        for endpoint in expired {
+            if let Some((MemberState::Suspect, incarnation)) = self.member_state(&endpoint)? {
+                info!("Suspect {} did not refute in time, marking dead", &endpoint);
+                let (version, node_id) = self.member_details(&endpoint)?;
+                self.apply_membership_updates(&[MembershipUpdate::new(
+                    endpoint.as_str(),
+                    version,
+                    MemberState::Dead,
+                    incarnation,
+                )
+                .with_node_id(node_id)]);
+            } else {
+                self.membership.state().suspects.remove(&endpoint);
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs one protocol period: probe a member, falling back to indirect
+    /// probes, and suspect it if nobody can reach it.
+    fn probe_once(&self) -> Result<()> {
+        self.expire_suspects()?;
+
+        let target = match self.next_probe_target()? {
+            Some(target) => target,
+            None => return Ok(()),
+        };
+        trace!("Probing {}", &target);
+        if self.ping_member(&target) || self.ping_member_indirectly(&target)? {
+            return Ok(());
+        }
+
+        if let Some((state, incarnation)) = self.member_state(&target)? {
+            if state == MemberState::Alive {
+                warn!("{} missed direct and indirect probes, suspecting", &target);
+                let (version, node_id) = self.member_details(&target)?;
+                self.apply_membership_updates(&[MembershipUpdate::new(
+                    target.as_str(),
+                    version,
+                    MemberState::Suspect,
+                    incarnation,
+                )
+                .with_node_id(node_id)]);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `endpoint` is in the member table, in any state.
+    pub fn is_member(&self, endpoint: &str) -> Result<bool> {
+        Ok(self.member_state(endpoint)?.is_some())
+    }
+
+    fn member_state(&self, endpoint: &str) -> Result<Option<(MemberState, u64)>> {
+        let row: Option<(String, i64)> = self
+            .connection()
+            .query_row(
+                "SELECT state, incarnation FROM peers WHERE endpoint = ?1",
+                params![endpoint],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+            .map_err(|e| member_query_err("member_state", e))?;
+        match row {
+            Some((state, incarnation)) => Ok(Some((state.parse()?, incarnation as u64))),
+            None => Ok(None),
+        }
+    }
+
+    /// Version and, if known, node ID of the member at `endpoint`.
+    fn member_details(&self, endpoint: &str) -> Result<(String, Option<String>)> {
+        self.connection()
+            .query_row(
+                "SELECT version, node_id FROM peers WHERE endpoint = ?1",
+                params![endpoint],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .map_err(|e| member_query_err("member_details", e))
+    }
+
+    /// Whether `update` is about an endpoint the member has since left, which
+    /// is the case when its node ID is recorded under another endpoint.
+    fn has_moved(&self, update: &MembershipUpdate) -> bool {
+        let node_id = match update.node_id() {
+            Some(node_id) => node_id,
+            None => return false,
+        };
+        let moved: std::result::Result<bool, rusqlite::Error> = self.connection().query_row(
+            "SELECT COUNT(*) > 0 FROM peers WHERE node_id = ?1 AND endpoint != ?2",
+            params![node_id, update.endpoint()],
+            |row| row.get(0),
+        );
+        match moved {
+            Ok(moved) => {
+                if moved {
+                    trace!(
+                        "Ignoring update about {}, {} has moved",
+                        update.endpoint(),
+                        node_id
+                    );
+                }
+                moved
+            }
+            Err(e) => {
+                warn!("Error checking whether {} has moved: {}", node_id, e);
+                false
+            }
+        }
+    }
+
+    fn member_count(&self) -> Result<usize> {
+        let count: i64 = self
+            .connection()
+            .query_row(
+                "SELECT COUNT(*) FROM peers WHERE state != 'dead'",
+                [],
+                |row| row.get(0),
+            )
+            .map_err(|e| member_query_err("member_count", e))?;
+        Ok(count as usize)
+    }
+
+    fn probe_candidates(&self) -> Result<Vec<String>> {
+        let conn = self.connection();
+        let mut stmt = conn
+            .prepare("SELECT endpoint FROM peers WHERE state != 'dead'")
+            .map_err(|e| member_query_err("probe_candidates", e))?;
+        let endpoints = stmt
+            .query_map([], |row| row.get(0))
+            .map_err(|e| member_query_err("probe_candidates", e))?
+            .collect::<std::result::Result<Vec<String>, _>>()
+            .map_err(|e| member_query_err("probe_candidates", e))?;
+        Ok(endpoints)
+    }
+
+    fn store_member_state(&self, update: &MembershipUpdate) -> Result<()> {
+        self.connection()
+            .execute(
+                "INSERT INTO peers (endpoint, version, first_seen, last_seen, state, incarnation, node_id) VALUES (?1, ?2, datetime('now'), datetime('now'), ?3, ?4, ?5)
+                 ON CONFLICT(endpoint) DO UPDATE SET version = excluded.version, state = excluded.state, incarnation = excluded.incarnation, node_id = COALESCE(excluded.node_id, peers.node_id)",
+                params![
+                    update.endpoint(),
+                    update.version(),
+                    update.state().to_string(),
+                    update.incarnation() as i64,
+                    update.node_id()
+                ],
+            )
+            .map_err(|e| member_query_err("store_member_state", e))?;
+        Ok(())
+    }
+
+    fn touch_member(&self, endpoint: &str) -> Result<()> {
+        self.connection()
+            .execute(
+                "UPDATE peers SET last_seen = datetime('now') WHERE endpoint = ?1",
+                params![endpoint],
+            )
+            .map_err(|e| member_query_err("touch_member", e))?;
+        Ok(())
+    }
+}
+
+fn member_query_err(operation: &str, e: rusqlite::Error) -> RqMeshError {
+    RqMeshError::from(StorageErrorKind::new_query_err(operation, format!("{}", e)))
+}
+
+/// Runs the failure detector on a background thread until shutdown.
+pub fn spawn_failure_detector(
+    agent: Arc<Agent>,
+    shutdown: Arc<AtomicBool>,
+) -> std::io::Result<JoinHandle<()>> {
+    thread::Builder::new()
+        .name("rqmesh-gossip".to_string())
+        .spawn(move || {
+            let period = agent.context.protocol_period();
+            let mut next_period = Instant::now() + period;
+            while !shutdown.load(Ordering::SeqCst) {
+                let now = Instant::now();
+                if now < next_period {
+                    thread::sleep(SHUTDOWN_POLL.min(next_period - now));
+                    continue;
+                }
+                next_period = Instant::now() + period;
+                if let Err(e) = agent.probe_once() {
+                    warn!("Error running failure detector: {}", e);
+                }
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::initialization::in_memory_agent;
+    use rqmesh_core::{AgentInitializationContext, RequestContext, RqMeshFrame};
+
+    fn piggybacked(endpoint: &str, context: RequestContext) -> RqMeshEnvelope {
+        RqMeshFrame::new(PingRequest::default(), "node-2")
+            .into_envelope()
+            .expect("encodes ping")
+            .with_piggyback(vec![MembershipUpdate::new(
+                endpoint,
+                "0.1.0",
+                MemberState::Alive,
+                0,
+            )])
+            .with_context(context)
+    }
+
+    #[test]
+    fn piggyback_is_only_merged_from_callers_allowed_to_gossip() {
+        let mut agent = in_memory_agent(AgentInitializationContext::new(":memory:", "", ""));
+        agent.policy = Some(
+            toml::from_str(
+                r#"
+                default = "deny"
+
+                [[rule]]
+                requestors = ["node-*"]
+                actions = ["gossip"]
+                "#,
+            )
+            .expect("parses policy"),
+        );
+        let peer = Some("127.0.0.1:5000".to_string());
+
+        agent.accept_piggyback(&piggybacked(
+            "127.0.0.1:6001",
+            RequestContext::new(peer.clone(), None),
+        ));
+        agent.accept_piggyback(&piggybacked(
+            "127.0.0.1:6002",
+            RequestContext::new(peer.clone(), None).with_key_id("ops"),
+        ));
+        agent.accept_piggyback(&piggybacked(
+            "127.0.0.1:6003",
+            RequestContext::new(peer, None).with_key_id("node-1"),
+        ));
+
+        assert_eq!(agent.member_state("127.0.0.1:6001").expect("queries"), None);
+        assert_eq!(agent.member_state("127.0.0.1:6002").expect("queries"), None);
+        assert_eq!(
+            agent.member_state("127.0.0.1:6003").expect("queries"),
+            Some((MemberState::Alive, 0))
+        );
+    }
+}
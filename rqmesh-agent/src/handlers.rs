@@ -0,0 +1,298 @@
+use crate::dispatch::{ActionHandler, Dispatcher};
+use crate::Agent;
+use rqmesh_core::{
+    AgentLoadRequest, AgentLoadResponse, AnnounceAgentRequest, BroadcastRequest, BroadcastResponse,
+    CancelJobRequest, DescribeAgentRequest, DescribeAgentResponse, EnqueueJobRequest, JobRecord,
+    JobStatusRequest, ListCapabilitiesRequest, ListCapabilitiesResponse, ListPeersRequest,
+    ListPeersResponse, PingReqRequest, PingReqResponse, PingRequest, PingResponse,
+    QueryAuditLogRequest, QueryAuditLogResponse, RouteRequest, RoutedResponse, RqMeshError,
+    RqMeshFrame, RqMeshProtocolAction, RunCommandRequest, StorageErrorKind, UnauthorizedErrorKind,
+};
+use std::sync::Arc;
+
+type Result<T> = std::result::Result<T, RqMeshError>;
+
+/// Builds a dispatcher with every action the agent supports out of the box.
+pub fn builtin_dispatcher() -> Dispatcher {
+    let local = Arc::new(local_dispatcher());
+    let mut dispatcher = local_dispatcher();
+    dispatcher.register(RouteHandler::new(Arc::clone(&local)));
+    dispatcher.register(BroadcastHandler::new(local));
+    dispatcher
+}
+
+/// Builds a dispatcher for the actions an agent handles itself, which are
+/// also the actions that can be routed or broadcast to it.
+fn local_dispatcher() -> Dispatcher {
+    let mut dispatcher = Dispatcher::new();
+    dispatcher.register(DescribeAgentHandler);
+    dispatcher.register(ListCapabilitiesHandler);
+    dispatcher.register(AnnounceAgentHandler);
+    dispatcher.register(ListPeersHandler);
+    dispatcher.register(PingHandler);
+    dispatcher.register(PingReqHandler);
+    dispatcher.register(RunCommandHandler);
+    dispatcher.register(JobStatusHandler);
+    dispatcher.register(EnqueueJobHandler);
+    dispatcher.register(CancelJobHandler);
+    dispatcher.register(AgentLoadHandler);
+    dispatcher.register(QueryAuditLogHandler);
+    dispatcher
+}
+
+pub struct DescribeAgentHandler;
+
+impl ActionHandler for DescribeAgentHandler {
+    type Action = DescribeAgentRequest;
+
+    fn handle(
+        &self,
+        agent: &Agent,
+        _frame: RqMeshFrame<DescribeAgentRequest>,
+    ) -> Result<DescribeAgentResponse> {
+        agent.describe()
+    }
+}
+
+pub struct ListCapabilitiesHandler;
+
+impl ActionHandler for ListCapabilitiesHandler {
+    type Action = ListCapabilitiesRequest;
+
+    fn handle(
+        &self,
+        agent: &Agent,
+        frame: RqMeshFrame<ListCapabilitiesRequest>,
+    ) -> Result<ListCapabilitiesResponse> {
+        let capabilities = agent.list_capabilities(frame.contents().capability_type())?;
+        Ok(ListCapabilitiesResponse::new(capabilities))
+    }
+}
+
+pub struct AnnounceAgentHandler;
+
+impl ActionHandler for AnnounceAgentHandler {
+    type Action = AnnounceAgentRequest;
+
+    fn handle(
+        &self,
+        agent: &Agent,
+        frame: RqMeshFrame<AnnounceAgentRequest>,
+    ) -> Result<DescribeAgentResponse> {
+        let announced = frame.contents().agent();
+        let endpoint = announced
+            .endpoint()
+            .expect("AnnounceAgentRequest::validate checked the endpoint");
+        agent.record_peer(announced, endpoint)?;
+        agent.announce_member(endpoint, announced.version(), Some(announced.node_id()))?;
+        agent.describe()
+    }
+}
+
+pub struct ListPeersHandler;
+
+impl ActionHandler for ListPeersHandler {
+    type Action = ListPeersRequest;
+
+    fn handle(
+        &self,
+        agent: &Agent,
+        _frame: RqMeshFrame<ListPeersRequest>,
+    ) -> Result<ListPeersResponse> {
+        Ok(ListPeersResponse::new(agent.list_peers()?))
+    }
+}
+
+pub struct PingHandler;
+
+impl ActionHandler for PingHandler {
+    type Action = PingRequest;
+
+    fn handle(&self, _agent: &Agent, _frame: RqMeshFrame<PingRequest>) -> Result<PingResponse> {
+        Ok(PingResponse::default())
+    }
+}
+
+/// Probes a member on the caller's behalf. Only members are probed, so that
+/// the agent cannot be used to reach arbitrary addresses.
+pub struct PingReqHandler;
+
+impl ActionHandler for PingReqHandler {
+    type Action = PingReqRequest;
+
+    fn handle(&self, agent: &Agent, frame: RqMeshFrame<PingReqRequest>) -> Result<PingReqResponse> {
+        let target = frame.contents().target();
+        if !agent.is_member(target)? {
+            return Err(RqMeshError::from(StorageErrorKind::new_not_found(
+                "member", target,
+            )));
+        }
+        Ok(PingReqResponse::new(agent.ping_member(target)))
+    }
+}
+
+pub struct RunCommandHandler;
+
+impl ActionHandler for RunCommandHandler {
+    type Action = RunCommandRequest;
+
+    fn handle(&self, agent: &Agent, frame: RqMeshFrame<RunCommandRequest>) -> Result<JobRecord> {
+        agent.run_command(frame.authenticated_caller(), frame.contents())
+    }
+
+    fn arguments(&self, action: &RunCommandRequest) -> Vec<(&'static str, String)> {
+        command_arguments(action)
+    }
+}
+
+pub struct JobStatusHandler;
+
+impl ActionHandler for JobStatusHandler {
+    type Action = JobStatusRequest;
+
+    fn handle(&self, agent: &Agent, frame: RqMeshFrame<JobStatusRequest>) -> Result<JobRecord> {
+        let job = agent.job_status(frame.contents().job_id())?;
+        check_job_owner(&job, &frame)?;
+        Ok(job)
+    }
+}
+
+pub struct EnqueueJobHandler;
+
+impl ActionHandler for EnqueueJobHandler {
+    type Action = EnqueueJobRequest;
+
+    fn handle(&self, agent: &Agent, frame: RqMeshFrame<EnqueueJobRequest>) -> Result<JobRecord> {
+        agent.enqueue_job(frame.authenticated_caller(), frame.contents())
+    }
+
+    fn arguments(&self, action: &EnqueueJobRequest) -> Vec<(&'static str, String)> {
+        command_arguments(action.command())
+    }
+}
+
+pub struct CancelJobHandler;
+
+impl ActionHandler for CancelJobHandler {
+    type Action = CancelJobRequest;
+
+    fn handle(&self, agent: &Agent, frame: RqMeshFrame<CancelJobRequest>) -> Result<JobRecord> {
+        let job = agent.job_status(frame.contents().job_id())?;
+        check_job_owner(&job, &frame)?;
+        agent.cancel_job(job.job_id())
+    }
+}
+
+pub struct AgentLoadHandler;
+
+impl ActionHandler for AgentLoadHandler {
+    type Action = AgentLoadRequest;
+
+    fn handle(
+        &self,
+        agent: &Agent,
+        _frame: RqMeshFrame<AgentLoadRequest>,
+    ) -> Result<AgentLoadResponse> {
+        agent.load()
+    }
+}
+
+pub struct QueryAuditLogHandler;
+
+impl ActionHandler for QueryAuditLogHandler {
+    type Action = QueryAuditLogRequest;
+
+    fn handle(
+        &self,
+        agent: &Agent,
+        frame: RqMeshFrame<QueryAuditLogRequest>,
+    ) -> Result<QueryAuditLogResponse> {
+        Ok(QueryAuditLogResponse::new(
+            agent.query_audit_log(frame.contents())?,
+        ))
+    }
+}
+
+/// Routes requests to a capable agent, dispatching them with `local` when
+/// this agent is chosen.
+pub struct RouteHandler {
+    local: Arc<Dispatcher>,
+}
+
+impl RouteHandler {
+    pub fn new(local: Arc<Dispatcher>) -> RouteHandler {
+        RouteHandler { local }
+    }
+}
+
+impl ActionHandler for RouteHandler {
+    type Action = RouteRequest;
+
+    fn handle(&self, agent: &Agent, frame: RqMeshFrame<RouteRequest>) -> Result<RoutedResponse> {
+        let context = frame.context().clone();
+        agent.route(&self.local, context, frame.into_contents())
+    }
+}
+
+/// Broadcasts requests to every matching agent, dispatching them with
+/// `local` when this agent matches.
+pub struct BroadcastHandler {
+    local: Arc<Dispatcher>,
+}
+
+impl BroadcastHandler {
+    pub fn new(local: Arc<Dispatcher>) -> BroadcastHandler {
+        BroadcastHandler { local }
+    }
+}
+
+impl ActionHandler for BroadcastHandler {
+    type Action = BroadcastRequest;
+
+    fn handle(
+        &self,
+        agent: &Agent,
+        frame: RqMeshFrame<BroadcastRequest>,
+    ) -> Result<BroadcastResponse> {
+        agent.broadcast(&self.local, frame.context(), frame.contents())
+    }
+}
+
+/// Checks that the sender of `frame` may perform its action on `job`: a job
+/// run for an authenticated caller belongs to that caller alone, while one
+/// run for an unauthenticated caller is open to anyone, as running it was.
+fn check_job_owner<T>(job: &JobRecord, frame: &RqMeshFrame<T>) -> Result<()>
+where
+    T: RqMeshProtocolAction,
+{
+    match job.requestor() {
+        Some(owner) if frame.authenticated_caller() != Some(owner) => Err(RqMeshError::from(
+            UnauthorizedErrorKind::new_not_job_owner(frame.caller(), job.job_id()),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Arguments of a command policy rules can restrict: the program, each of
+/// its arguments, each environment variable it sets, the directory it runs
+/// in and the user and group it runs as.
+fn command_arguments(command: &RunCommandRequest) -> Vec<(&'static str, String)> {
+    let spec = command.spec();
+    let mut arguments = vec![("program", spec.program().to_string())];
+    arguments.extend(spec.args().iter().map(|a| ("arg", a.clone())));
+    arguments.extend(
+        spec.env()
+            .iter()
+            .map(|(key, value)| ("env", format!("{}={}", key, value))),
+    );
+    if let Some(working_dir) = spec.working_dir() {
+        arguments.push(("working_dir", working_dir.to_string()));
+    }
+    if let Some(uid) = spec.uid() {
+        arguments.push(("uid", uid.to_string()));
+    }
+    if let Some(gid) = spec.gid() {
+        arguments.push(("gid", gid.to_string()));
+    }
+    arguments
+}
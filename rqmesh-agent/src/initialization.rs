@@ -1,9 +1,20 @@
-use crate::Agent;
+use crate::dependencies::{DependencyManifest, DependencyReport};
+use crate::gossip::Membership;
+use crate::install::Installer;
+use crate::jobs::RunningJobs;
+use crate::policy::Policy;
+use crate::routing::Router;
+use crate::{auth, capabilities, migrations, packages, process, tls, Agent};
 use log::{error, info, trace, warn};
-use rqmesh_core::{AgentInitializationContext, InitializationErrorKind, RqMeshError};
-use rusqlite::{params, Connection};
+use rqmesh_core::{
+    AgentInitializationContext, CommandSpec, InitializationErrorKind, RqMeshError, SigningKey,
+    SHELL,
+};
+use rusqlite::{params, Connection, OptionalExtension};
 use std::convert::TryFrom;
-use std::process::Command;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
 
 type Result<T> = std::result::Result<T, RqMeshError>;
 
@@ -16,19 +27,29 @@ impl TryFrom<AgentInitializationContext> for Agent {
             value.store_path().to_string_lossy()
         );
 
-        let check_deps_result = check_dependencies_present(&value);
-        if let Err(RqMeshError::InitializationError(
-            InitializationErrorKind::MissingRequiredDependencies { message },
-        )) = check_deps_result
-        {
-            warn!("Missing required dependencies: {}", &message);
-            try_install_missing_dependencies(&value)?;
+        let (acceptor, connector) = tls::load(value.tls())?;
+        if let Some(tls) = value.tls() {
+            info!(
+                "Using TLS certificate {}",
+                tls.cert_path().to_string_lossy()
+            );
+        }
 
-            check_dependencies_present(&value)?;
-            Ok(())
-        } else {
-            check_deps_result
-        }?;
+        let policy = match value.policy_path() {
+            Some(path) => {
+                info!(
+                    "Authorizing requests with policy {}{}",
+                    path.to_string_lossy(),
+                    if value.dry_run_policy() {
+                        " as a dry run"
+                    } else {
+                        ""
+                    }
+                );
+                Some(Policy::load(path)?)
+            }
+            None => None,
+        };
 
         check_store_path(&value)?;
         let conn = Connection::open(value.store_path()).map_err(|e| {
@@ -37,9 +58,187 @@ impl TryFrom<AgentInitializationContext> for Agent {
                 e
             )))
         })?;
-        let conn = validate_or_initialize_sqlite_connection(&value, conn)?;
-        Ok(Agent { connection: conn })
+        let (conn, node_id) = validate_or_initialize_sqlite_connection(&value, conn)?;
+        info!("Agent node ID is {}", &node_id);
+
+        // The store is opened first so that install attempts can be
+        // recorded in it, which is safe since sqlite is built in.
+        let dependencies = check_dependencies(&value, &Installer::new(&value, &conn)?)?;
+
+        let signing_key = match value.auth_key_id() {
+            Some(key_id) => {
+                info!("Signing requests to peers with key {}", key_id);
+                Some(auth::load_auth_key(&conn, key_id)?)
+            }
+            None => None,
+        };
+        if value.require_auth() {
+            info!("Requiring requests to be signed or sent over TLS");
+        }
+
+        let mut capabilities = capabilities::detect_capabilities();
+        capabilities.extend_from_slice(value.capabilities());
+        if let Some(dependencies) = dependencies {
+            capabilities.retain(|c| {
+                !dependencies.unavailable.contains(c.capability_type())
+                    && !dependencies
+                        .available
+                        .iter()
+                        .any(|a| a.capability_type() == c.capability_type())
+            });
+            capabilities.extend(dependencies.available);
+        }
+        capabilities::record_capabilities(&conn, &capabilities)?;
+
+        Ok(Agent {
+            connection: Mutex::new(conn),
+            context: value,
+            node_id,
+            membership: Membership::new(),
+            router: Router::new(),
+            acceptor,
+            connector,
+            signing_key,
+            policy,
+            running_jobs: RunningJobs::new(),
+            shutdown: Arc::new(AtomicBool::new(false)),
+        })
+    }
+}
+
+/// Migrates the store named by `ctx` to the current schema without
+/// checking dependencies or starting the agent.
+pub fn migrate_store(ctx: &AgentInitializationContext) -> Result<()> {
+    check_store_path(ctx)?;
+    let mut conn = Connection::open(ctx.store_path()).map_err(|e| {
+        RqMeshError::from(InitializationErrorKind::new_sqlite_init_err(format!(
+            "{}",
+            e
+        )))
+    })?;
+    let applied = migrations::migrate(&mut conn, ctx.store_path().to_str().unwrap_or("NA"))?;
+    info!(
+        "Applied {} schema migrations, store is at version {}",
+        applied,
+        migrations::latest_version()
+    );
+    if let Err((_, e)) = conn.close() {
+        error!("Error closing agent store: {}", e);
     }
+    Ok(())
+}
+
+/// Stores `key` in the store named by `ctx`, migrating it first, without
+/// checking dependencies or starting the agent.
+pub fn add_auth_key(ctx: &AgentInitializationContext, key: &SigningKey) -> Result<()> {
+    migrate_store(ctx)?;
+    let conn = Connection::open(ctx.store_path()).map_err(|e| {
+        RqMeshError::from(InitializationErrorKind::new_sqlite_init_err(format!(
+            "{}",
+            e
+        )))
+    })?;
+    auth::store_auth_key(&conn, key)?;
+    info!("Stored signing key {}", key.key_id());
+    if let Err((_, e)) = conn.close() {
+        error!("Error closing agent store: {}", e);
+    }
+    Ok(())
+}
+
+/// What a dry run found missing and would have installed.
+pub struct DryRunReport {
+    /// Why each way of checking dependencies found some missing.
+    pub missing: Vec<RqMeshError>,
+    /// Install commands that would have run, in order.
+    pub planned: Vec<CommandSpec>,
+}
+
+/// Checks the dependencies named by `ctx` without installing anything,
+/// touching the store or starting the agent, reporting what would have
+/// been installed.
+pub fn dry_run(ctx: &AgentInitializationContext) -> Result<DryRunReport> {
+    let installer = Installer::dry_run(ctx)?;
+    let mut missing = Vec::new();
+    let mut note = |result: Result<()>| match result {
+        Ok(()) => Ok(()),
+        Err(RqMeshError::InitializationError(
+            e @ InitializationErrorKind::MissingRequiredDependencies { .. },
+        ))
+        | Err(RqMeshError::InitializationError(
+            e @ InitializationErrorKind::MissingPackages { .. },
+        )) => {
+            missing.push(RqMeshError::from(e));
+            Ok(())
+        }
+        Err(e) => Err(e),
+    };
+
+    // Unlike a real start, carry on past whatever is missing so that every
+    // way of checking dependencies is reported on.
+    if let Some(path) = ctx.dependency_manifest() {
+        note(
+            DependencyManifest::load(path)?
+                .check(ctx.package_manager(), &installer)
+                .map(|_| ()),
+        )?;
+    }
+    if !ctx.packages().is_empty() {
+        let package_manager = packages::package_manager(ctx.package_manager())?;
+        note(packages::ensure_packages(
+            package_manager.as_ref(),
+            ctx.packages(),
+            &installer,
+        ))?;
+    } else if ctx.dependency_manifest().is_none() {
+        note(check_and_install_dependencies(ctx, &installer))?;
+    }
+    Ok(DryRunReport {
+        missing,
+        planned: installer.into_planned(),
+    })
+}
+
+/// Checks, and installs where missing, the dependencies in the manifest
+/// named by `ctx`, then the packages it declares, or with neither, runs the
+/// check and install commands.
+fn check_dependencies(
+    ctx: &AgentInitializationContext,
+    installer: &Installer,
+) -> Result<Option<DependencyReport>> {
+    let dependencies = match ctx.dependency_manifest() {
+        Some(path) => {
+            info!("Checking dependencies listed in {}", path.to_string_lossy());
+            Some(DependencyManifest::load(path)?.check(ctx.package_manager(), installer)?)
+        }
+        None => None,
+    };
+    if !ctx.packages().is_empty() {
+        let package_manager = packages::package_manager(ctx.package_manager())?;
+        packages::ensure_packages(package_manager.as_ref(), ctx.packages(), installer)?;
+    } else if dependencies.is_none() {
+        check_and_install_dependencies(ctx, installer)?;
+    }
+    Ok(dependencies)
+}
+
+/// Runs the check dependencies command, and if it reports dependencies
+/// missing, the install dependencies command followed by the check again.
+fn check_and_install_dependencies(
+    ctx: &AgentInitializationContext,
+    installer: &Installer,
+) -> Result<()> {
+    let check_deps_result = check_dependencies_present(ctx);
+    if let Err(RqMeshError::InitializationError(
+        InitializationErrorKind::MissingRequiredDependencies { message },
+    )) = &check_deps_result
+    {
+        warn!("Missing required dependencies: {}", message);
+        if try_install_missing_dependencies(ctx, installer)? {
+            return check_dependencies_present(ctx);
+        }
+    }
+    check_deps_result
 }
 
 fn check_dependencies_present(ctx: &AgentInitializationContext) -> Result<()> {
@@ -49,34 +248,17 @@ fn check_dependencies_present(ctx: &AgentInitializationContext) -> Result<()> {
     );
 
     let raw_cmd = ctx.check_deps_command();
-    let split_cmd: Vec<&str> = raw_cmd.split_ascii_whitespace().collect();
-
-    trace!(
-        "Attempting to get program name from string array {:?}",
-        &split_cmd
-    );
-    let program: String = split_cmd.get(0).map_or(
-        Err(RqMeshError::from(
-            InitializationErrorKind::new_invalid_check_deps_cmd(
-                raw_cmd,
-                "Command text must be non-empty",
-            ),
-        )),
-        |c| Ok(c.to_string()),
-    )?;
-    trace!("Retrieved program name {}", &program);
-
-    trace!(
-        "Attempting to get args array (if any) from string array {:?}",
-        &split_cmd
-    );
-    let args = &split_cmd[1..];
-    trace!("Found args array {:?}", &args);
+    let command = dependency_command(ctx, raw_cmd).map_err(|e| {
+        RqMeshError::from(InitializationErrorKind::new_invalid_check_deps_cmd(
+            raw_cmd,
+            format!("{}", e),
+        ))
+    })?;
 
-    trace!("Executing {} with args {:?}", &program, &args);
-    let output = Command::new(&program).args(args).output().map_err(|e| {
+    trace!("Executing {}", &command);
+    let output = process::run_spec(&command, &[]).map_err(|e| {
         RqMeshError::from(InitializationErrorKind::new_invalid_check_deps_cmd(
-            program,
+            command.program(),
             format!("{}", e),
         ))
     })?;
@@ -89,9 +271,9 @@ fn check_dependencies_present(ctx: &AgentInitializationContext) -> Result<()> {
             ),
         )),
         (_, sout, _) if sout.iter().filter(|b| !b.is_ascii_whitespace()).count() == 0 => Err(
-            RqMeshError::from(InitializationErrorKind::new_missing_deps(format!(
-                "Check dependency command returned empty, ensure dependencies are present"
-            ))),
+            RqMeshError::from(InitializationErrorKind::new_missing_deps(
+                "Check dependency command returned empty, ensure dependencies are present",
+            )),
         ),
         (_, sout, _) => {
             let sout = String::from_utf8(sout.to_vec()).map_err(|e| {
@@ -111,68 +293,31 @@ fn check_dependencies_present(ctx: &AgentInitializationContext) -> Result<()> {
     Ok(())
 }
 
-fn try_install_missing_dependencies(ctx: &AgentInitializationContext) -> Result<()> {
-    info!(
-        "Attempting to install missing dependencies using context cmd {}",
-        ctx.install_deps_command()
-    );
-
+/// Runs the install dependencies command, returning whether it ran.
+fn try_install_missing_dependencies(
+    ctx: &AgentInitializationContext,
+    installer: &Installer,
+) -> Result<bool> {
     let raw_cmd = ctx.install_deps_command();
-    let split_cmd: Vec<&str> = raw_cmd.split_ascii_whitespace().collect();
-
-    trace!(
-        "Attempting to get program name from string array {:?}",
-        &split_cmd
-    );
-    let program: String = split_cmd.get(0).map_or(
-        Err(RqMeshError::from(
-            InitializationErrorKind::new_invalid_install_deps_cmd(
-                raw_cmd,
-                "Command text must be non-empty",
-            ),
-        )),
-        |c| Ok(c.to_string()),
-    )?;
-    trace!("Retrieved program name {}", &program);
-
-    trace!(
-        "Attempting to get args array (if any) from string array {:?}",
-        &split_cmd
-    );
-    let args = &split_cmd[1..];
-    trace!("Found args array {:?}", &args);
-
-    trace!("Executing {} with args {:?}", &program, &args);
-    let output = Command::new(&program).args(args).output().map_err(|e| {
+    let command = dependency_command(ctx, raw_cmd).map_err(|e| {
         RqMeshError::from(InitializationErrorKind::new_invalid_install_deps_cmd(
-            program,
+            raw_cmd,
             format!("{}", e),
         ))
     })?;
+    installer.install("missing dependencies", command)
+}
 
-    match (&output.status, &output.stdout, &output.stderr) {
-        (ecode, sout, serr) if !ecode.success() => Err(RqMeshError::from(
-            InitializationErrorKind::new_invalid_install_deps_cmd(
-                raw_cmd,
-                format!("Exit code indicates error: {:?} + {:?}", sout, serr),
-            ),
-        )),
-        (_, sout, _) => {
-            let sout = String::from_utf8(sout.to_vec()).map_err(|e| {
-                RqMeshError::from(InitializationErrorKind::new_invalid_install_deps_cmd(
-                    raw_cmd,
-                    format!("Error inspecting std out: {}", e),
-                ))
-            })?;
-            info!(
-                "Install dependency command returned with valid exit code and output {}",
-                &sout.trim()
-            );
-            Ok(())
-        }
-    }?;
-
-    Ok(())
+/// The command `raw_cmd` runs, through the shell if the context says
+/// dependency commands should be.
+fn dependency_command(ctx: &AgentInitializationContext, raw_cmd: &str) -> Result<CommandSpec> {
+    if ctx.shell_commands() {
+        trace!("Running {} through {}", raw_cmd, SHELL);
+        Ok(CommandSpec::shell(raw_cmd))
+    } else {
+        trace!("Splitting command {} into program and args", raw_cmd);
+        CommandSpec::parse(raw_cmd)
+    }
 }
 
 fn check_store_path(ctx: &AgentInitializationContext) -> Result<()> {
@@ -226,19 +371,163 @@ fn check_store_path(ctx: &AgentInitializationContext) -> Result<()> {
     Ok(())
 }
 
+/// Migrates the store to the current schema, records the running version and
+/// returns the connection along with the agent's node ID, generating one the
+/// first time the store is initialized.
 fn validate_or_initialize_sqlite_connection(
     ctx: &AgentInitializationContext,
-    conn: Connection,
-) -> Result<Connection> {
-    trace!("Ensuring agent_details table exists");
-    conn.execute("
-        CREATE TABLE IF NOT EXISTS agent_details (version VARCHAR(10) NOT NULL, store_location NVARCHAR(1024) NOT NULL, initialized_at VARCHAR(100) NOT NULL, UNIQUE(version));
-       
-        ",
-          []).map_err(|e| RqMeshError::from(InitializationErrorKind::new_sqlite_init_err(format!("Error creating agent_details table: {}", e))))?;
-
-    trace!("Ensuring agent_details table is populated with current version ({}) and details", ctx.version());
+    mut conn: Connection,
+) -> Result<(Connection, String)> {
+    let applied = migrations::migrate(&mut conn, ctx.store_path().to_str().unwrap_or("NA"))?;
+    if applied > 0 {
+        info!(
+            "Applied {} schema migrations, store is at version {}",
+            applied,
+            migrations::latest_version()
+        );
+    }
+
+    trace!(
+        "Ensuring agent_details table is populated with current version ({}) and details",
+        ctx.version()
+    );
     conn.execute("INSERT OR IGNORE INTO agent_details (version, store_location, initialized_at) VALUES (?1, ?2, datetime('now'));", 
         params![ctx.version(), ctx.store_path().to_str().unwrap_or("NA")]).map_err(|e| RqMeshError::from(InitializationErrorKind::new_sqlite_init_err(format!("Error inserting into agent_details table: {}", e))))?;
-    Ok(conn)
+
+    let node_id = ensure_node_id(&conn)?;
+    Ok((conn, node_id))
+}
+
+/// Returns the node ID recorded by any previous version of the agent using
+/// this store, generating and recording a new one if there is none, so the
+/// ID survives both restarts and upgrades.
+fn ensure_node_id(conn: &Connection) -> Result<String> {
+    let init_err = |e: rusqlite::Error| {
+        RqMeshError::from(InitializationErrorKind::new_sqlite_init_err(format!(
+            "Error reading or recording node ID: {}",
+            e
+        )))
+    };
+
+    let existing: Option<String> = conn
+        .query_row(
+            "SELECT node_id FROM agent_details WHERE node_id IS NOT NULL ORDER BY initialized_at LIMIT 1",
+            [],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(init_err)?;
+    let node_id = match existing {
+        Some(node_id) => node_id,
+        None => {
+            let node_id = Uuid::new_v4().to_string();
+            info!("Generated node ID {} for new store", &node_id);
+            node_id
+        }
+    };
+
+    trace!(
+        "Ensuring every agent_details row carries node ID {}",
+        &node_id
+    );
+    conn.execute(
+        "UPDATE agent_details SET node_id = ?1 WHERE node_id IS NULL",
+        params![&node_id],
+    )
+    .map_err(init_err)?;
+    Ok(node_id)
+}
+
+/// An agent around a freshly migrated in-memory store, skipping every
+/// startup check, for testing the parts of the agent that only need the
+/// store.
+#[cfg(test)]
+pub fn in_memory_agent(context: AgentInitializationContext) -> Agent {
+    let mut conn = Connection::open_in_memory().expect("opens in-memory store");
+    migrations::migrate(&mut conn, ":memory:").expect("migrates store");
+    Agent {
+        connection: Mutex::new(conn),
+        context,
+        node_id: Uuid::new_v4().to_string(),
+        membership: Membership::new(),
+        router: Router::new(),
+        acceptor: None,
+        connector: None,
+        signing_key: None,
+        policy: None,
+        running_jobs: RunningJobs::new(),
+        shutdown: Arc::new(AtomicBool::new(false)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::{Path, PathBuf};
+
+    fn store_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "rqmesh-init-test-{}-{}.db",
+            std::process::id(),
+            name
+        ))
+    }
+
+    /// Opens the store at `path` as the agent does on startup, returning its
+    /// node ID.
+    fn open(path: &Path) -> String {
+        let ctx = AgentInitializationContext::new(path, "", "");
+        let conn = Connection::open(path).expect("opens store");
+        let (conn, node_id) =
+            validate_or_initialize_sqlite_connection(&ctx, conn).expect("initializes store");
+        conn.close().expect("closes store");
+        node_id
+    }
+
+    #[test]
+    fn node_ids_survive_reopening_the_store() {
+        let path = store_path("reopen");
+        let _ = std::fs::remove_file(&path);
+        let node_id = open(&path);
+        assert!(
+            Uuid::parse_str(&node_id).is_ok(),
+            "{} is not a UUID",
+            node_id
+        );
+        assert_eq!(open(&path), node_id);
+
+        let other = store_path("other");
+        let _ = std::fs::remove_file(&other);
+        assert_ne!(open(&other), node_id);
+        let _ = std::fs::remove_file(path);
+        let _ = std::fs::remove_file(other);
+    }
+
+    #[test]
+    fn node_ids_carry_over_to_upgraded_agents() {
+        let path = store_path("upgrade");
+        let _ = std::fs::remove_file(&path);
+        let node_id = open(&path);
+        // a later version adds its own row on first start
+        Connection::open(&path)
+            .expect("opens store")
+            .execute(
+                "INSERT INTO agent_details (version, store_location, initialized_at) VALUES ('9.9.9', 'upgraded', datetime('now', '+1 day'))",
+                [],
+            )
+            .expect("records later version");
+
+        assert_eq!(open(&path), node_id);
+        let conn = Connection::open(&path).expect("opens store");
+        let unset: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM agent_details WHERE node_id IS NULL OR node_id != ?1",
+                params![&node_id],
+                |row| row.get(0),
+            )
+            .expect("counts rows");
+        assert_eq!(unset, 0);
+        drop(conn);
+        let _ = std::fs::remove_file(path);
+    }
 }
@@ -0,0 +1,341 @@
+//! Running the commands that install an agent's missing dependencies.
+//!
+//! Every install goes through an [`Installer`], whichever way the missing
+//! dependency was found, so that installs can be previewed with a dry run,
+//! refused outright, or run through a privilege helper such as `sudo -n`
+//! while the agent itself stays unprivileged. Each install the agent runs is
+//! recorded in `install_attempts` along with its output.
+
+use crate::process::{self, ProcessOutput};
+use log::{error, info, warn};
+use rqmesh_core::{
+    AgentInitializationContext, CommandSpec, InitializationErrorKind, RqMeshError, StorageErrorKind,
+};
+use rusqlite::{params, Connection};
+use std::cell::RefCell;
+
+type Result<T> = std::result::Result<T, RqMeshError>;
+
+/// Most bytes of stdout and of stderr kept for an install attempt.
+const MAX_INSTALL_OUTPUT: usize = 64 * 1024;
+
+/// What an [`Installer`] does with the commands it is given.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstallMode {
+    /// Runs them.
+    Install,
+    /// Runs none of them, leaving whatever is missing missing.
+    NoInstall,
+    /// Runs none of them, but remembers them to report what would have run.
+    DryRun,
+}
+
+pub struct Installer<'a> {
+    mode: InstallMode,
+    privilege_helper: Option<CommandSpec>,
+    conn: Option<&'a Connection>,
+    planned: RefCell<Vec<CommandSpec>>,
+}
+
+impl<'a> Installer<'a> {
+    /// An installer for `ctx`, recording the installs it runs in `conn`.
+    pub fn new(ctx: &AgentInitializationContext, conn: &'a Connection) -> Result<Installer<'a>> {
+        let mode = if ctx.no_install() {
+            InstallMode::NoInstall
+        } else {
+            InstallMode::Install
+        };
+        Installer::with_mode(ctx, mode, Some(conn))
+    }
+
+    /// An installer for `ctx` that only remembers what it would run.
+    pub fn dry_run(ctx: &AgentInitializationContext) -> Result<Installer<'static>> {
+        Installer::with_mode(ctx, InstallMode::DryRun, None)
+    }
+
+    fn with_mode(
+        ctx: &AgentInitializationContext,
+        mode: InstallMode,
+        conn: Option<&'a Connection>,
+    ) -> Result<Installer<'a>> {
+        let privilege_helper = match ctx.privilege_helper() {
+            Some(helper) => Some(CommandSpec::parse(helper).map_err(|e| {
+                RqMeshError::from(InitializationErrorKind::new_invalid_install_deps_cmd(
+                    helper,
+                    format!("Invalid privilege helper: {}", e),
+                ))
+            })?),
+            None => None,
+        };
+        Ok(Installer {
+            mode,
+            privilege_helper,
+            conn,
+            planned: RefCell::new(Vec::new()),
+        })
+    }
+
+    /// Runs `command` to install `subject`, returning whether it was run,
+    /// and failing if it was run and did not succeed.
+    pub fn install(&self, subject: &str, command: CommandSpec) -> Result<bool> {
+        let command = self.privileged(command);
+        match self.mode {
+            InstallMode::NoInstall => {
+                warn!(
+                    "Not installing {} since installs are disabled, would run {}",
+                    subject, &command
+                );
+                return Ok(false);
+            }
+            InstallMode::DryRun => {
+                info!("Would install {} with {}", subject, &command);
+                self.planned.borrow_mut().push(command);
+                return Ok(false);
+            }
+            InstallMode::Install => {}
+        }
+
+        info!("Installing {} with {}", subject, &command);
+        let output = process::run_spec(&command, &[]);
+        self.record(subject, &command, &output);
+        let failed = |message: String| {
+            RqMeshError::from(InitializationErrorKind::new_invalid_install_deps_cmd(
+                command.to_string(),
+                message,
+            ))
+        };
+        let output = output.map_err(|e| failed(format!("{}", e)))?;
+        if !output.status.success() {
+            return Err(failed(format!(
+                "Exit code indicates error: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        info!(
+            "Installed {}: {}",
+            subject,
+            String::from_utf8_lossy(&output.stdout).trim()
+        );
+        Ok(true)
+    }
+
+    /// The commands a dry run would have run, in order.
+    pub fn into_planned(self) -> Vec<CommandSpec> {
+        self.planned.into_inner()
+    }
+
+    /// `command` run through the privilege helper, if there is one. The
+    /// command's environment is passed through `env`, since helpers such as
+    /// `sudo` do not pass on their own.
+    fn privileged(&self, command: CommandSpec) -> CommandSpec {
+        let helper = match &self.privilege_helper {
+            Some(helper) => helper,
+            None => return command,
+        };
+        let mut args = helper.args().to_vec();
+        if !command.env().is_empty() {
+            args.push("env".to_string());
+            args.extend(command.env().iter().map(|(k, v)| format!("{}={}", k, v)));
+        }
+        args.push(command.program().to_string());
+        args.extend_from_slice(command.args());
+        let mut privileged = CommandSpec::argv(helper.program(), args);
+        if let Some(working_dir) = command.working_dir() {
+            privileged = privileged.with_working_dir(working_dir);
+        }
+        if let Some(timeout) = command.timeout() {
+            privileged = privileged.with_timeout(timeout);
+        }
+        privileged
+    }
+
+    /// Records an install attempt. Failing to record it is logged rather
+    /// than returned, so as not to hide how the install itself went.
+    fn record(
+        &self,
+        subject: &str,
+        command: &CommandSpec,
+        output: &std::io::Result<ProcessOutput>,
+    ) {
+        let conn = match self.conn {
+            Some(conn) => conn,
+            None => return,
+        };
+        let (exit_code, succeeded, stdout, stderr, error) = match output {
+            Ok(output) => (
+                output.status.code(),
+                output.status.success(),
+                truncated(&output.stdout),
+                truncated(&output.stderr),
+                None,
+            ),
+            Err(e) => (None, false, &[][..], &[][..], Some(format!("{}", e))),
+        };
+        let recorded = conn.execute(
+            "INSERT INTO install_attempts (attempted_at, subject, command, exit_code, succeeded, stdout, stderr, error) VALUES (datetime('now'), ?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                subject,
+                command.to_string(),
+                exit_code,
+                succeeded,
+                stdout,
+                stderr,
+                error
+            ],
+        );
+        if let Err(e) = recorded {
+            error!(
+                "Error recording install of {}: {}",
+                subject,
+                RqMeshError::from(StorageErrorKind::new_query_err(
+                    "record_install",
+                    format!("{}", e)
+                ))
+            );
+        }
+    }
+}
+
+fn truncated(output: &[u8]) -> &[u8] {
+    &output[..output.len().min(MAX_INSTALL_OUTPUT)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrations;
+    use std::path::{Path, PathBuf};
+
+    /// Subject, command, exit code, success and stdout of a recorded install.
+    type Attempt = (String, String, Option<i32>, bool, Vec<u8>);
+
+    fn marker(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "rqmesh-install-test-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn touch(path: &Path) -> CommandSpec {
+        CommandSpec::argv("touch", vec![path.to_string_lossy().to_string()])
+    }
+
+    fn store() -> Connection {
+        let mut conn = Connection::open_in_memory().expect("opens in-memory store");
+        migrations::migrate(&mut conn, ":memory:").expect("migrates store");
+        conn
+    }
+
+    fn attempts(conn: &Connection) -> Vec<Attempt> {
+        let mut stmt = conn
+            .prepare("SELECT subject, command, exit_code, succeeded, stdout FROM install_attempts ORDER BY id")
+            .expect("prepares query");
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                ))
+            })
+            .expect("queries attempts");
+        rows.collect::<rusqlite::Result<_>>()
+            .expect("reads attempts")
+    }
+
+    #[test]
+    fn dry_runs_report_the_privileged_commands_without_running_them() {
+        let ctx =
+            AgentInitializationContext::new(":memory:", "", "").with_privilege_helper("sudo -n");
+        let installer = Installer::dry_run(&ctx).expect("creates installer");
+        let path = marker("dry-run");
+
+        let ran = installer
+            .install(
+                "marker",
+                touch(&path).with_env("DEBIAN_FRONTEND", "noninteractive"),
+            )
+            .expect("plans install");
+
+        assert!(!ran);
+        assert!(!path.exists());
+        assert_eq!(
+            installer.into_planned(),
+            vec![CommandSpec::argv(
+                "sudo",
+                vec![
+                    "-n".to_string(),
+                    "env".to_string(),
+                    "DEBIAN_FRONTEND=noninteractive".to_string(),
+                    "touch".to_string(),
+                    path.to_string_lossy().to_string(),
+                ]
+            )]
+        );
+    }
+
+    #[test]
+    fn no_install_mode_neither_runs_nor_records_installs() {
+        let conn = store();
+        let ctx = AgentInitializationContext::new(":memory:", "", "").with_no_install(true);
+        let installer = Installer::new(&ctx, &conn).expect("creates installer");
+        let path = marker("no-install");
+
+        assert!(!installer
+            .install("marker", touch(&path))
+            .expect("skips install"));
+        assert!(!path.exists());
+        assert!(attempts(&conn).is_empty());
+    }
+
+    #[test]
+    fn installs_run_through_the_privilege_helper_and_are_recorded() {
+        let conn = store();
+        let ctx = AgentInitializationContext::new(":memory:", "", "").with_privilege_helper("env");
+        let installer = Installer::new(&ctx, &conn).expect("creates installer");
+        let path = marker("installed");
+
+        assert!(installer.install("marker", touch(&path)).expect("installs"));
+        assert!(path.exists());
+        assert!(installer
+            .install("greeting", CommandSpec::argv("echo", vec!["hello"]))
+            .expect("installs"));
+        assert!(installer
+            .install("broken", CommandSpec::argv("false", Vec::<String>::new()))
+            .is_err());
+
+        let recorded = attempts(&conn);
+        assert_eq!(
+            recorded,
+            vec![
+                (
+                    "marker".to_string(),
+                    format!("env touch {}", path.to_string_lossy()),
+                    Some(0),
+                    true,
+                    Vec::new()
+                ),
+                (
+                    "greeting".to_string(),
+                    "env echo hello".to_string(),
+                    Some(0),
+                    true,
+                    b"hello\n".to_vec()
+                ),
+                (
+                    "broken".to_string(),
+                    "env false".to_string(),
+                    Some(1),
+                    false,
+                    Vec::new()
+                ),
+            ]
+        );
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,311 @@
+use crate::{process, Agent};
+use log::{debug, info, warn};
+use rqmesh_core::{
+    JobErrorKind, JobRecord, JobState, RqMeshError, RunCommandRequest, StorageErrorKind,
+};
+use rusqlite::{params, OptionalExtension};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use uuid::Uuid;
+
+type Result<T> = std::result::Result<T, RqMeshError>;
+
+/// Timeout given to commands that do not set one, so that no job can hold
+/// a worker, or a queue lease, forever.
+pub const DEFAULT_JOB_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// How one run of a job ended.
+pub struct JobOutcome {
+    pub state: JobState,
+    pub exit_code: Option<i32>,
+    pub error: Option<String>,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// The run was cut short by shutdown rather than finishing.
+    pub aborted: bool,
+}
+
+/// Flags that stop the program of each job being run, by job ID, so that
+/// cancelling a running job kills its program rather than only marking it
+/// cancelled.
+#[derive(Default)]
+pub struct RunningJobs {
+    jobs: Mutex<HashMap<String, Arc<AtomicBool>>>,
+}
+
+impl RunningJobs {
+    pub fn new() -> RunningJobs {
+        RunningJobs::default()
+    }
+
+    /// Stops the program of `job_id`, returning whether it was running.
+    pub fn abort(&self, job_id: &str) -> bool {
+        match self.lock().get(job_id) {
+            Some(flag) => {
+                flag.store(true, Ordering::SeqCst);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn start(&self, job_id: &str) -> Arc<AtomicBool> {
+        let flag = Arc::new(AtomicBool::new(false));
+        self.lock().insert(job_id.to_string(), Arc::clone(&flag));
+        flag
+    }
+
+    fn finish(&self, job_id: &str) {
+        self.lock().remove(job_id);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Arc<AtomicBool>>> {
+        self.jobs.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Agent {
+    /// Runs `request` on behalf of `requestor`, recorded as the job's owner
+    /// if the caller was authenticated, and
+    /// returns the finished job. The program is killed after
+    /// [`DEFAULT_JOB_TIMEOUT`] if the request sets no timeout, or when the
+    /// agent shuts down.
+    pub fn run_command(
+        &self,
+        requestor: Option<&str>,
+        request: &RunCommandRequest,
+    ) -> Result<JobRecord> {
+        let job_id = self.create_job(requestor, request)?;
+        let outcome = self.execute_job(&job_id, request)?;
+        info!(
+            "Job {} ({}) finished as {}",
+            &job_id,
+            request.program(),
+            outcome.state
+        );
+        self.finish_job(&job_id, &outcome)?;
+        self.job_status(&job_id)
+    }
+
+    /// Runs `request` as the job `job_id`, which must already be recorded as
+    /// running, until it finishes, times out, is cancelled or the agent shuts
+    /// down.
+    pub fn execute_job(&self, job_id: &str, request: &RunCommandRequest) -> Result<JobOutcome> {
+        let cancelled = self.running_jobs.start(job_id);
+        // A cancellation that came in before the flag was registered only
+        // changed the job's state, so check it once the flag is in place.
+        let state = self.job_state(job_id);
+        let outcome = match state {
+            Ok(JobState::Running) => {
+                let request = with_default_timeout(request);
+                Ok(execute(&request, &[&cancelled, self.shutdown()]))
+            }
+            Ok(state) => Ok(JobOutcome {
+                state,
+                exit_code: None,
+                error: None,
+                stdout: Vec::new(),
+                stderr: Vec::new(),
+                aborted: true,
+            }),
+            Err(e) => Err(e),
+        };
+        self.running_jobs.finish(job_id);
+        outcome
+    }
+
+    fn job_state(&self, job_id: &str) -> Result<JobState> {
+        let state: String = self
+            .connection()
+            .query_row(
+                "SELECT state FROM jobs WHERE job_id = ?1",
+                params![job_id],
+                |row| row.get(0),
+            )
+            .map_err(|e| job_query_err("job_state", e))?;
+        state.parse()
+    }
+
+    /// Records a job that is being run straight away, returning its ID.
+    fn create_job(&self, requestor: Option<&str>, request: &RunCommandRequest) -> Result<String> {
+        let job_id = Uuid::new_v4().to_string();
+        let encoded = encode_request("create_job", request)?;
+        debug!(
+            "Recording job {} for {}: {} {:?}",
+            &job_id,
+            requestor.unwrap_or("an unauthenticated caller"),
+            request.program(),
+            request.args()
+        );
+        self.connection()
+            .execute(
+                "INSERT INTO jobs (job_id, requestor, program, request, state, attempts, max_attempts, created_at, started_at) VALUES (?1, ?2, ?3, ?4, ?5, 1, 1, datetime('now'), datetime('now'))",
+                params![
+                    &job_id,
+                    requestor,
+                    request.program(),
+                    encoded,
+                    JobState::Running.to_string()
+                ],
+            )
+            .map_err(|e| job_query_err("create_job", e))?;
+        Ok(job_id)
+    }
+
+    /// Records the outcome of a job that is still running, leaving jobs that
+    /// were cancelled in the meantime alone.
+    pub fn finish_job(&self, job_id: &str, outcome: &JobOutcome) -> Result<()> {
+        let updated = self
+            .connection()
+            .execute(
+                "UPDATE jobs SET state = ?2, exit_code = ?3, error = ?4, stdout = ?5, stderr = ?6, finished_at = datetime('now'), lease_expires_at = NULL WHERE job_id = ?1 AND state = ?7",
+                params![
+                    job_id,
+                    outcome.state.to_string(),
+                    outcome.exit_code,
+                    outcome.error,
+                    outcome.stdout,
+                    outcome.stderr,
+                    JobState::Running.to_string()
+                ],
+            )
+            .map_err(|e| job_query_err("finish_job", e))?;
+        if updated == 0 {
+            warn!(
+                "Job {} was no longer running when it finished, discarding result",
+                job_id
+            );
+        }
+        Ok(())
+    }
+
+    pub fn job_status(&self, job_id: &str) -> Result<JobRecord> {
+        let row = self
+            .connection()
+            .query_row(
+                "SELECT requestor, request, state, exit_code, error, stdout, stderr, created_at, started_at, finished_at, attempts, max_attempts FROM jobs WHERE job_id = ?1",
+                params![job_id],
+                |row| {
+                    let requestor: Option<String> = row.get(0)?;
+                    let request: Vec<u8> = row.get(1)?;
+                    let state: String = row.get(2)?;
+                    let exit_code: Option<i32> = row.get(3)?;
+                    let error: Option<String> = row.get(4)?;
+                    let stdout: Option<Vec<u8>> = row.get(5)?;
+                    let stderr: Option<Vec<u8>> = row.get(6)?;
+                    let created_at: String = row.get(7)?;
+                    let started_at: Option<String> = row.get(8)?;
+                    let finished_at: Option<String> = row.get(9)?;
+                    let attempts: u32 = row.get(10)?;
+                    let max_attempts: u32 = row.get(11)?;
+                    Ok((
+                        requestor,
+                        request,
+                        state,
+                        (exit_code, error, stdout, stderr),
+                        (created_at, started_at, finished_at),
+                        (attempts, max_attempts),
+                    ))
+                },
+            )
+            .optional()
+            .map_err(|e| job_query_err("job_status", e))?;
+
+        let (requestor, request, state, outcome, times, attempts) =
+            row.ok_or_else(|| RqMeshError::from(StorageErrorKind::new_not_found("job", job_id)))?;
+        let request = decode_request("job_status", job_id, &request)?;
+        let (exit_code, error, stdout, stderr) = outcome;
+        let (created_at, started_at, finished_at) = times;
+        let (attempts, max_attempts) = attempts;
+        Ok(JobRecord::new(
+            job_id,
+            requestor,
+            request.program(),
+            request.args().to_vec(),
+            state.parse()?,
+            created_at,
+        )
+        .with_outcome(
+            exit_code,
+            error,
+            stdout.unwrap_or_default(),
+            stderr.unwrap_or_default(),
+        )
+        .with_times(started_at, finished_at)
+        .with_attempts(attempts, max_attempts))
+    }
+}
+
+/// `request`, with [`DEFAULT_JOB_TIMEOUT`] if it sets no timeout of its own.
+fn with_default_timeout(request: &RunCommandRequest) -> RunCommandRequest {
+    match request.timeout() {
+        Some(_) => request.clone(),
+        None => request.clone().with_timeout(DEFAULT_JOB_TIMEOUT),
+    }
+}
+
+/// Runs the program described by `request` to completion, or until any of
+/// `abort` is set.
+pub fn execute(request: &RunCommandRequest, abort: &[&AtomicBool]) -> JobOutcome {
+    match process::run_spec(request.spec(), abort) {
+        Ok(output) => {
+            let (state, error) = match request.timeout() {
+                Some(timeout) if output.timed_out => (
+                    JobState::TimedOut,
+                    Some(JobErrorKind::new_timed_out(request.program(), timeout).to_string()),
+                ),
+                _ if output.aborted => (
+                    JobState::Failed,
+                    Some(
+                        JobErrorKind::new_interrupted("Stopped before the program finished")
+                            .to_string(),
+                    ),
+                ),
+                _ if output.status.success() => (JobState::Succeeded, None),
+                _ => (JobState::Failed, None),
+            };
+            JobOutcome {
+                state,
+                exit_code: output.status.code(),
+                error,
+                stdout: output.stdout,
+                stderr: output.stderr,
+                aborted: output.aborted,
+            }
+        }
+        Err(e) => JobOutcome {
+            state: JobState::Failed,
+            exit_code: None,
+            error: Some(
+                JobErrorKind::new_spawn_failed(request.program(), format!("{}", e)).to_string(),
+            ),
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+            aborted: false,
+        },
+    }
+}
+
+pub fn encode_request(operation: &str, request: &RunCommandRequest) -> Result<Vec<u8>> {
+    bincode::serialize(request).map_err(|e| {
+        RqMeshError::from(StorageErrorKind::new_query_err(
+            operation,
+            format!("Error encoding request: {}", e),
+        ))
+    })
+}
+
+pub fn decode_request(operation: &str, job_id: &str, request: &[u8]) -> Result<RunCommandRequest> {
+    bincode::deserialize(request).map_err(|e| {
+        RqMeshError::from(StorageErrorKind::new_query_err(
+            operation,
+            format!("Error decoding request of job {}: {}", job_id, e),
+        ))
+    })
+}
+
+pub fn job_query_err(operation: &str, e: rusqlite::Error) -> RqMeshError {
+    RqMeshError::from(StorageErrorKind::new_query_err(operation, format!("{}", e)))
+}
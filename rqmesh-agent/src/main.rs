@@ -1,109 +1,494 @@
-use std::{
-    convert::{TryFrom, TryInto},
-    path::PathBuf,
-};
+use std::convert::TryInto;
 
+mod audit;
+mod auth;
+mod broadcast;
+mod capabilities;
+mod config;
+mod dependencies;
+mod dispatch;
+mod gossip;
+mod handlers;
 mod initialization;
-use serde::{Deserialize, Serialize};
-use bincode::{deserialize, serialize};
-use log::{info, LevelFilter};
-use rqmesh_core::{AgentInitializationContext, InitializationErrorKind, RqMeshError};
-use rqmesh_core::{RqMeshProtocolAction, RqMeshFrame, DescribeAgentRequest, DescribeAgentResponse };
+mod install;
+mod jobs;
+mod migrations;
+mod packages;
+mod peers;
+mod policy;
+mod process;
+mod queue;
+mod routing;
+mod server;
+mod tls;
+use config::AgentConfig;
+use dispatch::Dispatcher;
+use log::{error, info};
+use rqmesh_core::{AgentInitializationContext, RqMeshError, StorageErrorKind};
+use rqmesh_core::{DescribeAgentResponse, Handshake, SigningKey};
 use simplelog::{ColorChoice, Config, TermLogger, TerminalMode};
 
-use std::net::{SocketAddrV4, Ipv4Addr, TcpListener};
-use std::io::{Read, Error};
+use std::io::{Error, Read};
+use std::net::{IpAddr, SocketAddr, TcpListener};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
 
 fn main() {
-    let log_level = match std::env::var("RUST_LOG_LEVEL")
-        .and_then(|e| Ok(e.to_ascii_lowercase()))
-        .as_deref()
-    {
-        Ok("trace") => LevelFilter::Trace,
-        Ok("debug") => LevelFilter::Debug,
-        Ok("info") => LevelFilter::Info,
-        Ok("error") => LevelFilter::Error,
-        Ok("off") => LevelFilter::Off,
-        Ok("warn") => LevelFilter::Warn,
-        v => {
-            eprintln!(
-                "Error setting logging level option {:?}, defaulting to Warn",
-                v
-            );
-            LevelFilter::Warn
-        }
-    };
-
-    TermLogger::init(
-        log_level,
-        Config::default(),
-        TerminalMode::Mixed,
-        ColorChoice::Auto,
-    )
-    .expect("Error initializing logging");
-
     let matches = clap::App::new("rqmesh-agent")
         .arg(
-            clap::Arg::with_name("STORE_LOCATION")
-                .help("Location of sqlite database")
+            clap::Arg::with_name("CONFIG")
+                .long("config")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .global(true)
+                .help("TOML config file, see rqmesh-agent/src/config.rs for the schema; may be repeated, later files overriding earlier ones, and defaults to $RQMESH_CONFIG"),
+        )
+        .arg(
+            clap::Arg::with_name("LOG_LEVEL")
+                .long("log-level")
                 .takes_value(true)
                 .multiple(false)
-                .default_value("./.rqmesh-agent.db"),
+                .possible_values(&["off", "error", "warn", "info", "debug", "trace"])
+                .help("Level messages are logged at, warn by default"),
+        )
+        .arg(
+            clap::Arg::with_name("STORE_LOCATION")
+                .help("Location of sqlite database, ./.rqmesh-agent.db by default")
+                .takes_value(true)
+                .multiple(false),
         )
         .arg(
             clap::Arg::with_name("CHECK_CMD")
                 .long("check-cmd")
                 .takes_value(true)
                 .multiple(false)
-                .default_value("apk list sqlite --installed")
-                .help("Command to validate base dependencies present"),
+                .help("Command to validate base dependencies present, \"apk list sqlite --installed\" by default"),
         )
         .arg(
             clap::Arg::with_name("INSTALL_CMD")
                 .long("install-cmd")
                 .takes_value(true)
                 .multiple(false)
-                .default_value("apk add sqlite"),
+                .help("Command to install missing base dependencies, \"apk add sqlite\" by default"),
+        )
+        .arg(
+            clap::Arg::with_name("SHELL_CMDS")
+                .long("shell-cmds")
+                .takes_value(false)
+                .help("Run --check-cmd and --install-cmd with /bin/sh -c, allowing pipes, redirections and variables, instead of splitting them into arguments"),
+        )
+        .arg(
+            clap::Arg::with_name("DEPENDENCY_MANIFEST")
+                .long("dependency-manifest")
+                .takes_value(true)
+                .multiple(false)
+                .help("TOML or YAML manifest of dependencies to check and install, replacing --check-cmd and --install-cmd; see rqmesh-agent/src/dependencies.rs for the format"),
+        )
+        .arg(
+            clap::Arg::with_name("PACKAGE")
+                .long("package")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Package that must be installed, as NAME or NAME followed by =, >=, >, <= or < and a VERSION; may be repeated, and replaces --check-cmd and --install-cmd"),
+        )
+        .arg(
+            clap::Arg::with_name("PACKAGE_MANAGER")
+                .long("package-manager")
+                .takes_value(true)
+                .multiple(false)
+                .possible_values(packages::PACKAGE_MANAGERS)
+                .help("Package manager to check and install packages with, detected from the host by default"),
+        )
+        .arg(
+            clap::Arg::with_name("NO_INSTALL")
+                .long("no-install")
+                .takes_value(false)
+                .help("Fail on missing dependencies instead of installing them"),
+        )
+        .arg(
+            clap::Arg::with_name("PRIVILEGE_HELPER")
+                .long("privilege-helper")
+                .takes_value(true)
+                .multiple(false)
+                .help("Command, such as \"sudo -n\" or \"doas -n\", to run install commands through, so the agent itself need not be privileged"),
+        )
+        .arg(
+            clap::Arg::with_name("DRY_RUN")
+                .long("dry-run")
+                .takes_value(false)
+                .conflicts_with("NO_INSTALL")
+                .help("Print the dependencies missing and the commands that would install them, and exit without installing anything"),
+        )
+        .arg(
+            clap::Arg::with_name("WORKERS")
+                .long("workers")
+                .takes_value(true)
+                .multiple(false)
+                .help("Number of connections served concurrently, 8 by default"),
+        )
+        .arg(
+            clap::Arg::with_name("JOB_WORKERS")
+                .long("job-workers")
+                .takes_value(true)
+                .multiple(false)
+                .help("Number of queued jobs run concurrently, 2 by default"),
+        )
+        .arg(
+            clap::Arg::with_name("BIND")
+                .long("bind")
+                .takes_value(true)
+                .multiple(false)
+                .help("IPv4 or IPv6 address the listener binds to, 127.0.0.1 by default"),
+        )
+        .arg(
+            clap::Arg::with_name("PORT")
+                .long("port")
+                .takes_value(true)
+                .multiple(false)
+                .help("Port the listener binds to, 0 (the default) picks a free port"),
+        )
+        .arg(
+            clap::Arg::with_name("PORT_FILE")
+                .long("port-file")
+                .takes_value(true)
+                .multiple(false)
+                .help("File to write the bound port to once listening"),
+        )
+        .arg(
+            clap::Arg::with_name("ADVERTISE_HOST")
+                .long("advertise-host")
+                .takes_value(true)
+                .multiple(false)
+                .help("Host other nodes should use to reach this agent, defaults to the bind address"),
+        )
+        .arg(
+            clap::Arg::with_name("CAPABILITY")
+                .long("capability")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Capability to advertise, as NAME or NAME=VERSION; may be repeated"),
+        )
+        .arg(
+            clap::Arg::with_name("SEED")
+                .long("seed")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Address of an agent to join the mesh through; may be repeated"),
+        )
+        .arg(
+            clap::Arg::with_name("PROTOCOL_PERIOD_MS")
+                .long("protocol-period-ms")
+                .takes_value(true)
+                .multiple(false)
+                .help("Milliseconds between failure detector probes, 1000 by default"),
+        )
+        .arg(
+            clap::Arg::with_name("SUSPECT_TIMEOUT_MS")
+                .long("suspect-timeout-ms")
+                .takes_value(true)
+                .multiple(false)
+                .help("Milliseconds a suspect member has to refute before it is declared dead, 5000 by default"),
+        )
+        .arg(
+            clap::Arg::with_name("TLS_CERT")
+                .long("tls-cert")
+                .takes_value(true)
+                .multiple(false)
+                .requires_all(&["TLS_KEY", "TLS_CA"])
+                .help("PEM certificate to serve and connect to peers with; enables mutual TLS"),
+        )
+        .arg(
+            clap::Arg::with_name("TLS_KEY")
+                .long("tls-key")
+                .takes_value(true)
+                .multiple(false)
+                .requires_all(&["TLS_CERT", "TLS_CA"])
+                .help("PEM private key of the TLS certificate"),
+        )
+        .arg(
+            clap::Arg::with_name("TLS_CA")
+                .long("tls-ca")
+                .takes_value(true)
+                .multiple(false)
+                .requires_all(&["TLS_CERT", "TLS_KEY"])
+                .help("PEM bundle of CAs client and peer certificates must be issued by"),
+        )
+        .arg(
+            clap::Arg::with_name("AUTH_KEY")
+                .long("auth-key")
+                .takes_value(true)
+                .multiple(false)
+                .help("ID of the stored key to sign requests to peers with"),
+        )
+        .arg(
+            clap::Arg::with_name("REQUIRE_AUTH")
+                .long("require-auth")
+                .takes_value(false)
+                .help("Reject requests that are neither signed with a stored key nor sent over TLS"),
+        )
+        .arg(
+            clap::Arg::with_name("POLICY")
+                .long("policy")
+                .takes_value(true)
+                .multiple(false)
+                .help("TOML file of rules deciding which callers may perform which actions"),
+        )
+        .arg(
+            clap::Arg::with_name("DRY_RUN_POLICY")
+                .long("dry-run-policy")
+                .takes_value(false)
+                .requires("POLICY")
+                .help("Log the requests the policy would deny instead of denying them"),
+        )
+        .arg(
+            clap::Arg::with_name("AUDIT_RETENTION_DAYS")
+                .long("audit-retention-days")
+                .takes_value(true)
+                .multiple(false)
+                .help("Days audit records are kept, 0 keeps them forever, 30 by default"),
+        )
+        .arg(
+            clap::Arg::with_name("AUDIT_MAX_ROWS")
+                .long("audit-max-rows")
+                .takes_value(true)
+                .multiple(false)
+                .help("Most audit records kept, oldest forgotten first, 0 for no limit, 1000000 by default"),
+        )
+        .arg(
+            clap::Arg::with_name("ADD_AUTH_KEY")
+                .long("add-auth-key")
+                .takes_value(true)
+                .multiple(false)
+                .help("Generate and store a signing key for KEY_ID, or store the one read from --auth-key-secret-file, and exit"),
+        )
+        .arg(
+            clap::Arg::with_name("AUTH_KEY_SECRET_FILE")
+                .long("auth-key-secret-file")
+                .takes_value(true)
+                .multiple(false)
+                .requires("ADD_AUTH_KEY")
+                .help("File holding the hex secret for --add-auth-key, or - to read it from stdin"),
+        )
+        .arg(
+            clap::Arg::with_name("MIGRATE_ONLY")
+                .long("migrate-only")
+                .takes_value(false)
+                .help("Migrate the store to the current schema and exit"),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("config")
+                .about("Shows or checks the agent's settings")
+                .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    clap::SubCommand::with_name("print")
+                        .about("Prints the settings in effect, from config files, RQMESH_* environment variables and flags, as a config file"),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("validate")
+                        .about("Checks config files, and the dependency manifest and policy they name, without starting; checks the settings in effect without FILE")
+                        .arg(
+                            clap::Arg::with_name("FILE")
+                                .takes_value(true)
+                                .multiple(true),
+                        ),
+                ),
         )
         .get_matches();
 
-    let store_location = matches
-        .value_of("STORE_LOCATION")
-        .expect("Must set STORE_LOCATION");
-    let store_location: PathBuf =
-        std::path::PathBuf::try_from(store_location).expect("STORE_LOCATION must be a valid path");
-    let check_dependencies_command = matches
-        .value_of("CHECK_CMD")
-        .expect("Must set a command to check for required dependencies");
-    let install_dependcies_command = matches
-        .value_of("INSTALL_CMD")
-        .expect("Must set a command to install dependencies");
-
-    let init_context = AgentInitializationContext::new(
-        store_location,
-        check_dependencies_command,
-        install_dependcies_command,
-    );
+    if let ("config", Some(config_matches)) = matches.subcommand() {
+        std::process::exit(config_command(&matches, config_matches));
+    }
+
+    let config = match AgentConfig::load(&config::config_files(&matches), &matches) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error loading config: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    TermLogger::init(
+        config.log_level(),
+        Config::default(),
+        TerminalMode::Mixed,
+        ColorChoice::Auto,
+    )
+    .expect("Error initializing logging");
+
+    let workers = config.listener.workers;
+    let job_workers = config.listener.job_workers;
+    let init_context = config
+        .to_context()
+        .expect("config was checked when it was loaded");
+
+    if let Some(key_id) = matches.value_of("ADD_AUTH_KEY") {
+        let secret_file = matches.value_of("AUTH_KEY_SECRET_FILE");
+        let key = match signing_key(key_id, secret_file) {
+            Ok(key) => key,
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        };
+        if let Err(e) = initialization::add_auth_key(&init_context, &key) {
+            error!("Error storing signing key: {}", e);
+            std::process::exit(1);
+        }
+        if secret_file.is_some() {
+            println!("Stored signing key {}", key.key_id());
+        } else {
+            // A generated secret exists nowhere else, so this is the only chance to hand it out.
+            println!("{}={}", key.key_id(), key.secret_hex());
+        }
+        return;
+    }
+
+    if matches.is_present("DRY_RUN") {
+        match initialization::dry_run(&init_context) {
+            Ok(report) => {
+                for missing in &report.missing {
+                    println!("missing: {}", missing);
+                }
+                for command in &report.planned {
+                    println!("would run: {}", command);
+                }
+                if report.missing.is_empty() {
+                    println!("all dependencies present");
+                }
+            }
+            Err(e) => {
+                error!("Error checking dependencies: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if matches.is_present("MIGRATE_ONLY") {
+        if let Err(e) = initialization::migrate_store(&init_context) {
+            error!("Error migrating store: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
 
     let agent: Agent = init_context.try_into().expect("failed to create agent");
 
     info!("Successfully initialized {}", &agent);
     
-    info!("Starting listener");
-    agent.listen();
+    let dispatcher = handlers::builtin_dispatcher();
+
+    let signal_flag = Arc::clone(&agent.shutdown);
+    ctrlc::set_handler(move || {
+        info!("Received termination signal, shutting down");
+        signal_flag.store(true, Ordering::SeqCst);
+    })
+    .expect("Error installing signal handler");
+
+    info!(
+        "Starting listener with actions {:?}",
+        dispatcher.actions().collect::<Vec<_>>()
+    );
+    if let Err(e) = agent.listen(dispatcher, workers, job_workers) {
+        error!("Listener exited with error: {}", e);
+    }
+}
+
+/// Generates a key for `key_id`, or reads its hex secret from `secret_file` (`-` for stdin)
+/// so that the secret never appears on the command line.
+fn signing_key(key_id: &str, secret_file: Option<&str>) -> Result<SigningKey, String> {
+    if key_id.contains('=') {
+        return Err(format!(
+            "Invalid key ID {}: pass the secret with --auth-key-secret-file",
+            key_id.split('=').next().unwrap_or("")
+        ));
+    }
+    let secret = match secret_file {
+        None => return Ok(SigningKey::generate(key_id)),
+        Some("-") => {
+            let mut secret = String::new();
+            std::io::stdin()
+                .read_to_string(&mut secret)
+                .map_err(|e| format!("Error reading signing key secret from stdin: {}", e))?;
+            secret
+        }
+        Some(path) => std::fs::read_to_string(path)
+            .map_err(|e| format!("Error reading signing key secret from {}: {}", path, e))?,
+    };
+    SigningKey::parse(&format!("{}={}", key_id, secret.trim())).map_err(|e| e.to_string())
+}
+
+/// Runs `rqmesh-agent config`, returning the exit code.
+fn config_command(matches: &clap::ArgMatches, config_matches: &clap::ArgMatches) -> i32 {
+    match config_matches.subcommand() {
+        ("print", _) => match AgentConfig::load(&config::config_files(matches), matches)
+            .and_then(|config| config.to_toml())
+        {
+            Ok(toml) => {
+                print!("{}", toml);
+                0
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                1
+            }
+        },
+        ("validate", Some(validate_matches)) => match validate_matches.values_of("FILE") {
+            Some(files) => {
+                let mut code = 0;
+                for file in files {
+                    match AgentConfig::validate_file(file.as_ref()) {
+                        Ok(_) => println!("{}: ok", file),
+                        Err(e) => {
+                            eprintln!("{}: {}", file, e);
+                            code = 1;
+                        }
+                    }
+                }
+                code
+            }
+            None => match AgentConfig::load(&config::config_files(matches), matches)
+                .and_then(|config| config.check_referenced_files())
+            {
+                Ok(()) => {
+                    println!("ok");
+                    0
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    1
+                }
+            },
+        },
+        _ => 2,
+    }
 }
 
 pub struct Agent {
-    connection: rusqlite::Connection,
+    connection: Mutex<rusqlite::Connection>,
+    context: AgentInitializationContext,
+    node_id: String,
+    membership: gossip::Membership,
+    router: routing::Router,
+    acceptor: Option<tls::TlsAcceptor>,
+    connector: Option<rqmesh_client::tls::TlsConnector>,
+    signing_key: Option<SigningKey>,
+    policy: Option<policy::Policy>,
+    running_jobs: jobs::RunningJobs,
+    shutdown: Arc<AtomicBool>,
 }
 
 impl std::fmt::Display for Agent {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let vstr = self.connection.query_row("SELECT version, store_location, initialized_at FROM agent_details ORDER BY initialized_at DESC LIMIT 1", [], |row| {
+        let vstr = self.connection().query_row("SELECT version, store_location, initialized_at FROM agent_details ORDER BY initialized_at DESC LIMIT 1", [], |row| {
             let version = row.get(0).unwrap_or("RETRIEVAL ERROR".to_string());
             let store_location = row.get(1).unwrap_or("RETRIEVAL ERROR".to_string());
             let initialized_at = row.get(2).unwrap_or("RETRIEVAL ERROR".to_string());
-            Ok(format!("agent v{} @ {}: {}", version, initialized_at, store_location))
+            Ok(format!("agent {} v{} @ {}: {}", self.node_id, version, initialized_at, store_location))
         }).expect("retrieval error");
 
         write!(f, "{}", vstr)?;
@@ -112,29 +497,140 @@ impl std::fmt::Display for Agent {
 }
 
 impl Agent {
+    /// Locks the store for the duration of the returned guard.
+    ///
+    /// A panic in another handler cannot leave the sqlite connection in a
+    /// half-written state that matters to us, so poisoning is ignored.
+    fn connection(&self) -> MutexGuard<'_, rusqlite::Connection> {
+        self.connection.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Stable identifier of this agent, used as the requestor of every frame
+    /// it sends.
+    fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    /// Set once the agent is shutting down, at which point anything it is
+    /// running on behalf of a caller should stop.
+    fn shutdown(&self) -> &AtomicBool {
+        &self.shutdown
+    }
+
     fn describe(&self) -> Result<DescribeAgentResponse, RqMeshError> {
-        let res = self.connection.query_row("SELECT version, store_location, initialized_at FROM agent_details ORDER BY initialized_at DESC LIMIT 1", [], 
+        let res = self.connection().query_row("SELECT version, store_location, initialized_at, endpoint FROM agent_details ORDER BY initialized_at DESC LIMIT 1", [], 
         |row| {
             let version : String = row.get(0)?;
             let store_location : String = row.get(1)?;
             let initialized_at : String = row.get(2)?;
-            Ok(DescribeAgentResponse::new(version, store_location, initialized_at))
-        }).map_err(|e| RqMeshError::from(InitializationErrorKind::new_sqlite_init_err(format!("{}", e))))?;
+            let endpoint : Option<String> = row.get(3)?;
+            Ok(DescribeAgentResponse::new(self.node_id.as_str(), version, store_location, initialized_at, endpoint))
+        }).map_err(|e| RqMeshError::from(StorageErrorKind::new_query_err("describe", format!("{}", e))))?;
 
         Ok(res)
     }
 
-    fn listen(&self) -> Result<(), Error> {
-        let loopback = Ipv4Addr::new(127, 0, 0, 1);
-        let socket = SocketAddrV4::new(loopback, 0);
-        let listener = TcpListener::bind(socket)?;
-        let port = listener.local_addr()?;
-        info!("Listening on {}, access this port to end the program", port);
-        let (mut tcp_stream, addr) = listener.accept()?; //block  until requested
-        info!("Connection received! {:?} is sending data.", addr);
-        let mut input = String::new();
-        let _ = tcp_stream.read_to_string(&mut input)?;
-        info!("{:?} says {}", addr, input);
+    /// Serves requests until the agent is shut down, then closes the store.
+    fn listen(
+        self,
+        dispatcher: Dispatcher,
+        workers: usize,
+        job_workers: usize,
+    ) -> Result<(), Error> {
+        let shutdown = Arc::clone(&self.shutdown);
+        let listener = TcpListener::bind(self.context.listen_address())?;
+        let local_addr = listener.local_addr()?;
+        info!("Listening on {}", local_addr);
+
+        let endpoint = self.advertised_endpoint(local_addr);
+        info!("Advertising endpoint {}", &endpoint);
+        if let Err(e) = self.record_endpoint(&endpoint) {
+            error!("Error recording advertised endpoint: {}", e);
+        }
+        if let Some(port_file) = self.context.port_file() {
+            info!(
+                "Writing port {} to {}",
+                local_addr.port(),
+                port_file.to_string_lossy()
+            );
+            std::fs::write(port_file, format!("{}\n", local_addr.port()))?;
+        }
+
+        let identity = self.node_id().to_string();
+        let handshake = Handshake::new(identity.as_str(), dispatcher.actions());
+        self.join_membership(&endpoint, &identity);
+        if let Err(e) = self.recover_jobs() {
+            error!("Error recovering jobs from the previous run: {}", e);
+        }
+        let agent = Arc::new(self);
+        let mut background = vec![
+            peers::spawn_seed_contact(Arc::clone(&agent), identity, Arc::clone(&shutdown))?,
+            gossip::spawn_failure_detector(Arc::clone(&agent), Arc::clone(&shutdown))?,
+            audit::spawn_audit_pruner(Arc::clone(&agent), Arc::clone(&shutdown))?,
+        ];
+        for worker in 0..job_workers {
+            background.push(queue::spawn_job_worker(
+                worker,
+                Arc::clone(&agent),
+                Arc::clone(&shutdown),
+            )?);
+        }
+        server::serve(
+            Arc::clone(&agent),
+            Arc::new(dispatcher),
+            listener,
+            Arc::clone(&shutdown),
+            workers,
+            handshake,
+        )?;
+
+        for handle in background {
+            if handle.join().is_err() {
+                error!("Background task panicked");
+            }
+        }
+
+        match Arc::try_unwrap(agent) {
+            Ok(agent) => agent.close(),
+            Err(_) => error!("Store still in use after listener exited, not closing"),
+        }
+        Ok(())
+    }
+
+    /// Address other nodes should use to reach a listener bound to `local_addr`.
+    fn advertised_endpoint(&self, local_addr: SocketAddr) -> String {
+        match self.context.advertise_host() {
+            Some(host) => match host.parse::<IpAddr>() {
+                Ok(ip) => SocketAddr::new(ip, local_addr.port()).to_string(),
+                Err(_) => format!("{}:{}", host, local_addr.port()),
+            },
+            None => local_addr.to_string(),
+        }
+    }
+
+    fn record_endpoint(&self, endpoint: &str) -> Result<(), RqMeshError> {
+        self.connection()
+            .execute(
+                "UPDATE agent_details SET endpoint = ?1 WHERE version = ?2",
+                rusqlite::params![endpoint, self.context.version()],
+            )
+            .map_err(|e| {
+                RqMeshError::from(StorageErrorKind::new_query_err(
+                    "record_endpoint",
+                    format!("{}", e),
+                ))
+            })?;
         Ok(())
     }
-}
\ No newline at end of file
+
+    fn close(self) {
+        let connection = self
+            .connection
+            .into_inner()
+            .unwrap_or_else(|e| e.into_inner());
+        match connection.close() {
+            Ok(()) => info!("Closed agent store"),
+            Err((_, e)) => error!("Error closing agent store: {}", e),
+        }
+    }
+}
@@ -0,0 +1,345 @@
+//! Numbered changes to the agent store's schema.
+//!
+//! Each migration runs at most once per store, in its own transaction along
+//! with the `schema_migrations` row recording it, so a store is always left
+//! at some whole version. Migrations are only ever appended; changing one
+//! that has shipped would leave existing stores out of step with new ones.
+//!
+//! Stores created before migrations were tracked already have some of these
+//! tables and columns, so the early migrations create only what is missing.
+
+use log::{info, trace};
+use rqmesh_core::{InitializationErrorKind, RqMeshError};
+use rusqlite::{params, Connection};
+
+type Result<T> = std::result::Result<T, RqMeshError>;
+
+struct Migration {
+    version: u32,
+    description: &'static str,
+    apply: fn(&Connection) -> rusqlite::Result<()>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create agent_details",
+        apply: |conn| {
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS agent_details (version VARCHAR(10) NOT NULL, store_location NVARCHAR(1024) NOT NULL, initialized_at VARCHAR(100) NOT NULL, UNIQUE(version))",
+                [],
+            )?;
+            Ok(())
+        },
+    },
+    Migration {
+        version: 2,
+        description: "record advertised endpoint",
+        apply: |conn| add_column(conn, "agent_details", "endpoint", "NVARCHAR(1024) NULL"),
+    },
+    Migration {
+        version: 3,
+        description: "create capabilities",
+        apply: |conn| {
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS capabilities (capability_type NVARCHAR(256) NOT NULL PRIMARY KEY, version NVARCHAR(256) NULL, source VARCHAR(20) NOT NULL, updated_at VARCHAR(100) NOT NULL)",
+                [],
+            )?;
+            Ok(())
+        },
+    },
+    Migration {
+        version: 4,
+        description: "create peers",
+        apply: |conn| {
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS peers (endpoint NVARCHAR(1024) NOT NULL PRIMARY KEY, version VARCHAR(10) NOT NULL, first_seen VARCHAR(100) NOT NULL, last_seen VARCHAR(100) NOT NULL)",
+                [],
+            )?;
+            Ok(())
+        },
+    },
+    Migration {
+        version: 5,
+        description: "track peer membership state",
+        apply: |conn| {
+            add_column(conn, "peers", "state", "VARCHAR(10) NOT NULL DEFAULT 'alive'")?;
+            add_column(conn, "peers", "incarnation", "INTEGER NOT NULL DEFAULT 0")
+        },
+    },
+    Migration {
+        version: 6,
+        description: "record node IDs",
+        apply: |conn| {
+            add_column(conn, "agent_details", "node_id", "VARCHAR(36) NULL")?;
+            add_column(conn, "peers", "node_id", "VARCHAR(36) NULL")
+        },
+    },
+    Migration {
+        version: 7,
+        description: "create jobs",
+        apply: |conn| {
+            conn.execute(
+                "CREATE TABLE jobs (job_id VARCHAR(36) NOT NULL PRIMARY KEY, requestor NVARCHAR(1024) NOT NULL, program NVARCHAR(1024) NOT NULL, request BLOB NOT NULL, state VARCHAR(20) NOT NULL, exit_code INTEGER NULL, error NVARCHAR(1024) NULL, stdout BLOB NULL, stderr BLOB NULL, created_at VARCHAR(100) NOT NULL, started_at VARCHAR(100) NULL, finished_at VARCHAR(100) NULL)",
+                [],
+            )?;
+            Ok(())
+        },
+    },
+    Migration {
+        version: 8,
+        description: "queue jobs",
+        apply: |conn| {
+            conn.execute_batch(
+                "ALTER TABLE jobs ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0;
+                 ALTER TABLE jobs ADD COLUMN max_attempts INTEGER NOT NULL DEFAULT 1;
+                 ALTER TABLE jobs ADD COLUMN visibility_timeout INTEGER NULL;
+                 ALTER TABLE jobs ADD COLUMN available_at VARCHAR(100) NULL;
+                 ALTER TABLE jobs ADD COLUMN lease_expires_at VARCHAR(100) NULL;
+                 UPDATE jobs SET attempts = 1 WHERE started_at IS NOT NULL;
+                 CREATE INDEX jobs_available ON jobs (state, available_at);",
+            )
+        },
+    },
+    Migration {
+        version: 9,
+        description: "store signing keys and seen nonces",
+        apply: |conn| {
+            conn.execute_batch(
+                "CREATE TABLE auth_keys (key_id NVARCHAR(256) NOT NULL PRIMARY KEY, secret BLOB NOT NULL, created_at VARCHAR(100) NOT NULL);
+                 CREATE TABLE auth_nonces (key_id NVARCHAR(256) NOT NULL, nonce BLOB NOT NULL, signed_at INTEGER NOT NULL, PRIMARY KEY (key_id, nonce));
+                 CREATE INDEX auth_nonces_signed_at ON auth_nonces (signed_at);",
+            )
+        },
+    },
+    Migration {
+        version: 10,
+        description: "record handled requests in an append-only audit log",
+        apply: |conn| {
+            conn.execute_batch(
+                "CREATE TABLE audit_log (id INTEGER PRIMARY KEY AUTOINCREMENT, recorded_at VARCHAR(100) NOT NULL, requestor NVARCHAR(256) NOT NULL, claimed_requestor NVARCHAR(256) NOT NULL, action NVARCHAR(256) NOT NULL, outcome VARCHAR(20) NOT NULL, duration_us INTEGER NOT NULL, error TEXT, peer_addr NVARCHAR(256));
+                 CREATE INDEX audit_log_recorded_at ON audit_log (recorded_at);
+                 CREATE INDEX audit_log_requestor ON audit_log (requestor, recorded_at);
+                 CREATE TRIGGER audit_log_append_only BEFORE UPDATE ON audit_log BEGIN SELECT RAISE(ABORT, 'audit_log is append-only'); END;",
+            )
+        },
+    },
+    Migration {
+        version: 11,
+        description: "record dependency install attempts",
+        apply: |conn| {
+            conn.execute_batch(
+                "CREATE TABLE install_attempts (id INTEGER PRIMARY KEY AUTOINCREMENT, attempted_at VARCHAR(100) NOT NULL, subject NVARCHAR(256) NOT NULL, command TEXT NOT NULL, exit_code INTEGER NULL, succeeded INTEGER NOT NULL, stdout BLOB, stderr BLOB, error TEXT);
+                 CREATE INDEX install_attempts_attempted_at ON install_attempts (attempted_at);",
+            )
+        },
+    },
+    Migration {
+        version: 12,
+        description: "allow jobs and audit records without an authenticated requestor",
+        apply: |conn| {
+            // SQLite cannot drop NOT NULL from a column, so both tables are
+            // rebuilt with the same columns and copied over.
+            conn.execute_batch(
+                "CREATE TABLE jobs_new (job_id VARCHAR(36) NOT NULL PRIMARY KEY, requestor NVARCHAR(1024) NULL, program NVARCHAR(1024) NOT NULL, request BLOB NOT NULL, state VARCHAR(20) NOT NULL, exit_code INTEGER NULL, error NVARCHAR(1024) NULL, stdout BLOB NULL, stderr BLOB NULL, created_at VARCHAR(100) NOT NULL, started_at VARCHAR(100) NULL, finished_at VARCHAR(100) NULL, attempts INTEGER NOT NULL DEFAULT 0, max_attempts INTEGER NOT NULL DEFAULT 1, visibility_timeout INTEGER NULL, available_at VARCHAR(100) NULL, lease_expires_at VARCHAR(100) NULL);
+                 INSERT INTO jobs_new (job_id, requestor, program, request, state, exit_code, error, stdout, stderr, created_at, started_at, finished_at, attempts, max_attempts, visibility_timeout, available_at, lease_expires_at)
+                     SELECT job_id, requestor, program, request, state, exit_code, error, stdout, stderr, created_at, started_at, finished_at, attempts, max_attempts, visibility_timeout, available_at, lease_expires_at FROM jobs;
+                 DROP TABLE jobs;
+                 ALTER TABLE jobs_new RENAME TO jobs;
+                 CREATE INDEX jobs_available ON jobs (state, available_at);
+
+                 CREATE TABLE audit_log_new (id INTEGER PRIMARY KEY AUTOINCREMENT, recorded_at VARCHAR(100) NOT NULL, requestor NVARCHAR(256) NULL, claimed_requestor NVARCHAR(256) NOT NULL, action NVARCHAR(256) NOT NULL, outcome VARCHAR(20) NOT NULL, duration_us INTEGER NOT NULL, error TEXT, peer_addr NVARCHAR(256));
+                 INSERT INTO audit_log_new (id, recorded_at, requestor, claimed_requestor, action, outcome, duration_us, error, peer_addr)
+                     SELECT id, recorded_at, requestor, claimed_requestor, action, outcome, duration_us, error, peer_addr FROM audit_log;
+                 DROP TABLE audit_log;
+                 ALTER TABLE audit_log_new RENAME TO audit_log;
+                 CREATE INDEX audit_log_recorded_at ON audit_log (recorded_at);
+                 CREATE INDEX audit_log_requestor ON audit_log (requestor, recorded_at);
+                 CREATE TRIGGER audit_log_append_only BEFORE UPDATE ON audit_log BEGIN SELECT RAISE(ABORT, 'audit_log is append-only'); END;",
+            )
+        },
+    },
+];
+
+/// Schema version this agent expects its store to be at.
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map_or(0, |m| m.version)
+}
+
+/// Brings the store up to [`latest_version`], returning the number of
+/// migrations applied.
+///
+/// Refuses to touch a store already migrated past the latest version this
+/// agent knows about, since a newer agent may have changed it in ways this
+/// one would misread.
+pub fn migrate(conn: &mut Connection, store_location: &str) -> Result<usize> {
+    let migration_err = |e: rusqlite::Error| {
+        RqMeshError::from(InitializationErrorKind::new_sqlite_init_err(format!(
+            "Error migrating store: {}",
+            e
+        )))
+    };
+
+    trace!("Ensuring schema_migrations table exists");
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS schema_migrations (version INTEGER NOT NULL PRIMARY KEY, description NVARCHAR(256) NOT NULL, applied_at VARCHAR(100) NOT NULL)",
+        [],
+    )
+    .map_err(migration_err)?;
+
+    let current = current_version(conn).map_err(migration_err)?;
+    let latest = latest_version();
+    if current > latest {
+        return Err(RqMeshError::from(
+            InitializationErrorKind::new_unsupported_schema_version(
+                store_location,
+                current,
+                latest,
+            ),
+        ));
+    }
+    trace!("Store is at schema version {} of {}", current, latest);
+
+    let mut applied = 0;
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        info!(
+            "Applying schema migration {}: {}",
+            migration.version, migration.description
+        );
+        let tx = conn.transaction().map_err(migration_err)?;
+        (migration.apply)(&tx).map_err(|e| {
+            RqMeshError::from(InitializationErrorKind::new_sqlite_init_err(format!(
+                "Error applying schema migration {} ({}): {}",
+                migration.version, migration.description, e
+            )))
+        })?;
+        tx.execute(
+            "INSERT INTO schema_migrations (version, description, applied_at) VALUES (?1, ?2, datetime('now'))",
+            params![migration.version, migration.description],
+        )
+        .map_err(migration_err)?;
+        tx.commit().map_err(migration_err)?;
+        applied += 1;
+    }
+    Ok(applied)
+}
+
+fn current_version(conn: &Connection) -> rusqlite::Result<u32> {
+    conn.query_row(
+        "SELECT COALESCE(MAX(version), 0) FROM schema_migrations",
+        [],
+        |row| row.get(0),
+    )
+}
+
+/// Adds `column` to `table` unless a store created before migrations were
+/// tracked already has it.
+fn add_column(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> rusqlite::Result<()> {
+    let exists: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info(?1) WHERE name = ?2",
+        params![table, column],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        trace!("Adding column {} to {} table", column, table);
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+            [],
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn columns(conn: &Connection, table: &str) -> Vec<String> {
+        let mut statement = conn
+            .prepare("SELECT name FROM pragma_table_info(?1)")
+            .expect("prepares");
+        let names = statement
+            .query_map(params![table], |row| row.get(0))
+            .expect("queries")
+            .collect::<rusqlite::Result<Vec<String>>>()
+            .expect("reads columns");
+        names
+    }
+
+    #[test]
+    fn fresh_stores_reach_the_latest_version_once() {
+        let mut conn = Connection::open_in_memory().expect("opens store");
+        let applied = migrate(&mut conn, ":memory:").expect("migrates");
+        assert_eq!(applied, MIGRATIONS.len());
+        assert_eq!(
+            current_version(&conn).expect("reads version"),
+            latest_version()
+        );
+        assert!(columns(&conn, "jobs").contains(&"lease_expires_at".to_string()));
+
+        assert_eq!(migrate(&mut conn, ":memory:").expect("migrates again"), 0);
+    }
+
+    #[test]
+    fn stores_from_before_migrations_were_tracked_upgrade_in_place() {
+        let mut conn = Connection::open_in_memory().expect("opens store");
+        // tables as the last agent without schema_migrations left them
+        conn.execute_batch(
+            "CREATE TABLE agent_details (version VARCHAR(10) NOT NULL, store_location NVARCHAR(1024) NOT NULL, initialized_at VARCHAR(100) NOT NULL, endpoint NVARCHAR(1024) NULL, UNIQUE(version));
+             INSERT INTO agent_details (version, store_location, initialized_at, endpoint) VALUES ('0.1.0', 'old.db', datetime('now'), '10.0.0.1:4100');
+             CREATE TABLE peers (endpoint NVARCHAR(1024) NOT NULL PRIMARY KEY, version VARCHAR(10) NOT NULL, first_seen VARCHAR(100) NOT NULL, last_seen VARCHAR(100) NOT NULL, state VARCHAR(10) NOT NULL DEFAULT 'alive', incarnation INTEGER NOT NULL DEFAULT 0);
+             INSERT INTO peers (endpoint, version, first_seen, last_seen, state, incarnation) VALUES ('10.0.0.2:4100', '0.1.0', datetime('now'), datetime('now'), 'suspect', 3);",
+        )
+        .expect("creates old tables");
+
+        migrate(&mut conn, "old.db").expect("migrates");
+        assert_eq!(
+            current_version(&conn).expect("reads version"),
+            latest_version()
+        );
+        assert!(columns(&conn, "agent_details").contains(&"node_id".to_string()));
+        let endpoint: String = conn
+            .query_row("SELECT endpoint FROM agent_details", [], |row| row.get(0))
+            .expect("keeps agent details");
+        assert_eq!(endpoint, "10.0.0.1:4100");
+        let peer: (String, i64, Option<String>) = conn
+            .query_row(
+                "SELECT state, incarnation, node_id FROM peers WHERE endpoint = '10.0.0.2:4100'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .expect("keeps peers");
+        assert_eq!(peer, ("suspect".to_string(), 3, None));
+    }
+
+    #[test]
+    fn stores_from_a_newer_agent_are_left_alone() {
+        let mut conn = Connection::open_in_memory().expect("opens store");
+        migrate(&mut conn, "new.db").expect("migrates");
+        conn.execute(
+            "INSERT INTO schema_migrations (version, description, applied_at) VALUES (?1, 'from the future', datetime('now'))",
+            params![latest_version() + 1],
+        )
+        .expect("records newer migration");
+
+        match migrate(&mut conn, "new.db") {
+            Err(RqMeshError::InitializationError(
+                InitializationErrorKind::UnsupportedSchemaVersion {
+                    store_location,
+                    schema_version,
+                    supported_version,
+                },
+            )) => {
+                assert_eq!(store_location, "new.db");
+                assert_eq!(schema_version, latest_version() + 1);
+                assert_eq!(supported_version, latest_version());
+            }
+            other => panic!("expected UnsupportedSchemaVersion, got {:?}", other),
+        }
+    }
+}
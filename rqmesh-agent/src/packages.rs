@@ -0,0 +1,387 @@
+//! Checking and installing system packages through the host's package
+//! manager.
+//!
+//! Each supported package manager knows how to ask for the installed version
+//! of a single package and how to install a list of them, so initialization
+//! can say exactly which declared packages are missing or too old rather
+//! than relying on a check command printing something.
+
+use crate::install::Installer;
+use crate::process;
+use log::{info, trace, warn};
+use rqmesh_core::{CommandSpec, InitializationErrorKind, PackageRequirement, RqMeshError};
+use std::path::Path;
+
+type Result<T> = std::result::Result<T, RqMeshError>;
+
+/// Names accepted by [`by_name`], in the order [`detect`] tries them.
+pub const PACKAGE_MANAGERS: &[&str] = &["apk", "apt", "dnf", "pacman"];
+
+/// A declared package that is not installed, or not at a version meeting
+/// its requirement.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MissingPackage {
+    pub requirement: PackageRequirement,
+    pub installed_version: Option<String>,
+}
+
+impl std::fmt::Display for MissingPackage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.installed_version {
+            Some(version) => write!(f, "{} (found {})", self.requirement, version),
+            None => write!(f, "{} (not installed)", self.requirement),
+        }
+    }
+}
+
+/// A system package manager the agent can check and install packages with.
+pub trait PackageManager {
+    /// Name the package manager is chosen by, one of [`PACKAGE_MANAGERS`].
+    fn name(&self) -> &'static str;
+
+    /// Command printing the installed version of `package`, or exiting
+    /// unsuccessfully if it is not installed.
+    fn query_command(&self, package: &str) -> CommandSpec;
+
+    /// Pulls the version out of the output of [`query_command`], returning
+    /// `None` if the output shows the package is not installed.
+    ///
+    /// [`query_command`]: PackageManager::query_command
+    fn parse_version(&self, package: &str, stdout: &str) -> Option<String>;
+
+    /// Command installing the latest available version of each of
+    /// `packages` without prompting.
+    fn install_command(&self, packages: &[&str]) -> CommandSpec;
+
+    /// Version of `package` installed on the host, if any.
+    fn installed_version(&self, package: &str) -> Result<Option<String>> {
+        let command = self.query_command(package);
+        trace!("Querying {} for {} with {}", self.name(), package, &command);
+        let output = process::run_spec(&command, &[]).map_err(|e| {
+            RqMeshError::from(InitializationErrorKind::new_invalid_check_deps_cmd(
+                command.to_string(),
+                format!("{}", e),
+            ))
+        })?;
+        if !output.status.success() {
+            return Ok(None);
+        }
+        Ok(self.parse_version(package, &String::from_utf8_lossy(&output.stdout)))
+    }
+
+    /// Those of `required` that are not installed at a satisfying version.
+    fn missing(&self, required: &[PackageRequirement]) -> Result<Vec<MissingPackage>> {
+        let mut missing = Vec::new();
+        for requirement in required {
+            let installed_version = self.installed_version(requirement.name())?;
+            match &installed_version {
+                Some(version) if requirement.satisfied_by(version) => {
+                    info!(
+                        "Found package {} version {}, satisfying {}",
+                        requirement.name(),
+                        version,
+                        requirement
+                    );
+                }
+                _ => missing.push(MissingPackage {
+                    requirement: requirement.clone(),
+                    installed_version,
+                }),
+            }
+        }
+        Ok(missing)
+    }
+}
+
+/// Alpine's apk.
+pub struct Apk;
+
+impl PackageManager for Apk {
+    fn name(&self) -> &'static str {
+        "apk"
+    }
+
+    fn query_command(&self, package: &str) -> CommandSpec {
+        CommandSpec::argv("apk", vec!["list", "--installed", package])
+    }
+
+    fn parse_version(&self, package: &str, stdout: &str) -> Option<String> {
+        // Lines look like `sqlite-3.44.2-r0 x86_64 {sqlite} (blessing) [installed]`,
+        // and may include packages whose names merely start with `package`.
+        stdout
+            .lines()
+            .filter_map(|line| line.split_whitespace().next())
+            .filter_map(|token| token.strip_prefix(package)?.strip_prefix('-'))
+            .find(|version| version.starts_with(|c: char| c.is_ascii_digit()))
+            .map(|version| version.to_string())
+    }
+
+    fn install_command(&self, packages: &[&str]) -> CommandSpec {
+        let mut args = vec!["add", "--no-cache"];
+        args.extend_from_slice(packages);
+        CommandSpec::argv("apk", args)
+    }
+}
+
+/// Debian's apt, querying dpkg for what is installed.
+pub struct Apt;
+
+impl PackageManager for Apt {
+    fn name(&self) -> &'static str {
+        "apt"
+    }
+
+    fn query_command(&self, package: &str) -> CommandSpec {
+        CommandSpec::argv(
+            "dpkg-query",
+            vec!["-W", "-f=${Status}\t${Version}", package],
+        )
+    }
+
+    fn parse_version(&self, _package: &str, stdout: &str) -> Option<String> {
+        // dpkg remembers removed packages, so the status must say installed.
+        let (status, version) = stdout.split_once('\t')?;
+        if status.ends_with(" installed") && !version.trim().is_empty() {
+            Some(version.trim().to_string())
+        } else {
+            None
+        }
+    }
+
+    fn install_command(&self, packages: &[&str]) -> CommandSpec {
+        let mut args = vec!["install", "-y", "--no-install-recommends"];
+        args.extend_from_slice(packages);
+        CommandSpec::argv("apt-get", args).with_env("DEBIAN_FRONTEND", "noninteractive")
+    }
+}
+
+/// Fedora's dnf, querying rpm for what is installed.
+pub struct Dnf;
+
+impl PackageManager for Dnf {
+    fn name(&self) -> &'static str {
+        "dnf"
+    }
+
+    fn query_command(&self, package: &str) -> CommandSpec {
+        CommandSpec::argv(
+            "rpm",
+            vec!["-q", "--qf", "%{VERSION}-%{RELEASE}\n", package],
+        )
+    }
+
+    fn parse_version(&self, _package: &str, stdout: &str) -> Option<String> {
+        stdout
+            .lines()
+            .next()
+            .map(str::trim)
+            .filter(|version| !version.is_empty())
+            .map(|version| version.to_string())
+    }
+
+    fn install_command(&self, packages: &[&str]) -> CommandSpec {
+        let mut args = vec!["install", "-y"];
+        args.extend_from_slice(packages);
+        CommandSpec::argv("dnf", args)
+    }
+}
+
+/// Arch's pacman.
+pub struct Pacman;
+
+impl PackageManager for Pacman {
+    fn name(&self) -> &'static str {
+        "pacman"
+    }
+
+    fn query_command(&self, package: &str) -> CommandSpec {
+        CommandSpec::argv("pacman", vec!["-Q", package])
+    }
+
+    fn parse_version(&self, package: &str, stdout: &str) -> Option<String> {
+        // Prints `sqlite 3.44.2-1`.
+        let mut tokens = stdout.split_whitespace();
+        if tokens.next() != Some(package) {
+            return None;
+        }
+        tokens.next().map(|version| version.to_string())
+    }
+
+    fn install_command(&self, packages: &[&str]) -> CommandSpec {
+        let mut args = vec!["-S", "--noconfirm", "--needed"];
+        args.extend_from_slice(packages);
+        CommandSpec::argv("pacman", args)
+    }
+}
+
+/// The package manager named `name`, one of [`PACKAGE_MANAGERS`].
+pub fn by_name(name: &str) -> Option<Box<dyn PackageManager>> {
+    match name {
+        "apk" => Some(Box::new(Apk)),
+        "apt" => Some(Box::new(Apt)),
+        "dnf" => Some(Box::new(Dnf)),
+        "pacman" => Some(Box::new(Pacman)),
+        _ => None,
+    }
+}
+
+/// The first package manager whose tools are all on the `PATH`.
+pub fn detect() -> Option<Box<dyn PackageManager>> {
+    let tools: &[(&str, &[&str])] = &[
+        ("apk", &["apk"]),
+        ("apt", &["dpkg-query", "apt-get"]),
+        ("dnf", &["rpm", "dnf"]),
+        ("pacman", &["pacman"]),
+    ];
+    let (name, _) = tools
+        .iter()
+        .find(|(_, binaries)| binaries.iter().all(|b| find_on_path(b).is_some()))?;
+    by_name(name)
+}
+
+/// Full path of the executable `binary` in the first `PATH` directory
+/// holding it.
+pub fn find_on_path(binary: &str) -> Option<std::path::PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(binary))
+        .find(|candidate| is_executable(candidate))
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata()
+        .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+/// Picks the package manager named in `name`, or detects the host's one.
+pub fn package_manager(name: Option<&str>) -> Result<Box<dyn PackageManager>> {
+    match name {
+        Some(name) => by_name(name).ok_or_else(|| {
+            RqMeshError::from(InitializationErrorKind::new_no_package_manager(format!(
+                "Unknown package manager {}, expected one of {}",
+                name,
+                PACKAGE_MANAGERS.join(", ")
+            )))
+        }),
+        None => detect().ok_or_else(|| {
+            RqMeshError::from(InitializationErrorKind::new_no_package_manager(format!(
+                "None of {} found on PATH",
+                PACKAGE_MANAGERS.join(", ")
+            )))
+        }),
+    }
+}
+
+/// Makes sure every one of `required` is installed at a satisfying version,
+/// installing those that are not and failing with the exact list still
+/// missing afterwards.
+pub fn ensure_packages(
+    package_manager: &dyn PackageManager,
+    required: &[PackageRequirement],
+    installer: &Installer,
+) -> Result<()> {
+    info!(
+        "Checking {} packages using {}",
+        required.len(),
+        package_manager.name()
+    );
+    let missing = package_manager.missing(required)?;
+    if missing.is_empty() {
+        return Ok(());
+    }
+    warn!("Missing required packages: {}", describe(&missing));
+
+    let names: Vec<&str> = missing.iter().map(|m| m.requirement.name()).collect();
+    let installed = installer.install(
+        &format!("packages {}", names.join(", ")),
+        package_manager.install_command(&names),
+    )?;
+
+    let missing = if installed {
+        package_manager.missing(required)?
+    } else {
+        missing
+    };
+    if missing.is_empty() {
+        return Ok(());
+    }
+    Err(RqMeshError::from(
+        InitializationErrorKind::new_missing_packages(
+            package_manager.name(),
+            missing.iter().map(|m| m.to_string()).collect(),
+        ),
+    ))
+}
+
+fn describe(missing: &[MissingPackage]) -> String {
+    missing
+        .iter()
+        .map(|m| m.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apk_versions_come_from_the_matching_package() {
+        let stdout = "sqlite-libs-3.44.2-r0 x86_64 {sqlite} (blessing) [installed]\n\
+                      sqlite-3.44.2-r0 x86_64 {sqlite} (blessing) [installed]\n";
+        assert_eq!(
+            Apk.parse_version("sqlite", stdout).as_deref(),
+            Some("3.44.2-r0")
+        );
+        assert_eq!(Apk.parse_version("sqlite", ""), None);
+        let only_libs = "sqlite-libs-3.44.2-r0 x86_64 {sqlite} (blessing) [installed]\n";
+        assert_eq!(Apk.parse_version("sqlite", only_libs), None);
+    }
+
+    #[test]
+    fn apt_versions_require_an_installed_status() {
+        assert_eq!(
+            Apt.parse_version("git", "install ok installed\t1:2.39.2-1.1")
+                .as_deref(),
+            Some("1:2.39.2-1.1")
+        );
+        assert_eq!(
+            Apt.parse_version("git", "deinstall ok config-files\t1:2.39.2-1.1"),
+            None
+        );
+        assert_eq!(Apt.parse_version("git", "install ok installed\t"), None);
+        assert_eq!(Apt.parse_version("git", ""), None);
+    }
+
+    #[test]
+    fn dnf_versions_are_the_first_line() {
+        assert_eq!(
+            Dnf.parse_version("git", "2.43.0-1.fc39\n").as_deref(),
+            Some("2.43.0-1.fc39")
+        );
+        assert_eq!(Dnf.parse_version("git", "\n"), None);
+    }
+
+    #[test]
+    fn pacman_versions_follow_the_package_name() {
+        assert_eq!(
+            Pacman
+                .parse_version("sqlite", "sqlite 3.44.2-1\n")
+                .as_deref(),
+            Some("3.44.2-1")
+        );
+        assert_eq!(
+            Pacman.parse_version("sqlite", "sqlite-doc 3.44.2-1\n"),
+            None
+        );
+        assert_eq!(Pacman.parse_version("sqlite", "sqlite\n"), None);
+    }
+}
@@ -0,0 +1,187 @@
+use crate::Agent;
+use log::{debug, info, trace, warn};
+use rqmesh_core::{
+    AnnounceAgentRequest, DescribeAgentResponse, MemberState, PeerRecord, RqMeshError,
+    StorageErrorKind,
+};
+use rusqlite::params;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+type Result<T> = std::result::Result<T, RqMeshError>;
+
+/// Number of times each seed is tried before giving up on it.
+const SEED_ATTEMPTS: u32 = 5;
+
+/// Delay before the first retry of a seed, doubled after every failure.
+const SEED_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+const SEED_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl Agent {
+    /// Records `peer` as seen now, keyed by the endpoint it advertises or by
+    /// `fallback_endpoint` if it did not advertise one.
+    ///
+    /// A peer announcing a node ID we already know under another endpoint
+    /// has moved, so the stale endpoint is forgotten.
+    pub fn record_peer(&self, peer: &DescribeAgentResponse, fallback_endpoint: &str) -> Result<()> {
+        let endpoint = peer.endpoint().unwrap_or(fallback_endpoint);
+        trace!(
+            "Recording peer {} at {} (v{})",
+            peer.node_id(),
+            endpoint,
+            peer.version()
+        );
+        let query_err = |e: rusqlite::Error| {
+            RqMeshError::from(StorageErrorKind::new_query_err(
+                "record_peer",
+                format!("{}", e),
+            ))
+        };
+
+        let conn = self.connection();
+        let moved = conn
+            .execute(
+                "DELETE FROM peers WHERE node_id = ?1 AND endpoint != ?2",
+                params![peer.node_id(), endpoint],
+            )
+            .map_err(query_err)?;
+        if moved > 0 {
+            info!("Peer {} moved to {}", peer.node_id(), endpoint);
+        }
+        conn.execute(
+            "INSERT INTO peers (endpoint, version, first_seen, last_seen, node_id) VALUES (?1, ?2, datetime('now'), datetime('now'), ?3)
+             ON CONFLICT(endpoint) DO UPDATE SET version = excluded.version, last_seen = excluded.last_seen, node_id = excluded.node_id",
+            params![endpoint, peer.version(), peer.node_id()],
+        )
+        .map_err(query_err)?;
+        Ok(())
+    }
+
+    pub fn list_peers(&self) -> Result<Vec<PeerRecord>> {
+        let query_err = |e: rusqlite::Error| {
+            RqMeshError::from(StorageErrorKind::new_query_err(
+                "list_peers",
+                format!("{}", e),
+            ))
+        };
+
+        let conn = self.connection();
+        let mut stmt = conn
+            .prepare(
+                "SELECT endpoint, version, first_seen, last_seen, state, incarnation, node_id FROM peers ORDER BY last_seen DESC",
+            )
+            .map_err(query_err)?;
+        let rows = stmt
+            .query_map([], |row| {
+                let endpoint: String = row.get(0)?;
+                let version: String = row.get(1)?;
+                let first_seen: String = row.get(2)?;
+                let last_seen: String = row.get(3)?;
+                let state: String = row.get(4)?;
+                let incarnation: i64 = row.get(5)?;
+                let node_id: Option<String> = row.get(6)?;
+                Ok((
+                    endpoint,
+                    version,
+                    first_seen,
+                    last_seen,
+                    state,
+                    incarnation,
+                    node_id,
+                ))
+            })
+            .map_err(query_err)?;
+
+        let mut peers = Vec::new();
+        for row in rows {
+            let (endpoint, version, first_seen, last_seen, state, incarnation, node_id) =
+                row.map_err(query_err)?;
+            let state: MemberState = state.parse()?;
+            peers.push(
+                PeerRecord::new(
+                    endpoint,
+                    version,
+                    first_seen,
+                    last_seen,
+                    state,
+                    incarnation as u64,
+                )
+                .with_node_id(node_id),
+            );
+        }
+        Ok(peers)
+    }
+
+    /// Announces this agent to `seed` and records the seed as a peer.
+    fn announce_to(&self, seed: &str, requestor: &str) -> Result<()> {
+        let local = self.describe()?;
+        let mut client = self.connect_to(seed, requestor, SEED_TIMEOUT)?;
+        let (remote, received) =
+            client.call_with_piggyback(AnnounceAgentRequest::new(local), self.take_piggyback())?;
+        self.apply_membership_updates(&received);
+        self.record_peer(&remote, seed)?;
+        self.announce_member(
+            remote.endpoint().unwrap_or(seed),
+            remote.version(),
+            Some(remote.node_id()),
+        )?;
+        info!(
+            "Joined mesh via seed {} at {} (v{})",
+            remote.node_id(),
+            remote.endpoint().unwrap_or(seed),
+            remote.version()
+        );
+        Ok(())
+    }
+}
+
+/// Announces the agent to each configured seed on a background thread,
+/// retrying unreachable seeds with backoff until they answer, run out of
+/// attempts or shutdown is requested.
+pub fn spawn_seed_contact(
+    agent: Arc<Agent>,
+    requestor: String,
+    shutdown: Arc<AtomicBool>,
+) -> std::io::Result<JoinHandle<()>> {
+    thread::Builder::new()
+        .name("rqmesh-seeds".to_string())
+        .spawn(move || {
+            let mut pending: Vec<(String, u32, Instant)> = agent
+                .context
+                .seeds()
+                .iter()
+                .map(|s| (s.clone(), 0, Instant::now()))
+                .collect();
+            debug!("Contacting {} seeds", pending.len());
+
+            while !pending.is_empty() && !shutdown.load(Ordering::SeqCst) {
+                let now = Instant::now();
+                pending.retain_mut(|(seed, attempts, next_attempt)| {
+                    if *next_attempt > now {
+                        return true;
+                    }
+                    *attempts += 1;
+                    match agent.announce_to(seed, &requestor) {
+                        Ok(()) => false,
+                        Err(e) if *attempts >= SEED_ATTEMPTS => {
+                            warn!(
+                                "Giving up on seed {} after {} attempts: {}",
+                                seed, attempts, e
+                            );
+                            false
+                        }
+                        Err(e) => {
+                            let delay = SEED_RETRY_DELAY * 2u32.pow(*attempts - 1);
+                            debug!("Seed {} unavailable ({}), retrying in {:?}", seed, e, delay);
+                            *next_attempt = now + delay;
+                            true
+                        }
+                    }
+                });
+                thread::sleep(Duration::from_millis(200));
+            }
+        })
+}
@@ -0,0 +1,419 @@
+//! Per-action authorization of callers.
+//!
+//! A policy is a TOML file of rules, each allowing or denying a set of
+//! callers some actions, optionally only with arguments matching patterns:
+//!
+//! ```toml
+//! default = "deny"
+//!
+//! [roles]
+//! # members are certificate identities or signing key IDs
+//! operators = ["alice", "bob"]
+//! mesh = ["node-*"]  # certificates only the mesh's CA issues
+//!
+//! [[rule]]
+//! roles = ["mesh"]
+//! actions = ["*"]
+//!
+//! [[rule]]
+//! roles = ["operators"]
+//! actions = ["run_command", "enqueue_job"]
+//! arguments = { program = ["echo", "apk"] }
+//!
+//! [[rule]]
+//! effect = "deny"
+//! requestors = ["bob"]
+//! actions = ["enqueue_job"]
+//! ```
+//!
+//! Requestors, role members, actions and argument values are matched as
+//! patterns in which `*` stands for any run of characters and `?` for any
+//! one. An allowing rule only matches when every value of each argument it
+//! names matches one of its patterns, and a denying rule when any one does.
+//! Commands have a `program`, an `arg` for each of their arguments, an `env`
+//! of `NAME=value` for each environment variable they set, and a
+//! `working_dir`, `uid` and `gid` if they set them. Shell commands have
+//! `/bin/sh` as their program and `-c` and the script as their arguments.
+//!
+//! Since environment variables such as `PATH` or `LD_PRELOAD` change what a
+//! program does, an allowing rule that does not name `env` only matches
+//! commands that set no environment variables; `env = ["*"]` allows any.
+//!
+//! Besides the protocol's actions, a policy controls two things only agents
+//! should be allowed, usually through a role like `mesh` above:
+//!
+//! - `gossip`: the membership updates piggybacked on a request are only
+//!   merged if its caller is allowed to gossip;
+//! - `relay`: an unsigned request routed or broadcast by another agent is
+//!   only handled for the caller that agent vouches for if it is allowed to
+//!   relay, and for no authenticated caller otherwise. Signed requests are
+//!   always handled for their signer, whoever relays them.
+//!
+//! A request is denied if any matching rule denies it, otherwise allowed if
+//! any matching rule allows it, otherwise given the default.
+//!
+//! Callers are identified by [`RqMeshFrame::authenticated_caller`], so a
+//! policy names the identity in a TLS certificate or the ID of a signing key.
+//! The requestor a request merely claims to come from is never matched, so a
+//! request authenticated with neither matches no rule and gets the default.
+//!
+//! [`RqMeshFrame::authenticated_caller`]: rqmesh_core::RqMeshFrame::authenticated_caller
+
+use crate::Agent;
+use log::{trace, warn};
+use rqmesh_core::{InitializationErrorKind, RqMeshError, UnauthorizedErrorKind};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+type Result<T> = std::result::Result<T, RqMeshError>;
+
+/// Target denials are logged under, so they can be collected separately.
+const AUDIT_TARGET: &str = "rqmesh::audit";
+
+/// Arguments that an allowing rule must name to match a request giving them.
+const GUARDED_ARGUMENTS: &[&str] = &["env"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Effect {
+    Allow,
+    Deny,
+}
+
+impl std::fmt::Display for Effect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Effect::Allow => write!(f, "allow"),
+            Effect::Deny => write!(f, "deny"),
+        }
+    }
+}
+
+fn default_policy_effect() -> Effect {
+    Effect::Deny
+}
+
+fn default_rule_effect() -> Effect {
+    Effect::Allow
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Rule {
+    #[serde(default = "default_rule_effect")]
+    effect: Effect,
+    #[serde(default)]
+    requestors: Vec<String>,
+    #[serde(default)]
+    roles: Vec<String>,
+    actions: Vec<String>,
+    #[serde(default)]
+    arguments: BTreeMap<String, Vec<String>>,
+}
+
+impl Rule {
+    /// Whether the rule applies to `caller`, who holds `roles`, asking for
+    /// `action` with `arguments`.
+    fn matches(
+        &self,
+        caller: &str,
+        roles: &[&str],
+        action: &str,
+        arguments: &[(&str, String)],
+    ) -> bool {
+        let names_caller = self.requestors.iter().any(|r| pattern_matches(r, caller))
+            || self.roles.iter().any(|r| roles.contains(&r.as_str()));
+        let guarded_named = self.effect == Effect::Deny
+            || arguments.iter().all(|(name, _)| {
+                !GUARDED_ARGUMENTS.contains(name) || self.arguments.contains_key(*name)
+            });
+        names_caller
+            && guarded_named
+            && self.actions.iter().any(|a| pattern_matches(a, action))
+            && self.arguments.iter().all(|(name, patterns)| {
+                let mut values = arguments
+                    .iter()
+                    .filter(|(n, _)| n == name)
+                    .map(|(_, value)| value);
+                let allowed = |value: &String| patterns.iter().any(|p| pattern_matches(p, value));
+                match self.effect {
+                    Effect::Allow => values.all(allowed),
+                    Effect::Deny => values.any(allowed),
+                }
+            })
+    }
+}
+
+/// Rules deciding which callers may perform which actions.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    #[serde(default = "default_policy_effect")]
+    default: Effect,
+    #[serde(default)]
+    roles: BTreeMap<String, Vec<String>>,
+    #[serde(default, rename = "rule")]
+    rules: Vec<Rule>,
+}
+
+impl Policy {
+    /// Reads and checks the policy file at `path`.
+    pub fn load(path: &Path) -> Result<Policy> {
+        let invalid = |message: String| {
+            RqMeshError::from(InitializationErrorKind::new_invalid_policy(
+                path.to_string_lossy(),
+                message,
+            ))
+        };
+        let text = std::fs::read_to_string(path).map_err(|e| invalid(format!("{}", e)))?;
+        let policy: Policy = toml::from_str(&text).map_err(|e| invalid(format!("{}", e)))?;
+        for (i, rule) in policy.rules.iter().enumerate() {
+            if rule.requestors.is_empty() && rule.roles.is_empty() {
+                return Err(invalid(format!(
+                    "rule {} must name at least one requestor or role",
+                    i + 1
+                )));
+            }
+            if let Some(role) = rule
+                .roles
+                .iter()
+                .find(|r| !policy.roles.contains_key(r.as_str()))
+            {
+                return Err(invalid(format!(
+                    "rule {} refers to undefined role {}",
+                    i + 1,
+                    role
+                )));
+            }
+        }
+        Ok(policy)
+    }
+
+    /// Decides whether `caller` may perform `action` with `arguments`,
+    /// returning the effect and why it applies. A caller that did not
+    /// authenticate gets the default.
+    pub fn evaluate(
+        &self,
+        caller: Option<&str>,
+        action: &str,
+        arguments: &[(&str, String)],
+    ) -> (Effect, String) {
+        let caller = match caller {
+            Some(caller) => caller,
+            None => {
+                return (
+                    self.default,
+                    format!("caller not authenticated, default {}", self.default),
+                )
+            }
+        };
+        let roles: Vec<&str> = self
+            .roles
+            .iter()
+            .filter(|(_, members)| members.iter().any(|m| pattern_matches(m, caller)))
+            .map(|(role, _)| role.as_str())
+            .collect();
+        let mut allowed_by = None;
+        for (i, rule) in self.rules.iter().enumerate() {
+            if !rule.matches(caller, &roles, action, arguments) {
+                continue;
+            }
+            match rule.effect {
+                Effect::Deny => return (Effect::Deny, format!("denied by rule {}", i + 1)),
+                Effect::Allow => {
+                    allowed_by.get_or_insert(i);
+                }
+            }
+        }
+        match allowed_by {
+            Some(i) => (Effect::Allow, format!("allowed by rule {}", i + 1)),
+            None => (
+                self.default,
+                format!("no rule matched, default {}", self.default),
+            ),
+        }
+    }
+}
+
+impl Agent {
+    /// Checks that the policy allows `caller`, the authenticated sender of a
+    /// request claiming to come from `requestor`, to perform `action` with
+    /// `arguments`, logging every denial.
+    ///
+    /// Agents without a policy allow everything, and agents evaluating their
+    /// policy as a dry run only log what they would have denied.
+    pub fn authorize(
+        &self,
+        caller: Option<&str>,
+        requestor: &str,
+        action: &str,
+        arguments: &[(&str, String)],
+    ) -> Result<()> {
+        let policy = match &self.policy {
+            Some(policy) => policy,
+            None => return Ok(()),
+        };
+        let (effect, reason) = policy.evaluate(caller, action, arguments);
+        let caller = caller.unwrap_or(requestor);
+        if effect == Effect::Allow {
+            trace!("Allowing {} to {}: {}", caller, action, reason);
+            return Ok(());
+        }
+
+        let dry_run = self.context.dry_run_policy();
+        warn!(
+            target: AUDIT_TARGET,
+            "{}{} to {} with {:?}: {}",
+            if dry_run { "Would deny " } else { "Denied " },
+            caller,
+            action,
+            arguments,
+            reason
+        );
+        if dry_run {
+            Ok(())
+        } else {
+            Err(RqMeshError::from(UnauthorizedErrorKind::new_action_denied(
+                caller, action,
+            )))
+        }
+    }
+}
+
+/// Whether `value` matches `pattern`, in which `*` matches any run of
+/// characters and `?` any single character.
+fn pattern_matches(pattern: &str, value: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let value: Vec<char> = value.chars().collect();
+    let (mut p, mut v) = (0, 0);
+    // position of the last `*` seen and the value position it was tried at
+    let mut backtrack = None;
+    while v < value.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, v));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == value[v] => {
+                p += 1;
+                v += 1;
+            }
+            _ => match backtrack {
+                Some((star, tried)) => {
+                    p = star + 1;
+                    v = tried + 1;
+                    backtrack = Some((star, tried + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: &str = r#"
+        default = "deny"
+
+        [roles]
+        operators = ["alice", "bob"]
+        mesh = ["node-*"]
+
+        [[rule]]
+        roles = ["mesh"]
+        actions = ["*"]
+
+        [[rule]]
+        roles = ["operators"]
+        actions = ["run_command", "enqueue_job"]
+        arguments = { program = ["echo", "apk"] }
+
+        [[rule]]
+        effect = "deny"
+        requestors = ["bob"]
+        actions = ["enqueue_job"]
+    "#;
+
+    fn effect(caller: Option<&str>, action: &str, arguments: &[(&str, &str)]) -> Effect {
+        let policy: Policy = toml::from_str(POLICY).expect("parses");
+        let arguments: Vec<(&str, String)> = arguments
+            .iter()
+            .map(|(name, value)| (*name, value.to_string()))
+            .collect();
+        policy.evaluate(caller, action, &arguments).0
+    }
+
+    #[test]
+    fn patterns_match_literally_apart_from_wildcards() {
+        assert!(pattern_matches("alice", "alice"));
+        assert!(!pattern_matches("alice", "alicia"));
+        assert!(!pattern_matches("alice", "alic"));
+        assert!(pattern_matches("", ""));
+        assert!(!pattern_matches("", "a"));
+    }
+
+    #[test]
+    fn stars_match_any_run_of_characters() {
+        assert!(pattern_matches("*", ""));
+        assert!(pattern_matches("*", "anything"));
+        assert!(pattern_matches("node-*", "node-"));
+        assert!(pattern_matches("node-*", "node-eu-1"));
+        assert!(!pattern_matches("node-*", "nod"));
+        assert!(pattern_matches("*.sh", "build.test.sh"));
+        assert!(pattern_matches("a*b*c", "aXbYbZc"));
+        assert!(!pattern_matches("a*b*c", "aXbYbZ"));
+        assert!(pattern_matches("**", "x"));
+    }
+
+    #[test]
+    fn question_marks_match_exactly_one_character() {
+        assert!(pattern_matches("node-?", "node-1"));
+        assert!(!pattern_matches("node-?", "node-"));
+        assert!(!pattern_matches("node-?", "node-12"));
+        assert!(pattern_matches("?*", "x"));
+        assert!(!pattern_matches("?*", ""));
+    }
+
+    #[test]
+    fn rules_allow_roles_and_arguments_they_name() {
+        assert_eq!(effect(Some("node-1"), "route", &[]), Effect::Allow);
+        assert_eq!(
+            effect(Some("alice"), "run_command", &[("program", "echo")]),
+            Effect::Allow
+        );
+        assert_eq!(
+            effect(Some("alice"), "run_command", &[("program", "rm")]),
+            Effect::Deny
+        );
+        assert_eq!(
+            effect(Some("bob"), "enqueue_job", &[("program", "echo")]),
+            Effect::Deny
+        );
+        assert_eq!(effect(Some("mallory"), "describe", &[]), Effect::Deny);
+    }
+
+    #[test]
+    fn allowing_rules_must_name_the_environment() {
+        assert_eq!(
+            effect(
+                Some("alice"),
+                "run_command",
+                &[("program", "echo"), ("env", "LD_PRELOAD=/tmp/x.so")]
+            ),
+            Effect::Deny
+        );
+        assert_eq!(
+            effect(Some("node-1"), "run_command", &[("env", "A=b")]),
+            Effect::Deny
+        );
+    }
+
+    #[test]
+    fn unauthenticated_callers_get_the_default() {
+        assert_eq!(effect(None, "describe", &[]), Effect::Deny);
+    }
+}
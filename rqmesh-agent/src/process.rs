@@ -0,0 +1,179 @@
+use log::trace;
+use rqmesh_core::CommandSpec;
+use std::io::{Read, Write};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How often a process with a timeout or abort flags is checked for having
+/// exited.
+const WAIT_POLL: Duration = Duration::from_millis(20);
+
+/// How long a command probing the host for something the agent needs or
+/// advertises may run before it is taken to have failed, so that a hanging
+/// probe cannot hold up startup.
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Most bytes of stdout and of stderr kept from a process, so that a chatty
+/// program can neither exhaust the agent's memory nor produce a job record
+/// too large to send back in one frame.
+pub const MAX_OUTPUT: usize = 1024 * 1024;
+
+/// What a process left behind once it exited or was killed.
+pub struct ProcessOutput {
+    pub status: ExitStatus,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub timed_out: bool,
+    pub aborted: bool,
+}
+
+/// Builds the command described by `spec`, leaving its timeout and stdin to
+/// [`run_spec`].
+pub fn command(spec: &CommandSpec) -> Command {
+    let mut command = Command::new(spec.program());
+    command.args(spec.args());
+    command.envs(spec.env().iter().map(|(k, v)| (k, v)));
+    if let Some(working_dir) = spec.working_dir() {
+        command.current_dir(working_dir);
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        if let Some(gid) = spec.gid() {
+            command.gid(gid);
+        }
+        if let Some(uid) = spec.uid() {
+            command.uid(uid);
+        }
+    }
+    command
+}
+
+/// Runs the command described by `spec` to completion, the way [`run`]
+/// does, feeding it the spec's stdin and killing it after the spec's
+/// timeout.
+pub fn run_spec(spec: &CommandSpec, abort: &[&AtomicBool]) -> std::io::Result<ProcessOutput> {
+    spawn_and_wait(&mut command(spec), spec.stdin(), spec.timeout(), abort)
+}
+
+/// Runs `command` to completion with stdin closed, capturing its stdout and
+/// stderr, and kills it if it is still running after `timeout` or once any
+/// of `abort` is set.
+///
+/// The command runs in a process group of its own, and it is the whole
+/// group that is killed, so that nothing it started outlives it.
+pub fn run(
+    command: &mut Command,
+    timeout: Option<Duration>,
+    abort: &[&AtomicBool],
+) -> std::io::Result<ProcessOutput> {
+    spawn_and_wait(command, None, timeout, abort)
+}
+
+fn spawn_and_wait(
+    command: &mut Command,
+    stdin: Option<&[u8]>,
+    timeout: Option<Duration>,
+    abort: &[&AtomicBool],
+) -> std::io::Result<ProcessOutput> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        command.process_group(0);
+    }
+    trace!("Spawning {:?}", command);
+    let mut child = command
+        .stdin(if stdin.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let stdout = capture(child.stdout.take());
+    let stderr = capture(child.stderr.take());
+    let feeder = match (stdin, child.stdin.take()) {
+        (Some(input), Some(mut pipe)) => {
+            let input = input.to_vec();
+            // A program that exits without reading its input closes the
+            // pipe, which is no reason to fail the run.
+            Some(thread::spawn(move || {
+                let _ = pipe.write_all(&input);
+            }))
+        }
+        _ => None,
+    };
+
+    let mut timed_out = false;
+    let mut aborted = false;
+    let status = if timeout.is_none() && abort.is_empty() {
+        child.wait()?
+    } else {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            timed_out = deadline.is_some_and(|d| Instant::now() >= d);
+            aborted = abort.iter().any(|a| a.load(Ordering::SeqCst));
+            if timed_out || aborted {
+                trace!(
+                    "Killing process {} (timed out: {}, aborted: {})",
+                    child.id(),
+                    timed_out,
+                    aborted
+                );
+                kill_group(&mut child);
+                break child.wait()?;
+            }
+            thread::sleep(WAIT_POLL);
+        }
+    };
+
+    if let Some(feeder) = feeder {
+        let _ = feeder.join();
+    }
+    Ok(ProcessOutput {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+        timed_out,
+        aborted,
+    })
+}
+
+/// Kills `child` along with every process in its process group.
+fn kill_group(child: &mut Child) {
+    // The process may exit between the check and the kill, in which case
+    // wait still reaps it.
+    #[cfg(unix)]
+    {
+        // The child has not been reaped yet, so its ID still names its group.
+        let pgid = child.id() as libc::pid_t;
+        if unsafe { libc::kill(-pgid, libc::SIGKILL) } == 0 {
+            return;
+        }
+    }
+    let _ = child.kill();
+}
+
+/// Reads a child's pipe to the end on another thread, so that a child
+/// filling one pipe cannot block while we wait on the other. Only the first
+/// [`MAX_OUTPUT`] bytes are kept; the rest is read and thrown away so that
+/// the child is not left blocked on a full pipe.
+fn capture<R>(pipe: Option<R>) -> JoinHandle<Vec<u8>>
+where
+    R: Read + Send + 'static,
+{
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.by_ref().take(MAX_OUTPUT as u64).read_to_end(&mut buf);
+            let _ = std::io::copy(&mut pipe, &mut std::io::sink());
+        }
+        buf
+    })
+}
@@ -0,0 +1,473 @@
+//! Durable job queue kept in the `jobs` table.
+//!
+//! Queued jobs are leased by the worker for a visibility timeout, and a job
+//! whose lease runs out without an ack is returned to the queue. Failed
+//! attempts are retried with exponential backoff until the job has been
+//! tried `max_attempts` times, after which it is dead-lettered. Jobs leased
+//! when the agent stopped are returned to the queue on the next start.
+
+use crate::jobs::{self, JobOutcome};
+use crate::Agent;
+use log::{debug, info, warn};
+use rqmesh_core::{
+    AgentLoadResponse, EnqueueJobRequest, JobErrorKind, JobRecord, JobState, RqMeshError,
+    RunCommandRequest,
+};
+use rusqlite::{params, OptionalExtension};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+type Result<T> = std::result::Result<T, RqMeshError>;
+
+/// Attempts given to jobs that do not ask for a number of their own.
+const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+
+/// How much longer than its timeout a job stays leased, leaving the worker
+/// time to kill the program and record the outcome.
+const LEASE_MARGIN: Duration = Duration::from_secs(30);
+
+/// Delay before the first retry of a failed job, doubled for every further
+/// attempt up to [`MAX_RETRY_BACKOFF`].
+const RETRY_BACKOFF: Duration = Duration::from_secs(2);
+
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// How long the worker waits before checking an empty queue again.
+const QUEUE_POLL: Duration = Duration::from_millis(500);
+
+/// How often expired leases are returned to the queue.
+const LEASE_CHECK_PERIOD: Duration = Duration::from_secs(5);
+
+/// A job the worker holds the lease on.
+struct LeasedJob {
+    job_id: String,
+    request: RunCommandRequest,
+    attempts: u32,
+}
+
+impl Agent {
+    /// Adds a job to the queue on behalf of `requestor`, recorded as the
+    /// job's owner if the caller was authenticated.
+    pub fn enqueue_job(
+        &self,
+        requestor: Option<&str>,
+        request: &EnqueueJobRequest,
+    ) -> Result<JobRecord> {
+        let command = request.command();
+        let job_id = Uuid::new_v4().to_string();
+        let max_attempts = request
+            .max_attempts()
+            .unwrap_or(DEFAULT_MAX_ATTEMPTS)
+            .max(1);
+        // Workers run jobs with the default timeout too, so that a lease can
+        // never run out while the worker is still running the job.
+        let visibility_timeout =
+            command.timeout().unwrap_or(jobs::DEFAULT_JOB_TIMEOUT) + LEASE_MARGIN;
+        let encoded = jobs::encode_request("enqueue_job", command)?;
+        info!(
+            "Queueing job {} for {}: {} {:?} ({} attempts)",
+            &job_id,
+            requestor.unwrap_or("an unauthenticated caller"),
+            command.program(),
+            command.args(),
+            max_attempts
+        );
+        self.connection()
+            .execute(
+                "INSERT INTO jobs (job_id, requestor, program, request, state, attempts, max_attempts, visibility_timeout, created_at, available_at) VALUES (?1, ?2, ?3, ?4, ?5, 0, ?6, ?7, datetime('now'), datetime('now'))",
+                params![
+                    &job_id,
+                    requestor,
+                    command.program(),
+                    encoded,
+                    JobState::Queued.to_string(),
+                    max_attempts,
+                    visibility_timeout.as_secs() as i64
+                ],
+            )
+            .map_err(|e| jobs::job_query_err("enqueue_job", e))?;
+        self.job_status(&job_id)
+    }
+
+    /// Cancels a job that has not finished yet, killing its program if it is
+    /// running, and returns it as it stands.
+    pub fn cancel_job(&self, job_id: &str) -> Result<JobRecord> {
+        let cancelled = self
+            .connection()
+            .execute(
+                "UPDATE jobs SET state = ?2, finished_at = datetime('now'), lease_expires_at = NULL WHERE job_id = ?1 AND state IN (?3, ?4)",
+                params![
+                    job_id,
+                    JobState::Cancelled.to_string(),
+                    JobState::Queued.to_string(),
+                    JobState::Running.to_string()
+                ],
+            )
+            .map_err(|e| jobs::job_query_err("cancel_job", e))?;
+        if cancelled > 0 {
+            if self.running_jobs.abort(job_id) {
+                info!("Cancelled job {}, stopping its program", job_id);
+            } else {
+                info!("Cancelled job {}", job_id);
+            }
+        }
+        self.job_status(job_id)
+    }
+
+    /// Counts the jobs currently running and waiting in the queue.
+    pub fn load(&self) -> Result<AgentLoadResponse> {
+        let (running, queued): (i64, i64) = self
+            .connection()
+            .query_row(
+                "SELECT COUNT(CASE WHEN state = ?1 THEN 1 END), COUNT(CASE WHEN state = ?2 THEN 1 END) FROM jobs",
+                params![JobState::Running.to_string(), JobState::Queued.to_string()],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .map_err(|e| jobs::job_query_err("load", e))?;
+        Ok(AgentLoadResponse::new(running as u64, queued as u64))
+    }
+
+    /// Returns jobs leased before the agent last stopped to the queue, and
+    /// fails jobs that were being run directly, since nobody is waiting for
+    /// them any more.
+    pub fn recover_jobs(&self) -> Result<()> {
+        let conn = self.connection();
+        let requeued = conn
+            .execute(
+                "UPDATE jobs SET state = ?1, lease_expires_at = NULL, available_at = datetime('now') WHERE state = ?2 AND lease_expires_at IS NOT NULL",
+                params![JobState::Queued.to_string(), JobState::Running.to_string()],
+            )
+            .map_err(|e| jobs::job_query_err("recover_jobs", e))?;
+        let abandoned = conn
+            .execute(
+                "UPDATE jobs SET state = ?1, error = ?3, finished_at = datetime('now') WHERE state = ?2 AND lease_expires_at IS NULL",
+                params![
+                    JobState::Failed.to_string(),
+                    JobState::Running.to_string(),
+                    JobErrorKind::new_interrupted("Agent stopped while the job was running")
+                        .to_string()
+                ],
+            )
+            .map_err(|e| jobs::job_query_err("recover_jobs", e))?;
+        if requeued > 0 || abandoned > 0 {
+            info!(
+                "Returned {} leased jobs to the queue and failed {} interrupted jobs",
+                requeued, abandoned
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns jobs whose lease ran out without an ack to the queue.
+    fn requeue_expired_leases(&self) -> Result<()> {
+        let requeued = self
+            .connection()
+            .execute(
+                "UPDATE jobs SET state = ?1, lease_expires_at = NULL, available_at = datetime('now') WHERE state = ?2 AND lease_expires_at <= datetime('now')",
+                params![JobState::Queued.to_string(), JobState::Running.to_string()],
+            )
+            .map_err(|e| jobs::job_query_err("requeue_expired_leases", e))?;
+        if requeued > 0 {
+            warn!(
+                "Returned {} jobs with expired leases to the queue",
+                requeued
+            );
+        }
+        Ok(())
+    }
+
+    /// Leases the job that has been available the longest, if any.
+    fn lease_job(&self) -> Result<Option<LeasedJob>> {
+        let leased: Option<(String, Vec<u8>, u32)> = self
+            .connection()
+            .query_row(
+                "UPDATE jobs SET state = ?1, attempts = attempts + 1, started_at = datetime('now'), finished_at = NULL, lease_expires_at = datetime('now', '+' || visibility_timeout || ' seconds')
+                 WHERE job_id = (SELECT job_id FROM jobs WHERE state = ?2 AND available_at <= datetime('now') ORDER BY available_at, created_at LIMIT 1)
+                 RETURNING job_id, request, attempts",
+                params![JobState::Running.to_string(), JobState::Queued.to_string()],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()
+            .map_err(|e| jobs::job_query_err("lease_job", e))?;
+
+        match leased {
+            Some((job_id, request, attempts)) => {
+                let request = jobs::decode_request("lease_job", &job_id, &request)?;
+                Ok(Some(LeasedJob {
+                    job_id,
+                    request,
+                    attempts,
+                }))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Returns a failed job to the queue after a backoff, or dead-letters it
+    /// if it has no attempts left.
+    fn nack_job(&self, job: &LeasedJob, outcome: &JobOutcome) -> Result<()> {
+        let backoff = RETRY_BACKOFF
+            .checked_mul(2u32.saturating_pow(job.attempts.saturating_sub(1)))
+            .map_or(MAX_RETRY_BACKOFF, |b| b.min(MAX_RETRY_BACKOFF));
+        let state: Option<String> = self
+            .connection()
+            .query_row(
+                "UPDATE jobs SET state = CASE WHEN attempts >= max_attempts THEN ?2 ELSE ?3 END,
+                    finished_at = CASE WHEN attempts >= max_attempts THEN datetime('now') ELSE NULL END,
+                    available_at = datetime('now', '+' || ?4 || ' seconds'), lease_expires_at = NULL,
+                    exit_code = ?5, error = ?6, stdout = ?7, stderr = ?8
+                 WHERE job_id = ?1 AND state = ?9
+                 RETURNING state",
+                params![
+                    &job.job_id,
+                    JobState::DeadLettered.to_string(),
+                    JobState::Queued.to_string(),
+                    backoff.as_secs() as i64,
+                    outcome.exit_code,
+                    outcome.error,
+                    outcome.stdout,
+                    outcome.stderr,
+                    JobState::Running.to_string()
+                ],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(|e| jobs::job_query_err("nack_job", e))?;
+
+        match state.map(|s| s.parse::<JobState>()).transpose()? {
+            Some(JobState::DeadLettered) => warn!(
+                "Job {} {} on attempt {}, dead-lettering",
+                &job.job_id, outcome.state, job.attempts
+            ),
+            Some(_) => info!(
+                "Job {} {} on attempt {}, retrying in {:?}",
+                &job.job_id, outcome.state, job.attempts, backoff
+            ),
+            None => warn!(
+                "Job {} was no longer running when it {}, discarding result",
+                &job.job_id, outcome.state
+            ),
+        }
+        Ok(())
+    }
+
+    /// Leases and runs one job, returning whether there was one to run.
+    fn work_once(&self, shutdown: &AtomicBool) -> Result<bool> {
+        let job = match self.lease_job()? {
+            Some(job) => job,
+            None => return Ok(false),
+        };
+        debug!(
+            "Running job {} attempt {}: {} {:?}",
+            &job.job_id,
+            job.attempts,
+            job.request.program(),
+            job.request.args()
+        );
+
+        let outcome = self.execute_job(&job.job_id, &job.request)?;
+        if outcome.aborted && shutdown.load(Ordering::SeqCst) {
+            info!(
+                "Stopped job {} for shutdown, it will be requeued on the next start",
+                &job.job_id
+            );
+        } else if outcome.aborted {
+            info!("Stopped job {} after it was cancelled", &job.job_id);
+        } else if outcome.state == JobState::Succeeded {
+            info!("Job {} succeeded on attempt {}", &job.job_id, job.attempts);
+            self.finish_job(&job.job_id, &outcome)?;
+        } else {
+            self.nack_job(&job, &outcome)?;
+        }
+        Ok(true)
+    }
+}
+
+/// Drains the job queue on a background thread until shutdown. Any number
+/// of workers may run at once, since leasing a job is atomic.
+pub fn spawn_job_worker(
+    worker: usize,
+    agent: Arc<Agent>,
+    shutdown: Arc<AtomicBool>,
+) -> std::io::Result<JoinHandle<()>> {
+    thread::Builder::new()
+        .name(format!("rqmesh-jobs-{}", worker))
+        .spawn(move || {
+            let mut next_lease_check = Instant::now();
+            while !shutdown.load(Ordering::SeqCst) {
+                if Instant::now() >= next_lease_check {
+                    if let Err(e) = agent.requeue_expired_leases() {
+                        warn!("Error requeueing expired leases: {}", e);
+                    }
+                    next_lease_check = Instant::now() + LEASE_CHECK_PERIOD;
+                }
+                match agent.work_once(&shutdown) {
+                    Ok(true) => {}
+                    Ok(false) => thread::sleep(QUEUE_POLL),
+                    Err(e) => {
+                        warn!("Error running queued job: {}", e);
+                        thread::sleep(QUEUE_POLL);
+                    }
+                }
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::initialization::in_memory_agent;
+    use rqmesh_core::AgentInitializationContext;
+
+    fn agent() -> Agent {
+        in_memory_agent(AgentInitializationContext::new(":memory:", "", ""))
+    }
+
+    fn enqueue(agent: &Agent, max_attempts: u32) -> String {
+        let command = RunCommandRequest::new("true", Vec::<String>::new());
+        agent
+            .enqueue_job(
+                Some("ops"),
+                &EnqueueJobRequest::new(command).with_max_attempts(max_attempts),
+            )
+            .expect("enqueues job")
+            .job_id()
+            .to_string()
+    }
+
+    fn state(agent: &Agent, job_id: &str) -> JobState {
+        agent.job_status(job_id).expect("finds job").state()
+    }
+
+    /// Runs `sql` against the job, to move its times as a clock would.
+    fn update(agent: &Agent, job_id: &str, sql: &str) {
+        let updated = agent
+            .connection()
+            .execute(sql, params![job_id])
+            .expect("updates job");
+        assert_eq!(updated, 1);
+    }
+
+    fn failed() -> JobOutcome {
+        JobOutcome {
+            state: JobState::Failed,
+            exit_code: Some(1),
+            error: None,
+            stdout: Vec::new(),
+            stderr: b"boom".to_vec(),
+            aborted: false,
+        }
+    }
+
+    #[test]
+    fn jobs_are_leased_oldest_first_and_only_once() {
+        let agent = agent();
+        let first = enqueue(&agent, 3);
+        let second = enqueue(&agent, 3);
+        update(
+            &agent,
+            &second,
+            "UPDATE jobs SET available_at = datetime('now', '-1 minute') WHERE job_id = ?1",
+        );
+
+        let leased = agent.lease_job().expect("leases").expect("has a job");
+        assert_eq!(
+            (leased.job_id.as_str(), leased.attempts),
+            (second.as_str(), 1)
+        );
+        assert_eq!(state(&agent, &second), JobState::Running);
+        let leased = agent.lease_job().expect("leases").expect("has a job");
+        assert_eq!(leased.job_id, first);
+        assert!(agent.lease_job().expect("leases").is_none());
+    }
+
+    #[test]
+    fn failed_jobs_back_off_then_are_dead_lettered() {
+        let agent = agent();
+        let job_id = enqueue(&agent, 2);
+
+        let job = agent.lease_job().expect("leases").expect("has a job");
+        agent.nack_job(&job, &failed()).expect("nacks");
+        let record = agent.job_status(&job_id).expect("finds job");
+        assert_eq!((record.state(), record.attempts()), (JobState::Queued, 1));
+        assert_eq!(record.stderr(), b"boom");
+        assert!(
+            agent.lease_job().expect("leases").is_none(),
+            "retried before its backoff"
+        );
+
+        update(
+            &agent,
+            &job_id,
+            "UPDATE jobs SET available_at = datetime('now') WHERE job_id = ?1",
+        );
+        let job = agent.lease_job().expect("leases").expect("has a job");
+        assert_eq!(job.attempts, 2);
+        agent.nack_job(&job, &failed()).expect("nacks");
+        let record = agent.job_status(&job_id).expect("finds job");
+        assert_eq!(record.state(), JobState::DeadLettered);
+        assert!(record.finished_at().is_some());
+        update(
+            &agent,
+            &job_id,
+            "UPDATE jobs SET available_at = datetime('now') WHERE job_id = ?1",
+        );
+        assert!(agent.lease_job().expect("leases").is_none());
+    }
+
+    #[test]
+    fn expired_leases_are_returned_to_the_queue() {
+        let agent = agent();
+        let job_id = enqueue(&agent, 3);
+        agent.lease_job().expect("leases").expect("has a job");
+
+        agent.requeue_expired_leases().expect("requeues");
+        assert_eq!(state(&agent, &job_id), JobState::Running);
+
+        update(
+            &agent,
+            &job_id,
+            "UPDATE jobs SET lease_expires_at = datetime('now', '-1 second') WHERE job_id = ?1",
+        );
+        agent.requeue_expired_leases().expect("requeues");
+        assert_eq!(state(&agent, &job_id), JobState::Queued);
+        let job = agent.lease_job().expect("leases").expect("has a job");
+        assert_eq!((job.job_id, job.attempts), (job_id, 2));
+    }
+
+    #[test]
+    fn recovery_requeues_leased_jobs_and_fails_direct_runs() {
+        let agent = agent();
+        let leased = enqueue(&agent, 3);
+        agent.lease_job().expect("leases").expect("has a job");
+        // jobs run directly are running without a lease
+        let direct = enqueue(&agent, 1);
+        update(
+            &agent,
+            &direct,
+            "UPDATE jobs SET state = 'running', started_at = datetime('now') WHERE job_id = ?1",
+        );
+
+        agent.recover_jobs().expect("recovers");
+        assert_eq!(state(&agent, &leased), JobState::Queued);
+        let record = agent.job_status(&direct).expect("finds job");
+        assert_eq!(record.state(), JobState::Failed);
+        assert!(record.error().unwrap_or("").contains("Agent stopped"));
+    }
+
+    #[test]
+    fn workers_run_leased_jobs_to_completion() {
+        let agent = agent();
+        let job_id = enqueue(&agent, 1);
+        assert!(agent.work_once(&AtomicBool::new(false)).expect("works"));
+        let record = agent.job_status(&job_id).expect("finds job");
+        assert_eq!(
+            (record.state(), record.exit_code()),
+            (JobState::Succeeded, Some(0))
+        );
+        assert!(!agent.work_once(&AtomicBool::new(false)).expect("works"));
+    }
+}
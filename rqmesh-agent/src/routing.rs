@@ -0,0 +1,371 @@
+//! Capability based routing of requests across the mesh.
+//!
+//! An agent handed a [`RouteRequest`] asks each live peer for its
+//! capabilities, and its load when routing to the least loaded agent, then
+//! picks one of the agents advertising every required capability, itself
+//! included, using the request's strategy. The request is forwarded to the
+//! chosen peer over the connection used to probe it, and the peer's response
+//! relayed back unchanged.
+//!
+//! An agent that is forwarded a request handles it itself whenever it is
+//! capable, so a request only travels further when the forwarding agent's
+//! view of the mesh was out of date, and never more than `max_hops` times,
+//! or [`DEFAULT_MAX_HOPS`] times if the request allows more.
+//!
+//! The agent a request is forwarded to does not take the forwarding agent
+//! for its sender. It verifies a signed envelope itself, and otherwise only
+//! takes the forwarding agent's word for who sent it if its policy allows
+//! that agent to relay, as described in [`Agent::authenticate_relayed`].
+
+use crate::dispatch::Dispatcher;
+use crate::jobs::DEFAULT_JOB_TIMEOUT;
+use crate::Agent;
+use log::{debug, info, trace};
+use rand::seq::SliceRandom;
+use rqmesh_client::Client;
+use rqmesh_core::{
+    AgentLoadRequest, CapabilityBroadcast, ListCapabilitiesRequest, MemberState, RequestContext,
+    RouteRequest, RoutedResponse, RoutingErrorKind, RoutingStrategy, RqMeshError,
+    RqMeshProtocolAction, DEFAULT_MAX_HOPS,
+};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+type Result<T> = std::result::Result<T, RqMeshError>;
+
+/// How long a peer may take to connect and answer each probe before it is
+/// left out of the candidates.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long the chosen peer may take to answer a forwarded request, enough
+/// for a command run with the default job timeout to finish.
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(DEFAULT_JOB_TIMEOUT.as_secs() + 60);
+
+/// Routing state shared by every request the agent routes.
+#[derive(Default)]
+pub struct Router {
+    next: AtomicUsize,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router::default()
+    }
+
+    /// Picks the candidate to handle a request, or `None` if there are none.
+    fn choose(
+        &self,
+        strategy: RoutingStrategy,
+        mut candidates: Vec<Candidate>,
+    ) -> Option<Candidate> {
+        if candidates.is_empty() {
+            return None;
+        }
+        let index = match strategy {
+            RoutingStrategy::LeastLoaded => {
+                // shuffle first so that ties are broken at random
+                candidates.shuffle(&mut rand::thread_rng());
+                candidates
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(_, c)| c.load)
+                    .map(|(i, _)| i)?
+            }
+            RoutingStrategy::RoundRobin => {
+                candidates.sort_by(|a, b| a.node_id.cmp(&b.node_id));
+                self.next.fetch_add(1, Ordering::Relaxed) % candidates.len()
+            }
+            RoutingStrategy::Random => {
+                candidates.shuffle(&mut rand::thread_rng());
+                0
+            }
+        };
+        Some(candidates.swap_remove(index))
+    }
+}
+
+/// An agent able to handle a routed request.
+struct Candidate {
+    node_id: String,
+    load: u64,
+    /// Connection to the agent, or `None` for this agent.
+    client: Option<Client>,
+}
+
+impl Agent {
+    /// Handles `request` here or on a capable peer, using `local` to
+    /// dispatch the routed envelope with `context` when this agent is chosen.
+    pub fn route(
+        &self,
+        local: &Dispatcher,
+        context: RequestContext,
+        request: RouteRequest,
+    ) -> Result<RoutedResponse> {
+        let action = request.envelope().action().to_string();
+        // a forwarded request comes from the forwarding agent, not from
+        // whoever sent the envelope it carries
+        let context = if request.hops() > 0 {
+            self.authenticate_relayed(&context, request.envelope(), request.on_behalf_of())?
+        } else {
+            context
+        };
+        local.authorize(
+            self,
+            &request.envelope().clone().with_context(context.clone()),
+        )?;
+        let locally_capable =
+            local.actions().any(|a| a == action) && self.is_capable(request.required())?;
+
+        if locally_capable && request.hops() > 0 {
+            trace!(
+                "Handling {} forwarded to us after {} hops",
+                &action,
+                request.hops()
+            );
+            return Ok(self.handle_routed(local, context, request));
+        }
+        let max_hops = request.max_hops().min(DEFAULT_MAX_HOPS);
+        if request.hops() >= max_hops {
+            return Err(RqMeshError::from(RoutingErrorKind::new_hop_limit_exceeded(
+                request.hops(),
+                max_hops,
+            )));
+        }
+
+        let mut candidates = self.capable_peers(&request)?;
+        if locally_capable {
+            let load = match request.strategy() {
+                RoutingStrategy::LeastLoaded => self.load()?.load(),
+                _ => 0,
+            };
+            candidates.push(Candidate {
+                node_id: self.node_id().to_string(),
+                load,
+                client: None,
+            });
+        }
+
+        let chosen = self
+            .router
+            .choose(request.strategy(), candidates)
+            .ok_or_else(|| {
+                RqMeshError::from(RoutingErrorKind::new_no_capable_agent(
+                    request.required().iter().map(|c| c.to_string()),
+                ))
+            })?;
+        match chosen.client {
+            None => {
+                debug!("Routing {} to ourselves", &action);
+                Ok(self.handle_routed(local, context, request))
+            }
+            Some(mut client) => {
+                info!(
+                    "Routing {} from {} to {} ({})",
+                    &action,
+                    request.envelope().requestor(),
+                    &chosen.node_id,
+                    request.strategy()
+                );
+                client.set_timeout(Some(FORWARD_TIMEOUT))?;
+                client.call(request.forwarded(context.authenticated_caller()))
+            }
+        }
+    }
+
+    /// Dispatches the envelope of `request` to the local handlers.
+    fn handle_routed(
+        &self,
+        local: &Dispatcher,
+        context: RequestContext,
+        request: RouteRequest,
+    ) -> RoutedResponse {
+        let hops = request.hops();
+        let response = local.dispatch(self, request.into_envelope().with_context(context));
+        RoutedResponse::new(self.node_id(), hops, response)
+    }
+
+    /// Whether this agent advertises every capability in `required`.
+    fn is_capable(&self, required: &[CapabilityBroadcast]) -> Result<bool> {
+        let capabilities = self.list_capabilities(None)?;
+        Ok(satisfies_all(&capabilities, required))
+    }
+
+    /// Probes every live peer, returning those able to handle `request`
+    /// along with an open connection to each.
+    fn capable_peers(&self, request: &RouteRequest) -> Result<Vec<Candidate>> {
+        let action = request.envelope().action();
+        let peers = self
+            .list_peers()?
+            .into_iter()
+            .filter(|p| p.state() == MemberState::Alive);
+
+        let mut candidates = Vec::new();
+        for peer in peers {
+            let probed = self
+                .connect_to(peer.endpoint(), self.node_id(), PROBE_TIMEOUT)
+                .and_then(|mut client| {
+                    if !client.remote().supports(action)
+                        || !client.remote().supports(RouteRequest::ACTION)
+                    {
+                        return Ok(None);
+                    }
+                    let capabilities = client
+                        .call(ListCapabilitiesRequest::default())?
+                        .into_capabilities();
+                    if !satisfies_all(&capabilities, request.required()) {
+                        return Ok(None);
+                    }
+                    let load = match request.strategy() {
+                        RoutingStrategy::LeastLoaded => {
+                            client.call(AgentLoadRequest::default())?.load()
+                        }
+                        _ => 0,
+                    };
+                    let node_id = client.remote().identity().to_string();
+                    Ok(Some(Candidate {
+                        node_id,
+                        load,
+                        client: Some(client),
+                    }))
+                });
+            match probed {
+                Ok(Some(candidate)) => {
+                    trace!(
+                        "{} at {} can take {} (load {})",
+                        &candidate.node_id,
+                        peer.endpoint(),
+                        action,
+                        candidate.load
+                    );
+                    candidates.push(candidate);
+                }
+                Ok(None) => trace!("{} cannot take {}", peer.endpoint(), action),
+                Err(e) => debug!(
+                    "Leaving {} out of routing, probe failed: {}",
+                    peer.endpoint(),
+                    e
+                ),
+            }
+        }
+        Ok(candidates)
+    }
+}
+
+/// Whether `capabilities` meet every one of `required`.
+pub fn satisfies_all(
+    capabilities: &[CapabilityBroadcast],
+    required: &[CapabilityBroadcast],
+) -> bool {
+    required
+        .iter()
+        .all(|r| capabilities.iter().any(|c| c.satisfies(r)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::initialization::in_memory_agent;
+    use rqmesh_core::{AgentInitializationContext, DescribeAgentRequest, RqMeshFrame};
+
+    /// A request for a capability no agent has, forwarded `hops` times and
+    /// allowing any number of hops.
+    fn forwarded(hops: u32) -> RouteRequest {
+        let envelope = RqMeshFrame::new(DescribeAgentRequest::default(), "client")
+            .into_envelope()
+            .expect("encodes request");
+        let required = vec![CapabilityBroadcast::parse_declared("gpu").expect("parses")];
+        (0..hops).fold(
+            RouteRequest::new(envelope, required).with_max_hops(u32::MAX),
+            |request, _| request.forwarded(None),
+        )
+    }
+
+    fn candidates(loads: &[(&str, u64)]) -> Vec<Candidate> {
+        loads
+            .iter()
+            .map(|(node_id, load)| Candidate {
+                node_id: node_id.to_string(),
+                load: *load,
+                client: None,
+            })
+            .collect()
+    }
+
+    fn chosen(router: &Router, strategy: RoutingStrategy, loads: &[(&str, u64)]) -> String {
+        router
+            .choose(strategy, candidates(loads))
+            .expect("chooses a candidate")
+            .node_id
+    }
+
+    #[test]
+    fn least_loaded_routing_picks_the_idlest_agent() {
+        let router = Router::new();
+        let loads = [("a", 3), ("b", 1), ("c", 2)];
+        for _ in 0..10 {
+            assert_eq!(chosen(&router, RoutingStrategy::LeastLoaded, &loads), "b");
+        }
+    }
+
+    #[test]
+    fn round_robin_routing_takes_turns_whatever_the_order() {
+        let router = Router::new();
+        let picks: Vec<String> = [
+            [("b", 0), ("a", 0), ("c", 0)],
+            [("c", 0), ("b", 0), ("a", 0)],
+            [("a", 0), ("c", 0), ("b", 0)],
+            [("b", 0), ("c", 0), ("a", 0)],
+        ]
+        .iter()
+        .map(|loads| chosen(&router, RoutingStrategy::RoundRobin, loads))
+        .collect();
+        assert_eq!(picks, vec!["a", "b", "c", "a"]);
+    }
+
+    #[test]
+    fn random_routing_picks_one_of_the_candidates() {
+        let router = Router::new();
+        let loads = [("a", 0), ("b", 0)];
+        let picks: Vec<String> = (0..50)
+            .map(|_| chosen(&router, RoutingStrategy::Random, &loads))
+            .collect();
+        assert!(picks.iter().all(|p| p == "a" || p == "b"));
+        assert!(picks.iter().any(|p| p == "a") && picks.iter().any(|p| p == "b"));
+    }
+
+    #[test]
+    fn no_candidates_means_no_choice() {
+        let router = Router::new();
+        for strategy in [
+            RoutingStrategy::LeastLoaded,
+            RoutingStrategy::RoundRobin,
+            RoutingStrategy::Random,
+        ] {
+            assert!(router.choose(strategy, Vec::new()).is_none());
+        }
+    }
+
+    #[test]
+    fn requests_are_never_forwarded_past_the_default_hop_limit() {
+        let agent = in_memory_agent(AgentInitializationContext::new(":memory:", "", ""));
+        let route = |hops| {
+            agent.route(
+                &Dispatcher::new(),
+                RequestContext::default(),
+                forwarded(hops),
+            )
+        };
+
+        match route(DEFAULT_MAX_HOPS - 1) {
+            Err(RqMeshError::RoutingError(RoutingErrorKind::NoCapableAgent { .. })) => {}
+            other => panic!("expected NoCapableAgent, got {:?}", other),
+        }
+        match route(DEFAULT_MAX_HOPS) {
+            Err(RqMeshError::RoutingError(RoutingErrorKind::HopLimitExceeded {
+                hops,
+                max_hops,
+            })) => assert_eq!((hops, max_hops), (DEFAULT_MAX_HOPS, DEFAULT_MAX_HOPS)),
+            other => panic!("expected HopLimitExceeded, got {:?}", other),
+        }
+    }
+}
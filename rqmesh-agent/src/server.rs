@@ -0,0 +1,420 @@
+use crate::dispatch::Dispatcher;
+use crate::Agent;
+use log::{debug, error, info, trace, warn};
+use rqmesh_client::tls::Transport;
+use rqmesh_core::{
+    FrameErrorKind, Handshake, HandshakeResponse, ProtocolErrorKind, RequestContext, RqMeshCodec,
+    RqMeshEnvelope, RqMeshError, RqMeshResponseEnvelope, TransportErrorKind, FRAME_HEADER_LEN,
+};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::any::Any;
+use std::convert::TryInto;
+use std::io::{ErrorKind, Read};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+type Result<T> = std::result::Result<T, RqMeshError>;
+
+/// How long blocking socket operations wait before re-checking for shutdown.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// How long a connection may sit between frames before it is closed, so
+/// that idle clients do not hold on to a worker.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long a client has to send the rest of a frame once it has started
+/// sending it.
+const FRAME_TIMEOUT: Duration = Duration::from_secs(30);
+
+const READ_CHUNK_SIZE: usize = 8 * 1024;
+
+/// Accepts connections on `listener` until `shutdown` is set, serving them
+/// on a fixed pool of `workers` threads. `local` is the handshake sent to
+/// every client that connects.
+///
+/// Once shutdown is requested no new connections are accepted; workers finish
+/// any request they are handling, dropping frames only partly received,
+/// before closing their connection, and this function only returns after
+/// every worker has exited.
+pub fn serve(
+    agent: Arc<Agent>,
+    dispatcher: Arc<Dispatcher>,
+    listener: TcpListener,
+    shutdown: Arc<AtomicBool>,
+    workers: usize,
+    local: Handshake,
+) -> std::io::Result<()> {
+    listener.set_nonblocking(true)?;
+
+    let (sender, receiver) = mpsc::channel::<(TcpStream, SocketAddr)>();
+    let receiver = Arc::new(Mutex::new(receiver));
+    let local = Arc::new(local);
+    let pool: Vec<JoinHandle<()>> = (0..workers.max(1))
+        .map(|id| {
+            let agent = Arc::clone(&agent);
+            let dispatcher = Arc::clone(&dispatcher);
+            let receiver = Arc::clone(&receiver);
+            let shutdown = Arc::clone(&shutdown);
+            let local = Arc::clone(&local);
+            thread::Builder::new()
+                .name(format!("rqmesh-worker-{}", id))
+                .spawn(move || worker_loop(&agent, &dispatcher, &local, &receiver, &shutdown))
+        })
+        .collect::<std::io::Result<_>>()?;
+    debug!("Started {} connection workers", pool.len());
+
+    while !shutdown.load(Ordering::SeqCst) {
+        match listener.accept() {
+            Ok((stream, addr)) => {
+                info!("Connection received from {:?}", addr);
+                if let Err(e) = stream.set_nonblocking(false) {
+                    error!("Error configuring connection from {:?}: {}", addr, e);
+                    continue;
+                }
+                if sender.send((stream, addr)).is_err() {
+                    error!("All connection workers have exited, stopping listener");
+                    break;
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => warn!("Error accepting connection: {}", e),
+        }
+    }
+
+    info!("Shutdown requested, waiting for in-flight connections to finish");
+    drop(sender);
+    for handle in pool {
+        if handle.join().is_err() {
+            error!("Connection worker panicked");
+        }
+    }
+    Ok(())
+}
+
+fn worker_loop(
+    agent: &Agent,
+    dispatcher: &Dispatcher,
+    local: &Handshake,
+    receiver: &Mutex<Receiver<(TcpStream, SocketAddr)>>,
+    shutdown: &AtomicBool,
+) {
+    loop {
+        let next = receiver.lock().unwrap_or_else(|e| e.into_inner()).recv();
+        let (stream, addr) = match next {
+            Ok(c) => c,
+            Err(_) => break,
+        };
+        // The pool is never replenished, so a handler that panics must not
+        // take its worker down with it.
+        let served = panic::catch_unwind(AssertUnwindSafe(|| {
+            serve_connection(agent, dispatcher, local, stream, shutdown)
+        }));
+        match served {
+            Ok(Ok(())) => info!("Connection from {:?} closed", addr),
+            Ok(Err(e)) => error!("Connection from {:?} closed with error: {}", addr, e),
+            Err(payload) => error!(
+                "Connection from {:?} closed after a panic: {}",
+                addr,
+                panic_message(payload.as_ref())
+            ),
+        }
+    }
+}
+
+/// The message a panic was raised with, if it was raised with one.
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(|s| s.as_str()))
+        .unwrap_or("unknown panic")
+}
+
+/// Performs the connection handshake, then serves sequential request frames
+/// from a single client until it disconnects, the stream becomes unusable or
+/// shutdown is requested.
+fn serve_connection(
+    agent: &Agent,
+    dispatcher: &Dispatcher,
+    local: &Handshake,
+    stream: TcpStream,
+    shutdown: &AtomicBool,
+) -> Result<()> {
+    stream
+        .set_read_timeout(Some(POLL_INTERVAL))
+        .map_err(|e| RqMeshError::from(TransportErrorKind::from_io(&e)))?;
+    let peer_addr = stream.peer_addr().ok().map(|a| a.to_string());
+    let (stream, peer_identity): (Box<dyn Transport>, _) = match agent.acceptor.as_ref() {
+        Some(acceptor) => match acceptor.accept(stream, shutdown)? {
+            Some((stream, identity)) => {
+                info!(
+                    "TLS handshake with {} completed, certificate identity {}",
+                    peer_addr.as_deref().unwrap_or("unknown peer"),
+                    identity.as_deref().unwrap_or("unknown")
+                );
+                (Box::new(stream), identity)
+            }
+            None => return Ok(()),
+        },
+        None => (Box::new(stream), None),
+    };
+    let context = RequestContext::new(peer_addr, peer_identity);
+    let mut conn = FrameStream::new(stream);
+
+    let remote = match conn.next_frame::<Handshake>(shutdown) {
+        Ok(Some(remote)) => remote,
+        Ok(None) => return Ok(()),
+        Err(RqMeshError::FrameError(FrameErrorKind::DecodeError { message })) => {
+            let err = RqMeshError::from(ProtocolErrorKind::new_handshake_required(message));
+            let _ = conn.send(&HandshakeResponse::Rejected(err.clone()));
+            return Err(err);
+        }
+        Err(e) => return Err(e),
+    };
+    if let Err(e) = local.check_compatible(&remote) {
+        warn!(
+            "Rejecting handshake from {} (protocol {}): {}",
+            remote.identity(),
+            remote.protocol_version(),
+            e
+        );
+        let _ = conn.send(&HandshakeResponse::Rejected(e.clone()));
+        return Err(e);
+    }
+    debug!(
+        "Accepted handshake from {} (protocol {})",
+        remote.identity(),
+        remote.protocol_version()
+    );
+    conn.send(&HandshakeResponse::Accepted(local.clone()))?;
+
+    loop {
+        match conn.next_frame::<RqMeshEnvelope>(shutdown) {
+            Ok(Some(envelope)) => {
+                let started = Instant::now();
+                let action = envelope.action().to_string();
+                let requestor = envelope.requestor().to_string();
+                let envelope = match agent.authenticate(envelope.with_context(context.clone())) {
+                    Ok(envelope) => envelope,
+                    Err(e) => {
+                        warn!(
+                            "Rejecting {} from {:?}: {}",
+                            &action,
+                            context.peer_addr(),
+                            e
+                        );
+                        agent.record_audit(
+                            &context,
+                            &requestor,
+                            &action,
+                            Err(&e),
+                            started.elapsed(),
+                        );
+                        conn.send(&RqMeshResponseEnvelope::new_err(action, e))?;
+                        continue;
+                    }
+                };
+                trace!("{} sent {}", envelope.requestor(), envelope.action());
+                agent.accept_piggyback(&envelope);
+                let response = dispatcher
+                    .dispatch(agent, envelope)
+                    .with_piggyback(agent.take_response_piggyback(&action));
+                conn.send(&response)?;
+            }
+            Ok(None) => return Ok(()),
+            Err(RqMeshError::FrameError(FrameErrorKind::DecodeError { message })) => {
+                // the malformed frame has already been consumed so the
+                // stream is still aligned on a frame boundary
+                warn!("Discarding malformed frame: {}", &message);
+                let err = RqMeshError::from(FrameErrorKind::new_decode_err(message));
+                conn.send(&RqMeshResponseEnvelope::new_err("", err))?;
+            }
+            Err(e) => {
+                let _ = conn.send(&RqMeshResponseEnvelope::new_err("", e.clone()));
+                return Err(e);
+            }
+        }
+    }
+}
+
+/// Buffers bytes read from a connection so that frames can be assembled
+/// across reads that time out or return part of a frame.
+struct FrameStream {
+    stream: Box<dyn Transport>,
+    codec: RqMeshCodec,
+    buf: Vec<u8>,
+}
+
+impl FrameStream {
+    fn new(stream: Box<dyn Transport>) -> FrameStream {
+        FrameStream {
+            stream,
+            codec: RqMeshCodec::default(),
+            buf: Vec::new(),
+        }
+    }
+
+    fn send<M>(&mut self, message: &M) -> Result<()>
+    where
+        M: Serialize,
+    {
+        self.codec.write_frame(&mut self.stream, message)
+    }
+
+    /// Reads until a full frame is buffered. Returns `Ok(None)` when the
+    /// peer closes the connection, when it sends nothing for
+    /// [`IDLE_TIMEOUT`], or when shutdown is requested, dropping any frame
+    /// partially received. Fails if a frame is not completed within
+    /// [`FRAME_TIMEOUT`] of its first byte arriving.
+    fn next_frame<M>(&mut self, shutdown: &AtomicBool) -> Result<Option<M>>
+    where
+        M: DeserializeOwned,
+    {
+        let mut chunk = [0u8; READ_CHUNK_SIZE];
+        let waiting_since = Instant::now();
+        let mut frame_started = if self.buf.is_empty() {
+            None
+        } else {
+            Some(waiting_since)
+        };
+        loop {
+            if let Some(message) = self.codec.decode(&mut self.buf)? {
+                return Ok(Some(message));
+            }
+
+            if shutdown.load(Ordering::SeqCst) {
+                if self.buf.is_empty() {
+                    debug!("Closing idle connection for shutdown");
+                } else {
+                    warn!(
+                        "Dropping partially received frame ({} of {} bytes) for shutdown",
+                        self.buf.len(),
+                        expected_frame_len(&self.buf)
+                    );
+                }
+                return Ok(None);
+            }
+            match frame_started {
+                Some(started) if started.elapsed() > FRAME_TIMEOUT => {
+                    return Err(RqMeshError::from(TransportErrorKind::new_timed_out(
+                        format!(
+                            "received {} of {} bytes of a frame in {:?}",
+                            self.buf.len(),
+                            expected_frame_len(&self.buf),
+                            FRAME_TIMEOUT
+                        ),
+                    )));
+                }
+                None if waiting_since.elapsed() > IDLE_TIMEOUT => {
+                    debug!("Closing connection idle for {:?}", IDLE_TIMEOUT);
+                    return Ok(None);
+                }
+                _ => {}
+            }
+
+            match self.stream.read(&mut chunk) {
+                Ok(0) if self.buf.is_empty() => return Ok(None),
+                Ok(0) => {
+                    return Err(RqMeshError::from(FrameErrorKind::new_truncated(
+                        expected_frame_len(&self.buf),
+                        self.buf.len(),
+                    )))
+                }
+                Ok(n) => {
+                    frame_started.get_or_insert_with(Instant::now);
+                    self.buf.extend_from_slice(&chunk[..n]);
+                }
+                // TLS peers commonly disconnect without a close_notify,
+                // which is harmless between frames
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof && self.buf.is_empty() => {
+                    return Ok(None)
+                }
+                Err(e)
+                    if e.kind() == ErrorKind::WouldBlock
+                        || e.kind() == ErrorKind::TimedOut
+                        || e.kind() == ErrorKind::Interrupted =>
+                {
+                    continue
+                }
+                Err(e) => return Err(RqMeshError::from(TransportErrorKind::from_io(&e))),
+            }
+        }
+    }
+}
+
+/// Total length of the frame at the front of `buf`, as far as can be told
+/// from the bytes received so far.
+fn expected_frame_len(buf: &[u8]) -> usize {
+    match buf.get(..FRAME_HEADER_LEN).map(|h| h.try_into()) {
+        Some(Ok(header)) => FRAME_HEADER_LEN + u32::from_be_bytes(header) as usize,
+        _ => FRAME_HEADER_LEN,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::initialization::in_memory_agent;
+    use rqmesh_core::{AgentInitializationContext, PingRequest, RqMeshFrame};
+    use std::io::Write;
+
+    /// Serves a single connection on which `first_frame` is sent, returning
+    /// how the agent answered it and how serving the connection ended.
+    fn answer_to<M: Serialize>(first_frame: &M) -> (HandshakeResponse, Result<()>) {
+        let agent = in_memory_agent(AgentInitializationContext::new(":memory:", "", ""));
+        let dispatcher = Dispatcher::new();
+        let local = Handshake::new("agent", dispatcher.actions());
+        let shutdown = AtomicBool::new(false);
+        let listener = TcpListener::bind("127.0.0.1:0").expect("binds");
+        let codec = RqMeshCodec::default();
+
+        let mut client =
+            TcpStream::connect(listener.local_addr().expect("has address")).expect("connects");
+        let (stream, _) = listener.accept().expect("accepts");
+        thread::scope(|scope| {
+            let served =
+                scope.spawn(|| serve_connection(&agent, &dispatcher, &local, stream, &shutdown));
+            client
+                .write_all(&codec.encode(first_frame).expect("encodes"))
+                .expect("sends first frame");
+            let response = codec
+                .read_frame::<_, HandshakeResponse>(&mut client)
+                .expect("reads answer")
+                .expect("is answered");
+            drop(client);
+            (response, served.join().expect("serves without panicking"))
+        })
+    }
+
+    #[test]
+    fn connections_must_start_with_a_handshake() {
+        let request = RqMeshFrame::new(PingRequest::default(), "alice")
+            .into_envelope()
+            .expect("encodes request");
+        match answer_to(&request) {
+            (
+                HandshakeResponse::Rejected(RqMeshError::ProtocolError(
+                    ProtocolErrorKind::HandshakeRequired { .. },
+                )),
+                Err(_),
+            ) => {}
+            other => panic!("expected the request to be rejected, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn handshakes_from_compatible_peers_are_accepted() {
+        let (response, _) = answer_to(&Handshake::new("alice", Vec::<String>::new()));
+        match response {
+            HandshakeResponse::Accepted(local) => assert_eq!(local.identity(), "agent"),
+            other => panic!("expected the handshake to be accepted, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,101 @@
+//! Agent side of mutual TLS: accepting connections from clients and other
+//! agents, and connecting to peers with the agent's own certificate.
+
+use crate::Agent;
+use rqmesh_client::tls::{self, TlsConnector};
+use rqmesh_client::Client;
+use rqmesh_core::{InitializationErrorKind, RqMeshError, TlsConfig};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use std::io::ErrorKind;
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+type Result<T> = std::result::Result<T, RqMeshError>;
+
+/// An accepted connection once its TLS handshake has completed.
+pub type TlsStream = StreamOwned<ServerConnection, TcpStream>;
+
+/// Server side TLS settings, requiring every connection to present a
+/// certificate issued by a CA in the configured bundle.
+pub struct TlsAcceptor {
+    config: Arc<ServerConfig>,
+}
+
+impl TlsAcceptor {
+    /// Loads the certificate, key and CA bundle named by `tls`.
+    pub fn new(tls: &TlsConfig) -> Result<TlsAcceptor> {
+        let roots = tls::load_roots(tls.ca_path())?;
+        let certs = tls::load_certs(tls.cert_path())?;
+        let key = tls::load_private_key(tls.key_path())?;
+        let invalid = |e: &dyn std::fmt::Display| {
+            RqMeshError::from(InitializationErrorKind::new_invalid_tls_config(
+                tls.cert_path().to_string_lossy(),
+                format!("{}", e),
+            ))
+        };
+        let verifier =
+            WebPkiClientVerifier::builder_with_provider(Arc::new(roots), tls::provider())
+                .build()
+                .map_err(|e| invalid(&e))?;
+        let config = ServerConfig::builder_with_provider(tls::provider())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| invalid(&e))?
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(certs, key)
+            .map_err(|e| invalid(&e))?;
+        Ok(TlsAcceptor {
+            config: Arc::new(config),
+        })
+    }
+
+    /// Performs the TLS handshake over `stream`, whose read timeout bounds
+    /// how long to wait before re-checking `shutdown`. Returns the session
+    /// and the identity in the peer's certificate, or `None` if shutdown was
+    /// requested before the handshake completed.
+    pub fn accept(
+        &self,
+        stream: TcpStream,
+        shutdown: &AtomicBool,
+    ) -> Result<Option<(TlsStream, Option<String>)>> {
+        let mut conn = ServerConnection::new(Arc::clone(&self.config)).map_err(tls::tls_err)?;
+        let mut stream = stream;
+        while conn.is_handshaking() {
+            match conn.complete_io(&mut stream) {
+                Ok(_) => {}
+                Err(e)
+                    if e.kind() == ErrorKind::WouldBlock
+                        || e.kind() == ErrorKind::TimedOut
+                        || e.kind() == ErrorKind::Interrupted =>
+                {
+                    if shutdown.load(Ordering::SeqCst) {
+                        return Ok(None);
+                    }
+                }
+                Err(e) => return Err(tls::handshake_err(e)),
+            }
+        }
+        let identity = conn.peer_certificates().and_then(tls::peer_identity);
+        Ok(Some((StreamOwned::new(conn, stream), identity)))
+    }
+}
+
+impl Agent {
+    /// Connects to the agent at `endpoint` as `requestor`, over TLS when the
+    /// agent is configured with a certificate, and signing requests when it
+    /// is configured with a key.
+    pub fn connect_to(&self, endpoint: &str, requestor: &str, timeout: Duration) -> Result<Client> {
+        Client::connect_with(endpoint, requestor, Some(timeout), self.connector.as_ref())
+            .map(|client| client.with_signing_key(self.signing_key.clone()))
+    }
+}
+
+/// Loads both sides of the TLS configuration in `tls`, if any.
+pub fn load(tls: Option<&TlsConfig>) -> Result<(Option<TlsAcceptor>, Option<TlsConnector>)> {
+    match tls {
+        Some(tls) => Ok((Some(TlsAcceptor::new(tls)?), Some(TlsConnector::new(tls)?))),
+        None => Ok((None, None)),
+    }
+}
@@ -0,0 +1,141 @@
+//! Helpers shared by the tests that run agent binaries.
+
+#![allow(dead_code)]
+
+use rqmesh_client::Client;
+use rqmesh_core::{ListPeersRequest, MemberState, SigningKey};
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Longest to wait for an agent to start listening or for the mesh to
+/// settle. A death takes a few protocol periods to suspect and the
+/// suspicion timeout to confirm.
+pub const CONVERGENCE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// An agent binary running on loopback with a store of its own.
+pub struct TestAgent {
+    child: Child,
+    pub dir: PathBuf,
+    pub addr: String,
+}
+
+impl TestAgent {
+    /// Starts an agent, called `name` in its temporary directory, with
+    /// `args` on top of those it needs to run in a test, and waits for it
+    /// to listen.
+    pub fn start(name: &str, args: &[&str]) -> TestAgent {
+        let dir =
+            std::env::temp_dir().join(format!("rqmesh-agent-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).expect("creates agent directory");
+        let port_file = dir.join("port");
+
+        let mut command = Command::new(env!("CARGO_BIN_EXE_rqmesh-agent"));
+        command
+            .arg(dir.join("agent.db"))
+            .args(["--check-cmd", "echo ok", "--port-file"])
+            .arg(&port_file)
+            .args(args)
+            .env_remove("RQMESH_CONFIG")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        let mut child = command.spawn().expect("starts agent");
+
+        let port = wait_for(&format!("{} to listen", name), || {
+            if let Ok(Some(status)) = child.try_wait() {
+                panic!("agent {} exited before listening: {}", name, status);
+            }
+            let port = std::fs::read_to_string(&port_file).ok()?;
+            port.trim().parse::<u16>().ok()
+        });
+        TestAgent {
+            child,
+            dir,
+            addr: format!("127.0.0.1:{}", port),
+        }
+    }
+
+    /// Stores `key` in the agent's store, as `--add-auth-key` does.
+    pub fn add_auth_key(&self, key: &SigningKey) {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_rqmesh-agent"))
+            .arg(self.dir.join("agent.db"))
+            .args(["--check-cmd", "echo ok", "--add-auth-key", key.key_id()])
+            .args(["--auth-key-secret-file", "-"])
+            .env_remove("RQMESH_CONFIG")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("starts agent to add key");
+        child
+            .stdin
+            .take()
+            .expect("pipes stdin")
+            .write_all(key.secret_hex().as_bytes())
+            .expect("writes secret");
+        let status = child.wait().expect("waits for agent");
+        assert!(
+            status.success(),
+            "adding key {} failed: {}",
+            key.key_id(),
+            status
+        );
+    }
+
+    /// How this agent sees every other member of the mesh.
+    pub fn members(&self) -> Option<Vec<(String, MemberState)>> {
+        let mut client = Client::connect(
+            self.addr.as_str(),
+            "gossip-test",
+            Some(Duration::from_secs(2)),
+        )
+        .ok()?;
+        let response = client.call(ListPeersRequest::default()).ok()?;
+        Some(
+            response
+                .peers()
+                .iter()
+                .map(|peer| (peer.endpoint().to_string(), peer.state()))
+                .collect(),
+        )
+    }
+
+    pub fn sees(&self, other: &TestAgent, state: MemberState) -> bool {
+        self.members()
+            .unwrap_or_default()
+            .iter()
+            .any(|(endpoint, s)| *endpoint == other.addr && *s == state)
+    }
+
+    pub fn kill(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+impl Drop for TestAgent {
+    fn drop(&mut self) {
+        self.kill();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+pub fn wait_for<T>(what: &str, mut check: impl FnMut() -> Option<T>) -> T {
+    let deadline = Instant::now() + CONVERGENCE_TIMEOUT;
+    loop {
+        if let Some(value) = check() {
+            return value;
+        }
+        if Instant::now() >= deadline {
+            panic!("timed out waiting for {}", what);
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
+}
+
+pub fn wait_until(what: &str, mut check: impl FnMut() -> bool) {
+    wait_for(what, || Some(()).filter(|_| check()))
+}
@@ -0,0 +1,48 @@
+//! Membership over loopback: agents joining through a seed, learning about
+//! each other from gossip, and detecting an agent that has died.
+
+mod common;
+
+use common::{wait_until, TestAgent};
+use rqmesh_core::MemberState;
+
+/// Probes and suspicions run faster than by default so a dead agent is
+/// noticed well within the convergence timeout.
+const TIMINGS: [&str; 4] = [
+    "--protocol-period-ms",
+    "200",
+    "--suspect-timeout-ms",
+    "1000",
+];
+
+#[test]
+fn agents_join_disseminate_and_detect_failures() {
+    let seed = TestAgent::start("seed", &TIMINGS);
+    let joiner = |name| TestAgent::start(name, &[&TIMINGS[..], &["--seed", &seed.addr]].concat());
+    let first = joiner("first");
+    let mut second = joiner("second");
+    let agents = [&seed, &first, &second];
+
+    // The two joiners only know the seed, so each can only learn of the
+    // other from membership updates gossiped through it.
+    for observer in agents {
+        for other in agents {
+            if observer.addr != other.addr {
+                wait_until(
+                    &format!("{} to see {} alive", observer.addr, other.addr),
+                    || observer.sees(other, MemberState::Alive),
+                );
+            }
+        }
+    }
+
+    second.kill();
+    for observer in [&seed, &first] {
+        wait_until(
+            &format!("{} to see {} dead", observer.addr, second.addr),
+            || observer.sees(&second, MemberState::Dead),
+        );
+    }
+    assert!(seed.sees(&first, MemberState::Alive));
+    assert!(first.sees(&seed, MemberState::Alive));
+}
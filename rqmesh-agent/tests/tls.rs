@@ -0,0 +1,205 @@
+//! Mutual TLS between a client and an agent, with certificates issued at
+//! test time by a trusted CA and by one neither end trusts, and whose
+//! requests agents relaying over it handle.
+
+mod common;
+
+use common::{wait_until, TestAgent};
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa,
+    KeyPair,
+};
+use rqmesh_client::tls::TlsConnector;
+use rqmesh_client::Client;
+use rqmesh_core::{
+    BroadcastRequest, DescribeAgentRequest, ListCapabilitiesRequest, ListPeersRequest, MemberState,
+    QueryAuditLogRequest, RqMeshError, RqMeshFrame, RqMeshProtocolAction, SigningKey, TlsConfig,
+};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const TIMEOUT: Option<Duration> = Some(Duration::from_secs(5));
+
+struct Ca {
+    cert: Certificate,
+    key: KeyPair,
+}
+
+impl Ca {
+    fn new(name: &str) -> Ca {
+        let key = KeyPair::generate().expect("generates CA key");
+        let mut params = CertificateParams::new(Vec::<String>::new()).expect("CA params");
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let cert = params.self_signed(&key).expect("signs CA");
+        Ca { cert, key }
+    }
+
+    /// Issues a certificate for `name`, valid for loopback and usable to
+    /// both serve and connect as agents do, and writes it, its key and the
+    /// certificate of `trusted` to `dir` as the files `--tls-cert`,
+    /// `--tls-key` and `--tls-ca` take.
+    fn issue(&self, name: &str, dir: &Path, trusted: &Ca) -> TlsConfig {
+        let key = KeyPair::generate().expect("generates key");
+        let mut params = CertificateParams::new(vec!["127.0.0.1".to_string()]).expect("params");
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.extended_key_usages = vec![
+            ExtendedKeyUsagePurpose::ServerAuth,
+            ExtendedKeyUsagePurpose::ClientAuth,
+        ];
+        let cert = params
+            .signed_by(&key, &self.cert, &self.key)
+            .expect("signs certificate");
+
+        std::fs::create_dir_all(dir).expect("creates certificate directory");
+        let write = |file: &str, pem: String| -> PathBuf {
+            let path = dir.join(format!("{}-{}", name, file));
+            std::fs::write(&path, pem).expect("writes PEM");
+            path
+        };
+        TlsConfig::new(
+            write("cert.pem", cert.pem()),
+            write("key.pem", key.serialize_pem()),
+            write("ca.pem", trusted.cert.pem()),
+        )
+    }
+}
+
+fn start_agent(name: &str, tls: &TlsConfig, args: &[&str]) -> TestAgent {
+    let tls_args = [
+        "--tls-cert",
+        &tls.cert_path().to_string_lossy(),
+        "--tls-key",
+        &tls.key_path().to_string_lossy(),
+        "--tls-ca",
+        &tls.ca_path().to_string_lossy(),
+    ]
+    .map(|arg| arg.to_string());
+    let tls_args: Vec<&str> = tls_args.iter().map(|arg| arg.as_str()).collect();
+    TestAgent::start(name, &[&tls_args[..], args].concat())
+}
+
+fn connect(agent: &TestAgent, tls: &TlsConfig) -> Result<Client, RqMeshError> {
+    let connector = TlsConnector::new(tls)?;
+    let mut client = Client::connect_with(&agent.addr, "tls-test", TIMEOUT, Some(&connector))?;
+    client.call(DescribeAgentRequest::default())?;
+    Ok(client)
+}
+
+/// Checks that a connection failed because one end refused the other's
+/// certificate, which in TLS 1.3 the client may only learn from an alert
+/// after its half of the handshake is done.
+fn assert_tls_error(result: Result<Client, RqMeshError>) {
+    match result {
+        Err(RqMeshError::TransportError(e)) => {
+            let message = format!("{}", e);
+            assert!(
+                message.contains("TLS") || message.contains("alert"),
+                "expected a TLS error, got {}",
+                message
+            )
+        }
+        Err(e) => panic!("expected a TLS error, got {}", e),
+        Ok(_) => panic!("connected despite an untrusted certificate"),
+    }
+}
+
+fn certificate_dir(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("rqmesh-tls-test-{}-{}", std::process::id(), name))
+}
+
+#[test]
+fn clients_and_agents_with_certificates_from_the_ca_connect() {
+    let dir = certificate_dir("trusted");
+    let ca = Ca::new("rqmesh test CA");
+    let agent = start_agent("tls-agent", &ca.issue("node-1", &dir, &ca), &[]);
+
+    let client = connect(&agent, &ca.issue("alice", &dir, &ca)).expect("connects");
+    assert_eq!(client.peer_identity(), Some("node-1"));
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[test]
+fn agents_reject_client_certificates_from_another_ca() {
+    let dir = certificate_dir("rogue-client");
+    let ca = Ca::new("rqmesh test CA");
+    let rogue = Ca::new("rogue CA");
+    let agent = start_agent("tls-rogue-client", &ca.issue("node-1", &dir, &ca), &[]);
+
+    // Trusting the agent's CA is not enough without a certificate it issued.
+    let mallory = rogue.issue("mallory", &dir, &ca);
+    assert_tls_error(connect(&agent, &mallory));
+
+    // The agent keeps serving clients it does trust.
+    connect(&agent, &ca.issue("alice", &dir, &ca)).expect("connects");
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[test]
+fn clients_reject_agent_certificates_from_another_ca() {
+    let dir = certificate_dir("rogue-agent");
+    let ca = Ca::new("rqmesh test CA");
+    let rogue = Ca::new("rogue CA");
+    // The impostor trusts the real CA, so only its own certificate is wrong.
+    let agent = start_agent("tls-rogue-agent", &rogue.issue("node-1", &dir, &ca), &[]);
+
+    assert_tls_error(connect(&agent, &ca.issue("alice", &dir, &ca)));
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[test]
+fn agents_relaying_a_broadcast_do_not_stand_in_for_its_sender() {
+    let dir = certificate_dir("broadcast");
+    let ca = Ca::new("rqmesh test CA");
+    let seed = start_agent("tls-broadcast-seed", &ca.issue("node-1", &dir, &ca), &[]);
+    let peer = start_agent(
+        "tls-broadcast-peer",
+        &ca.issue("node-2", &dir, &ca),
+        &["--seed", &seed.addr],
+    );
+    let key = SigningKey::generate("ops");
+    seed.add_auth_key(&key);
+    peer.add_auth_key(&key);
+    let alice = ca.issue("alice", &dir, &ca);
+    wait_until("the seed to see its peer alive", || {
+        connect(&seed, &alice)
+            .and_then(|mut client| client.call(ListPeersRequest::default()))
+            .map(|response| {
+                response
+                    .peers()
+                    .iter()
+                    .any(|p| p.endpoint() == peer.addr && p.state() == MemberState::Alive)
+            })
+            .unwrap_or(false)
+    });
+
+    // Alice broadcasts one request signed with the key and one unsigned,
+    // over a connection the seed knows is hers from her certificate.
+    let request = RqMeshFrame::new(ListCapabilitiesRequest::default(), "alice")
+        .into_envelope()
+        .expect("encodes request");
+    let mut client = connect(&seed, &alice).expect("connects");
+    for envelope in [key.sign(request.clone()).expect("signs"), request] {
+        let results = client
+            .call(BroadcastRequest::new(envelope, Vec::new()))
+            .expect("broadcasts")
+            .into_results::<ListCapabilitiesRequest>();
+        assert_eq!(results.len(), 2, "ran on {:?}", results.keys());
+        assert!(results.values().all(|result| result.is_ok()));
+    }
+
+    // The peer only heard from the seed, yet handled the signed request
+    // for its signer and the unsigned one for the caller the seed vouched
+    // for.
+    let records = connect(&peer, &alice)
+        .and_then(|mut client| client.call(QueryAuditLogRequest::new()))
+        .expect("queries audit log")
+        .into_records();
+    let callers: Vec<_> = records
+        .iter()
+        .filter(|r| r.action() == ListCapabilitiesRequest::ACTION)
+        .map(|r| r.requestor())
+        .collect();
+    assert_eq!(callers, vec![Some("ops"), Some("alice")]);
+    let _ = std::fs::remove_dir_all(dir);
+}
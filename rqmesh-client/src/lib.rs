@@ -0,0 +1,290 @@
+use rqmesh_core::{
+    CapabilityBroadcast, Handshake, HandshakeResponse, ListCapabilitiesRequest, MembershipUpdate,
+    ProtocolErrorKind, RouteRequest, RoutedResponse, RoutingStrategy, RqMeshCodec, RqMeshEnvelope,
+    RqMeshError, RqMeshFrame, RqMeshProtocolAction, RqMeshResponseEnvelope, SigningKey,
+    TransportErrorKind,
+};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+use tls::{TlsConnector, Transport};
+
+pub mod tls;
+
+type Result<T> = std::result::Result<T, RqMeshError>;
+
+/// A connection to a single agent.
+///
+/// Requests are sent one at a time over the same connection, each call
+/// blocking until the agent's response frame has been read.
+pub struct Client {
+    /// The underlying socket, kept to adjust timeouts and look up addresses.
+    socket: TcpStream,
+    /// What frames are read from and written to, `socket` itself or a TLS
+    /// session over it.
+    stream: Box<dyn Transport>,
+    peer_identity: Option<String>,
+    signing_key: Option<SigningKey>,
+    codec: RqMeshCodec,
+    requestor: String,
+    remote: Handshake,
+}
+
+impl Client {
+    /// Connects to the agent at `addr` and performs the protocol handshake,
+    /// identifying as `requestor` in the handshake and in every frame sent.
+    /// `timeout` bounds connecting as well as each read and write.
+    pub fn connect<A, S>(addr: A, requestor: S, timeout: Option<Duration>) -> Result<Client>
+    where
+        A: ToSocketAddrs + std::fmt::Debug,
+        S: Into<String>,
+    {
+        let socket = connect_socket(addr, timeout)?;
+        let stream = socket.try_clone().map_err(io_err)?;
+        Client::handshake(socket, Box::new(stream), None, requestor.into())
+    }
+
+    /// Like [`Client::connect`], but over TLS when `tls` is given. The host
+    /// part of `addr` is the name the agent's certificate must be valid for.
+    pub fn connect_with<S>(
+        addr: &str,
+        requestor: S,
+        timeout: Option<Duration>,
+        tls: Option<&TlsConnector>,
+    ) -> Result<Client>
+    where
+        S: Into<String>,
+    {
+        let tls = match tls {
+            Some(tls) => tls,
+            None => return Client::connect(addr, requestor, timeout),
+        };
+        let socket = connect_socket(addr, timeout)?;
+        let (stream, identity) =
+            tls.connect(server_name(addr), socket.try_clone().map_err(io_err)?)?;
+        Client::handshake(socket, Box::new(stream), identity, requestor.into())
+    }
+
+    fn handshake(
+        socket: TcpStream,
+        mut stream: Box<dyn Transport>,
+        peer_identity: Option<String>,
+        requestor: String,
+    ) -> Result<Client> {
+        let codec = RqMeshCodec::default();
+        let local = Handshake::new(requestor.as_str(), Vec::<String>::new());
+        codec.write_frame(&mut stream, &local)?;
+        let response: HandshakeResponse = codec.read_frame(&mut stream)?.ok_or_else(|| {
+            RqMeshError::from(ProtocolErrorKind::new_handshake_required(
+                "Connection closed before the handshake completed",
+            ))
+        })?;
+        let remote = match response {
+            HandshakeResponse::Accepted(remote) => remote,
+            HandshakeResponse::Rejected(e) => return Err(e),
+        };
+        local.check_compatible(&remote)?;
+        Ok(Client {
+            socket,
+            stream,
+            peer_identity,
+            signing_key: None,
+            codec,
+            requestor,
+            remote,
+        })
+    }
+
+    /// Signs every request sent with [`Client::call`] and its variants with
+    /// `key`, or stops signing them if `None`.
+    pub fn with_signing_key(mut self, key: Option<SigningKey>) -> Client {
+        self.signing_key = key;
+        self
+    }
+
+    /// Signs `envelope` with the client's key, if it has one.
+    pub fn sign(&self, envelope: RqMeshEnvelope) -> Result<RqMeshEnvelope> {
+        match &self.signing_key {
+            Some(key) => key.sign(envelope),
+            None => Ok(envelope),
+        }
+    }
+
+    /// The handshake the agent answered with, describing its protocol
+    /// version, identity and supported actions.
+    pub fn remote(&self) -> &Handshake {
+        &self.remote
+    }
+
+    pub fn requestor(&self) -> &str {
+        &self.requestor
+    }
+
+    pub fn peer_addr(&self) -> Result<SocketAddr> {
+        self.socket.peer_addr().map_err(io_err)
+    }
+
+    /// Identity in the certificate the agent presented, if connected over
+    /// TLS.
+    pub fn peer_identity(&self) -> Option<&str> {
+        self.peer_identity.as_deref()
+    }
+
+    /// Replaces the timeout applied to each read and write, e.g. to wait
+    /// longer for a request known to take a while.
+    pub fn set_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        self.socket.set_read_timeout(timeout).map_err(io_err)?;
+        self.socket.set_write_timeout(timeout).map_err(io_err)
+    }
+
+    /// Sends `action` and waits for its typed response. Errors reported by
+    /// the agent are returned as [`RqMeshError::Remote`] errors from it, and
+    /// actions the agent did not advertise in its handshake are rejected
+    /// without being sent.
+    pub fn call<A>(&mut self, action: A) -> Result<A::ResponseType>
+    where
+        A: RqMeshProtocolAction,
+    {
+        self.call_with_piggyback(action, Vec::new())
+            .map(|(response, _)| response)
+    }
+
+    /// Like [`Client::call`], but attaches membership updates to the request
+    /// and returns the updates the agent piggybacked on its response.
+    pub fn call_with_piggyback<A>(
+        &mut self,
+        action: A,
+        piggyback: Vec<MembershipUpdate>,
+    ) -> Result<(A::ResponseType, Vec<MembershipUpdate>)>
+    where
+        A: RqMeshProtocolAction,
+    {
+        if !self.remote.supports(A::ACTION) {
+            return Err(RqMeshError::from(ProtocolErrorKind::new_unknown_action(
+                A::ACTION,
+            )));
+        }
+        let envelope = RqMeshFrame::new(action, self.requestor.as_str())
+            .into_envelope()?
+            .with_piggyback(piggyback);
+        let envelope = self.sign(envelope)?;
+        let response = self.call_envelope(&envelope)?;
+        if let Err(e) = response.result() {
+            return Err(RqMeshError::new_remote(self.remote.identity(), e.clone()));
+        }
+        let received = response.piggyback().to_vec();
+        Ok((response.into_response::<A>()?, received))
+    }
+
+    /// Sends `action` to be handled by whichever agent in the mesh the
+    /// connected agent picks among those advertising every `required`
+    /// capability. Decode the response with [`RoutedResponse::into_response`].
+    pub fn call_routed<A>(
+        &mut self,
+        action: A,
+        required: Vec<CapabilityBroadcast>,
+        strategy: RoutingStrategy,
+    ) -> Result<RoutedResponse>
+    where
+        A: RqMeshProtocolAction,
+    {
+        let envelope = RqMeshFrame::new(action, self.requestor.as_str()).into_envelope()?;
+        self.call(RouteRequest::new(envelope, required).with_strategy(strategy))
+    }
+
+    /// Sends an already encoded envelope as is and returns the raw response.
+    pub fn call_envelope(&mut self, envelope: &RqMeshEnvelope) -> Result<RqMeshResponseEnvelope> {
+        self.codec.write_frame(&mut self.stream, envelope)?;
+        self.codec
+            .read_frame(&mut self.stream)?
+            .ok_or_else(|| RqMeshError::from(TransportErrorKind::new_connection_closed()))
+    }
+}
+
+/// Asks each agent in `addrs` whether it advertises `capability_type`.
+///
+/// Returns one entry per agent, in the order given, with the matching
+/// capabilities (empty if the agent does not have it) or the error that
+/// prevented asking it.
+pub fn find_capable_agents<'a, I, S>(
+    addrs: I,
+    capability_type: &str,
+    requestor: S,
+    timeout: Option<Duration>,
+    tls: Option<&TlsConnector>,
+    signing_key: Option<&SigningKey>,
+) -> Vec<(&'a str, Result<Vec<CapabilityBroadcast>>)>
+where
+    I: IntoIterator<Item = &'a str>,
+    S: Into<String>,
+{
+    let requestor = requestor.into();
+    addrs
+        .into_iter()
+        .map(|addr| {
+            let capabilities = Client::connect_with(addr, requestor.as_str(), timeout, tls)
+                .map(|c| c.with_signing_key(signing_key.cloned()))
+                .and_then(|mut c| {
+                    c.call(ListCapabilitiesRequest::new(Some(
+                        capability_type.to_string(),
+                    )))
+                    .map(|r| r.into_capabilities())
+                });
+            (addr, capabilities)
+        })
+        .collect()
+}
+
+/// Opens a TCP connection to the first address `addr` resolves to that
+/// accepts one, applying `timeout` to connecting and to every read and write.
+fn connect_socket<A>(addr: A, timeout: Option<Duration>) -> Result<TcpStream>
+where
+    A: ToSocketAddrs + std::fmt::Debug,
+{
+    let addrs: Vec<SocketAddr> = addr
+        .to_socket_addrs()
+        .map_err(|e| connect_err(format!("{:?}", addr), e))?
+        .collect();
+    let mut last_err = None;
+    for addr in addrs {
+        let connected = match timeout {
+            Some(t) => TcpStream::connect_timeout(&addr, t),
+            None => TcpStream::connect(addr),
+        };
+        match connected {
+            Ok(stream) => {
+                stream.set_read_timeout(timeout).map_err(io_err)?;
+                stream.set_write_timeout(timeout).map_err(io_err)?;
+                return Ok(stream);
+            }
+            Err(e) => last_err = Some(connect_err(addr.to_string(), e)),
+        }
+    }
+    Err(match last_err {
+        Some(e) => e,
+        None => RqMeshError::from(TransportErrorKind::new_connect_failed(
+            format!("{:?}", addr),
+            "Address did not resolve to any socket address",
+        )),
+    })
+}
+
+/// The host part of a `host:port` address, without the brackets around an
+/// IPv6 address.
+fn server_name(addr: &str) -> &str {
+    let host = match addr.rfind(':') {
+        Some(i) if !addr[i..].contains(']') => &addr[..i],
+        _ => addr,
+    };
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+fn connect_err(addr: String, e: std::io::Error) -> RqMeshError {
+    RqMeshError::from(TransportErrorKind::new_connect_failed(
+        addr,
+        format!("{}", e),
+    ))
+}
+
+fn io_err(e: std::io::Error) -> RqMeshError {
+    RqMeshError::from(TransportErrorKind::from_io(&e))
+}
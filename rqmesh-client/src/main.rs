@@ -0,0 +1,915 @@
+use rqmesh_client::tls::TlsConnector;
+use rqmesh_client::{find_capable_agents, Client};
+use rqmesh_core::{
+    AuditRecord, BroadcastRequest, CancelJobRequest, CapabilityBroadcast, CommandSpec,
+    DescribeAgentRequest, DescribeAgentResponse, EnqueueJobRequest, JobErrorKind, JobRecord,
+    JobState, JobStatusRequest, ListCapabilitiesRequest, ListPeersRequest, PeerRecord,
+    QueryAuditLogRequest, RoutingStrategy, RqMeshError, RqMeshFrame, RqMeshProtocolAction,
+    RunCommandRequest, SigningKey, TlsConfig, TransportErrorKind,
+};
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::time::Duration;
+
+fn main() {
+    let addr_arg = clap::Arg::with_name("ADDR")
+        .help("Address of the agent, e.g. 127.0.0.1:4100")
+        .required(true)
+        .takes_value(true);
+    let format_arg = clap::Arg::with_name("FORMAT")
+        .long("format")
+        .takes_value(true)
+        .multiple(false)
+        .possible_values(&["table", "json"])
+        .default_value("table")
+        .help("Output format");
+    let job_id_arg = clap::Arg::with_name("JOB_ID")
+        .help("ID of the job")
+        .required(true)
+        .takes_value(true);
+    let command_args = [
+        clap::Arg::with_name("ENV")
+            .long("env")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .validator(env_pair)
+            .help("Environment variable to set, as KEY=VALUE; may be repeated"),
+        clap::Arg::with_name("CWD")
+            .long("cwd")
+            .takes_value(true)
+            .multiple(false)
+            .help("Working directory on the agent"),
+        clap::Arg::with_name("COMMAND_TIMEOUT")
+            .long("command-timeout")
+            .takes_value(true)
+            .multiple(false)
+            .validator(seconds)
+            .help("Seconds after which the agent kills the program and everything it started"),
+        clap::Arg::with_name("SHELL")
+            .long("shell")
+            .takes_value(false)
+            .help("Run COMMAND, given as a single script, with /bin/sh -c"),
+        clap::Arg::with_name("STDIN")
+            .long("stdin")
+            .takes_value(true)
+            .multiple(false)
+            .help("File to feed to the program's stdin, or - for our own stdin"),
+        clap::Arg::with_name("UID")
+            .long("uid")
+            .takes_value(true)
+            .multiple(false)
+            .validator(numeric_id)
+            .help("User ID to run the program as"),
+        clap::Arg::with_name("GID")
+            .long("gid")
+            .takes_value(true)
+            .multiple(false)
+            .validator(numeric_id)
+            .help("Group ID to run the program with"),
+        clap::Arg::with_name("COMMAND")
+            .help("Program and arguments to run, or with --shell the script, after --")
+            .required(true)
+            .multiple(true)
+            .last(true),
+    ];
+    let route_args = [
+        clap::Arg::with_name("REQUIRE")
+            .long("require")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Capability, as NAME or NAME=VERSION, the agent running the program must have; the agent at ADDR routes the request to one that does. May be repeated"),
+        clap::Arg::with_name("STRATEGY")
+            .long("strategy")
+            .takes_value(true)
+            .multiple(false)
+            .possible_values(&["least-loaded", "round-robin", "random"])
+            .requires("REQUIRE")
+            .help("How to pick among agents with the required capabilities [default: least-loaded]"),
+    ];
+
+    let matches = clap::App::new("rqmesh")
+        .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            clap::Arg::with_name("REQUESTOR")
+                .long("requestor")
+                .takes_value(true)
+                .multiple(false)
+                .default_value("rqmesh-cli")
+                .global(true)
+                .help("Identity sent with every request"),
+        )
+        .arg(
+            clap::Arg::with_name("TIMEOUT")
+                .long("timeout")
+                .takes_value(true)
+                .multiple(false)
+                .default_value("10")
+                .validator(seconds)
+                .global(true)
+                .help("Seconds to wait when connecting and for each response"),
+        )
+        .arg(
+            clap::Arg::with_name("TLS_CERT")
+                .long("tls-cert")
+                .takes_value(true)
+                .multiple(false)
+                .global(true)
+                .requires_all(&["TLS_KEY", "TLS_CA"])
+                .help("PEM certificate to authenticate to agents with over TLS"),
+        )
+        .arg(
+            clap::Arg::with_name("TLS_KEY")
+                .long("tls-key")
+                .takes_value(true)
+                .multiple(false)
+                .global(true)
+                .requires_all(&["TLS_CERT", "TLS_CA"])
+                .help("PEM private key of the TLS certificate"),
+        )
+        .arg(
+            clap::Arg::with_name("TLS_CA")
+                .long("tls-ca")
+                .takes_value(true)
+                .multiple(false)
+                .global(true)
+                .requires_all(&["TLS_CERT", "TLS_KEY"])
+                .help("PEM bundle of CAs agent certificates must be issued by"),
+        )
+        .arg(
+            clap::Arg::with_name("AUTH_KEY_FILE")
+                .long("auth-key-file")
+                .takes_value(true)
+                .multiple(false)
+                .global(true)
+                .help(
+                    "File holding the key to sign requests with as KEY_ID=HEX_SECRET, or - for \
+                     stdin; defaults to the RQMESH_AUTH_KEY environment variable",
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("describe")
+                .about("Describe a single agent")
+                .arg(addr_arg.clone())
+                .arg(format_arg.clone()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("capabilities")
+                .about("List the capabilities advertised by a single agent")
+                .arg(addr_arg.clone())
+                .arg(format_arg.clone()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("peers")
+                .about("List the peers known to a single agent")
+                .arg(addr_arg.clone())
+                .arg(format_arg.clone()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("find")
+                .about("Report which of the given agents advertise a capability")
+                .arg(
+                    clap::Arg::with_name("CAPABILITY")
+                        .help("Capability type to look for, e.g. docker")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(addr_arg.clone().multiple(true))
+                .arg(format_arg.clone()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("run")
+                .about("Run a program on an agent and wait for it to finish")
+                .arg(addr_arg.clone())
+                .args(&command_args)
+                .args(&route_args)
+                .arg(format_arg.clone()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("enqueue")
+                .about("Queue a program to be run in the background by an agent")
+                .arg(addr_arg.clone())
+                .args(&command_args)
+                .args(&route_args)
+                .arg(
+                    clap::Arg::with_name("MAX_ATTEMPTS")
+                        .long("max-attempts")
+                        .takes_value(true)
+                        .multiple(false)
+                        .validator(positive_integer)
+                        .help("Times to try the program before giving up on it"),
+                )
+                .arg(format_arg.clone()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("broadcast")
+                .about("Run a program on every agent in the mesh with the given capabilities")
+                .arg(addr_arg.clone())
+                .args(&command_args)
+                .arg(
+                    clap::Arg::with_name("REQUIRE")
+                        .long("require")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Capability, as NAME or NAME=VERSION, an agent must have to run the program; may be repeated. Without it the program runs on every agent"),
+                )
+                .arg(
+                    clap::Arg::with_name("NODE_TIMEOUT")
+                        .long("node-timeout")
+                        .takes_value(true)
+                        .multiple(false)
+                        .validator(seconds)
+                        .help("Seconds each agent has to answer before it is reported as failed"),
+                )
+                .arg(
+                    clap::Arg::with_name("MAX_CONCURRENCY")
+                        .long("max-concurrency")
+                        .takes_value(true)
+                        .multiple(false)
+                        .validator(positive_integer)
+                        .help("Most agents running the program at once"),
+                )
+                .arg(format_arg.clone()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("audit")
+                .about("Show the requests an agent has handled, oldest first")
+                .arg(addr_arg.clone())
+                .arg(
+                    clap::Arg::with_name("SINCE")
+                        .long("since")
+                        .takes_value(true)
+                        .multiple(false)
+                        .help("Earliest time to show, in UTC as YYYY-MM-DD HH:MM:SS"),
+                )
+                .arg(
+                    clap::Arg::with_name("UNTIL")
+                        .long("until")
+                        .takes_value(true)
+                        .multiple(false)
+                        .help("Latest time to show, in UTC as YYYY-MM-DD HH:MM:SS"),
+                )
+                .arg(
+                    clap::Arg::with_name("CALLER")
+                        .long("caller")
+                        .takes_value(true)
+                        .multiple(false)
+                        .help("Only show requests made by this caller"),
+                )
+                .arg(
+                    clap::Arg::with_name("LIMIT")
+                        .long("limit")
+                        .takes_value(true)
+                        .multiple(false)
+                        .validator(positive_integer)
+                        .help("Most records to show"),
+                )
+                .arg(format_arg.clone()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("job")
+                .about("Show a job previously run or queued on an agent")
+                .arg(addr_arg.clone())
+                .arg(job_id_arg.clone())
+                .arg(format_arg.clone()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("cancel")
+                .about("Cancel a job that has not finished yet")
+                .arg(addr_arg)
+                .arg(job_id_arg)
+                .arg(format_arg),
+        )
+        .get_matches();
+
+    let requestor = matches
+        .value_of("REQUESTOR")
+        .expect("Must set a requestor")
+        .to_string();
+    let timeout: u64 = matches
+        .value_of("TIMEOUT")
+        .expect("Must set a timeout")
+        .parse()
+        .expect("TIMEOUT was checked by its validator");
+    let timeout = Some(Duration::from_secs(timeout));
+    let credentials = match Credentials::from_matches(&matches) {
+        Ok(credentials) => credentials,
+        Err(e) => {
+            eprintln!("error[{}]: {}", e.code(), e);
+            std::process::exit(1);
+        }
+    };
+
+    let result = match matches.subcommand() {
+        ("describe", Some(sub)) => describe(
+            sub.value_of("ADDR").expect("Must set ADDR"),
+            &requestor,
+            timeout,
+            &credentials,
+            sub.value_of("FORMAT") == Some("json"),
+        ),
+        ("capabilities", Some(sub)) => capabilities(
+            sub.value_of("ADDR").expect("Must set ADDR"),
+            &requestor,
+            timeout,
+            &credentials,
+            sub.value_of("FORMAT") == Some("json"),
+        ),
+        ("peers", Some(sub)) => peers(
+            sub.value_of("ADDR").expect("Must set ADDR"),
+            &requestor,
+            timeout,
+            &credentials,
+            sub.value_of("FORMAT") == Some("json"),
+        ),
+        ("find", Some(sub)) => find(
+            sub.value_of("CAPABILITY").expect("Must set CAPABILITY"),
+            sub.values_of("ADDR").expect("Must set ADDR").collect(),
+            &requestor,
+            timeout,
+            &credentials,
+            sub.value_of("FORMAT") == Some("json"),
+        ),
+        ("run", Some(sub)) => run(sub, &requestor, timeout, &credentials),
+        ("enqueue", Some(sub)) => enqueue(sub, &requestor, timeout, &credentials),
+        ("broadcast", Some(sub)) => broadcast(sub, &requestor, timeout, &credentials),
+        ("audit", Some(sub)) => audit(sub, &requestor, timeout, &credentials),
+        ("job", Some(sub)) => job(
+            sub.value_of("ADDR").expect("Must set ADDR"),
+            JobStatusRequest::new(sub.value_of("JOB_ID").expect("Must set JOB_ID")),
+            &requestor,
+            timeout,
+            &credentials,
+            sub.value_of("FORMAT") == Some("json"),
+        ),
+        ("cancel", Some(sub)) => job(
+            sub.value_of("ADDR").expect("Must set ADDR"),
+            CancelJobRequest::new(sub.value_of("JOB_ID").expect("Must set JOB_ID")),
+            &requestor,
+            timeout,
+            &credentials,
+            sub.value_of("FORMAT") == Some("json"),
+        ),
+        _ => unreachable!("clap requires a subcommand"),
+    };
+
+    if let Err(e) = result {
+        eprintln!("error[{}]: {}", e.code(), e);
+        std::process::exit(1);
+    }
+}
+
+/// How the CLI proves who it is to agents.
+struct Credentials {
+    tls: Option<TlsConnector>,
+    signing_key: Option<SigningKey>,
+}
+
+impl Credentials {
+    fn from_matches(matches: &clap::ArgMatches) -> Result<Credentials, RqMeshError> {
+        let tls = match (
+            matches.value_of("TLS_CERT"),
+            matches.value_of("TLS_KEY"),
+            matches.value_of("TLS_CA"),
+        ) {
+            (Some(cert), Some(key), Some(ca)) => {
+                Some(TlsConnector::new(&TlsConfig::new(cert, key, ca))?)
+            }
+            _ => None,
+        };
+        let signing_key = match matches.value_of("AUTH_KEY_FILE") {
+            Some("-")
+                if matches.subcommand().1.and_then(|sub| sub.value_of("STDIN")) == Some("-") =>
+            {
+                return Err(RqMeshError::from(TransportErrorKind::new_io_err(
+                    "--auth-key-file and --stdin cannot both read stdin",
+                )))
+            }
+            Some(path) => {
+                let declared = String::from_utf8_lossy(&read_input(path)?).into_owned();
+                Some(SigningKey::parse(declared.trim())?)
+            }
+            None => match std::env::var("RQMESH_AUTH_KEY") {
+                Ok(declared) => Some(SigningKey::parse(declared.trim())?),
+                Err(_) => None,
+            },
+        };
+        Ok(Credentials { tls, signing_key })
+    }
+
+    fn connect(
+        &self,
+        addr: &str,
+        requestor: &str,
+        timeout: Option<Duration>,
+    ) -> Result<Client, RqMeshError> {
+        Client::connect_with(addr, requestor, timeout, self.tls.as_ref())
+            .map(|client| client.with_signing_key(self.signing_key.clone()))
+    }
+}
+
+fn describe(
+    addr: &str,
+    requestor: &str,
+    timeout: Option<Duration>,
+    credentials: &Credentials,
+    json: bool,
+) -> Result<(), RqMeshError> {
+    let mut client = credentials.connect(addr, requestor, timeout)?;
+    let response = client.call(DescribeAgentRequest::default())?;
+    if json {
+        print_json(&response);
+    } else {
+        print_describe_table(&response);
+    }
+    Ok(())
+}
+
+fn capabilities(
+    addr: &str,
+    requestor: &str,
+    timeout: Option<Duration>,
+    credentials: &Credentials,
+    json: bool,
+) -> Result<(), RqMeshError> {
+    let mut client = credentials.connect(addr, requestor, timeout)?;
+    let response = client.call(ListCapabilitiesRequest::default())?;
+    if json {
+        print_json(&response);
+    } else {
+        print_capability_table(response.capabilities());
+    }
+    Ok(())
+}
+
+fn peers(
+    addr: &str,
+    requestor: &str,
+    timeout: Option<Duration>,
+    credentials: &Credentials,
+    json: bool,
+) -> Result<(), RqMeshError> {
+    let mut client = credentials.connect(addr, requestor, timeout)?;
+    let response = client.call(ListPeersRequest::default())?;
+    if json {
+        print_json(&response);
+    } else {
+        print_peer_table(response.peers());
+    }
+    Ok(())
+}
+
+fn find(
+    capability_type: &str,
+    addrs: Vec<&str>,
+    requestor: &str,
+    timeout: Option<Duration>,
+    credentials: &Credentials,
+    json: bool,
+) -> Result<(), RqMeshError> {
+    let results = find_capable_agents(
+        addrs,
+        capability_type,
+        requestor,
+        timeout,
+        credentials.tls.as_ref(),
+        credentials.signing_key.as_ref(),
+    );
+    if json {
+        let by_addr: BTreeMap<&str, std::result::Result<Vec<CapabilityBroadcast>, String>> =
+            results
+                .into_iter()
+                .map(|(addr, r)| (addr, r.map_err(|e| format!("{}", e))))
+                .collect();
+        print_json(&by_addr);
+    } else {
+        for (addr, result) in results {
+            match result {
+                Ok(caps) if caps.is_empty() => {}
+                Ok(caps) => {
+                    for cap in caps {
+                        println!("{}  {}", addr, cap.version().unwrap_or("-"));
+                    }
+                }
+                Err(e) => eprintln!("{}  error: {}", addr, e),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reads the file at `path`, or our own stdin if it is `-`.
+fn read_input(path: &str) -> Result<Vec<u8>, RqMeshError> {
+    let mut input = Vec::new();
+    let read = if path == "-" {
+        std::io::stdin().read_to_end(&mut input).map(|_| ())
+    } else {
+        std::fs::read(path).map(|contents| input = contents)
+    };
+    read.map_err(|e| TransportErrorKind::new_io_err(format!("Error reading {}: {}", path, e)))?;
+    Ok(input)
+}
+
+/// Builds the command described by the arguments shared by `run` and
+/// `enqueue`.
+fn command_request(sub: &clap::ArgMatches) -> Result<RunCommandRequest, RqMeshError> {
+    let mut command = sub.values_of("COMMAND").expect("Must set COMMAND");
+    let mut spec = if sub.is_present("SHELL") {
+        let script = command.next().expect("COMMAND is never empty");
+        if command.next().is_some() {
+            return Err(JobErrorKind::new_invalid_command(
+                script,
+                "COMMAND must be a single script with --shell; quote it",
+            )
+            .into());
+        }
+        CommandSpec::shell(script)
+    } else {
+        let program = command.next().expect("COMMAND is never empty");
+        CommandSpec::argv(program, command)
+    };
+    for pair in sub.values_of("ENV").into_iter().flatten() {
+        let (key, value) = pair
+            .split_once('=')
+            .expect("ENV was checked by its validator");
+        spec = spec.with_env(key, value);
+    }
+    if let Some(cwd) = sub.value_of("CWD") {
+        spec = spec.with_working_dir(cwd);
+    }
+    if let Some(secs) = sub.value_of("COMMAND_TIMEOUT") {
+        let command_timeout = Duration::from_secs(
+            secs.parse()
+                .expect("COMMAND_TIMEOUT was checked by its validator"),
+        );
+        spec = spec.with_timeout(command_timeout);
+    }
+    if let Some(path) = sub.value_of("STDIN") {
+        spec = spec.with_stdin(read_input(path)?);
+    }
+    if let Some(uid) = sub.value_of("UID") {
+        spec = spec.with_uid(uid.parse().expect("UID was checked by its validator"));
+    }
+    if let Some(gid) = sub.value_of("GID") {
+        spec = spec.with_gid(gid.parse().expect("GID was checked by its validator"));
+    }
+    Ok(RunCommandRequest::from_spec(spec))
+}
+
+fn run(
+    sub: &clap::ArgMatches,
+    requestor: &str,
+    timeout: Option<Duration>,
+    credentials: &Credentials,
+) -> Result<(), RqMeshError> {
+    let request = command_request(sub)?;
+    // Leave the agent time to kill the program and answer before giving up.
+    let timeout = match request.timeout() {
+        Some(command_timeout) => timeout.map(|t| t + command_timeout),
+        None => timeout,
+    };
+
+    let mut client = credentials.connect(
+        sub.value_of("ADDR").expect("Must set ADDR"),
+        requestor,
+        timeout,
+    )?;
+    let record = call_maybe_routed(&mut client, sub, request)?;
+    if sub.value_of("FORMAT") == Some("json") {
+        print_json(&record);
+    } else {
+        print_job(&record);
+    }
+    record.check()
+}
+
+fn enqueue(
+    sub: &clap::ArgMatches,
+    requestor: &str,
+    timeout: Option<Duration>,
+    credentials: &Credentials,
+) -> Result<(), RqMeshError> {
+    let mut request = EnqueueJobRequest::new(command_request(sub)?);
+    if let Some(max_attempts) = sub.value_of("MAX_ATTEMPTS") {
+        request = request.with_max_attempts(
+            max_attempts
+                .parse()
+                .expect("MAX_ATTEMPTS was checked by its validator"),
+        );
+    }
+
+    let mut client = credentials.connect(
+        sub.value_of("ADDR").expect("Must set ADDR"),
+        requestor,
+        timeout,
+    )?;
+    let record = call_maybe_routed(&mut client, sub, request)?;
+    if sub.value_of("FORMAT") == Some("json") {
+        print_json(&record);
+    } else {
+        print_job(&record);
+    }
+    Ok(())
+}
+
+fn broadcast(
+    sub: &clap::ArgMatches,
+    requestor: &str,
+    timeout: Option<Duration>,
+    credentials: &Credentials,
+) -> Result<(), RqMeshError> {
+    let command = command_request(sub)?;
+    let selector = sub
+        .values_of("REQUIRE")
+        .into_iter()
+        .flatten()
+        .map(CapabilityBroadcast::parse_declared)
+        .collect::<Result<_, _>>()?;
+    // Give each agent as long to answer as `run` would wait for it.
+    let node_timeout = match sub.value_of("NODE_TIMEOUT") {
+        Some(secs) => Some(Duration::from_secs(
+            secs.parse()
+                .expect("NODE_TIMEOUT was checked by its validator"),
+        )),
+        None => command
+            .timeout()
+            .and_then(|command_timeout| timeout.map(|t| t + command_timeout)),
+    };
+
+    let mut client = credentials.connect(
+        sub.value_of("ADDR").expect("Must set ADDR"),
+        requestor,
+        timeout,
+    )?;
+    // each agent checks the signature of the broadcast envelope itself
+    let envelope = client.sign(RqMeshFrame::new(command, requestor).into_envelope()?)?;
+    let mut request = BroadcastRequest::new(envelope, selector);
+    if let Some(node_timeout) = node_timeout {
+        request = request.with_node_timeout(node_timeout);
+    }
+    if let Some(max_concurrency) = sub.value_of("MAX_CONCURRENCY") {
+        request = request.with_max_concurrency(
+            max_concurrency
+                .parse()
+                .expect("MAX_CONCURRENCY was checked by its validator"),
+        );
+    }
+    // The agent bounds how long each node may take, so wait for the whole
+    // broadcast rather than for a single response.
+    client.set_timeout(None)?;
+    let results = client.call(request)?.into_results::<RunCommandRequest>();
+
+    let ran = results.len();
+    let failed = results
+        .values()
+        .filter(|r| !matches!(r, Ok(record) if record.state() == JobState::Succeeded))
+        .count();
+    if sub.value_of("FORMAT") == Some("json") {
+        let results: BTreeMap<String, std::result::Result<JobRecord, String>> = results
+            .into_iter()
+            .map(|(node, r)| (node, r.map_err(|e| format!("{}", e))))
+            .collect();
+        print_json(&results);
+    } else {
+        for (node, result) in &results {
+            match result {
+                Ok(record) => {
+                    println!(
+                        "== {}  {}  exit {}",
+                        node,
+                        record.state(),
+                        record
+                            .exit_code()
+                            .map_or_else(|| "-".to_string(), |c| c.to_string())
+                    );
+                    let _ = std::io::stdout().write_all(record.stdout());
+                    let _ = std::io::stderr().write_all(record.stderr());
+                }
+                Err(e) => println!("== {}  error: {}", node, e),
+            }
+        }
+    }
+    eprintln!("ran on {} agents, {} failed", ran, failed);
+    Ok(())
+}
+
+/// Sends `action` to the connected agent, or when capabilities are required
+/// has the agent route it to one that advertises them.
+fn call_maybe_routed<A>(
+    client: &mut Client,
+    sub: &clap::ArgMatches,
+    action: A,
+) -> Result<A::ResponseType, RqMeshError>
+where
+    A: RqMeshProtocolAction,
+{
+    let required: Vec<CapabilityBroadcast> = match sub.values_of("REQUIRE") {
+        Some(values) => values
+            .map(CapabilityBroadcast::parse_declared)
+            .collect::<Result<_, _>>()?,
+        None => return client.call(action),
+    };
+    let strategy = match sub.value_of("STRATEGY") {
+        Some(strategy) => strategy.parse()?,
+        None => RoutingStrategy::default(),
+    };
+    let routed = client.call_routed(action, required, strategy)?;
+    eprintln!(
+        "routed to {} after {} hops",
+        routed.node_id(),
+        routed.hops()
+    );
+    routed.into_response::<A>()
+}
+
+/// Sends `action`, which answers with a job, and prints the job.
+fn job<A>(
+    addr: &str,
+    action: A,
+    requestor: &str,
+    timeout: Option<Duration>,
+    credentials: &Credentials,
+    json: bool,
+) -> Result<(), RqMeshError>
+where
+    A: RqMeshProtocolAction<ResponseType = JobRecord>,
+{
+    let mut client = credentials.connect(addr, requestor, timeout)?;
+    let record = client.call(action)?;
+    if json {
+        print_json(&record);
+    } else {
+        print_job(&record);
+    }
+    Ok(())
+}
+
+fn audit(
+    sub: &clap::ArgMatches,
+    requestor: &str,
+    timeout: Option<Duration>,
+    credentials: &Credentials,
+) -> Result<(), RqMeshError> {
+    let mut request = QueryAuditLogRequest::new();
+    if let Some(since) = sub.value_of("SINCE") {
+        request = request.with_since(since);
+    }
+    if let Some(until) = sub.value_of("UNTIL") {
+        request = request.with_until(until);
+    }
+    if let Some(caller) = sub.value_of("CALLER") {
+        request = request.with_requestor(caller);
+    }
+    if let Some(limit) = sub.value_of("LIMIT") {
+        request = request.with_limit(limit.parse().expect("LIMIT was checked by its validator"));
+    }
+
+    let mut client = credentials.connect(
+        sub.value_of("ADDR").expect("Must set ADDR"),
+        requestor,
+        timeout,
+    )?;
+    let response = client.call(request)?;
+    if sub.value_of("FORMAT") == Some("json") {
+        print_json(&response);
+    } else {
+        print_audit_table(response.records());
+    }
+    Ok(())
+}
+
+fn seconds(value: String) -> Result<(), String> {
+    value
+        .parse::<u64>()
+        .map(|_| ())
+        .map_err(|_| format!("{} is not a whole number of seconds", value))
+}
+
+fn positive_integer(value: String) -> Result<(), String> {
+    match value.parse::<u32>() {
+        Ok(n) if n > 0 => Ok(()),
+        _ => Err(format!("{} is not a positive integer", value)),
+    }
+}
+
+fn numeric_id(value: String) -> Result<(), String> {
+    value
+        .parse::<u32>()
+        .map(|_| ())
+        .map_err(|_| format!("{} is not a numeric ID", value))
+}
+
+fn env_pair(value: String) -> Result<(), String> {
+    match value.split_once('=') {
+        Some((key, _)) if !key.is_empty() => Ok(()),
+        _ => Err(format!("{} is not KEY=VALUE", value)),
+    }
+}
+
+fn print_json<T>(value: &T)
+where
+    T: serde::Serialize,
+{
+    let out = serde_json::to_string_pretty(value).expect("response is always valid json");
+    println!("{}", out);
+}
+
+fn print_describe_table(response: &DescribeAgentResponse) {
+    print_rows(&[
+        ("node_id", response.node_id()),
+        ("version", response.version()),
+        ("storage_location", response.storage_location()),
+        ("initialized_at", response.initialized_at()),
+        ("endpoint", response.endpoint().unwrap_or("-")),
+    ]);
+}
+
+fn print_capability_table(capabilities: &[CapabilityBroadcast]) {
+    let width = capabilities
+        .iter()
+        .map(|c| c.capability_type().len())
+        .max()
+        .unwrap_or(0);
+    for cap in capabilities {
+        println!(
+            "{:width$}  {:10}  {}",
+            cap.capability_type(),
+            cap.version().unwrap_or("-"),
+            cap.source(),
+            width = width
+        );
+    }
+}
+
+fn print_peer_table(peers: &[PeerRecord]) {
+    let width = peers.iter().map(|p| p.endpoint().len()).max().unwrap_or(0);
+    for peer in peers {
+        println!(
+            "{:width$}  {:36}  {:8}  {:7}  {:4}  {}",
+            peer.endpoint(),
+            peer.node_id().unwrap_or("-"),
+            peer.version(),
+            peer.state().to_string(),
+            peer.incarnation(),
+            peer.last_seen(),
+            width = width
+        );
+    }
+}
+
+fn print_audit_table(records: &[AuditRecord]) {
+    let width = records
+        .iter()
+        .map(|r| r.requestor().map_or(1, str::len))
+        .max()
+        .unwrap_or(0);
+    for record in records {
+        println!(
+            "{}  {:width$}  {:16}  {:6}  {:>8.3}s  {}",
+            record.recorded_at(),
+            record.requestor().unwrap_or("-"),
+            record.action(),
+            record.outcome().to_string(),
+            record.duration().as_secs_f64(),
+            record.error().unwrap_or("-"),
+            width = width
+        );
+    }
+}
+
+/// Prints a job's details, followed by its output on our own stdout and
+/// stderr.
+fn print_job(record: &JobRecord) {
+    let state = record.state().to_string();
+    let exit_code = record
+        .exit_code()
+        .map_or_else(|| "-".to_string(), |c| c.to_string());
+    let attempts = format!("{}/{}", record.attempts(), record.max_attempts());
+    print_rows(&[
+        ("job_id", record.job_id()),
+        ("requestor", record.requestor().unwrap_or("-")),
+        ("program", record.program()),
+        ("state", state.as_str()),
+        ("exit_code", exit_code.as_str()),
+        ("attempts", attempts.as_str()),
+        ("error", record.error().unwrap_or("-")),
+        ("created_at", record.created_at()),
+        ("started_at", record.started_at().unwrap_or("-")),
+        ("finished_at", record.finished_at().unwrap_or("-")),
+    ]);
+    if !record.stdout().is_empty() || !record.stderr().is_empty() {
+        println!();
+    }
+    let _ = std::io::stdout().write_all(record.stdout());
+    let _ = std::io::stderr().write_all(record.stderr());
+}
+
+/// Prints key/value pairs as a two column table.
+fn print_rows(rows: &[(&str, &str)]) {
+    let width = rows.iter().map(|(k, _)| k.len()).max().unwrap_or(0);
+    for (key, value) in rows {
+        println!("{:width$}  {}", key, value, width = width);
+    }
+}
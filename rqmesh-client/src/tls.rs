@@ -0,0 +1,161 @@
+//! Mutual TLS for connections between clients and agents.
+//!
+//! Both ends present a certificate issued by a CA in the other end's bundle,
+//! and the identity in the peer's certificate is what agents record as the
+//! caller of each request.
+
+use rqmesh_core::{InitializationErrorKind, RqMeshError, TlsConfig, TransportErrorKind};
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::Arc;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+type Result<T> = std::result::Result<T, RqMeshError>;
+
+/// A byte stream frames can be exchanged over, either a plain TCP stream or
+/// a TLS session wrapping one.
+pub trait Transport: Read + Write + Send {}
+
+impl<T> Transport for T where T: Read + Write + Send {}
+
+/// Client side TLS settings, loaded once and shared by every connection.
+#[derive(Clone)]
+pub struct TlsConnector {
+    config: Arc<ClientConfig>,
+}
+
+impl TlsConnector {
+    /// Loads the certificate, key and CA bundle named by `tls`.
+    pub fn new(tls: &TlsConfig) -> Result<TlsConnector> {
+        let roots = load_roots(tls.ca_path())?;
+        let certs = load_certs(tls.cert_path())?;
+        let key = load_private_key(tls.key_path())?;
+        let config = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .and_then(|b| {
+                b.with_root_certificates(roots)
+                    .with_client_auth_cert(certs, key)
+            })
+            .map_err(|e| invalid_config(tls.cert_path(), e))?;
+        Ok(TlsConnector {
+            config: Arc::new(config),
+        })
+    }
+
+    /// Performs the TLS handshake over `stream`, verifying the agent's
+    /// certificate against `server_name`. Returns the session and the
+    /// identity in the agent's certificate.
+    pub fn connect(
+        &self,
+        server_name: &str,
+        stream: TcpStream,
+    ) -> Result<(StreamOwned<ClientConnection, TcpStream>, Option<String>)> {
+        let name = ServerName::try_from(server_name.to_string()).map_err(|e| {
+            RqMeshError::from(TransportErrorKind::new_tls_err(format!(
+                "Invalid TLS server name {}: {}",
+                server_name, e
+            )))
+        })?;
+        let mut conn = ClientConnection::new(Arc::clone(&self.config), name).map_err(tls_err)?;
+        let mut stream = stream;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut stream).map_err(handshake_err)?;
+        }
+        let identity = conn.peer_certificates().and_then(peer_identity);
+        Ok((StreamOwned::new(conn, stream), identity))
+    }
+}
+
+/// The crypto provider used for every TLS session.
+pub fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+/// Reads every certificate in the PEM file at `path`.
+pub fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let mut reader = open(path)?;
+    let certs = rustls_pemfile::certs(&mut reader)
+        .collect::<std::io::Result<Vec<_>>>()
+        .map_err(|e| invalid_config(path, e))?;
+    if certs.is_empty() {
+        return Err(invalid_config(path, "No certificates found"));
+    }
+    Ok(certs)
+}
+
+/// Reads the first private key in the PEM file at `path`.
+pub fn load_private_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let mut reader = open(path)?;
+    rustls_pemfile::private_key(&mut reader)
+        .map_err(|e| invalid_config(path, e))?
+        .ok_or_else(|| invalid_config(path, "No private key found"))
+}
+
+/// Builds a trust store from the CA certificates in the PEM file at `path`.
+pub fn load_roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert).map_err(|e| invalid_config(path, e))?;
+    }
+    Ok(roots)
+}
+
+/// The identity a certificate chain was issued to: the subject common name
+/// of the leaf certificate, or failing that its first DNS name.
+pub fn peer_identity(certs: &[CertificateDer<'_>]) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(certs.first()?.as_ref()).ok()?;
+    let common_name = cert
+        .subject()
+        .iter_common_name()
+        .next()
+        .and_then(|cn| cn.as_str().ok())
+        .map(|cn| cn.to_string());
+    common_name.or_else(|| {
+        cert.subject_alternative_name()
+            .ok()
+            .flatten()?
+            .value
+            .general_names
+            .iter()
+            .find_map(|name| match name {
+                GeneralName::DNSName(dns) => Some(dns.to_string()),
+                _ => None,
+            })
+    })
+}
+
+/// Maps an error raised while performing a TLS handshake.
+pub fn handshake_err(e: std::io::Error) -> RqMeshError {
+    RqMeshError::from(TransportErrorKind::new_tls_err(format!(
+        "TLS handshake failed: {}",
+        e
+    )))
+}
+
+/// Maps an error raised by the TLS library itself.
+pub fn tls_err(e: rustls::Error) -> RqMeshError {
+    RqMeshError::from(TransportErrorKind::new_tls_err(format!("{}", e)))
+}
+
+fn open(path: &Path) -> Result<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| invalid_config(path, e))
+}
+
+fn invalid_config<E>(path: &Path, e: E) -> RqMeshError
+where
+    E: std::fmt::Display,
+{
+    RqMeshError::from(InitializationErrorKind::new_invalid_tls_config(
+        path.to_string_lossy(),
+        format!("{}", e),
+    ))
+}
@@ -0,0 +1,343 @@
+//! Shared secret authentication of request envelopes.
+//!
+//! A sender holding a [`SigningKey`] attaches an [`EnvelopeSignature`] to
+//! each envelope: an HMAC-SHA256 over the envelope contents, a random nonce
+//! and the time it was signed. An agent holding the same key checks the
+//! HMAC, that the timestamp is recent and that the nonce has not been seen
+//! before, so a captured envelope can neither be altered nor replayed.
+
+use crate::{FrameErrorKind, RqMeshEnvelope, RqMeshError, UnauthorizedErrorKind};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+type Result<T> = std::result::Result<T, RqMeshError>;
+
+/// Bytes of randomness in every nonce.
+const NONCE_LEN: usize = 16;
+
+/// Bytes in a secret created by [`SigningKey::generate`].
+const SECRET_LEN: usize = 32;
+
+/// Proof that an envelope was sent by a holder of the key `key_id`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct EnvelopeSignature {
+    key_id: String,
+    nonce: Vec<u8>,
+    timestamp: u64,
+    signature: Vec<u8>,
+}
+
+impl EnvelopeSignature {
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    /// Random value unique to the signed envelope.
+    pub fn nonce(&self) -> &[u8] {
+        &self.nonce
+    }
+
+    /// Seconds since the Unix epoch at which the envelope was signed.
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+}
+
+/// A secret shared between a sender and the agents it talks to, and the ID
+/// under which the agents store it.
+#[derive(Clone, PartialEq, Eq)]
+pub struct SigningKey {
+    key_id: String,
+    secret: Vec<u8>,
+}
+
+impl std::fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SigningKey")
+            .field("key_id", &self.key_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SigningKey {
+    pub fn new<S>(key_id: S, secret: Vec<u8>) -> SigningKey
+    where
+        S: Into<String>,
+    {
+        let key_id = key_id.into();
+        SigningKey { key_id, secret }
+    }
+
+    /// Creates a key with a random secret.
+    pub fn generate<S>(key_id: S) -> SigningKey
+    where
+        S: Into<String>,
+    {
+        let mut secret = vec![0u8; SECRET_LEN];
+        rand::thread_rng().fill_bytes(&mut secret);
+        SigningKey::new(key_id, secret)
+    }
+
+    /// Parses a key given as `KEY_ID=HEX_SECRET`.
+    pub fn parse(declared: &str) -> Result<SigningKey> {
+        let invalid = |message: &str| {
+            RqMeshError::from(FrameErrorKind::new_decode_err(format!(
+                "Invalid signing key {}: {}",
+                declared.split('=').next().unwrap_or(""),
+                message
+            )))
+        };
+        let (key_id, secret) = declared
+            .split_once('=')
+            .ok_or_else(|| invalid("expected KEY_ID=HEX_SECRET"))?;
+        if key_id.is_empty() {
+            return Err(invalid("key ID must not be empty"));
+        }
+        let secret = from_hex(secret).ok_or_else(|| invalid("secret must be hex encoded"))?;
+        if secret.is_empty() {
+            return Err(invalid("secret must not be empty"));
+        }
+        Ok(SigningKey::new(key_id, secret))
+    }
+
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    pub fn secret(&self) -> &[u8] {
+        &self.secret
+    }
+
+    /// The secret as lower case hex, the form [`SigningKey::parse`] reads.
+    pub fn secret_hex(&self) -> String {
+        self.secret.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Signs `envelope` with a fresh nonce and the current time.
+    pub fn sign(&self, envelope: RqMeshEnvelope) -> Result<RqMeshEnvelope> {
+        let mut nonce = vec![0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let timestamp = unix_time(SystemTime::now());
+        let signature = self.mac(&envelope, &nonce, timestamp)?.finalize();
+        let signature = EnvelopeSignature {
+            key_id: self.key_id.clone(),
+            nonce,
+            timestamp,
+            signature: signature.into_bytes().to_vec(),
+        };
+        Ok(envelope.with_signature(signature))
+    }
+
+    /// Checks that `envelope` carries a valid signature by this key made
+    /// within `window` of `now`, over a nonce as long as [`SigningKey::sign`]
+    /// makes. Whether the nonce was seen before is left to the caller, who
+    /// knows which envelopes it has accepted.
+    pub fn verify(
+        &self,
+        envelope: &RqMeshEnvelope,
+        now: SystemTime,
+        window: Duration,
+    ) -> Result<()> {
+        let signature = envelope
+            .signature()
+            .ok_or_else(|| RqMeshError::from(UnauthorizedErrorKind::new_missing_signature()))?;
+        if signature.key_id != self.key_id {
+            return Err(RqMeshError::from(UnauthorizedErrorKind::new_unknown_key(
+                signature.key_id.as_str(),
+            )));
+        }
+        if signature.nonce.len() != NONCE_LEN {
+            return Err(RqMeshError::from(UnauthorizedErrorKind::new_bad_signature(
+                self.key_id.as_str(),
+            )));
+        }
+        self.mac(envelope, &signature.nonce, signature.timestamp)?
+            .verify_slice(&signature.signature)
+            .map_err(|_| {
+                RqMeshError::from(UnauthorizedErrorKind::new_bad_signature(
+                    self.key_id.as_str(),
+                ))
+            })?;
+        let now = unix_time(now);
+        if now.abs_diff(signature.timestamp) > window.as_secs() {
+            return Err(RqMeshError::from(
+                UnauthorizedErrorKind::new_stale_signature(signature.timestamp, window),
+            ));
+        }
+        Ok(())
+    }
+
+    fn mac(&self, envelope: &RqMeshEnvelope, nonce: &[u8], timestamp: u64) -> Result<Hmac<Sha256>> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret)
+            .map_err(|e| RqMeshError::from(FrameErrorKind::new_encode_err(format!("{}", e))))?;
+        mac.update(&envelope.signed_bytes(&self.key_id, nonce, timestamp)?);
+        Ok(mac)
+    }
+}
+
+/// Seconds since the Unix epoch, saturating at zero for earlier times.
+pub fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DescribeAgentRequest, MemberState, MembershipUpdate, RqMeshFrame};
+
+    const WINDOW: Duration = Duration::from_secs(300);
+
+    fn envelope() -> RqMeshEnvelope {
+        RqMeshFrame::new(DescribeAgentRequest::default(), "alice")
+            .into_envelope()
+            .expect("encodes envelope")
+    }
+
+    fn key() -> SigningKey {
+        SigningKey::parse("ops=00112233445566778899aabbccddeeff").expect("parses key")
+    }
+
+    fn assert_unauthorized(result: Result<()>, expected: UnauthorizedErrorKind) {
+        match result {
+            Err(RqMeshError::Unauthorized(kind)) => assert_eq!(kind, expected),
+            other => panic!("expected {}, got {:?}", expected, other),
+        }
+    }
+
+    #[test]
+    fn signed_envelopes_verify_with_the_same_key() {
+        let signed = key().sign(envelope()).expect("signs");
+        let signature = signed.signature().expect("is signed");
+        assert_eq!(signature.key_id(), "ops");
+        assert_eq!(signature.nonce().len(), NONCE_LEN);
+        key()
+            .verify(&signed, SystemTime::now(), WINDOW)
+            .expect("verifies");
+    }
+
+    #[test]
+    fn every_signature_has_a_fresh_nonce() {
+        let first = key().sign(envelope()).expect("signs");
+        let second = key().sign(envelope()).expect("signs");
+        assert_ne!(
+            first.signature().map(|s| s.nonce()),
+            second.signature().map(|s| s.nonce())
+        );
+    }
+
+    #[test]
+    fn unsigned_envelopes_and_other_keys_are_rejected() {
+        assert_unauthorized(
+            key().verify(&envelope(), SystemTime::now(), WINDOW),
+            UnauthorizedErrorKind::new_missing_signature(),
+        );
+
+        let signed = SigningKey::generate("other")
+            .sign(envelope())
+            .expect("signs");
+        assert_unauthorized(
+            key().verify(&signed, SystemTime::now(), WINDOW),
+            UnauthorizedErrorKind::new_unknown_key("other"),
+        );
+
+        // Same ID, different secret.
+        let signed = SigningKey::generate("ops").sign(envelope()).expect("signs");
+        assert_unauthorized(
+            key().verify(&signed, SystemTime::now(), WINDOW),
+            UnauthorizedErrorKind::new_bad_signature("ops"),
+        );
+    }
+
+    #[test]
+    fn tampering_with_the_envelope_or_signature_is_detected() {
+        let signed = key().sign(envelope()).expect("signs");
+
+        let update = MembershipUpdate::new("10.0.0.9:4000", "0.1.0", MemberState::Dead, 7);
+        let altered = signed.clone().with_piggyback(vec![update]);
+        assert_unauthorized(
+            key().verify(&altered, SystemTime::now(), WINDOW),
+            UnauthorizedErrorKind::new_bad_signature("ops"),
+        );
+
+        let mut signature = signed.signature().cloned().expect("is signed");
+        signature.timestamp += 1;
+        let altered = signed.clone().with_signature(signature);
+        assert_unauthorized(
+            key().verify(&altered, SystemTime::now(), WINDOW),
+            UnauthorizedErrorKind::new_bad_signature("ops"),
+        );
+
+        let mut signature = signed.signature().cloned().expect("is signed");
+        signature.nonce[0] ^= 1;
+        let altered = signed.with_signature(signature);
+        assert_unauthorized(
+            key().verify(&altered, SystemTime::now(), WINDOW),
+            UnauthorizedErrorKind::new_bad_signature("ops"),
+        );
+    }
+
+    #[test]
+    fn nonces_of_the_wrong_length_are_rejected() {
+        let key = key();
+        for len in [0, NONCE_LEN - 1, NONCE_LEN + 1, 4096] {
+            let envelope = envelope();
+            let nonce = vec![7u8; len];
+            let timestamp = unix_time(SystemTime::now());
+            // A correct MAC over the odd nonce, so only its length is wrong.
+            let mac = key.mac(&envelope, &nonce, timestamp).expect("macs");
+            let signed = envelope.with_signature(EnvelopeSignature {
+                key_id: "ops".to_string(),
+                nonce,
+                timestamp,
+                signature: mac.finalize().into_bytes().to_vec(),
+            });
+            assert_unauthorized(
+                key.verify(&signed, SystemTime::now(), WINDOW),
+                UnauthorizedErrorKind::new_bad_signature("ops"),
+            );
+        }
+    }
+
+    #[test]
+    fn signatures_outside_the_replay_window_are_stale() {
+        let signed = key().sign(envelope()).expect("signs");
+        let timestamp = signed.signature().expect("is signed").timestamp();
+        let later = SystemTime::now() + WINDOW + Duration::from_secs(60);
+        assert_unauthorized(
+            key().verify(&signed, later, WINDOW),
+            UnauthorizedErrorKind::new_stale_signature(timestamp, WINDOW),
+        );
+        key()
+            .verify(&signed, SystemTime::now() + WINDOW / 2, WINDOW)
+            .expect("is still fresh");
+    }
+
+    #[test]
+    fn keys_parse_from_id_and_hex_secret() {
+        let key = key();
+        assert_eq!(key.key_id(), "ops");
+        assert_eq!(key.secret_hex(), "00112233445566778899aabbccddeeff");
+        assert_eq!(
+            SigningKey::parse(&format!("ops={}", key.secret_hex())).expect("parses"),
+            key
+        );
+        for invalid in ["ops", "=00ff", "ops=", "ops=0g", "ops=abc"] {
+            assert!(SigningKey::parse(invalid).is_err(), "{} parsed", invalid);
+        }
+    }
+}
@@ -0,0 +1,443 @@
+//! Commands agents run, whether to check their own dependencies or on
+//! behalf of a requestor.
+//!
+//! A [`CommandSpec`] is normally a program and its arguments, passed to the
+//! program as they are. Command lines written by hand are split into those
+//! arguments with [`CommandSpec::parse`], following the POSIX shell's
+//! quoting rules but refusing anything, such as a pipe or a variable, that
+//! only a shell would understand. Running the line through `/bin/sh`
+//! instead takes an explicit [`CommandSpec::shell`].
+
+use crate::{JobErrorKind, RqMeshError};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+type Result<T> = std::result::Result<T, RqMeshError>;
+
+/// Shell that runs [`CommandSpec::shell`] scripts.
+pub const SHELL: &str = "/bin/sh";
+
+/// Characters with a meaning to the shell that [`CommandSpec::parse`]
+/// refuses unless they are quoted.
+const SHELL_METACHARACTERS: &[char] = &['|', '&', ';', '<', '>', '(', ')', '$', '`', '*', '?', '['];
+
+/// A program to run along with everything about how to run it.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+pub struct CommandSpec {
+    argv: Vec<String>,
+    shell: bool,
+    env: Vec<(String, String)>,
+    working_dir: Option<String>,
+    timeout: Option<Duration>,
+    stdin: Option<Vec<u8>>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+}
+
+impl CommandSpec {
+    /// Runs `program` with `args`, passed to it exactly as given.
+    pub fn argv<S, I, A>(program: S, args: I) -> CommandSpec
+    where
+        S: Into<String>,
+        I: IntoIterator<Item = A>,
+        A: Into<String>,
+    {
+        let mut argv = vec![program.into()];
+        argv.extend(args.into_iter().map(|a| a.into()));
+        CommandSpec::from_argv(argv, false)
+    }
+
+    /// Runs `script` with `/bin/sh -c`, so that it may use pipes,
+    /// redirections, variables and anything else the shell understands.
+    pub fn shell<S>(script: S) -> CommandSpec
+    where
+        S: Into<String>,
+    {
+        let argv = vec![SHELL.to_string(), "-c".to_string(), script.into()];
+        CommandSpec::from_argv(argv, true)
+    }
+
+    fn from_argv(argv: Vec<String>, shell: bool) -> CommandSpec {
+        CommandSpec {
+            argv,
+            shell,
+            env: Vec::new(),
+            working_dir: None,
+            timeout: None,
+            stdin: None,
+            uid: None,
+            gid: None,
+        }
+    }
+
+    /// Splits `line` into a program and arguments the way a POSIX shell
+    /// would, honouring single quotes, double quotes and backslashes, and
+    /// taking leading `NAME=value` words as environment variables.
+    ///
+    /// Fails if the line is empty, leaves a quote open, or uses pipes,
+    /// redirections, variables, globs or anything else that needs a shell
+    /// to mean what it says; such lines must be run with
+    /// [`shell`](CommandSpec::shell) instead.
+    pub fn parse(line: &str) -> Result<CommandSpec> {
+        let invalid =
+            |message: String| RqMeshError::from(JobErrorKind::new_invalid_command(line, message));
+        let mut words = split_words(line).map_err(invalid)?.into_iter().peekable();
+
+        let mut env = Vec::new();
+        while let Some((name, value)) = words.peek().and_then(|w| w.assignment()) {
+            env.push((name, value));
+            words.next();
+        }
+        let argv: Vec<String> = words.map(|w| w.text).collect();
+        if argv.is_empty() {
+            return Err(invalid("Command must name a program".to_string()));
+        }
+
+        let mut spec = CommandSpec::from_argv(argv, false);
+        spec.env = env;
+        Ok(spec)
+    }
+
+    /// Checks that the command names a program, and for shell commands that
+    /// it is a single script, which specs decoded from a request need not.
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |message: &str| {
+            RqMeshError::from(JobErrorKind::new_invalid_command(self.to_string(), message))
+        };
+        if self.program().is_empty() {
+            return Err(invalid("Command must name a program"));
+        }
+        if self.shell && (self.argv.len() != 3 || self.argv[0] != SHELL || self.argv[1] != "-c") {
+            return Err(invalid("Shell command must be a single script"));
+        }
+        Ok(())
+    }
+
+    /// Sets an environment variable for the program, on top of the
+    /// environment of whoever runs it.
+    pub fn with_env<S1, S2>(mut self, key: S1, value: S2) -> CommandSpec
+    where
+        S1: Into<String>,
+        S2: Into<String>,
+    {
+        self.env.push((key.into(), value.into()));
+        self
+    }
+
+    pub fn with_working_dir<S>(mut self, working_dir: S) -> CommandSpec
+    where
+        S: Into<String>,
+    {
+        self.working_dir = Some(working_dir.into());
+        self
+    }
+
+    /// Kills the program, and every process it started, if it has not
+    /// finished after `timeout`.
+    pub fn with_timeout(mut self, timeout: Duration) -> CommandSpec {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Feeds `stdin` to the program, instead of leaving its stdin closed.
+    pub fn with_stdin(mut self, stdin: Vec<u8>) -> CommandSpec {
+        self.stdin = Some(stdin);
+        self
+    }
+
+    /// Runs the program as the user `uid`, which needs whoever runs it to
+    /// be privileged.
+    pub fn with_uid(mut self, uid: u32) -> CommandSpec {
+        self.uid = Some(uid);
+        self
+    }
+
+    /// Runs the program with the group `gid`, which needs whoever runs it
+    /// to be privileged.
+    pub fn with_gid(mut self, gid: u32) -> CommandSpec {
+        self.gid = Some(gid);
+        self
+    }
+
+    /// Program run, which is [`SHELL`] for shell scripts.
+    pub fn program(&self) -> &str {
+        self.argv.first().map_or("", |program| program.as_str())
+    }
+
+    pub fn args(&self) -> &[String] {
+        self.argv.get(1..).unwrap_or_default()
+    }
+
+    /// Script run by the shell, if this is a [`shell`](CommandSpec::shell)
+    /// command.
+    pub fn script(&self) -> Option<&str> {
+        if self.shell {
+            self.argv.get(2).map(|s| s.as_str())
+        } else {
+            None
+        }
+    }
+
+    pub fn env(&self) -> &[(String, String)] {
+        &self.env
+    }
+
+    pub fn working_dir(&self) -> Option<&str> {
+        self.working_dir.as_deref()
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    pub fn stdin(&self) -> Option<&[u8]> {
+        self.stdin.as_deref()
+    }
+
+    pub fn uid(&self) -> Option<u32> {
+        self.uid
+    }
+
+    pub fn gid(&self) -> Option<u32> {
+        self.gid
+    }
+}
+
+/// Writes the command as a line [`CommandSpec::parse`], or for shell
+/// scripts the shell, would read back as the same command.
+impl std::fmt::Display for CommandSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (key, value) in &self.env {
+            write!(f, "{}={} ", key, quote(value))?;
+        }
+        match self.script() {
+            Some(script) => write!(f, "{} -c {}", SHELL, quote(script))?,
+            None => {
+                // A bare program containing `=` would read back as a variable.
+                let program = self.program();
+                if program.contains('=') {
+                    write!(f, "'{}'", program.replace('\'', "'\\''"))?;
+                } else {
+                    write!(f, "{}", quote(program))?;
+                }
+                for arg in self.args() {
+                    write!(f, " {}", quote(arg))?;
+                }
+            }
+        };
+        Ok(())
+    }
+}
+
+/// Quotes `word` so that a POSIX shell, or [`CommandSpec::parse`], reads it
+/// back as a single word with exactly the same text.
+pub fn quote(word: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_./:,+@%=".contains(c);
+    if !word.is_empty() && word.chars().all(safe) {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', "'\\''"))
+    }
+}
+
+/// A word of a command line, along with how much of its start was
+/// unquoted, since only an unquoted `=` makes an environment assignment.
+struct Word {
+    text: String,
+    unquoted_prefix: usize,
+}
+
+impl Word {
+    fn assignment(&self) -> Option<(String, String)> {
+        let (name, value) = self.text.split_once('=')?;
+        let valid_name = name.len() < self.unquoted_prefix
+            && name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if valid_name {
+            Some((name.to_string(), value.to_string()))
+        } else {
+            None
+        }
+    }
+}
+
+/// Splits `line` into words following the POSIX shell's quoting rules.
+fn split_words(line: &str) -> std::result::Result<Vec<Word>, String> {
+    let mut words = Vec::new();
+    let mut current: Option<Word> = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c.is_whitespace() {
+            words.extend(current.take());
+            continue;
+        }
+        let starting = current.is_none();
+        let word = current.get_or_insert_with(|| Word {
+            text: String::new(),
+            unquoted_prefix: usize::MAX,
+        });
+        match c {
+            '\'' => {
+                word.unquoted_prefix = word.unquoted_prefix.min(word.text.len());
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.text.push(c),
+                        None => return Err("Unterminated single quote".to_string()),
+                    }
+                }
+            }
+            '"' => {
+                word.unquoted_prefix = word.unquoted_prefix.min(word.text.len());
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\' | '$' | '`')) => word.text.push(c),
+                            Some('\n') => {}
+                            Some(c) => {
+                                word.text.push('\\');
+                                word.text.push(c);
+                            }
+                            None => return Err("Unterminated double quote".to_string()),
+                        },
+                        Some(c @ ('$' | '`')) => {
+                            return Err(format!(
+                                "{} inside double quotes needs a shell; run the command with the shell instead",
+                                c
+                            ))
+                        }
+                        Some(c) => word.text.push(c),
+                        None => return Err("Unterminated double quote".to_string()),
+                    }
+                }
+            }
+            '\\' => {
+                word.unquoted_prefix = word.unquoted_prefix.min(word.text.len());
+                match chars.next() {
+                    Some('\n') => {}
+                    Some(c) => word.text.push(c),
+                    None => return Err("Trailing backslash".to_string()),
+                }
+            }
+            c if SHELL_METACHARACTERS.contains(&c) || (starting && (c == '~' || c == '#')) => {
+                return Err(format!(
+                    "Unquoted {} needs a shell; quote it, or run the command with the shell instead",
+                    c
+                ));
+            }
+            c => word.text.push(c),
+        }
+    }
+    words.extend(current);
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(line: &str) -> Vec<String> {
+        let spec = CommandSpec::parse(line).expect("parses");
+        let mut argv = vec![spec.program().to_string()];
+        argv.extend(spec.args().iter().cloned());
+        argv
+    }
+
+    #[test]
+    fn parse_follows_shell_quoting() {
+        assert_eq!(parsed("echo hi  there"), ["echo", "hi", "there"]);
+        assert_eq!(parsed("echo 'a b' \"c d\""), ["echo", "a b", "c d"]);
+        assert_eq!(parsed(r"echo a\ b 'it'\''s'"), ["echo", "a b", "it's"]);
+        assert_eq!(parsed(r#"echo "\"\\\$" "\n""#), ["echo", "\"\\$", "\\n"]);
+        assert_eq!(parsed("echo ''"), ["echo", ""]);
+        assert_eq!(parsed(r"echo 'a|b' \;"), ["echo", "a|b", ";"]);
+    }
+
+    #[test]
+    fn parse_takes_leading_assignments_as_environment() {
+        let spec = CommandSpec::parse("LANG=C A_1='x y' sort FOO=bar").expect("parses");
+        assert_eq!(
+            spec.env(),
+            [
+                ("LANG".to_string(), "C".to_string()),
+                ("A_1".to_string(), "x y".to_string())
+            ]
+        );
+        assert_eq!(spec.program(), "sort");
+        assert_eq!(spec.args(), ["FOO=bar"]);
+        assert_eq!(parsed("'LANG=C' sort"), ["LANG=C", "sort"]);
+    }
+
+    #[test]
+    fn parse_rejects_what_needs_a_shell() {
+        for line in [
+            "",
+            "   ",
+            "LANG=C",
+            "echo 'open",
+            "echo \"open",
+            "echo \\",
+            "ls | wc",
+            "echo $HOME",
+            "echo \"$HOME\"",
+            "ls *.rs",
+            "cd ~",
+            "true && false",
+            "echo `id`",
+        ] {
+            assert!(
+                CommandSpec::parse(line).is_err(),
+                "{:?} should be rejected",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn quote_round_trips_through_parse() {
+        for word in [
+            "plain", "", "a b", "it's", "$HOME", "*", "~", "#", "a\nb", "\"\\",
+        ] {
+            let line = format!("echo {}", quote(word));
+            assert_eq!(parsed(&line), ["echo", word], "{}", line);
+        }
+    }
+
+    #[test]
+    fn display_round_trips_through_parse() {
+        let specs = [
+            CommandSpec::argv("echo", vec!["hello world", "it's", ""]),
+            CommandSpec::argv("A=b", vec!["c"]),
+            CommandSpec::argv("env", Vec::<String>::new()).with_env("PS1", "$ ~ # "),
+        ];
+        for spec in specs {
+            assert_eq!(CommandSpec::parse(&spec.to_string()).expect("parses"), spec);
+        }
+    }
+
+    #[test]
+    fn shell_commands_display_the_script_quoted() {
+        let spec = CommandSpec::shell("ls | wc -l");
+        assert_eq!(spec.to_string(), "/bin/sh -c 'ls | wc -l'");
+        assert_eq!(spec.script(), Some("ls | wc -l"));
+        assert!(spec.validate().is_ok());
+    }
+
+    #[test]
+    fn commands_without_a_program_are_invalid_rather_than_a_panic() {
+        let empty = CommandSpec::from_argv(Vec::new(), false);
+        assert_eq!(empty.program(), "");
+        assert!(empty.args().is_empty());
+        assert!(matches!(
+            empty.validate(),
+            Err(RqMeshError::JobError(JobErrorKind::InvalidCommand { .. }))
+        ));
+        assert!(CommandSpec::argv("", vec!["x"]).validate().is_err());
+
+        let script_without_shell = CommandSpec::from_argv(vec!["ls | wc".to_string()], true);
+        assert!(script_without_shell.validate().is_err());
+        assert_eq!(script_without_shell.script(), None);
+        assert!(CommandSpec::argv("echo", vec!["hi"]).validate().is_ok());
+    }
+}
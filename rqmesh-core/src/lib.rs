@@ -1,188 +1,1887 @@
+mod auth;
+mod command;
 mod protocol;
-pub use protocol::{RqMeshFrame, DescribeAgentResponse, DescribeAgentRequest, RqMeshProtocolAction};
+pub use auth::{unix_time, EnvelopeSignature, SigningKey};
+pub use command::{quote, CommandSpec, SHELL};
+pub use protocol::{
+    AgentLoadRequest, AgentLoadResponse, AnnounceAgentRequest, AuditOutcome, AuditRecord,
+    BroadcastRequest, BroadcastResponse, CancelJobRequest, DescribeAgentRequest,
+    DescribeAgentResponse, EnqueueJobRequest, Handshake, HandshakeResponse, JobRecord, JobState,
+    JobStatusRequest, ListCapabilitiesRequest, ListCapabilitiesResponse, ListPeersRequest,
+    ListPeersResponse, MemberState, MembershipUpdate, PeerRecord, PingReqRequest, PingReqResponse,
+    PingRequest, PingResponse, QueryAuditLogRequest, QueryAuditLogResponse, RequestContext,
+    RouteRequest, RoutedResponse, RoutingStrategy, RqMeshCodec, RqMeshEnvelope, RqMeshFrame,
+    RqMeshProtocolAction, RqMeshResponseEnvelope, RunCommandRequest, DEFAULT_MAX_FRAME_SIZE,
+    DEFAULT_MAX_HOPS, FRAME_HEADER_LEN,
+};
+use serde::{Deserialize, Serialize};
+use std::net::{Ipv4Addr, SocketAddr};
 use std::path::PathBuf;
-use serde::{Serialize, Deserialize};
+use std::time::Duration;
 
-const VERSION: &'static str = "0.1.0";
+const VERSION: &str = "0.1.0";
 
-#[derive(Debug, Clone, PartialEq, PartialOrd, Eq, Ord)]
+/// How long audit records are kept unless configured otherwise.
+pub const DEFAULT_AUDIT_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Most audit records kept unless configured otherwise.
+pub const DEFAULT_AUDIT_MAX_ROWS: u64 = 1_000_000;
+
+/// Time between successive failure detector probes unless configured
+/// otherwise.
+pub const DEFAULT_PROTOCOL_PERIOD: Duration = Duration::from_secs(1);
+
+/// How long a member may stay suspect before it is declared dead unless
+/// configured otherwise.
+pub const DEFAULT_SUSPECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Version of the wire protocol spoken by this build, exchanged in the
+/// connection [`Handshake`].
+pub const PROTOCOL_VERSION: &str = VERSION;
+
+/// Where an agent learned that it has a capability.
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Eq, Ord, Serialize, Deserialize)]
+pub enum CapabilitySource {
+    /// Declared by the operator on the command line or in configuration.
+    Declared,
+    /// Discovered by probing the host while initializing.
+    Detected,
+}
+
+impl std::fmt::Display for CapabilitySource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self {
+            CapabilitySource::Declared => write!(f, "declared"),
+            CapabilitySource::Detected => write!(f, "detected"),
+        }
+    }
+}
+
+impl std::str::FromStr for CapabilitySource {
+    type Err = RqMeshError;
+
+    fn from_str(s: &str) -> Result<CapabilitySource, RqMeshError> {
+        match s {
+            "declared" => Ok(CapabilitySource::Declared),
+            "detected" => Ok(CapabilitySource::Detected),
+            _ => Err(RqMeshError::from(
+                InitializationErrorKind::new_invalid_capability(s, "Unknown capability source"),
+            )),
+        }
+    }
+}
+
+/// A capability advertised by an agent, such as an installed tool or an
+/// operator supplied label, optionally with a version.
+#[derive(Debug, Clone, PartialEq, PartialOrd, Eq, Ord, Serialize, Deserialize)]
 pub struct CapabilityBroadcast {
     capability_type: String,
+    version: Option<String>,
+    source: CapabilitySource,
+}
+
+impl CapabilityBroadcast {
+    pub fn new<S>(
+        capability_type: S,
+        version: Option<String>,
+        source: CapabilitySource,
+    ) -> CapabilityBroadcast
+    where
+        S: Into<String>,
+    {
+        let capability_type = capability_type.into();
+        CapabilityBroadcast {
+            capability_type,
+            version,
+            source,
+        }
+    }
+
+    /// Parses a declared capability written as `name` or `name=version`.
+    pub fn parse_declared(declaration: &str) -> Result<CapabilityBroadcast, RqMeshError> {
+        let (name, version) = match declaration.split_once('=') {
+            Some((name, version)) => (name.trim(), Some(version.trim().to_string())),
+            None => (declaration.trim(), None),
+        };
+        if name.is_empty() || name.chars().any(|c| c.is_whitespace()) {
+            return Err(RqMeshError::from(
+                InitializationErrorKind::new_invalid_capability(
+                    declaration,
+                    "Capability name must be non-empty and contain no whitespace",
+                ),
+            ));
+        }
+        if version.as_deref() == Some("") {
+            return Err(RqMeshError::from(
+                InitializationErrorKind::new_invalid_capability(
+                    declaration,
+                    "Capability version must be non-empty when given",
+                ),
+            ));
+        }
+        Ok(CapabilityBroadcast::new(
+            name,
+            version,
+            CapabilitySource::Declared,
+        ))
+    }
+
+    pub fn capability_type(&self) -> &str {
+        &self.capability_type
+    }
+
+    pub fn version(&self) -> Option<&str> {
+        self.version.as_deref()
+    }
+
+    pub fn source(&self) -> CapabilitySource {
+        self.source
+    }
+
+    /// Whether this capability meets `required`: the types must match, and
+    /// so must the versions when `required` names one.
+    pub fn satisfies(&self, required: &CapabilityBroadcast) -> bool {
+        self.capability_type == required.capability_type
+            && (required.version.is_none() || self.version == required.version)
+    }
+}
+
+impl std::fmt::Display for CapabilityBroadcast {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.version {
+            Some(version) => write!(f, "{}={}", self.capability_type, version),
+            None => write!(f, "{}", self.capability_type),
+        }
+    }
+}
+
+/// How a [`PackageRequirement`] constrains the installed version.
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Eq, Ord, Serialize, Deserialize)]
+pub enum VersionOp {
+    /// The installed version starts with the given one, so `=3.40` accepts
+    /// `3.40` and `3.40.1-2`.
+    Eq,
+    Ge,
+    Gt,
+    Le,
+    Lt,
+}
+
+impl std::fmt::Display for VersionOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self {
+            VersionOp::Eq => write!(f, "="),
+            VersionOp::Ge => write!(f, ">="),
+            VersionOp::Gt => write!(f, ">"),
+            VersionOp::Le => write!(f, "<="),
+            VersionOp::Lt => write!(f, "<"),
+        }
+    }
+}
+
+/// A system package the agent needs installed before it starts, optionally
+/// constrained to a range of versions.
+#[derive(Debug, Clone, PartialEq, PartialOrd, Eq, Ord, Serialize, Deserialize)]
+pub struct PackageRequirement {
+    name: String,
+    constraint: Option<(VersionOp, String)>,
+}
+
+impl PackageRequirement {
+    pub fn new<S>(name: S, constraint: Option<(VersionOp, String)>) -> PackageRequirement
+    where
+        S: Into<String>,
+    {
+        let name = name.into();
+        PackageRequirement { name, constraint }
+    }
+
+    /// Parses a requirement written as `name` or `name` followed by one of
+    /// `=`, `>=`, `>`, `<=` or `<` and a version, e.g. `sqlite>=3.40`.
+    pub fn parse(requirement: &str) -> Result<PackageRequirement, RqMeshError> {
+        let invalid = |message: &str| {
+            RqMeshError::from(InitializationErrorKind::new_invalid_package_requirement(
+                requirement,
+                message,
+            ))
+        };
+        let (name, constraint) = match requirement.find(['=', '<', '>']) {
+            Some(at) => {
+                let (name, rest) = requirement.split_at(at);
+                let (op, version) = if let Some(version) = rest.strip_prefix(">=") {
+                    (VersionOp::Ge, version)
+                } else if let Some(version) = rest.strip_prefix("<=") {
+                    (VersionOp::Le, version)
+                } else if let Some(version) = rest.strip_prefix('>') {
+                    (VersionOp::Gt, version)
+                } else if let Some(version) = rest.strip_prefix('<') {
+                    (VersionOp::Lt, version)
+                } else {
+                    (VersionOp::Eq, rest.trim_start_matches('='))
+                };
+                let version = version.trim();
+                if version.is_empty() || version.chars().any(|c| c.is_whitespace()) {
+                    return Err(invalid(
+                        "Package version must be non-empty and contain no whitespace",
+                    ));
+                }
+                (name.trim(), Some((op, version.to_string())))
+            }
+            None => (requirement.trim(), None),
+        };
+        if name.is_empty() || name.chars().any(|c| c.is_whitespace()) {
+            return Err(invalid(
+                "Package name must be non-empty and contain no whitespace",
+            ));
+        }
+        Ok(PackageRequirement::new(name, constraint))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn constraint(&self) -> Option<(VersionOp, &str)> {
+        self.constraint
+            .as_ref()
+            .map(|(op, version)| (*op, version.as_str()))
+    }
+
+    /// Whether `installed` meets the version constraint, if there is one.
+    pub fn satisfied_by(&self, installed: &str) -> bool {
+        let (op, required) = match &self.constraint {
+            Some((op, required)) => (op, required),
+            None => return true,
+        };
+        match op {
+            VersionOp::Eq => version_segments(installed).starts_with(&version_segments(required)),
+            VersionOp::Ge => compare_versions(installed, required).is_ge(),
+            VersionOp::Gt => compare_versions(installed, required).is_gt(),
+            VersionOp::Le => compare_versions(installed, required).is_le(),
+            VersionOp::Lt => compare_versions(installed, required).is_lt(),
+        }
+    }
+}
+
+impl std::fmt::Display for PackageRequirement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.constraint {
+            Some((op, version)) => write!(f, "{}{}{}", self.name, op, version),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+/// One run of digits or letters in a version string.
+#[derive(Debug, Clone, PartialEq, PartialOrd, Eq, Ord)]
+enum VersionSegment {
+    // Declared first so that `1.0rc1 < 1.0.1`, as most package managers
+    // order pre-releases.
+    Alpha(String),
+    Numeric(u64),
+}
+
+/// Orders two version strings the way package managers do, comparing runs
+/// of digits numerically and runs of letters alphabetically, so that
+/// `3.9 < 3.40` and `1:2.0-1 == 2.0.1`.
+pub fn compare_versions(a: &str, b: &str) -> std::cmp::Ordering {
+    version_segments(a).cmp(&version_segments(b))
+}
+
+/// Splits `version` into runs of digits and letters, dropping any `epoch:`
+/// prefix and the punctuation between runs, so that versions from every
+/// package manager compare segment by segment.
+fn version_segments(version: &str) -> Vec<VersionSegment> {
+    let version = match version.split_once(':') {
+        Some((epoch, rest)) if epoch.chars().all(|c| c.is_ascii_digit()) => rest,
+        _ => version,
+    };
+    let mut segments = Vec::new();
+    let mut chars = version.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_ascii_digit() {
+            let mut digits = String::new();
+            while let Some(&d) = chars.peek().filter(|d| d.is_ascii_digit()) {
+                digits.push(d);
+                chars.next();
+            }
+            segments.push(VersionSegment::Numeric(digits.parse().unwrap_or(u64::MAX)));
+        } else if c.is_ascii_alphabetic() {
+            let mut letters = String::new();
+            while let Some(&l) = chars.peek().filter(|l| l.is_ascii_alphabetic()) {
+                letters.push(l);
+                chars.next();
+            }
+            segments.push(VersionSegment::Alpha(letters));
+        } else {
+            chars.next();
+        }
+    }
+    segments
+}
+
+/// Certificate, private key and CA bundle, all PEM encoded, used to
+/// authenticate both ends of every connection with mutual TLS.
+#[derive(Debug, Clone, PartialEq, PartialOrd, Eq, Ord)]
+pub struct TlsConfig {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    ca_path: PathBuf,
+}
+
+impl TlsConfig {
+    pub fn new<T1, T2, T3>(cert_path: T1, key_path: T2, ca_path: T3) -> TlsConfig
+    where
+        T1: Into<PathBuf>,
+        T2: Into<PathBuf>,
+        T3: Into<PathBuf>,
+    {
+        let cert_path = cert_path.into();
+        let key_path = key_path.into();
+        let ca_path = ca_path.into();
+        TlsConfig {
+            cert_path,
+            key_path,
+            ca_path,
+        }
+    }
+
+    /// Certificate chain presented to peers, leaf first.
+    pub fn cert_path(&self) -> &PathBuf {
+        &self.cert_path
+    }
+
+    pub fn key_path(&self) -> &PathBuf {
+        &self.key_path
+    }
+
+    /// CA certificates that peers' certificates must chain to.
+    pub fn ca_path(&self) -> &PathBuf {
+        &self.ca_path
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, PartialOrd, Eq, Ord)]
+pub struct AgentInitializationContext {
+    store_path: PathBuf,
+    check_deps_command: String,
+    install_deps_command: String,
+    version: &'static str,
+    listen_address: SocketAddr,
+    port_file: Option<PathBuf>,
+    advertise_host: Option<String>,
+    capabilities: Vec<CapabilityBroadcast>,
+    seeds: Vec<String>,
+    tls: Option<TlsConfig>,
+    auth_key_id: Option<String>,
+    require_auth: bool,
+    policy_path: Option<PathBuf>,
+    dry_run_policy: bool,
+    audit_retention: Option<Duration>,
+    audit_max_rows: Option<u64>,
+    protocol_period: Duration,
+    suspect_timeout: Duration,
+    packages: Vec<PackageRequirement>,
+    package_manager: Option<String>,
+    dependency_manifest: Option<PathBuf>,
+    shell_commands: bool,
+    no_install: bool,
+    privilege_helper: Option<String>,
+}
+
+impl AgentInitializationContext {
+    pub fn new<T, S1, S2>(
+        store_path: T,
+        check_deps_command: S1,
+        install_deps_command: S2,
+    ) -> AgentInitializationContext
+    where
+        T: Into<PathBuf>,
+        S1: Into<String>,
+        S2: Into<String>,
+    {
+        let store_path: PathBuf = store_path.into();
+        let check_deps_command: String = check_deps_command.into();
+        let install_deps_command: String = install_deps_command.into();
+        AgentInitializationContext {
+            store_path,
+            check_deps_command,
+            install_deps_command,
+            version: VERSION,
+            listen_address: SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+            port_file: None,
+            advertise_host: None,
+            capabilities: Vec::new(),
+            seeds: Vec::new(),
+            tls: None,
+            auth_key_id: None,
+            require_auth: false,
+            policy_path: None,
+            dry_run_policy: false,
+            audit_retention: Some(DEFAULT_AUDIT_RETENTION),
+            audit_max_rows: Some(DEFAULT_AUDIT_MAX_ROWS),
+            protocol_period: DEFAULT_PROTOCOL_PERIOD,
+            suspect_timeout: DEFAULT_SUSPECT_TIMEOUT,
+            packages: Vec::new(),
+            package_manager: None,
+            dependency_manifest: None,
+            shell_commands: false,
+            no_install: false,
+            privilege_helper: None,
+        }
+    }
+
+    /// Adds the address of an agent to announce ourselves to on startup.
+    pub fn with_seed<S>(mut self, seed: S) -> AgentInitializationContext
+    where
+        S: Into<String>,
+    {
+        self.seeds.push(seed.into());
+        self
+    }
+
+    /// Signs requests the agent sends with the stored key `key_id`.
+    pub fn with_auth_key<S>(mut self, key_id: S) -> AgentInitializationContext
+    where
+        S: Into<String>,
+    {
+        self.auth_key_id = Some(key_id.into());
+        self
+    }
+
+    /// Rejects requests that are neither signed nor received over TLS.
+    pub fn with_require_auth(mut self, require_auth: bool) -> AgentInitializationContext {
+        self.require_auth = require_auth;
+        self
+    }
+
+    /// Only handles requests the policy in `policy_path` allows.
+    pub fn with_policy<P>(mut self, policy_path: P) -> AgentInitializationContext
+    where
+        P: Into<PathBuf>,
+    {
+        self.policy_path = Some(policy_path.into());
+        self
+    }
+
+    /// Logs the requests the policy would deny but handles them anyway.
+    pub fn with_dry_run_policy(mut self, dry_run_policy: bool) -> AgentInitializationContext {
+        self.dry_run_policy = dry_run_policy;
+        self
+    }
+
+    /// Forgets audit records older than `retention`, or never if `None`.
+    pub fn with_audit_retention(
+        mut self,
+        retention: Option<Duration>,
+    ) -> AgentInitializationContext {
+        self.audit_retention = retention;
+        self
+    }
+
+    /// Keeps at most `max_rows` audit records, forgetting the oldest first,
+    /// or any number if `None`.
+    pub fn with_audit_max_rows(mut self, max_rows: Option<u64>) -> AgentInitializationContext {
+        self.audit_max_rows = max_rows;
+        self
+    }
+
+    /// Probes one member of the mesh every `period`.
+    pub fn with_protocol_period(mut self, period: Duration) -> AgentInitializationContext {
+        self.protocol_period = period;
+        self
+    }
+
+    /// Declares a member dead once it has been suspect for `timeout`.
+    pub fn with_suspect_timeout(mut self, timeout: Duration) -> AgentInitializationContext {
+        self.suspect_timeout = timeout;
+        self
+    }
+
+    /// Adds a package that must be installed before the agent starts. Once
+    /// any package is declared, packages are checked and installed through
+    /// the host's package manager instead of the check and install commands.
+    pub fn with_package(mut self, package: PackageRequirement) -> AgentInitializationContext {
+        self.packages.push(package);
+        self
+    }
+
+    /// Uses the package manager named `package_manager` instead of
+    /// detecting the one on the host.
+    pub fn with_package_manager<S>(mut self, package_manager: S) -> AgentInitializationContext
+    where
+        S: Into<String>,
+    {
+        self.package_manager = Some(package_manager.into());
+        self
+    }
+
+    /// Checks, and where needed installs, the dependencies listed in the
+    /// manifest at `manifest_path` in place of the check and install
+    /// commands.
+    pub fn with_dependency_manifest<P>(mut self, manifest_path: P) -> AgentInitializationContext
+    where
+        P: Into<PathBuf>,
+    {
+        self.dependency_manifest = Some(manifest_path.into());
+        self
+    }
+
+    /// Runs the check and install commands with `/bin/sh -c` instead of
+    /// splitting them into a program and arguments.
+    pub fn with_shell_commands(mut self, shell_commands: bool) -> AgentInitializationContext {
+        self.shell_commands = shell_commands;
+        self
+    }
+
+    /// Fails as soon as a dependency is found missing instead of trying to
+    /// install it.
+    pub fn with_no_install(mut self, no_install: bool) -> AgentInitializationContext {
+        self.no_install = no_install;
+        self
+    }
+
+    /// Runs install commands through `privilege_helper`, such as `sudo -n`
+    /// or `doas -n`, so that the agent itself can run unprivileged.
+    pub fn with_privilege_helper<S>(mut self, privilege_helper: S) -> AgentInitializationContext
+    where
+        S: Into<String>,
+    {
+        self.privilege_helper = Some(privilege_helper.into());
+        self
+    }
+
+    /// Serves and makes connections over mutual TLS instead of plain TCP.
+    pub fn with_tls(mut self, tls: TlsConfig) -> AgentInitializationContext {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Adds a capability the agent advertises in addition to the ones it
+    /// detects on the host.
+    pub fn with_capability(
+        mut self,
+        capability: CapabilityBroadcast,
+    ) -> AgentInitializationContext {
+        self.capabilities.push(capability);
+        self
+    }
+
+    /// Address the agent listener binds to; port 0 picks a random free port.
+    pub fn with_listen_address<A>(mut self, listen_address: A) -> AgentInitializationContext
+    where
+        A: Into<SocketAddr>,
+    {
+        self.listen_address = listen_address.into();
+        self
+    }
+
+    /// File the bound port is written to once the listener is up.
+    pub fn with_port_file<T>(mut self, port_file: T) -> AgentInitializationContext
+    where
+        T: Into<PathBuf>,
+    {
+        self.port_file = Some(port_file.into());
+        self
+    }
+
+    /// Host other nodes should use to reach this agent, when it differs from
+    /// the bind address (e.g. when bound to `0.0.0.0`).
+    pub fn with_advertise_host<S>(mut self, advertise_host: S) -> AgentInitializationContext
+    where
+        S: Into<String>,
+    {
+        self.advertise_host = Some(advertise_host.into());
+        self
+    }
+
+    pub fn store_path(&self) -> &PathBuf {
+        &self.store_path
+    }
+
+    pub fn check_deps_command(&self) -> &str {
+        &self.check_deps_command
+    }
+
+    pub fn install_deps_command(&self) -> &str {
+        &self.install_deps_command
+    }
+
+    pub fn version(&self) -> &str {
+        self.version
+    }
+
+    pub fn listen_address(&self) -> SocketAddr {
+        self.listen_address
+    }
+
+    pub fn port_file(&self) -> Option<&PathBuf> {
+        self.port_file.as_ref()
+    }
+
+    pub fn advertise_host(&self) -> Option<&str> {
+        self.advertise_host.as_deref()
+    }
+
+    pub fn capabilities(&self) -> &[CapabilityBroadcast] {
+        &self.capabilities
+    }
+
+    pub fn seeds(&self) -> &[String] {
+        &self.seeds
+    }
+
+    pub fn tls(&self) -> Option<&TlsConfig> {
+        self.tls.as_ref()
+    }
+
+    pub fn auth_key_id(&self) -> Option<&str> {
+        self.auth_key_id.as_deref()
+    }
+
+    pub fn require_auth(&self) -> bool {
+        self.require_auth
+    }
+
+    pub fn policy_path(&self) -> Option<&PathBuf> {
+        self.policy_path.as_ref()
+    }
+
+    pub fn dry_run_policy(&self) -> bool {
+        self.dry_run_policy
+    }
+
+    pub fn audit_retention(&self) -> Option<Duration> {
+        self.audit_retention
+    }
+
+    pub fn audit_max_rows(&self) -> Option<u64> {
+        self.audit_max_rows
+    }
+
+    pub fn protocol_period(&self) -> Duration {
+        self.protocol_period
+    }
+
+    pub fn suspect_timeout(&self) -> Duration {
+        self.suspect_timeout
+    }
+
+    pub fn packages(&self) -> &[PackageRequirement] {
+        &self.packages
+    }
+
+    pub fn package_manager(&self) -> Option<&str> {
+        self.package_manager.as_deref()
+    }
+
+    pub fn dependency_manifest(&self) -> Option<&PathBuf> {
+        self.dependency_manifest.as_ref()
+    }
+
+    pub fn shell_commands(&self) -> bool {
+        self.shell_commands
+    }
+
+    pub fn no_install(&self) -> bool {
+        self.no_install
+    }
+
+    pub fn privilege_helper(&self) -> Option<&str> {
+        self.privilege_helper.as_deref()
+    }
+}
+
+/// Every error raised by agents and clients. Errors travel back to the
+/// requestor inside response envelopes, so each one carries a stable
+/// [`code`](RqMeshError::code) clients can match on instead of its text.
+///
+/// | Codes | Category                          |
+/// |-------|-----------------------------------|
+/// | 1xxx  | [`ErrorCategory::Initialization`] |
+/// | 2xxx  | [`ErrorCategory::Transport`]      |
+/// | 3xxx  | [`ErrorCategory::Frame`]          |
+/// | 4xxx  | [`ErrorCategory::Protocol`]       |
+/// | 5xxx  | [`ErrorCategory::Storage`]        |
+/// | 6xxx  | [`ErrorCategory::Routing`]        |
+/// | 7xxx  | [`ErrorCategory::Authorization`]  |
+/// | 8xxx  | [`ErrorCategory::Job`]            |
+///
+/// Codes are never reused or renumbered once released.
+#[derive(Debug, Clone, PartialEq, PartialOrd, Eq, Ord, Serialize, Deserialize)]
+pub enum RqMeshError {
+    InitializationError(InitializationErrorKind),
+    FrameError(FrameErrorKind),
+    ProtocolError(ProtocolErrorKind),
+    IncompatiblePeer {
+        local_version: String,
+        remote_version: String,
+    },
+    StorageError(StorageErrorKind),
+    RoutingError(RoutingErrorKind),
+    Unauthorized(UnauthorizedErrorKind),
+    TransportError(TransportErrorKind),
+    JobError(JobErrorKind),
+    /// An error reported by the agent `node` rather than raised locally.
+    Remote {
+        node: String,
+        error: Box<RqMeshError>,
+    },
+}
+
+impl RqMeshError {
+    pub fn new_incompatible_peer<S1, S2>(local_version: S1, remote_version: S2) -> RqMeshError
+    where
+        S1: Into<String>,
+        S2: Into<String>,
+    {
+        let local_version = local_version.into();
+        let remote_version = remote_version.into();
+        RqMeshError::IncompatiblePeer {
+            local_version,
+            remote_version,
+        }
+    }
+
+    pub fn new_remote<S>(node: S, error: RqMeshError) -> RqMeshError
+    where
+        S: Into<String>,
+    {
+        let node = node.into();
+        let error = Box::new(error);
+        RqMeshError::Remote { node, error }
+    }
+
+    /// The error as originally raised, looking through the agents that
+    /// reported it on.
+    pub fn root(&self) -> &RqMeshError {
+        match self {
+            RqMeshError::Remote { error, .. } => error.root(),
+            _ => self,
+        }
+    }
+
+    /// Stable numeric code of the error; a remote error has the code of the
+    /// error it reports.
+    pub fn code(&self) -> u32 {
+        match self {
+            RqMeshError::InitializationError(i) => i.code(),
+            RqMeshError::TransportError(i) => i.code(),
+            RqMeshError::FrameError(i) => i.code(),
+            RqMeshError::ProtocolError(i) => i.code(),
+            RqMeshError::IncompatiblePeer { .. } => 4005,
+            RqMeshError::StorageError(i) => i.code(),
+            RqMeshError::RoutingError(i) => i.code(),
+            RqMeshError::Unauthorized(i) => i.code(),
+            RqMeshError::JobError(i) => i.code(),
+            RqMeshError::Remote { error, .. } => error.code(),
+        }
+    }
+
+    pub fn category(&self) -> ErrorCategory {
+        match self.code() / 1000 {
+            1 => ErrorCategory::Initialization,
+            2 => ErrorCategory::Transport,
+            3 => ErrorCategory::Frame,
+            4 => ErrorCategory::Protocol,
+            5 => ErrorCategory::Storage,
+            6 => ErrorCategory::Routing,
+            7 => ErrorCategory::Authorization,
+            _ => ErrorCategory::Job,
+        }
+    }
+}
+
+impl From<InitializationErrorKind> for RqMeshError {
+    fn from(value: InitializationErrorKind) -> RqMeshError {
+        RqMeshError::InitializationError(value)
+    }
+}
+
+impl From<FrameErrorKind> for RqMeshError {
+    fn from(value: FrameErrorKind) -> RqMeshError {
+        RqMeshError::FrameError(value)
+    }
+}
+
+impl From<ProtocolErrorKind> for RqMeshError {
+    fn from(value: ProtocolErrorKind) -> RqMeshError {
+        RqMeshError::ProtocolError(value)
+    }
+}
+
+impl From<StorageErrorKind> for RqMeshError {
+    fn from(value: StorageErrorKind) -> RqMeshError {
+        RqMeshError::StorageError(value)
+    }
+}
+
+impl From<RoutingErrorKind> for RqMeshError {
+    fn from(value: RoutingErrorKind) -> RqMeshError {
+        RqMeshError::RoutingError(value)
+    }
+}
+
+impl From<UnauthorizedErrorKind> for RqMeshError {
+    fn from(value: UnauthorizedErrorKind) -> RqMeshError {
+        RqMeshError::Unauthorized(value)
+    }
+}
+
+impl From<TransportErrorKind> for RqMeshError {
+    fn from(value: TransportErrorKind) -> RqMeshError {
+        RqMeshError::TransportError(value)
+    }
+}
+
+impl From<JobErrorKind> for RqMeshError {
+    fn from(value: JobErrorKind) -> RqMeshError {
+        RqMeshError::JobError(value)
+    }
+}
+
+impl std::fmt::Display for RqMeshError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self {
+            RqMeshError::InitializationError(i) => write!(f, "{}", i),
+            RqMeshError::FrameError(i) => write!(f, "{}", i),
+            RqMeshError::ProtocolError(i) => write!(f, "{}", i),
+            RqMeshError::IncompatiblePeer {
+                local_version,
+                remote_version,
+            } => write!(
+                f,
+                "IncompatiblePeer: local protocol version {} cannot talk to remote version {}",
+                local_version, remote_version
+            ),
+            RqMeshError::StorageError(i) => write!(f, "{}", i),
+            RqMeshError::RoutingError(i) => write!(f, "{}", i),
+            RqMeshError::Unauthorized(i) => write!(f, "{}", i),
+            RqMeshError::TransportError(i) => write!(f, "{}", i),
+            RqMeshError::JobError(i) => write!(f, "{}", i),
+            RqMeshError::Remote { node, error } => write!(f, "Remote ({}): {}", node, error),
+        }?;
+        Ok(())
+    }
+}
+
+impl std::error::Error for RqMeshError {}
+
+/// Broad kind of an [`RqMeshError`], given by the thousands of its code.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum ErrorCategory {
+    Initialization,
+    /// Connecting to or talking with another node failed.
+    Transport,
+    /// A frame could not be encoded or decoded.
+    Frame,
+    Protocol,
+    Storage,
+    Routing,
+    Authorization,
+    /// A program run as a job did not complete successfully.
+    Job,
+}
+
+impl std::fmt::Display for ErrorCategory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self {
+            ErrorCategory::Initialization => write!(f, "initialization"),
+            ErrorCategory::Transport => write!(f, "transport"),
+            ErrorCategory::Frame => write!(f, "frame"),
+            ErrorCategory::Protocol => write!(f, "protocol"),
+            ErrorCategory::Storage => write!(f, "storage"),
+            ErrorCategory::Routing => write!(f, "routing"),
+            ErrorCategory::Authorization => write!(f, "authorization"),
+            ErrorCategory::Job => write!(f, "job"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum InitializationErrorKind {
+    InvalidStoreLocation {
+        store_location: String,
+        message: String,
+    },
+    InvalidCheckDependenciesCommand {
+        command: String,
+        message: String,
+    },
+    MissingRequiredDependencies {
+        message: String,
+    },
+    InvalidInstallDependenciesCommand {
+        command: String,
+        message: String,
+    },
+    SqliteInitializationError {
+        message: String,
+    },
+    InvalidCapability {
+        capability: String,
+        message: String,
+    },
+    UnsupportedSchemaVersion {
+        store_location: String,
+        schema_version: u32,
+        supported_version: u32,
+    },
+    InvalidTlsConfig {
+        path: String,
+        message: String,
+    },
+    InvalidPolicy {
+        path: String,
+        message: String,
+    },
+    InvalidPackageRequirement {
+        requirement: String,
+        message: String,
+    },
+    NoPackageManager {
+        message: String,
+    },
+    MissingPackages {
+        package_manager: String,
+        missing: Vec<String>,
+    },
+    InvalidDependencyManifest {
+        path: String,
+        message: String,
+    },
+    InvalidConfig {
+        source: String,
+        message: String,
+    },
+}
+
+impl InitializationErrorKind {
+    pub fn code(&self) -> u32 {
+        match self {
+            InitializationErrorKind::InvalidStoreLocation { .. } => 1001,
+            InitializationErrorKind::InvalidCheckDependenciesCommand { .. } => 1002,
+            InitializationErrorKind::MissingRequiredDependencies { .. } => 1003,
+            InitializationErrorKind::InvalidInstallDependenciesCommand { .. } => 1004,
+            InitializationErrorKind::SqliteInitializationError { .. } => 1005,
+            InitializationErrorKind::InvalidCapability { .. } => 1006,
+            InitializationErrorKind::UnsupportedSchemaVersion { .. } => 1007,
+            InitializationErrorKind::InvalidTlsConfig { .. } => 1008,
+            InitializationErrorKind::InvalidPolicy { .. } => 1009,
+            InitializationErrorKind::InvalidPackageRequirement { .. } => 1010,
+            InitializationErrorKind::NoPackageManager { .. } => 1011,
+            InitializationErrorKind::MissingPackages { .. } => 1012,
+            InitializationErrorKind::InvalidDependencyManifest { .. } => 1013,
+            InitializationErrorKind::InvalidConfig { .. } => 1014,
+        }
+    }
+
+    pub fn new_sqlite_init_err<S>(message: S) -> InitializationErrorKind
+    where
+        S: Into<String>,
+    {
+        let message = message.into();
+        InitializationErrorKind::SqliteInitializationError { message }
+    }
+
+    pub fn new_invalid_capability<S1, S2>(capability: S1, message: S2) -> InitializationErrorKind
+    where
+        S1: Into<String>,
+        S2: Into<String>,
+    {
+        let capability: String = capability.into();
+        let message: String = message.into();
+        InitializationErrorKind::InvalidCapability {
+            capability,
+            message,
+        }
+    }
+
+    pub fn new_invalid_check_deps_cmd<S1, S2>(command: S1, message: S2) -> InitializationErrorKind
+    where
+        S1: Into<String>,
+        S2: Into<String>,
+    {
+        let command: String = command.into();
+        let message: String = message.into();
+        InitializationErrorKind::InvalidCheckDependenciesCommand { command, message }
+    }
+
+    pub fn new_missing_deps<S>(message: S) -> InitializationErrorKind
+    where
+        S: Into<String>,
+    {
+        let message = message.into();
+        InitializationErrorKind::MissingRequiredDependencies { message }
+    }
+
+    pub fn new_invalid_install_deps_cmd<S1, S2>(command: S1, message: S2) -> InitializationErrorKind
+    where
+        S1: Into<String>,
+        S2: Into<String>,
+    {
+        let command: String = command.into();
+        let message: String = message.into();
+        InitializationErrorKind::InvalidInstallDependenciesCommand { command, message }
+    }
+
+    pub fn new_unsupported_schema_version<S>(
+        store_location: S,
+        schema_version: u32,
+        supported_version: u32,
+    ) -> InitializationErrorKind
+    where
+        S: Into<String>,
+    {
+        let store_location = store_location.into();
+        InitializationErrorKind::UnsupportedSchemaVersion {
+            store_location,
+            schema_version,
+            supported_version,
+        }
+    }
+
+    pub fn new_invalid_policy<S1, S2>(path: S1, message: S2) -> InitializationErrorKind
+    where
+        S1: Into<String>,
+        S2: Into<String>,
+    {
+        let path = path.into();
+        let message = message.into();
+        InitializationErrorKind::InvalidPolicy { path, message }
+    }
+
+    pub fn new_invalid_package_requirement<S1, S2>(
+        requirement: S1,
+        message: S2,
+    ) -> InitializationErrorKind
+    where
+        S1: Into<String>,
+        S2: Into<String>,
+    {
+        let requirement = requirement.into();
+        let message = message.into();
+        InitializationErrorKind::InvalidPackageRequirement {
+            requirement,
+            message,
+        }
+    }
+
+    pub fn new_no_package_manager<S>(message: S) -> InitializationErrorKind
+    where
+        S: Into<String>,
+    {
+        let message = message.into();
+        InitializationErrorKind::NoPackageManager { message }
+    }
+
+    /// Packages still missing after any install, each described as the
+    /// requirement along with what, if anything, was found instead.
+    pub fn new_missing_packages<S>(
+        package_manager: S,
+        missing: Vec<String>,
+    ) -> InitializationErrorKind
+    where
+        S: Into<String>,
+    {
+        let package_manager = package_manager.into();
+        InitializationErrorKind::MissingPackages {
+            package_manager,
+            missing,
+        }
+    }
+
+    pub fn new_invalid_dependency_manifest<S1, S2>(path: S1, message: S2) -> InitializationErrorKind
+    where
+        S1: Into<String>,
+        S2: Into<String>,
+    {
+        let path = path.into();
+        let message = message.into();
+        InitializationErrorKind::InvalidDependencyManifest { path, message }
+    }
+
+    /// `source` is the file, environment variable or flag the bad setting
+    /// came from.
+    pub fn new_invalid_config<S1, S2>(source: S1, message: S2) -> InitializationErrorKind
+    where
+        S1: Into<String>,
+        S2: Into<String>,
+    {
+        let source = source.into();
+        let message = message.into();
+        InitializationErrorKind::InvalidConfig { source, message }
+    }
+
+    pub fn new_invalid_tls_config<S1, S2>(path: S1, message: S2) -> InitializationErrorKind
+    where
+        S1: Into<String>,
+        S2: Into<String>,
+    {
+        let path = path.into();
+        let message = message.into();
+        InitializationErrorKind::InvalidTlsConfig { path, message }
+    }
+
+    pub fn new_invalid_store_location<S1, S2>(
+        store_location: S1,
+        message: S2,
+    ) -> InitializationErrorKind
+    where
+        S1: Into<String>,
+        S2: Into<String>,
+    {
+        let store_location = store_location.into();
+        let message = message.into();
+        InitializationErrorKind::InvalidStoreLocation {
+            store_location,
+            message,
+        }
+    }
+}
+
+impl std::fmt::Display for InitializationErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self {
+            InitializationErrorKind::InvalidStoreLocation {
+                store_location,
+                message,
+            } => write!(f, "InvalidStoreLocation ({}): {}", store_location, message),
+            InitializationErrorKind::InvalidCheckDependenciesCommand { command, message } => {
+                write!(
+                    f,
+                    "InvalidCheckDependenciesCommand ({}): {}",
+                    command, message
+                )
+            }
+            InitializationErrorKind::MissingRequiredDependencies { message } => {
+                write!(f, "MissingRequiredDependencies: {}", message)
+            }
+            InitializationErrorKind::InvalidInstallDependenciesCommand { command, message } => {
+                write!(
+                    f,
+                    "InvalidInstallDependenciesCommand ({}): {}",
+                    command, message
+                )
+            }
+            InitializationErrorKind::SqliteInitializationError { message } => {
+                write!(f, "SqliteInitializationError: {}", message)
+            }
+            InitializationErrorKind::InvalidCapability {
+                capability,
+                message,
+            } => write!(f, "InvalidCapability ({}): {}", capability, message),
+            InitializationErrorKind::UnsupportedSchemaVersion {
+                store_location,
+                schema_version,
+                supported_version,
+            } => write!(
+                f,
+                "UnsupportedSchemaVersion ({}): store is at schema version {} but this agent only supports up to {}",
+                store_location, schema_version, supported_version
+            ),
+            InitializationErrorKind::InvalidTlsConfig { path, message } => {
+                write!(f, "InvalidTlsConfig ({}): {}", path, message)
+            }
+            InitializationErrorKind::InvalidPolicy { path, message } => {
+                write!(f, "InvalidPolicy ({}): {}", path, message)
+            }
+            InitializationErrorKind::InvalidPackageRequirement {
+                requirement,
+                message,
+            } => write!(
+                f,
+                "InvalidPackageRequirement ({}): {}",
+                requirement, message
+            ),
+            InitializationErrorKind::NoPackageManager { message } => {
+                write!(f, "NoPackageManager: {}", message)
+            }
+            InitializationErrorKind::MissingPackages {
+                package_manager,
+                missing,
+            } => write!(
+                f,
+                "MissingPackages ({}): {}",
+                package_manager,
+                missing.join(", ")
+            ),
+            InitializationErrorKind::InvalidDependencyManifest { path, message } => {
+                write!(f, "InvalidDependencyManifest ({}): {}", path, message)
+            }
+            InitializationErrorKind::InvalidConfig { source, message } => {
+                write!(f, "InvalidConfig ({}): {}", source, message)
+            }
+        }?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum FrameErrorKind {
+    FrameTooLarge { size: usize, max_frame_size: u32 },
+    TruncatedFrame { expected: usize, received: usize },
+    DecodeError { message: String },
+    EncodeError { message: String },
+}
+
+impl FrameErrorKind {
+    pub fn code(&self) -> u32 {
+        match self {
+            FrameErrorKind::FrameTooLarge { .. } => 3001,
+            FrameErrorKind::TruncatedFrame { .. } => 3002,
+            FrameErrorKind::DecodeError { .. } => 3003,
+            FrameErrorKind::EncodeError { .. } => 3004,
+        }
+    }
+
+    pub fn new_too_large(size: usize, max_frame_size: u32) -> FrameErrorKind {
+        FrameErrorKind::FrameTooLarge {
+            size,
+            max_frame_size,
+        }
+    }
+
+    pub fn new_truncated(expected: usize, received: usize) -> FrameErrorKind {
+        FrameErrorKind::TruncatedFrame { expected, received }
+    }
+
+    pub fn new_decode_err<S>(message: S) -> FrameErrorKind
+    where
+        S: Into<String>,
+    {
+        let message = message.into();
+        FrameErrorKind::DecodeError { message }
+    }
+
+    pub fn new_encode_err<S>(message: S) -> FrameErrorKind
+    where
+        S: Into<String>,
+    {
+        let message = message.into();
+        FrameErrorKind::EncodeError { message }
+    }
+}
+
+impl std::fmt::Display for FrameErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self {
+            FrameErrorKind::FrameTooLarge {
+                size,
+                max_frame_size,
+            } => write!(
+                f,
+                "FrameTooLarge: frame of {} bytes exceeds maximum of {} bytes",
+                size, max_frame_size
+            ),
+            FrameErrorKind::TruncatedFrame { expected, received } => write!(
+                f,
+                "TruncatedFrame: expected {} bytes but stream ended after {}",
+                expected, received
+            ),
+            FrameErrorKind::DecodeError { message } => write!(f, "DecodeError: {}", message),
+            FrameErrorKind::EncodeError { message } => write!(f, "EncodeError: {}", message),
+        }?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum ProtocolErrorKind {
+    UnknownAction { action: String },
+    UnexpectedAction { expected: String, received: String },
+    HandlerError { action: String, message: String },
+    HandshakeRequired { message: String },
+}
+
+impl ProtocolErrorKind {
+    pub fn code(&self) -> u32 {
+        match self {
+            ProtocolErrorKind::UnknownAction { .. } => 4001,
+            ProtocolErrorKind::UnexpectedAction { .. } => 4002,
+            ProtocolErrorKind::HandlerError { .. } => 4003,
+            ProtocolErrorKind::HandshakeRequired { .. } => 4004,
+        }
+    }
+
+    pub fn new_unknown_action<S>(action: S) -> ProtocolErrorKind
+    where
+        S: Into<String>,
+    {
+        let action = action.into();
+        ProtocolErrorKind::UnknownAction { action }
+    }
+
+    pub fn new_unexpected_action<S1, S2>(expected: S1, received: S2) -> ProtocolErrorKind
+    where
+        S1: Into<String>,
+        S2: Into<String>,
+    {
+        let expected = expected.into();
+        let received = received.into();
+        ProtocolErrorKind::UnexpectedAction { expected, received }
+    }
+
+    pub fn new_handler_err<S1, S2>(action: S1, message: S2) -> ProtocolErrorKind
+    where
+        S1: Into<String>,
+        S2: Into<String>,
+    {
+        let action = action.into();
+        let message = message.into();
+        ProtocolErrorKind::HandlerError { action, message }
+    }
+
+    pub fn new_handshake_required<S>(message: S) -> ProtocolErrorKind
+    where
+        S: Into<String>,
+    {
+        let message = message.into();
+        ProtocolErrorKind::HandshakeRequired { message }
+    }
+}
+
+impl std::fmt::Display for ProtocolErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self {
+            ProtocolErrorKind::UnknownAction { action } => {
+                write!(f, "UnknownAction: no handler registered for {}", action)
+            }
+            ProtocolErrorKind::UnexpectedAction { expected, received } => write!(
+                f,
+                "UnexpectedAction: expected {} but received {}",
+                expected, received
+            ),
+            ProtocolErrorKind::HandlerError { action, message } => {
+                write!(f, "HandlerError ({}): {}", action, message)
+            }
+            ProtocolErrorKind::HandshakeRequired { message } => {
+                write!(f, "HandshakeRequired: {}", message)
+            }
+        }?;
+        Ok(())
+    }
 }
 
-#[derive(Debug, Clone, PartialEq, PartialOrd, Eq, Ord)]
-pub struct AgentInitializationContext {
-    store_path: PathBuf,
-    check_deps_command: String,
-    install_deps_command: String,
-    version: &'static str,
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum StorageErrorKind {
+    QueryError { operation: String, message: String },
+    NotFound { entity: String, key: String },
 }
 
-impl AgentInitializationContext {
-    pub fn new<T, S1, S2>(
-        store_path: T,
-        check_deps_command: S1,
-        install_deps_command: S2,
-    ) -> AgentInitializationContext
+impl StorageErrorKind {
+    pub fn code(&self) -> u32 {
+        match self {
+            StorageErrorKind::QueryError { .. } => 5001,
+            StorageErrorKind::NotFound { .. } => 5002,
+        }
+    }
+
+    pub fn new_query_err<S1, S2>(operation: S1, message: S2) -> StorageErrorKind
     where
-        T: Into<PathBuf>,
         S1: Into<String>,
         S2: Into<String>,
     {
-        let store_path: PathBuf = store_path.into();
-        let check_deps_command: String = check_deps_command.into();
-        let install_deps_command: String = install_deps_command.into();
-        AgentInitializationContext {
-            store_path,
-            check_deps_command,
-            install_deps_command,
-            version: VERSION,
+        let operation = operation.into();
+        let message = message.into();
+        StorageErrorKind::QueryError { operation, message }
+    }
+
+    pub fn new_not_found<S1, S2>(entity: S1, key: S2) -> StorageErrorKind
+    where
+        S1: Into<String>,
+        S2: Into<String>,
+    {
+        let entity = entity.into();
+        let key = key.into();
+        StorageErrorKind::NotFound { entity, key }
+    }
+}
+
+impl std::fmt::Display for StorageErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self {
+            StorageErrorKind::QueryError { operation, message } => {
+                write!(f, "QueryError ({}): {}", operation, message)
+            }
+            StorageErrorKind::NotFound { entity, key } => {
+                write!(f, "NotFound: no {} {}", entity, key)
+            }
+        }?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum RoutingErrorKind {
+    NoCapableAgent { required: Vec<String> },
+    HopLimitExceeded { hops: u32, max_hops: u32 },
+    NodeTimedOut { node: String, timeout: Duration },
+    NestedFanOut { action: String },
+}
+
+impl RoutingErrorKind {
+    pub fn code(&self) -> u32 {
+        match self {
+            RoutingErrorKind::NoCapableAgent { .. } => 6001,
+            RoutingErrorKind::HopLimitExceeded { .. } => 6002,
+            RoutingErrorKind::NodeTimedOut { .. } => 6003,
+            RoutingErrorKind::NestedFanOut { .. } => 6004,
         }
     }
 
-    pub fn store_path(&self) -> &PathBuf {
-        &self.store_path
+    pub fn new_no_capable_agent<I, S>(required: I) -> RoutingErrorKind
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let required = required.into_iter().map(|r| r.into()).collect();
+        RoutingErrorKind::NoCapableAgent { required }
     }
 
-    pub fn check_deps_command(&self) -> &str {
-        &self.check_deps_command
+    pub fn new_hop_limit_exceeded(hops: u32, max_hops: u32) -> RoutingErrorKind {
+        RoutingErrorKind::HopLimitExceeded { hops, max_hops }
     }
 
-    pub fn install_deps_command(&self) -> &str {
-        &self.install_deps_command
+    pub fn new_node_timed_out<S>(node: S, timeout: Duration) -> RoutingErrorKind
+    where
+        S: Into<String>,
+    {
+        let node = node.into();
+        RoutingErrorKind::NodeTimedOut { node, timeout }
     }
 
-    pub fn version(&self) -> &str {
-        &self.version
+    pub fn new_nested_fan_out<S>(action: S) -> RoutingErrorKind
+    where
+        S: Into<String>,
+    {
+        let action = action.into();
+        RoutingErrorKind::NestedFanOut { action }
     }
 }
 
-#[derive(Debug, Clone, PartialEq, PartialOrd, Eq, Ord, Serialize, Deserialize)]
-pub enum RqMeshError {
-    InitializationError(InitializationErrorKind),
+impl std::fmt::Display for RoutingErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self {
+            RoutingErrorKind::NoCapableAgent { required } => write!(
+                f,
+                "NoCapableAgent: no reachable agent advertises {}",
+                required.join(", ")
+            ),
+            RoutingErrorKind::HopLimitExceeded { hops, max_hops } => write!(
+                f,
+                "HopLimitExceeded: request was forwarded {} times without reaching a capable agent (limit {})",
+                hops, max_hops
+            ),
+            RoutingErrorKind::NodeTimedOut { node, timeout } => write!(
+                f,
+                "NodeTimedOut: {} did not answer within {:?}",
+                node, timeout
+            ),
+            RoutingErrorKind::NestedFanOut { action } => write!(
+                f,
+                "NestedFanOut: a broadcast cannot carry a {} request, which would fan out again on every agent",
+                action
+            ),
+        }?;
+        Ok(())
+    }
 }
 
-impl From<InitializationErrorKind> for RqMeshError {
-    fn from(value: InitializationErrorKind) -> RqMeshError {
-        RqMeshError::InitializationError(value)
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum UnauthorizedErrorKind {
+    MissingSignature,
+    UnknownKey { key_id: String },
+    BadSignature { key_id: String },
+    StaleSignature { timestamp: u64, window: Duration },
+    ReplayedNonce { key_id: String },
+    ActionDenied { caller: String, action: String },
+    NotJobOwner { caller: String, job_id: String },
+}
+
+impl UnauthorizedErrorKind {
+    pub fn code(&self) -> u32 {
+        match self {
+            UnauthorizedErrorKind::MissingSignature => 7001,
+            UnauthorizedErrorKind::UnknownKey { .. } => 7002,
+            UnauthorizedErrorKind::BadSignature { .. } => 7003,
+            UnauthorizedErrorKind::StaleSignature { .. } => 7004,
+            UnauthorizedErrorKind::ReplayedNonce { .. } => 7005,
+            UnauthorizedErrorKind::ActionDenied { .. } => 7006,
+            UnauthorizedErrorKind::NotJobOwner { .. } => 7007,
+        }
+    }
+
+    pub fn new_missing_signature() -> UnauthorizedErrorKind {
+        UnauthorizedErrorKind::MissingSignature
+    }
+
+    pub fn new_unknown_key<S>(key_id: S) -> UnauthorizedErrorKind
+    where
+        S: Into<String>,
+    {
+        let key_id = key_id.into();
+        UnauthorizedErrorKind::UnknownKey { key_id }
+    }
+
+    pub fn new_bad_signature<S>(key_id: S) -> UnauthorizedErrorKind
+    where
+        S: Into<String>,
+    {
+        let key_id = key_id.into();
+        UnauthorizedErrorKind::BadSignature { key_id }
+    }
+
+    pub fn new_stale_signature(timestamp: u64, window: Duration) -> UnauthorizedErrorKind {
+        UnauthorizedErrorKind::StaleSignature { timestamp, window }
+    }
+
+    pub fn new_replayed_nonce<S>(key_id: S) -> UnauthorizedErrorKind
+    where
+        S: Into<String>,
+    {
+        let key_id = key_id.into();
+        UnauthorizedErrorKind::ReplayedNonce { key_id }
+    }
+
+    pub fn new_action_denied<S1, S2>(caller: S1, action: S2) -> UnauthorizedErrorKind
+    where
+        S1: Into<String>,
+        S2: Into<String>,
+    {
+        let caller = caller.into();
+        let action = action.into();
+        UnauthorizedErrorKind::ActionDenied { caller, action }
+    }
+
+    pub fn new_not_job_owner<S1, S2>(caller: S1, job_id: S2) -> UnauthorizedErrorKind
+    where
+        S1: Into<String>,
+        S2: Into<String>,
+    {
+        let caller = caller.into();
+        let job_id = job_id.into();
+        UnauthorizedErrorKind::NotJobOwner { caller, job_id }
     }
 }
 
-impl std::fmt::Display for RqMeshError {
+impl std::fmt::Display for UnauthorizedErrorKind {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match &self {
-            RqMeshError::InitializationError(i) => write!(f, "{}", i),
+            UnauthorizedErrorKind::MissingSignature => {
+                write!(f, "Unauthorized: request must be signed")
+            }
+            UnauthorizedErrorKind::UnknownKey { key_id } => {
+                write!(f, "Unauthorized: unknown signing key {}", key_id)
+            }
+            UnauthorizedErrorKind::BadSignature { key_id } => {
+                write!(f, "Unauthorized: signature does not match key {}", key_id)
+            }
+            UnauthorizedErrorKind::StaleSignature { timestamp, window } => write!(
+                f,
+                "Unauthorized: request signed at {} is outside the {:?} replay window",
+                timestamp, window
+            ),
+            UnauthorizedErrorKind::ReplayedNonce { key_id } => write!(
+                f,
+                "Unauthorized: request signed with key {} was already received",
+                key_id
+            ),
+            UnauthorizedErrorKind::ActionDenied { caller, action } => write!(
+                f,
+                "Unauthorized: policy does not allow {} to {}",
+                caller, action
+            ),
+            UnauthorizedErrorKind::NotJobOwner { caller, job_id } => {
+                write!(f, "Unauthorized: job {} was not run for {}", job_id, caller)
+            }
         }?;
         Ok(())
     }
 }
 
-impl std::error::Error for RqMeshError {}
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum TransportErrorKind {
+    ConnectFailed { addr: String, message: String },
+    ConnectionClosed,
+    TimedOut { message: String },
+    TlsError { message: String },
+    IoError { message: String },
+}
+
+impl TransportErrorKind {
+    pub fn code(&self) -> u32 {
+        match self {
+            TransportErrorKind::ConnectFailed { .. } => 2001,
+            TransportErrorKind::ConnectionClosed => 2002,
+            TransportErrorKind::TimedOut { .. } => 2003,
+            TransportErrorKind::TlsError { .. } => 2004,
+            TransportErrorKind::IoError { .. } => 2005,
+        }
+    }
+
+    pub fn new_connect_failed<S1, S2>(addr: S1, message: S2) -> TransportErrorKind
+    where
+        S1: Into<String>,
+        S2: Into<String>,
+    {
+        let addr = addr.into();
+        let message = message.into();
+        TransportErrorKind::ConnectFailed { addr, message }
+    }
+
+    pub fn new_connection_closed() -> TransportErrorKind {
+        TransportErrorKind::ConnectionClosed
+    }
+
+    pub fn new_timed_out<S>(message: S) -> TransportErrorKind
+    where
+        S: Into<String>,
+    {
+        let message = message.into();
+        TransportErrorKind::TimedOut { message }
+    }
+
+    pub fn new_tls_err<S>(message: S) -> TransportErrorKind
+    where
+        S: Into<String>,
+    {
+        let message = message.into();
+        TransportErrorKind::TlsError { message }
+    }
+
+    pub fn new_io_err<S>(message: S) -> TransportErrorKind
+    where
+        S: Into<String>,
+    {
+        let message = message.into();
+        TransportErrorKind::IoError { message }
+    }
+
+    /// Maps an I/O error on a connection, telling timeouts apart from other
+    /// failures.
+    pub fn from_io(e: &std::io::Error) -> TransportErrorKind {
+        match e.kind() {
+            std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => {
+                TransportErrorKind::new_timed_out(format!("{}", e))
+            }
+            _ => TransportErrorKind::new_io_err(format!("{}", e)),
+        }
+    }
+}
+
+impl std::fmt::Display for TransportErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self {
+            TransportErrorKind::ConnectFailed { addr, message } => {
+                write!(f, "ConnectFailed ({}): {}", addr, message)
+            }
+            TransportErrorKind::ConnectionClosed => write!(
+                f,
+                "ConnectionClosed: connection closed before a response was received"
+            ),
+            TransportErrorKind::TimedOut { message } => write!(f, "TimedOut: {}", message),
+            TransportErrorKind::TlsError { message } => write!(f, "TlsError: {}", message),
+            TransportErrorKind::IoError { message } => write!(f, "IoError: {}", message),
+        }?;
+        Ok(())
+    }
+}
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
-pub enum InitializationErrorKind {
-    InvalidStoreLocation {
-        store_location: String,
+pub enum JobErrorKind {
+    SpawnFailed {
+        program: String,
         message: String,
     },
-    InvalidCheckDependenciesCommand {
-        command: String,
-        message: String,
+    TimedOut {
+        program: String,
+        timeout: Duration,
     },
-    MissingRequiredDependencies {
+    Interrupted {
         message: String,
     },
-    InvalidInstallDependenciesCommand {
-        command: String,
-        message: String,
+    Unsuccessful {
+        job_id: String,
+        state: JobState,
+        exit_code: Option<i32>,
     },
-    SqliteInitializationError {
+    InvalidCommand {
+        command: String,
         message: String,
     },
 }
 
-impl InitializationErrorKind {
-    pub fn new_sqlite_init_err<S>(message: S) -> InitializationErrorKind
+impl JobErrorKind {
+    pub fn code(&self) -> u32 {
+        match self {
+            JobErrorKind::SpawnFailed { .. } => 8001,
+            JobErrorKind::TimedOut { .. } => 8002,
+            JobErrorKind::Interrupted { .. } => 8003,
+            JobErrorKind::Unsuccessful { .. } => 8004,
+            JobErrorKind::InvalidCommand { .. } => 8005,
+        }
+    }
+
+    pub fn new_spawn_failed<S1, S2>(program: S1, message: S2) -> JobErrorKind
     where
-        S: Into<String>,
+        S1: Into<String>,
+        S2: Into<String>,
     {
+        let program = program.into();
         let message = message.into();
-        InitializationErrorKind::SqliteInitializationError { message }
+        JobErrorKind::SpawnFailed { program, message }
     }
 
-    pub fn new_invalid_check_deps_cmd<S1, S2>(command: S1, message: S2) -> InitializationErrorKind
+    pub fn new_timed_out<S>(program: S, timeout: Duration) -> JobErrorKind
     where
-        S1: Into<String>,
-        S2: Into<String>,
+        S: Into<String>,
     {
-        let command: String = command.into();
-        let message: String = message.into();
-        InitializationErrorKind::InvalidCheckDependenciesCommand { command, message }
+        let program = program.into();
+        JobErrorKind::TimedOut { program, timeout }
     }
 
-    pub fn new_missing_deps<S>(message: S) -> InitializationErrorKind
+    pub fn new_interrupted<S>(message: S) -> JobErrorKind
     where
         S: Into<String>,
     {
         let message = message.into();
-        InitializationErrorKind::MissingRequiredDependencies { message }
+        JobErrorKind::Interrupted { message }
     }
 
-    pub fn new_invalid_install_deps_cmd<S1, S2>(command: S1, message: S2) -> InitializationErrorKind
+    pub fn new_invalid_command<S1, S2>(command: S1, message: S2) -> JobErrorKind
     where
         S1: Into<String>,
         S2: Into<String>,
     {
-        let command: String = command.into();
-        let message: String = message.into();
-        InitializationErrorKind::InvalidInstallDependenciesCommand { command, message }
+        let command = command.into();
+        let message = message.into();
+        JobErrorKind::InvalidCommand { command, message }
     }
 
-    pub fn new_invalid_store_location<S1, S2>(
-        store_location: S1,
-        message: S2,
-    ) -> InitializationErrorKind
+    pub fn new_unsuccessful<S>(job_id: S, state: JobState, exit_code: Option<i32>) -> JobErrorKind
     where
-        S1: Into<String>,
-        S2: Into<String>,
+        S: Into<String>,
     {
-        let store_location = store_location.into();
-        let message = message.into();
-        InitializationErrorKind::InvalidStoreLocation {
-            store_location,
-            message,
+        let job_id = job_id.into();
+        JobErrorKind::Unsuccessful {
+            job_id,
+            state,
+            exit_code,
         }
     }
 }
 
-impl std::fmt::Display for InitializationErrorKind {
+impl std::fmt::Display for JobErrorKind {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match &self {
-            InitializationErrorKind::InvalidStoreLocation {
-                store_location,
-                message,
-            } => write!(f, "InvalidStoreLocation ({}): {}", store_location, message),
-            InitializationErrorKind::InvalidCheckDependenciesCommand { command, message } => {
-                write!(
-                    f,
-                    "InvalidCheckDependenciesCommand ({}): {}",
-                    command, message
-                )
-            }
-            InitializationErrorKind::MissingRequiredDependencies { message } => {
-                write!(f, "MissingRequiredDependencies: {}", message)
+            JobErrorKind::SpawnFailed { program, message } => {
+                write!(f, "SpawnFailed ({}): {}", program, message)
             }
-            InitializationErrorKind::InvalidInstallDependenciesCommand { command, message } => {
-                write!(
+            JobErrorKind::TimedOut { program, timeout } => write!(
+                f,
+                "TimedOut ({}): killed after running for {:?}",
+                program, timeout
+            ),
+            JobErrorKind::Interrupted { message } => write!(f, "Interrupted: {}", message),
+            JobErrorKind::Unsuccessful {
+                job_id,
+                state,
+                exit_code,
+            } => match exit_code {
+                Some(code) => write!(
                     f,
-                    "InvalidInstallDependenciesCommand ({}): {}",
-                    command, message
-                )
-            }
-            InitializationErrorKind::SqliteInitializationError { message } => {
-                write!(f, "SqliteInitializationError: {}", message)
+                    "Unsuccessful ({}): job {} with exit code {}",
+                    job_id, state, code
+                ),
+                None => write!(f, "Unsuccessful ({}): job {}", job_id, state),
+            },
+            JobErrorKind::InvalidCommand { command, message } => {
+                write!(f, "InvalidCommand ({}): {}", command, message)
             }
         }?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cmp::Ordering;
+
+    fn satisfied(requirement: &str, installed: &str) -> bool {
+        PackageRequirement::parse(requirement)
+            .expect("parses")
+            .satisfied_by(installed)
+    }
+
+    #[test]
+    fn versions_compare_numerically_segment_by_segment() {
+        assert_eq!(compare_versions("3.9", "3.40"), Ordering::Less);
+        assert_eq!(compare_versions("3.40.1", "3.40"), Ordering::Greater);
+        assert_eq!(compare_versions("1.2.3", "1.2.3"), Ordering::Equal);
+        assert_eq!(compare_versions("010", "10"), Ordering::Equal);
+    }
+
+    #[test]
+    fn versions_ignore_epochs_and_punctuation() {
+        assert_eq!(compare_versions("1:2.0-1", "2.0.1"), Ordering::Equal);
+        assert_eq!(compare_versions("3.44.2-r0", "3.44.2_r0"), Ordering::Equal);
+    }
+
+    #[test]
+    fn pre_releases_sort_before_releases() {
+        assert_eq!(compare_versions("1.0rc1", "1.0.1"), Ordering::Less);
+        assert_eq!(compare_versions("1.0a", "1.0b"), Ordering::Less);
+    }
+
+    #[test]
+    fn requirements_parse_each_operator() {
+        for (requirement, op) in [
+            ("sqlite=3.40", VersionOp::Eq),
+            ("sqlite>=3.40", VersionOp::Ge),
+            ("sqlite>3.40", VersionOp::Gt),
+            ("sqlite<=3.40", VersionOp::Le),
+            ("sqlite<3.40", VersionOp::Lt),
+        ] {
+            let parsed = PackageRequirement::parse(requirement).expect("parses");
+            assert_eq!(parsed.name(), "sqlite");
+            assert_eq!(parsed.constraint(), Some((op, "3.40")));
+            assert_eq!(parsed.to_string(), requirement);
+        }
+        let bare = PackageRequirement::parse(" git ").expect("parses");
+        assert_eq!((bare.name(), bare.constraint()), ("git", None));
+    }
+
+    #[test]
+    fn malformed_requirements_are_rejected() {
+        for requirement in ["", ">=1.0", "sqlite>=", "sqlite>= 3 4", "my package"] {
+            assert!(
+                PackageRequirement::parse(requirement).is_err(),
+                "{} should be rejected",
+                requirement
+            );
+        }
+    }
+
+    #[test]
+    fn requirements_are_satisfied_by_matching_versions() {
+        assert!(satisfied("sqlite", "0.1"));
+        assert!(satisfied("sqlite>=3.40", "3.40"));
+        assert!(satisfied("sqlite>=3.40", "3.44.2-r0"));
+        assert!(!satisfied("sqlite>=3.40", "3.9"));
+        assert!(satisfied("sqlite>3.40", "3.40.1"));
+        assert!(!satisfied("sqlite>3.40", "3.40"));
+        assert!(satisfied("sqlite<=3.40", "3.40"));
+        assert!(!satisfied("sqlite<3.40", "3.40"));
+        assert!(satisfied("sqlite<3.40", "3.9"));
+    }
+
+    #[test]
+    fn equality_matches_versions_the_requirement_is_a_prefix_of() {
+        assert!(satisfied("sqlite=3.40", "3.40"));
+        assert!(satisfied("sqlite=3.40", "3.40.1-r0"));
+        assert!(!satisfied("sqlite=3.40", "3.4"));
+        assert!(!satisfied("sqlite=3.40", "3.41"));
+    }
+}
@@ -1,34 +1,2134 @@
-use serde::{Serialize,Deserialize};
+use crate::{
+    CapabilityBroadcast, CommandSpec, EnvelopeSignature, FrameErrorKind, JobErrorKind,
+    ProtocolErrorKind, RoutingErrorKind, RqMeshError, TransportErrorKind, PROTOCOL_VERSION,
+};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::io::{ErrorKind, Read, Write};
+use std::time::Duration;
 
-#[derive(Serialize,Deserialize)]
-pub struct RqMeshFrame<T> where T : RqMeshProtocolAction {
+/// Number of bytes used by the big-endian length prefix of every frame.
+pub const FRAME_HEADER_LEN: usize = 4;
+
+/// Largest payload accepted by [`RqMeshCodec::default`].
+pub const DEFAULT_MAX_FRAME_SIZE: u32 = 8 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(bound = "T: RqMeshProtocolAction")]
+pub struct RqMeshFrame<T>
+where
+    T: RqMeshProtocolAction,
+{
     contents: T,
-    requestor: String
+    requestor: String,
+    #[serde(skip)]
+    context: RequestContext,
+}
+
+impl<T> RqMeshFrame<T>
+where
+    T: RqMeshProtocolAction,
+{
+    pub fn new<S>(contents: T, requestor: S) -> RqMeshFrame<T>
+    where
+        S: Into<String>,
+    {
+        let requestor = requestor.into();
+        RqMeshFrame {
+            contents,
+            requestor,
+            context: RequestContext::default(),
+        }
+    }
+
+    pub fn contents(&self) -> &T {
+        &self.contents
+    }
+
+    /// Identity the sender claims for itself.
+    pub fn requestor(&self) -> &str {
+        &self.requestor
+    }
+
+    /// What the receiving agent established about the sender itself.
+    pub fn context(&self) -> &RequestContext {
+        &self.context
+    }
+
+    /// Who sent the frame: the identity in the sender's certificate, or the
+    /// key it signed the frame with, falling back to the identity it claims.
+    pub fn caller(&self) -> &str {
+        self.context.caller(&self.requestor)
+    }
+
+    /// Who sent the frame, if the receiving agent established it rather
+    /// than taking the sender's word for it.
+    pub fn authenticated_caller(&self) -> Option<&str> {
+        self.context.authenticated_caller()
+    }
+
+    pub fn into_contents(self) -> T {
+        self.contents
+    }
+
+    /// Encodes the typed frame into the untyped envelope sent on the wire.
+    pub fn into_envelope(self) -> Result<RqMeshEnvelope, RqMeshError> {
+        let payload = bincode::serialize(&self.contents)
+            .map_err(|e| RqMeshError::from(FrameErrorKind::new_encode_err(format!("{}", e))))?;
+        Ok(RqMeshEnvelope {
+            action: T::ACTION.to_string(),
+            requestor: self.requestor,
+            payload,
+            piggyback: Vec::new(),
+            signature: None,
+            context: RequestContext::default(),
+        })
+    }
+
+    /// Recovers a typed frame from an envelope, failing if the envelope was
+    /// built for a different action.
+    pub fn from_envelope(envelope: RqMeshEnvelope) -> Result<RqMeshFrame<T>, RqMeshError> {
+        if envelope.action != T::ACTION {
+            return Err(RqMeshError::from(ProtocolErrorKind::new_unexpected_action(
+                T::ACTION,
+                envelope.action,
+            )));
+        }
+        let contents: T = bincode::deserialize(&envelope.payload)
+            .map_err(|e| RqMeshError::from(FrameErrorKind::new_decode_err(format!("{}", e))))?;
+        contents.validate()?;
+        Ok(RqMeshFrame {
+            contents,
+            requestor: envelope.requestor,
+            context: envelope.context,
+        })
+    }
+}
+
+/// A request or command that can be sent to an agent.
+///
+/// `ACTION` is the discriminant carried in [`RqMeshEnvelope`] and is what the
+/// agent uses to route an incoming frame to its handler, so it must be unique
+/// across all actions and must never change once released.
+pub trait RqMeshProtocolAction: Serialize + DeserializeOwned {
+    const ACTION: &'static str;
+    type ResponseType: Serialize + DeserializeOwned;
+
+    /// Checks what decoding alone cannot, before the action is authorized or
+    /// handled, since its contents come from whoever sent it.
+    fn validate(&self) -> Result<(), RqMeshError> {
+        Ok(())
+    }
+}
+
+/// What the agent receiving a request established about its sender from the
+/// connection, as opposed to what the request claims. It is filled in by
+/// the receiving agent and never sent on the wire, so a forwarded request
+/// carries the context of the agent that forwarded it.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct RequestContext {
+    peer_addr: Option<String>,
+    peer_identity: Option<String>,
+    key_id: Option<String>,
+    relay: Option<String>,
+    relayed_caller: Option<String>,
+}
+
+impl RequestContext {
+    pub fn new(peer_addr: Option<String>, peer_identity: Option<String>) -> RequestContext {
+        RequestContext {
+            peer_addr,
+            peer_identity,
+            key_id: None,
+            relay: None,
+            relayed_caller: None,
+        }
+    }
+
+    /// Records the key the request was verified to be signed with.
+    pub fn with_key_id<S>(mut self, key_id: S) -> RequestContext
+    where
+        S: Into<String>,
+    {
+        self.key_id = Some(key_id.into());
+        self
+    }
+
+    /// Records that the request was relayed by the authenticated caller
+    /// `relay`, and that the agent trusts the relay's word that `caller`
+    /// sent it if given.
+    pub fn with_relay<S>(mut self, relay: S, caller: Option<String>) -> RequestContext
+    where
+        S: Into<String>,
+    {
+        self.relay = Some(relay.into());
+        self.relayed_caller = caller;
+        self
+    }
+
+    /// Address the request was received from.
+    pub fn peer_addr(&self) -> Option<&str> {
+        self.peer_addr.as_deref()
+    }
+
+    /// Identity from the certificate the sender authenticated with, if the
+    /// request arrived over TLS.
+    pub fn peer_identity(&self) -> Option<&str> {
+        self.peer_identity.as_deref()
+    }
+
+    /// ID of the key the request was signed with, if it was signed.
+    pub fn key_id(&self) -> Option<&str> {
+        self.key_id.as_deref()
+    }
+
+    /// Who relayed the request on behalf of its sender, if it was routed or
+    /// broadcast to this agent by another.
+    pub fn relay(&self) -> Option<&str> {
+        self.relay.as_deref()
+    }
+
+    /// Who sent a request claiming to come from `requestor`: the identity in
+    /// the sender's certificate, or the key it signed the request with,
+    /// falling back to the identity it claims.
+    pub fn caller<'a>(&'a self, requestor: &'a str) -> &'a str {
+        self.authenticated_caller().unwrap_or(requestor)
+    }
+
+    /// Who sent the request, if the receiving agent established it: the
+    /// identity in the sender's certificate, or the key it signed the
+    /// request with, or for a relayed request the caller the relay vouched
+    /// for.
+    pub fn authenticated_caller(&self) -> Option<&str> {
+        self.peer_identity()
+            .or(self.key_id())
+            .or(self.relayed_caller.as_deref())
+    }
+}
+
+/// Untyped request envelope, the unit actually written to the wire.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct RqMeshEnvelope {
+    action: String,
+    requestor: String,
+    payload: Vec<u8>,
+    piggyback: Vec<MembershipUpdate>,
+    signature: Option<EnvelopeSignature>,
+    #[serde(skip)]
+    context: RequestContext,
+}
+
+impl RqMeshEnvelope {
+    /// Records what the receiving agent knows about the sender.
+    pub fn with_context(mut self, context: RequestContext) -> RqMeshEnvelope {
+        self.context = context;
+        self
+    }
+
+    pub fn context(&self) -> &RequestContext {
+        &self.context
+    }
+
+    /// Who sent the envelope, as for [`RqMeshFrame::caller`].
+    pub fn caller(&self) -> &str {
+        self.context.caller(&self.requestor)
+    }
+
+    /// Attaches membership updates to be disseminated along with the request.
+    pub fn with_piggyback(mut self, piggyback: Vec<MembershipUpdate>) -> RqMeshEnvelope {
+        self.piggyback = piggyback;
+        self
+    }
+
+    pub fn piggyback(&self) -> &[MembershipUpdate] {
+        &self.piggyback
+    }
+
+    pub fn action(&self) -> &str {
+        &self.action
+    }
+
+    pub fn requestor(&self) -> &str {
+        &self.requestor
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    /// Attaches a signature, see [`SigningKey::sign`](crate::SigningKey::sign).
+    pub fn with_signature(mut self, signature: EnvelopeSignature) -> RqMeshEnvelope {
+        self.signature = Some(signature);
+        self
+    }
+
+    pub fn signature(&self) -> Option<&EnvelopeSignature> {
+        self.signature.as_ref()
+    }
+
+    /// Bytes a signature over the envelope covers: everything that is sent,
+    /// apart from the signature itself.
+    pub(crate) fn signed_bytes(
+        &self,
+        key_id: &str,
+        nonce: &[u8],
+        timestamp: u64,
+    ) -> Result<Vec<u8>, RqMeshError> {
+        bincode::serialize(&(
+            &self.action,
+            &self.requestor,
+            &self.payload,
+            &self.piggyback,
+            key_id,
+            nonce,
+            timestamp,
+        ))
+        .map_err(|e| RqMeshError::from(FrameErrorKind::new_encode_err(format!("{}", e))))
+    }
+}
+
+/// Untyped response envelope, carrying either the encoded
+/// `ResponseType` of the action or the error that prevented handling it.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct RqMeshResponseEnvelope {
+    action: String,
+    result: Result<Vec<u8>, RqMeshError>,
+    piggyback: Vec<MembershipUpdate>,
+}
+
+impl RqMeshResponseEnvelope {
+    pub fn new_ok<S>(action: S, payload: Vec<u8>) -> RqMeshResponseEnvelope
+    where
+        S: Into<String>,
+    {
+        let action = action.into();
+        RqMeshResponseEnvelope {
+            action,
+            result: Ok(payload),
+            piggyback: Vec::new(),
+        }
+    }
+
+    pub fn new_err<S>(action: S, error: RqMeshError) -> RqMeshResponseEnvelope
+    where
+        S: Into<String>,
+    {
+        let action = action.into();
+        RqMeshResponseEnvelope {
+            action,
+            result: Err(error),
+            piggyback: Vec::new(),
+        }
+    }
+
+    /// Encodes a typed response (or error) for action `A`.
+    pub fn from_response<A>(
+        response: Result<A::ResponseType, RqMeshError>,
+    ) -> Result<RqMeshResponseEnvelope, RqMeshError>
+    where
+        A: RqMeshProtocolAction,
+    {
+        match response {
+            Ok(r) => {
+                let payload = bincode::serialize(&r).map_err(|e| {
+                    RqMeshError::from(FrameErrorKind::new_encode_err(format!("{}", e)))
+                })?;
+                Ok(RqMeshResponseEnvelope::new_ok(A::ACTION, payload))
+            }
+            Err(e) => Ok(RqMeshResponseEnvelope::new_err(A::ACTION, e)),
+        }
+    }
+
+    /// Decodes the typed response for action `A`, surfacing any error the
+    /// remote side reported.
+    pub fn into_response<A>(self) -> Result<A::ResponseType, RqMeshError>
+    where
+        A: RqMeshProtocolAction,
+    {
+        let payload = self.result?;
+        if self.action != A::ACTION {
+            return Err(RqMeshError::from(ProtocolErrorKind::new_unexpected_action(
+                A::ACTION,
+                self.action,
+            )));
+        }
+        let response = bincode::deserialize(&payload)
+            .map_err(|e| RqMeshError::from(FrameErrorKind::new_decode_err(format!("{}", e))))?;
+        Ok(response)
+    }
+
+    pub fn action(&self) -> &str {
+        &self.action
+    }
+
+    pub fn result(&self) -> &Result<Vec<u8>, RqMeshError> {
+        &self.result
+    }
+
+    /// Attaches membership updates to be disseminated along with the response.
+    pub fn with_piggyback(mut self, piggyback: Vec<MembershipUpdate>) -> RqMeshResponseEnvelope {
+        self.piggyback = piggyback;
+        self
+    }
+
+    pub fn piggyback(&self) -> &[MembershipUpdate] {
+        &self.piggyback
+    }
+}
+
+/// Marker at the start of every handshake so that a peer speaking something
+/// other than rqmesh is rejected instead of misread.
+const HANDSHAKE_MAGIC: [u8; 4] = *b"RQMH";
+
+/// First frame sent in each direction on a new connection.
+///
+/// The connecting side sends its handshake and the accepting side answers
+/// with a [`HandshakeResponse`]. No envelopes may be sent until the
+/// connecting side has received `Accepted`; after `Rejected` the accepting
+/// side closes the connection.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Handshake {
+    magic: [u8; 4],
+    protocol_version: String,
+    actions: Vec<String>,
+    identity: String,
+}
+
+impl Handshake {
+    pub fn new<S, I, A>(identity: S, actions: I) -> Handshake
+    where
+        S: Into<String>,
+        I: IntoIterator<Item = A>,
+        A: Into<String>,
+    {
+        let identity = identity.into();
+        let actions = actions.into_iter().map(|a| a.into()).collect();
+        Handshake {
+            magic: HANDSHAKE_MAGIC,
+            protocol_version: PROTOCOL_VERSION.to_string(),
+            actions,
+            identity,
+        }
+    }
+
+    pub fn protocol_version(&self) -> &str {
+        &self.protocol_version
+    }
+
+    /// Actions the sender is able to handle.
+    pub fn actions(&self) -> &[String] {
+        &self.actions
+    }
+
+    pub fn identity(&self) -> &str {
+        &self.identity
+    }
+
+    pub fn supports(&self, action: &str) -> bool {
+        self.actions.iter().any(|a| a == action)
+    }
+
+    /// Checks whether a peer that sent `remote` can talk to us.
+    ///
+    /// Versions are compatible when their major components match, or for
+    /// `0.x` releases when their minor components match as well.
+    pub fn check_compatible(&self, remote: &Handshake) -> Result<(), RqMeshError> {
+        if remote.magic != HANDSHAKE_MAGIC {
+            return Err(RqMeshError::from(
+                ProtocolErrorKind::new_handshake_required(
+                    "First frame on a connection must be a handshake",
+                ),
+            ));
+        }
+
+        let ours = version_prefix(&self.protocol_version);
+        let theirs = version_prefix(&remote.protocol_version);
+        if ours.is_none() || ours != theirs {
+            return Err(RqMeshError::new_incompatible_peer(
+                self.protocol_version.as_str(),
+                remote.protocol_version.as_str(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The components of a `major.minor.patch` version that must match for two
+/// peers to be compatible.
+fn version_prefix(version: &str) -> Option<(u64, Option<u64>)> {
+    let mut parts = version.split('.').map(|p| p.parse::<u64>());
+    let major = parts.next()?.ok()?;
+    let minor = parts.next()?.ok()?;
+    if major == 0 {
+        Some((major, Some(minor)))
+    } else {
+        Some((major, None))
+    }
 }
 
-pub trait RqMeshProtocolAction: Serialize {
-    type ResponseType : Serialize;
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum HandshakeResponse {
+    Accepted(Handshake),
+    Rejected(RqMeshError),
 }
 
-#[derive(Debug,Copy,Clone,Eq,PartialEq,Ord,PartialOrd,Serialize,Deserialize,Default)]
-pub struct DescribeAgentRequest { }
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize, Default)]
+pub struct DescribeAgentRequest {}
 
-#[derive(Debug,Clone,Eq,PartialEq,Ord,PartialOrd,Serialize,Deserialize)]
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
 pub struct DescribeAgentResponse {
+    node_id: String,
     version: String,
     storage_location: String,
-    initialized_at: String
+    initialized_at: String,
+    endpoint: Option<String>,
 }
 
 impl DescribeAgentResponse {
-    pub fn new<S1, S2, S3>(version: S1, storage_location: S2, initialized_at: S3) -> DescribeAgentResponse where S1: Into<String>, S2: Into<String>, S3: Into<String> {
+    pub fn new<S1, S2, S3, S4>(
+        node_id: S1,
+        version: S2,
+        storage_location: S3,
+        initialized_at: S4,
+        endpoint: Option<String>,
+    ) -> DescribeAgentResponse
+    where
+        S1: Into<String>,
+        S2: Into<String>,
+        S3: Into<String>,
+        S4: Into<String>,
+    {
+        let node_id = node_id.into();
         let version = version.into();
         let storage_location = storage_location.into();
         let initialized_at = initialized_at.into();
-        DescribeAgentResponse { version, storage_location, initialized_at }
+        DescribeAgentResponse {
+            node_id,
+            version,
+            storage_location,
+            initialized_at,
+            endpoint,
+        }
+    }
+
+    /// Identifier generated when the agent's store was first initialized,
+    /// stable across restarts and address changes.
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    pub fn storage_location(&self) -> &str {
+        &self.storage_location
+    }
+
+    pub fn initialized_at(&self) -> &str {
+        &self.initialized_at
+    }
+
+    /// Address other nodes can reach the agent on, once it is listening.
+    pub fn endpoint(&self) -> Option<&str> {
+        self.endpoint.as_deref()
     }
 }
 
 impl RqMeshProtocolAction for DescribeAgentRequest {
+    const ACTION: &'static str = "describe_agent";
+    type ResponseType = DescribeAgentResponse;
+}
+
+/// Asks an agent for the capabilities it advertises, optionally only those
+/// of a single type.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize, Default)]
+pub struct ListCapabilitiesRequest {
+    capability_type: Option<String>,
+}
+
+impl ListCapabilitiesRequest {
+    pub fn new(capability_type: Option<String>) -> ListCapabilitiesRequest {
+        ListCapabilitiesRequest { capability_type }
+    }
+
+    pub fn capability_type(&self) -> Option<&str> {
+        self.capability_type.as_deref()
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+pub struct ListCapabilitiesResponse {
+    capabilities: Vec<CapabilityBroadcast>,
+}
+
+impl ListCapabilitiesResponse {
+    pub fn new(capabilities: Vec<CapabilityBroadcast>) -> ListCapabilitiesResponse {
+        ListCapabilitiesResponse { capabilities }
+    }
+
+    pub fn capabilities(&self) -> &[CapabilityBroadcast] {
+        &self.capabilities
+    }
+
+    pub fn into_capabilities(self) -> Vec<CapabilityBroadcast> {
+        self.capabilities
+    }
+}
+
+impl RqMeshProtocolAction for ListCapabilitiesRequest {
+    const ACTION: &'static str = "list_capabilities";
+    type ResponseType = ListCapabilitiesResponse;
+}
+
+/// Introduces the sending agent to the receiver, which records the sender as
+/// a peer and answers with its own description.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+pub struct AnnounceAgentRequest {
+    agent: DescribeAgentResponse,
+}
+
+impl AnnounceAgentRequest {
+    pub fn new(agent: DescribeAgentResponse) -> AnnounceAgentRequest {
+        AnnounceAgentRequest { agent }
+    }
+
+    pub fn agent(&self) -> &DescribeAgentResponse {
+        &self.agent
+    }
+}
+
+impl RqMeshProtocolAction for AnnounceAgentRequest {
+    const ACTION: &'static str = "announce_agent";
     type ResponseType = DescribeAgentResponse;
-}
\ No newline at end of file
+
+    /// The receiver records the announced agent under the endpoint it
+    /// advertises, having no other address it could be reached at.
+    fn validate(&self) -> Result<(), RqMeshError> {
+        if self.agent.endpoint().is_none() {
+            return Err(RqMeshError::from(ProtocolErrorKind::new_handler_err(
+                Self::ACTION,
+                "announced agent does not advertise an endpoint",
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// An agent known to the reporting node, with the times (UTC, sqlite
+/// `datetime` format) it was first and most recently heard from.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+pub struct PeerRecord {
+    node_id: Option<String>,
+    endpoint: String,
+    version: String,
+    first_seen: String,
+    last_seen: String,
+    state: MemberState,
+    incarnation: u64,
+}
+
+impl PeerRecord {
+    pub fn new<S1, S2, S3, S4>(
+        endpoint: S1,
+        version: S2,
+        first_seen: S3,
+        last_seen: S4,
+        state: MemberState,
+        incarnation: u64,
+    ) -> PeerRecord
+    where
+        S1: Into<String>,
+        S2: Into<String>,
+        S3: Into<String>,
+        S4: Into<String>,
+    {
+        let endpoint = endpoint.into();
+        let version = version.into();
+        let first_seen = first_seen.into();
+        let last_seen = last_seen.into();
+        PeerRecord {
+            node_id: None,
+            endpoint,
+            version,
+            first_seen,
+            last_seen,
+            state,
+            incarnation,
+        }
+    }
+
+    pub fn with_node_id(mut self, node_id: Option<String>) -> PeerRecord {
+        self.node_id = node_id;
+        self
+    }
+
+    /// Node ID of the peer, if it has been learned yet.
+    pub fn node_id(&self) -> Option<&str> {
+        self.node_id.as_deref()
+    }
+
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    pub fn first_seen(&self) -> &str {
+        &self.first_seen
+    }
+
+    pub fn last_seen(&self) -> &str {
+        &self.last_seen
+    }
+
+    pub fn state(&self) -> MemberState {
+        self.state
+    }
+
+    pub fn incarnation(&self) -> u64 {
+        self.incarnation
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize, Default)]
+pub struct ListPeersRequest {}
+
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+pub struct ListPeersResponse {
+    peers: Vec<PeerRecord>,
+}
+
+impl ListPeersResponse {
+    pub fn new(peers: Vec<PeerRecord>) -> ListPeersResponse {
+        ListPeersResponse { peers }
+    }
+
+    pub fn peers(&self) -> &[PeerRecord] {
+        &self.peers
+    }
+
+    pub fn into_peers(self) -> Vec<PeerRecord> {
+        self.peers
+    }
+}
+
+impl RqMeshProtocolAction for ListPeersRequest {
+    const ACTION: &'static str = "list_peers";
+    type ResponseType = ListPeersResponse;
+}
+
+/// Liveness of a mesh member as seen by the failure detector.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+pub enum MemberState {
+    Alive,
+    Suspect,
+    Dead,
+}
+
+impl std::fmt::Display for MemberState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self {
+            MemberState::Alive => write!(f, "alive"),
+            MemberState::Suspect => write!(f, "suspect"),
+            MemberState::Dead => write!(f, "dead"),
+        }
+    }
+}
+
+impl std::str::FromStr for MemberState {
+    type Err = RqMeshError;
+
+    fn from_str(s: &str) -> Result<MemberState, RqMeshError> {
+        match s {
+            "alive" => Ok(MemberState::Alive),
+            "suspect" => Ok(MemberState::Suspect),
+            "dead" => Ok(MemberState::Dead),
+            _ => Err(RqMeshError::from(FrameErrorKind::new_decode_err(format!(
+                "Unknown member state {}",
+                s
+            )))),
+        }
+    }
+}
+
+/// A claim about the state of one member, disseminated by piggybacking it on
+/// envelopes. Claims are ordered by incarnation and then by state, and a
+/// member only ever moves to a claim that orders after its current one;
+/// members refute suspicion of themselves by raising their incarnation.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+pub struct MembershipUpdate {
+    endpoint: String,
+    version: String,
+    state: MemberState,
+    incarnation: u64,
+    node_id: Option<String>,
+}
+
+impl MembershipUpdate {
+    pub fn new<S1, S2>(
+        endpoint: S1,
+        version: S2,
+        state: MemberState,
+        incarnation: u64,
+    ) -> MembershipUpdate
+    where
+        S1: Into<String>,
+        S2: Into<String>,
+    {
+        let endpoint = endpoint.into();
+        let version = version.into();
+        MembershipUpdate {
+            endpoint,
+            version,
+            state,
+            incarnation,
+            node_id: None,
+        }
+    }
+
+    /// Attaches the node ID of the member, when the sender knows it.
+    pub fn with_node_id(mut self, node_id: Option<String>) -> MembershipUpdate {
+        self.node_id = node_id;
+        self
+    }
+
+    pub fn node_id(&self) -> Option<&str> {
+        self.node_id.as_deref()
+    }
+
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    pub fn state(&self) -> MemberState {
+        self.state
+    }
+
+    pub fn incarnation(&self) -> u64 {
+        self.incarnation
+    }
+
+    /// Whether this claim should replace a member currently believed to be
+    /// in `state` at `incarnation`.
+    pub fn supersedes(&self, state: MemberState, incarnation: u64) -> bool {
+        (self.incarnation, self.state) > (incarnation, state)
+    }
+}
+
+/// Direct liveness probe; any successful response counts as an ack.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize, Default)]
+pub struct PingRequest {}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize, Default)]
+pub struct PingResponse {}
+
+impl RqMeshProtocolAction for PingRequest {
+    const ACTION: &'static str = "ping";
+    type ResponseType = PingResponse;
+}
+
+/// Asks the receiver to probe `target` on the sender's behalf, used when the
+/// sender's own probe went unanswered.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+pub struct PingReqRequest {
+    target: String,
+}
+
+impl PingReqRequest {
+    pub fn new<S>(target: S) -> PingReqRequest
+    where
+        S: Into<String>,
+    {
+        let target = target.into();
+        PingReqRequest { target }
+    }
+
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+pub struct PingReqResponse {
+    acked: bool,
+}
+
+impl PingReqResponse {
+    pub fn new(acked: bool) -> PingReqResponse {
+        PingReqResponse { acked }
+    }
+
+    pub fn acked(&self) -> bool {
+        self.acked
+    }
+}
+
+impl RqMeshProtocolAction for PingReqRequest {
+    const ACTION: &'static str = "ping_req";
+    type ResponseType = PingReqResponse;
+}
+
+/// Runs a program on the receiving agent and waits for it to finish. The
+/// run is recorded as a job, so its outcome can be fetched again later with
+/// [`JobStatusRequest`].
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+pub struct RunCommandRequest {
+    command: CommandSpec,
+}
+
+impl RunCommandRequest {
+    pub fn new<S, I, A>(program: S, args: I) -> RunCommandRequest
+    where
+        S: Into<String>,
+        I: IntoIterator<Item = A>,
+        A: Into<String>,
+    {
+        RunCommandRequest::from_spec(CommandSpec::argv(program, args))
+    }
+
+    pub fn from_spec(command: CommandSpec) -> RunCommandRequest {
+        RunCommandRequest { command }
+    }
+
+    /// Sets an environment variable for the program, on top of the agent's
+    /// own environment.
+    pub fn with_env<S1, S2>(mut self, key: S1, value: S2) -> RunCommandRequest
+    where
+        S1: Into<String>,
+        S2: Into<String>,
+    {
+        self.command = self.command.with_env(key, value);
+        self
+    }
+
+    pub fn with_working_dir<S>(mut self, working_dir: S) -> RunCommandRequest
+    where
+        S: Into<String>,
+    {
+        self.command = self.command.with_working_dir(working_dir);
+        self
+    }
+
+    /// Kills the program if it has not finished after `timeout`.
+    pub fn with_timeout(mut self, timeout: Duration) -> RunCommandRequest {
+        self.command = self.command.with_timeout(timeout);
+        self
+    }
+
+    pub fn spec(&self) -> &CommandSpec {
+        &self.command
+    }
+
+    pub fn program(&self) -> &str {
+        self.command.program()
+    }
+
+    pub fn args(&self) -> &[String] {
+        self.command.args()
+    }
+
+    pub fn env(&self) -> &[(String, String)] {
+        self.command.env()
+    }
+
+    pub fn working_dir(&self) -> Option<&str> {
+        self.command.working_dir()
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.command.timeout()
+    }
+}
+
+impl RqMeshProtocolAction for RunCommandRequest {
+    const ACTION: &'static str = "run_command";
+    type ResponseType = JobRecord;
+
+    fn validate(&self) -> Result<(), RqMeshError> {
+        self.command.validate()
+    }
+}
+
+/// Where a job is in its lifecycle. Jobs move from `Queued` through
+/// `Running` to one of the finished states, except that a queued job whose
+/// attempt fails goes back to `Queued` until it runs out of attempts and is
+/// dead-lettered.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+pub enum JobState {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    TimedOut,
+    Cancelled,
+    DeadLettered,
+}
+
+impl JobState {
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            JobState::Succeeded
+                | JobState::Failed
+                | JobState::TimedOut
+                | JobState::Cancelled
+                | JobState::DeadLettered
+        )
+    }
+}
+
+impl std::fmt::Display for JobState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self {
+            JobState::Queued => write!(f, "queued"),
+            JobState::Running => write!(f, "running"),
+            JobState::Succeeded => write!(f, "succeeded"),
+            JobState::Failed => write!(f, "failed"),
+            JobState::TimedOut => write!(f, "timed_out"),
+            JobState::Cancelled => write!(f, "cancelled"),
+            JobState::DeadLettered => write!(f, "dead_lettered"),
+        }
+    }
+}
+
+impl std::str::FromStr for JobState {
+    type Err = RqMeshError;
+
+    fn from_str(s: &str) -> Result<JobState, RqMeshError> {
+        match s {
+            "queued" => Ok(JobState::Queued),
+            "running" => Ok(JobState::Running),
+            "succeeded" => Ok(JobState::Succeeded),
+            "failed" => Ok(JobState::Failed),
+            "timed_out" => Ok(JobState::TimedOut),
+            "cancelled" => Ok(JobState::Cancelled),
+            "dead_lettered" => Ok(JobState::DeadLettered),
+            _ => Err(RqMeshError::from(FrameErrorKind::new_decode_err(format!(
+                "Unknown job state {}",
+                s
+            )))),
+        }
+    }
+}
+
+/// A job as recorded by the agent that ran it. Times are UTC, in sqlite
+/// `datetime` format.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+pub struct JobRecord {
+    job_id: String,
+    requestor: Option<String>,
+    program: String,
+    args: Vec<String>,
+    state: JobState,
+    exit_code: Option<i32>,
+    error: Option<String>,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    created_at: String,
+    started_at: Option<String>,
+    finished_at: Option<String>,
+    attempts: u32,
+    max_attempts: u32,
+}
+
+impl JobRecord {
+    pub fn new<S1, S2, S3>(
+        job_id: S1,
+        requestor: Option<String>,
+        program: S2,
+        args: Vec<String>,
+        state: JobState,
+        created_at: S3,
+    ) -> JobRecord
+    where
+        S1: Into<String>,
+        S2: Into<String>,
+        S3: Into<String>,
+    {
+        let job_id = job_id.into();
+        let program = program.into();
+        let created_at = created_at.into();
+        JobRecord {
+            job_id,
+            requestor,
+            program,
+            args,
+            state,
+            exit_code: None,
+            error: None,
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+            created_at,
+            started_at: None,
+            finished_at: None,
+            attempts: 0,
+            max_attempts: 1,
+        }
+    }
+
+    /// Sets the outcome of a job that has finished or is running.
+    pub fn with_outcome(
+        mut self,
+        exit_code: Option<i32>,
+        error: Option<String>,
+        stdout: Vec<u8>,
+        stderr: Vec<u8>,
+    ) -> JobRecord {
+        self.exit_code = exit_code;
+        self.error = error;
+        self.stdout = stdout;
+        self.stderr = stderr;
+        self
+    }
+
+    pub fn with_times(
+        mut self,
+        started_at: Option<String>,
+        finished_at: Option<String>,
+    ) -> JobRecord {
+        self.started_at = started_at;
+        self.finished_at = finished_at;
+        self
+    }
+
+    pub fn with_attempts(mut self, attempts: u32, max_attempts: u32) -> JobRecord {
+        self.attempts = attempts;
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    pub fn job_id(&self) -> &str {
+        &self.job_id
+    }
+
+    /// Who the job was run for, if the agent authenticated them.
+    pub fn requestor(&self) -> Option<&str> {
+        self.requestor.as_deref()
+    }
+
+    pub fn program(&self) -> &str {
+        &self.program
+    }
+
+    pub fn args(&self) -> &[String] {
+        &self.args
+    }
+
+    pub fn state(&self) -> JobState {
+        self.state
+    }
+
+    /// Exit code of the program, if it exited normally.
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    /// Why the program could not be run, if it never started.
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    pub fn stdout(&self) -> &[u8] {
+        &self.stdout
+    }
+
+    pub fn stderr(&self) -> &[u8] {
+        &self.stderr
+    }
+
+    pub fn created_at(&self) -> &str {
+        &self.created_at
+    }
+
+    pub fn started_at(&self) -> Option<&str> {
+        self.started_at.as_deref()
+    }
+
+    pub fn finished_at(&self) -> Option<&str> {
+        self.finished_at.as_deref()
+    }
+
+    /// Number of times the job has been started.
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// Fails with a job error if the job finished without succeeding.
+    pub fn check(&self) -> Result<(), RqMeshError> {
+        if self.state.is_finished() && self.state != JobState::Succeeded {
+            return Err(RqMeshError::from(JobErrorKind::new_unsuccessful(
+                self.job_id.as_str(),
+                self.state,
+                self.exit_code,
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Fetches a job previously run by the receiving agent.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+pub struct JobStatusRequest {
+    job_id: String,
+}
+
+impl JobStatusRequest {
+    pub fn new<S>(job_id: S) -> JobStatusRequest
+    where
+        S: Into<String>,
+    {
+        let job_id = job_id.into();
+        JobStatusRequest { job_id }
+    }
+
+    pub fn job_id(&self) -> &str {
+        &self.job_id
+    }
+}
+
+impl RqMeshProtocolAction for JobStatusRequest {
+    const ACTION: &'static str = "job_status";
+    type ResponseType = JobRecord;
+}
+
+/// Adds a command to the receiving agent's job queue, to be run in the
+/// background and retried with backoff until it succeeds or has been tried
+/// `max_attempts` times. Answers with the queued job.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+pub struct EnqueueJobRequest {
+    command: RunCommandRequest,
+    max_attempts: Option<u32>,
+}
+
+impl EnqueueJobRequest {
+    pub fn new(command: RunCommandRequest) -> EnqueueJobRequest {
+        EnqueueJobRequest {
+            command,
+            max_attempts: None,
+        }
+    }
+
+    /// Overrides the agent's default number of attempts.
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> EnqueueJobRequest {
+        self.max_attempts = Some(max_attempts);
+        self
+    }
+
+    pub fn command(&self) -> &RunCommandRequest {
+        &self.command
+    }
+
+    pub fn max_attempts(&self) -> Option<u32> {
+        self.max_attempts
+    }
+}
+
+impl RqMeshProtocolAction for EnqueueJobRequest {
+    const ACTION: &'static str = "enqueue_job";
+    type ResponseType = JobRecord;
+
+    fn validate(&self) -> Result<(), RqMeshError> {
+        self.command.validate()
+    }
+}
+
+/// Cancels a queued job. A job that is already running is marked cancelled
+/// and its program, with every process it started, is killed. Answers with
+/// the job as it stands after the request, so a job that had already
+/// finished is returned unchanged.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+pub struct CancelJobRequest {
+    job_id: String,
+}
+
+impl CancelJobRequest {
+    pub fn new<S>(job_id: S) -> CancelJobRequest
+    where
+        S: Into<String>,
+    {
+        let job_id = job_id.into();
+        CancelJobRequest { job_id }
+    }
+
+    pub fn job_id(&self) -> &str {
+        &self.job_id
+    }
+}
+
+impl RqMeshProtocolAction for CancelJobRequest {
+    const ACTION: &'static str = "cancel_job";
+    type ResponseType = JobRecord;
+}
+
+/// Counts of the jobs an agent has in hand, used to pick the least loaded
+/// of several agents able to take a request.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize, Default)]
+pub struct AgentLoadRequest {}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+pub struct AgentLoadResponse {
+    running_jobs: u64,
+    queued_jobs: u64,
+}
+
+impl AgentLoadResponse {
+    pub fn new(running_jobs: u64, queued_jobs: u64) -> AgentLoadResponse {
+        AgentLoadResponse {
+            running_jobs,
+            queued_jobs,
+        }
+    }
+
+    pub fn running_jobs(&self) -> u64 {
+        self.running_jobs
+    }
+
+    pub fn queued_jobs(&self) -> u64 {
+        self.queued_jobs
+    }
+
+    /// Jobs the agent would have to get through before starting a new one.
+    pub fn load(&self) -> u64 {
+        self.running_jobs + self.queued_jobs
+    }
+}
+
+impl RqMeshProtocolAction for AgentLoadRequest {
+    const ACTION: &'static str = "agent_load";
+    type ResponseType = AgentLoadResponse;
+}
+
+/// How the handling of an audited request ended.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+pub enum AuditOutcome {
+    Ok,
+    /// The caller was not allowed to make the request.
+    Denied,
+    Error,
+}
+
+impl std::fmt::Display for AuditOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self {
+            AuditOutcome::Ok => write!(f, "ok"),
+            AuditOutcome::Denied => write!(f, "denied"),
+            AuditOutcome::Error => write!(f, "error"),
+        }
+    }
+}
+
+impl std::str::FromStr for AuditOutcome {
+    type Err = RqMeshError;
+
+    fn from_str(s: &str) -> Result<AuditOutcome, RqMeshError> {
+        match s {
+            "ok" => Ok(AuditOutcome::Ok),
+            "denied" => Ok(AuditOutcome::Denied),
+            "error" => Ok(AuditOutcome::Error),
+            _ => Err(RqMeshError::from(FrameErrorKind::new_decode_err(format!(
+                "Unknown audit outcome {}",
+                s
+            )))),
+        }
+    }
+}
+
+/// A request handled by an agent, as recorded in its audit log. Times are
+/// UTC, in sqlite `datetime` format.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+pub struct AuditRecord {
+    id: u64,
+    recorded_at: String,
+    requestor: Option<String>,
+    claimed_requestor: String,
+    action: String,
+    outcome: AuditOutcome,
+    duration: Duration,
+    error: Option<String>,
+    peer_addr: Option<String>,
+}
+
+impl AuditRecord {
+    pub fn new<S1, S2, S3>(
+        id: u64,
+        recorded_at: S1,
+        requestor: Option<String>,
+        claimed_requestor: S2,
+        action: S3,
+        outcome: AuditOutcome,
+        duration: Duration,
+    ) -> AuditRecord
+    where
+        S1: Into<String>,
+        S2: Into<String>,
+        S3: Into<String>,
+    {
+        let recorded_at = recorded_at.into();
+        let claimed_requestor = claimed_requestor.into();
+        let action = action.into();
+        AuditRecord {
+            id,
+            recorded_at,
+            requestor,
+            claimed_requestor,
+            action,
+            outcome,
+            duration,
+            error: None,
+            peer_addr: None,
+        }
+    }
+
+    pub fn with_error(mut self, error: Option<String>) -> AuditRecord {
+        self.error = error;
+        self
+    }
+
+    pub fn with_peer_addr(mut self, peer_addr: Option<String>) -> AuditRecord {
+        self.peer_addr = peer_addr;
+        self
+    }
+
+    /// Position of the record in the log, increasing with every request.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn recorded_at(&self) -> &str {
+        &self.recorded_at
+    }
+
+    /// Who made the request, if the agent authenticated them, as for
+    /// [`RqMeshFrame::authenticated_caller`].
+    pub fn requestor(&self) -> Option<&str> {
+        self.requestor.as_deref()
+    }
+
+    /// Identity the request claimed to come from.
+    pub fn claimed_requestor(&self) -> &str {
+        &self.claimed_requestor
+    }
+
+    pub fn action(&self) -> &str {
+        &self.action
+    }
+
+    pub fn outcome(&self) -> AuditOutcome {
+        self.outcome
+    }
+
+    /// How long the agent took to handle the request.
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    /// Why the request failed or was denied.
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    /// Address the request was received from.
+    pub fn peer_addr(&self) -> Option<&str> {
+        self.peer_addr.as_deref()
+    }
+}
+
+/// Fetches records from the receiving agent's audit log, oldest first.
+/// Times are UTC, in sqlite `datetime` format, and bound the range
+/// inclusively.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize, Default)]
+pub struct QueryAuditLogRequest {
+    since: Option<String>,
+    until: Option<String>,
+    requestor: Option<String>,
+    limit: Option<u32>,
+}
+
+impl QueryAuditLogRequest {
+    pub fn new() -> QueryAuditLogRequest {
+        QueryAuditLogRequest::default()
+    }
+
+    pub fn with_since<S>(mut self, since: S) -> QueryAuditLogRequest
+    where
+        S: Into<String>,
+    {
+        self.since = Some(since.into());
+        self
+    }
+
+    pub fn with_until<S>(mut self, until: S) -> QueryAuditLogRequest
+    where
+        S: Into<String>,
+    {
+        self.until = Some(until.into());
+        self
+    }
+
+    /// Only returns requests made by `requestor`.
+    pub fn with_requestor<S>(mut self, requestor: S) -> QueryAuditLogRequest
+    where
+        S: Into<String>,
+    {
+        self.requestor = Some(requestor.into());
+        self
+    }
+
+    /// Caps the number of records returned, overriding the agent's default.
+    pub fn with_limit(mut self, limit: u32) -> QueryAuditLogRequest {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn since(&self) -> Option<&str> {
+        self.since.as_deref()
+    }
+
+    pub fn until(&self) -> Option<&str> {
+        self.until.as_deref()
+    }
+
+    pub fn requestor(&self) -> Option<&str> {
+        self.requestor.as_deref()
+    }
+
+    pub fn limit(&self) -> Option<u32> {
+        self.limit
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+pub struct QueryAuditLogResponse {
+    records: Vec<AuditRecord>,
+}
+
+impl QueryAuditLogResponse {
+    pub fn new(records: Vec<AuditRecord>) -> QueryAuditLogResponse {
+        QueryAuditLogResponse { records }
+    }
+
+    pub fn records(&self) -> &[AuditRecord] {
+        &self.records
+    }
+
+    pub fn into_records(self) -> Vec<AuditRecord> {
+        self.records
+    }
+}
+
+impl RqMeshProtocolAction for QueryAuditLogRequest {
+    const ACTION: &'static str = "query_audit_log";
+    type ResponseType = QueryAuditLogResponse;
+}
+
+/// How an agent picks among several agents able to take a routed request.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize, Default)]
+pub enum RoutingStrategy {
+    /// The agent with the fewest running and queued jobs.
+    #[default]
+    LeastLoaded,
+    /// Each capable agent in turn.
+    RoundRobin,
+    Random,
+}
+
+impl std::fmt::Display for RoutingStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self {
+            RoutingStrategy::LeastLoaded => write!(f, "least-loaded"),
+            RoutingStrategy::RoundRobin => write!(f, "round-robin"),
+            RoutingStrategy::Random => write!(f, "random"),
+        }
+    }
+}
+
+impl std::str::FromStr for RoutingStrategy {
+    type Err = RqMeshError;
+
+    fn from_str(s: &str) -> Result<RoutingStrategy, RqMeshError> {
+        match s {
+            "least-loaded" => Ok(RoutingStrategy::LeastLoaded),
+            "round-robin" => Ok(RoutingStrategy::RoundRobin),
+            "random" => Ok(RoutingStrategy::Random),
+            _ => Err(RqMeshError::from(FrameErrorKind::new_decode_err(format!(
+                "Unknown routing strategy {}",
+                s
+            )))),
+        }
+    }
+}
+
+/// Most times a routed request is forwarded before it is given up on.
+pub const DEFAULT_MAX_HOPS: u32 = 4;
+
+/// Hands an already encoded request to the receiving agent to be run by an
+/// agent advertising every one of the `required` capabilities.
+///
+/// The receiver handles the request itself or forwards it to a capable peer
+/// chosen by `strategy`, and relays the response back. An agent that is
+/// forwarded the request handles it if it is capable, and otherwise forwards
+/// it again, up to `max_hops` times.
+///
+/// Agents a request is forwarded to establish who sent the envelope for
+/// themselves: a signed envelope is verified and handled for its signer,
+/// while an unsigned one is handled for the caller the forwarding agent
+/// vouches for in `on_behalf_of`, if it is trusted to, and for no
+/// authenticated caller otherwise.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+pub struct RouteRequest {
+    required: Vec<CapabilityBroadcast>,
+    strategy: RoutingStrategy,
+    hops: u32,
+    max_hops: u32,
+    on_behalf_of: Option<String>,
+    envelope: RqMeshEnvelope,
+}
+
+impl RouteRequest {
+    pub fn new(envelope: RqMeshEnvelope, required: Vec<CapabilityBroadcast>) -> RouteRequest {
+        RouteRequest {
+            required,
+            strategy: RoutingStrategy::default(),
+            hops: 0,
+            max_hops: DEFAULT_MAX_HOPS,
+            on_behalf_of: None,
+            envelope,
+        }
+    }
+
+    pub fn with_strategy(mut self, strategy: RoutingStrategy) -> RouteRequest {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Limits how many times the request is forwarded; agents never forward
+    /// it more than [`DEFAULT_MAX_HOPS`] times, whatever the limit.
+    pub fn with_max_hops(mut self, max_hops: u32) -> RouteRequest {
+        self.max_hops = max_hops;
+        self
+    }
+
+    /// The request as sent on by an agent forwarding it for `caller`, the
+    /// sender of the envelope as established by the forwarding agent.
+    pub fn forwarded(mut self, caller: Option<&str>) -> RouteRequest {
+        self.hops += 1;
+        self.on_behalf_of = caller.map(|c| c.to_string());
+        self
+    }
+
+    /// Capabilities the handling agent must advertise; a required capability
+    /// without a version is satisfied by any version.
+    pub fn required(&self) -> &[CapabilityBroadcast] {
+        &self.required
+    }
+
+    pub fn strategy(&self) -> RoutingStrategy {
+        self.strategy
+    }
+
+    /// Number of times the request has been forwarded so far.
+    pub fn hops(&self) -> u32 {
+        self.hops
+    }
+
+    pub fn max_hops(&self) -> u32 {
+        self.max_hops
+    }
+
+    /// Who the forwarding agent established sent the envelope; only trusted
+    /// for an unsigned envelope, and only from agents allowed to relay.
+    pub fn on_behalf_of(&self) -> Option<&str> {
+        self.on_behalf_of.as_deref()
+    }
+
+    pub fn envelope(&self) -> &RqMeshEnvelope {
+        &self.envelope
+    }
+
+    pub fn into_envelope(self) -> RqMeshEnvelope {
+        self.envelope
+    }
+}
+
+/// The response to a routed request, along with the agent that handled it.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+pub struct RoutedResponse {
+    node_id: String,
+    hops: u32,
+    response: RqMeshResponseEnvelope,
+}
+
+impl RoutedResponse {
+    pub fn new<S>(node_id: S, hops: u32, response: RqMeshResponseEnvelope) -> RoutedResponse
+    where
+        S: Into<String>,
+    {
+        let node_id = node_id.into();
+        RoutedResponse {
+            node_id,
+            hops,
+            response,
+        }
+    }
+
+    /// Node ID of the agent that handled the request.
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    /// Number of times the request was forwarded before it was handled.
+    pub fn hops(&self) -> u32 {
+        self.hops
+    }
+
+    pub fn response(&self) -> &RqMeshResponseEnvelope {
+        &self.response
+    }
+
+    /// Decodes the typed response of the routed action `A`. An error the
+    /// handling agent reported is returned as a remote error from it.
+    pub fn into_response<A>(self) -> Result<A::ResponseType, RqMeshError>
+    where
+        A: RqMeshProtocolAction,
+    {
+        if let Err(e) = self.response.result() {
+            return Err(RqMeshError::new_remote(self.node_id, e.clone()));
+        }
+        self.response.into_response::<A>()
+    }
+}
+
+impl RqMeshProtocolAction for RouteRequest {
+    const ACTION: &'static str = "route";
+    type ResponseType = RoutedResponse;
+}
+
+/// Runs an already encoded request on every agent in the mesh advertising
+/// all of the capabilities in `selector`, the receiving agent included, and
+/// collects each agent's response.
+///
+/// Agents are contacted at most `max_concurrency` at a time, and a peer
+/// that does not answer within `node_timeout` is reported as having failed
+/// rather than holding up the others; the receiving agent handles its own
+/// share like any other request. Unset limits use the receiving agent's
+/// defaults.
+///
+/// The request reaches each peer as a [`RouteRequest`] that may not travel
+/// further, so peers handle it for its sender just as they would a routed
+/// request, rather than for the receiving agent.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+pub struct BroadcastRequest {
+    selector: Vec<CapabilityBroadcast>,
+    node_timeout: Option<Duration>,
+    max_concurrency: Option<u32>,
+    envelope: RqMeshEnvelope,
+}
+
+impl BroadcastRequest {
+    /// An empty `selector` matches every agent.
+    pub fn new(envelope: RqMeshEnvelope, selector: Vec<CapabilityBroadcast>) -> BroadcastRequest {
+        BroadcastRequest {
+            selector,
+            node_timeout: None,
+            max_concurrency: None,
+            envelope,
+        }
+    }
+
+    pub fn with_node_timeout(mut self, node_timeout: Duration) -> BroadcastRequest {
+        self.node_timeout = Some(node_timeout);
+        self
+    }
+
+    pub fn with_max_concurrency(mut self, max_concurrency: u32) -> BroadcastRequest {
+        self.max_concurrency = Some(max_concurrency);
+        self
+    }
+
+    pub fn selector(&self) -> &[CapabilityBroadcast] {
+        &self.selector
+    }
+
+    pub fn node_timeout(&self) -> Option<Duration> {
+        self.node_timeout
+    }
+
+    pub fn max_concurrency(&self) -> Option<u32> {
+        self.max_concurrency
+    }
+
+    pub fn envelope(&self) -> &RqMeshEnvelope {
+        &self.envelope
+    }
+}
+
+/// The response of every agent a broadcast ran on, keyed by node ID.
+///
+/// Agents that could not be reached or did not answer in time are included
+/// with the error that prevented it, keyed by their endpoint if their node
+/// ID is not known, so a broadcast that reached only some agents still
+/// reports the results it did get.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+pub struct BroadcastResponse {
+    results: BTreeMap<String, RqMeshResponseEnvelope>,
+}
+
+impl BroadcastResponse {
+    pub fn new(results: BTreeMap<String, RqMeshResponseEnvelope>) -> BroadcastResponse {
+        BroadcastResponse { results }
+    }
+
+    pub fn results(&self) -> &BTreeMap<String, RqMeshResponseEnvelope> {
+        &self.results
+    }
+
+    /// Decodes each agent's typed response to the broadcast action `A`.
+    pub fn into_results<A>(self) -> BTreeMap<String, Result<A::ResponseType, RqMeshError>>
+    where
+        A: RqMeshProtocolAction,
+    {
+        self.results
+            .into_iter()
+            .map(|(node, response)| (node, response.into_response::<A>()))
+            .collect()
+    }
+}
+
+impl RqMeshProtocolAction for BroadcastRequest {
+    const ACTION: &'static str = "broadcast";
+    type ResponseType = BroadcastResponse;
+
+    /// Every agent the broadcast reaches handles its envelope, so an
+    /// envelope that is itself a broadcast or a route would multiply with
+    /// each level of nesting.
+    fn validate(&self) -> Result<(), RqMeshError> {
+        let action = self.envelope.action();
+        if action == BroadcastRequest::ACTION || action == RouteRequest::ACTION {
+            return Err(RqMeshError::from(RoutingErrorKind::new_nested_fan_out(
+                action,
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Length-prefixed bincode framing used for everything that crosses the wire.
+///
+/// Each frame is a 4 byte big-endian payload length followed by the bincode
+/// encoding of the message. Frames larger than `max_frame_size` are rejected
+/// on both encode and decode so a misbehaving peer cannot make us allocate
+/// arbitrary amounts of memory.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RqMeshCodec {
+    max_frame_size: u32,
+}
+
+impl Default for RqMeshCodec {
+    fn default() -> RqMeshCodec {
+        RqMeshCodec::new(DEFAULT_MAX_FRAME_SIZE)
+    }
+}
+
+impl RqMeshCodec {
+    pub fn new(max_frame_size: u32) -> RqMeshCodec {
+        RqMeshCodec { max_frame_size }
+    }
+
+    pub fn max_frame_size(&self) -> u32 {
+        self.max_frame_size
+    }
+
+    /// Serializes `message` into a complete frame, header included.
+    pub fn encode<M>(&self, message: &M) -> Result<Vec<u8>, RqMeshError>
+    where
+        M: Serialize,
+    {
+        let payload = bincode::serialize(message)
+            .map_err(|e| RqMeshError::from(FrameErrorKind::new_encode_err(format!("{}", e))))?;
+        let len = self.check_frame_size(payload.len())?;
+
+        let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+        frame.extend_from_slice(&len.to_be_bytes());
+        frame.extend_from_slice(&payload);
+        Ok(frame)
+    }
+
+    /// Attempts to take one complete frame off the front of `buf`.
+    ///
+    /// Returns `Ok(None)` without touching `buf` when it does not yet hold a
+    /// full frame, so callers can keep appending bytes as they arrive.
+    pub fn decode<M>(&self, buf: &mut Vec<u8>) -> Result<Option<M>, RqMeshError>
+    where
+        M: DeserializeOwned,
+    {
+        if buf.len() < FRAME_HEADER_LEN {
+            return Ok(None);
+        }
+
+        let mut header = [0u8; FRAME_HEADER_LEN];
+        header.copy_from_slice(&buf[..FRAME_HEADER_LEN]);
+        let len = self.check_frame_size(u32::from_be_bytes(header) as usize)? as usize;
+        if buf.len() < FRAME_HEADER_LEN + len {
+            return Ok(None);
+        }
+
+        let frame: Vec<u8> = buf.drain(..FRAME_HEADER_LEN + len).collect();
+        let message = bincode::deserialize(&frame[FRAME_HEADER_LEN..])
+            .map_err(|e| RqMeshError::from(FrameErrorKind::new_decode_err(format!("{}", e))))?;
+        Ok(Some(message))
+    }
+
+    pub fn write_frame<W, M>(&self, writer: &mut W, message: &M) -> Result<(), RqMeshError>
+    where
+        W: Write,
+        M: Serialize,
+    {
+        let frame = self.encode(message)?;
+        writer
+            .write_all(&frame)
+            .and_then(|_| writer.flush())
+            .map_err(|e| RqMeshError::from(TransportErrorKind::from_io(&e)))?;
+        Ok(())
+    }
+
+    /// Blocks until a full frame has been read from `reader`.
+    ///
+    /// Returns `Ok(None)` if the stream is closed cleanly before any byte of a
+    /// new frame arrives; a stream closed part way through a frame is an error.
+    pub fn read_frame<R, M>(&self, reader: &mut R) -> Result<Option<M>, RqMeshError>
+    where
+        R: Read,
+        M: DeserializeOwned,
+    {
+        let mut header = [0u8; FRAME_HEADER_LEN];
+        let received = read_fully(reader, &mut header)?;
+        if received == 0 {
+            return Ok(None);
+        } else if received < FRAME_HEADER_LEN {
+            return Err(RqMeshError::from(FrameErrorKind::new_truncated(
+                FRAME_HEADER_LEN,
+                received,
+            )));
+        }
+
+        let len = self.check_frame_size(u32::from_be_bytes(header) as usize)? as usize;
+        let mut payload = vec![0u8; len];
+        let received = read_fully(reader, &mut payload)?;
+        if received < len {
+            return Err(RqMeshError::from(FrameErrorKind::new_truncated(
+                len, received,
+            )));
+        }
+
+        let message = bincode::deserialize(&payload)
+            .map_err(|e| RqMeshError::from(FrameErrorKind::new_decode_err(format!("{}", e))))?;
+        Ok(Some(message))
+    }
+
+    fn check_frame_size(&self, size: usize) -> Result<u32, RqMeshError> {
+        match u32::try_from(size) {
+            Ok(len) if len <= self.max_frame_size => Ok(len),
+            _ => Err(RqMeshError::from(FrameErrorKind::new_too_large(
+                size,
+                self.max_frame_size,
+            ))),
+        }
+    }
+}
+
+/// Reads until `buf` is full or the stream reports EOF, returning the number
+/// of bytes actually read.
+fn read_fully<R>(reader: &mut R, buf: &mut [u8]) -> Result<usize, RqMeshError>
+where
+    R: Read,
+{
+    let mut received = 0;
+    while received < buf.len() {
+        match reader.read(&mut buf[received..]) {
+            Ok(0) => break,
+            Ok(n) => received += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(RqMeshError::from(TransportErrorKind::from_io(&e))),
+        }
+    }
+    Ok(received)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn envelope<T>(contents: T) -> RqMeshEnvelope
+    where
+        T: RqMeshProtocolAction,
+    {
+        RqMeshFrame::new(contents, "tester")
+            .into_envelope()
+            .expect("encodes")
+    }
+
+    fn handshake(protocol_version: &str) -> Handshake {
+        Handshake {
+            protocol_version: protocol_version.to_string(),
+            ..Handshake::new("tester", vec!["ping"])
+        }
+    }
+
+    #[test]
+    fn handshakes_agree_on_major_and_pre_1_0_minor_versions() {
+        let compatible =
+            |ours: &str, theirs: &str| handshake(ours).check_compatible(&handshake(theirs)).is_ok();
+        assert!(compatible("0.1.0", "0.1.7"));
+        assert!(!compatible("0.1.0", "0.2.0"));
+        assert!(compatible("1.0.0", "1.4.2"));
+        assert!(!compatible("1.0.0", "2.0.0"));
+        assert!(!compatible("0.1.0", "latest"));
+        assert!(!compatible("latest", "latest"));
+    }
+
+    #[test]
+    fn handshakes_reject_incompatible_peers_naming_both_versions() {
+        match handshake("0.1.0").check_compatible(&handshake("0.2.3")) {
+            Err(RqMeshError::IncompatiblePeer {
+                local_version,
+                remote_version,
+            }) => assert_eq!(
+                (local_version.as_str(), remote_version.as_str()),
+                ("0.1.0", "0.2.3")
+            ),
+            other => panic!("expected IncompatiblePeer, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn handshakes_without_the_magic_are_not_handshakes() {
+        let foreign = Handshake {
+            magic: *b"HTTP",
+            ..handshake(PROTOCOL_VERSION)
+        };
+        match handshake(PROTOCOL_VERSION).check_compatible(&foreign) {
+            Err(RqMeshError::ProtocolError(ProtocolErrorKind::HandshakeRequired { .. })) => {}
+            other => panic!("expected HandshakeRequired, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn codec_round_trips_a_frame() {
+        let codec = RqMeshCodec::default();
+        let mut buf = codec.encode(&"hello".to_string()).expect("encodes");
+        buf.extend_from_slice(&codec.encode(&"again".to_string()).expect("encodes"));
+
+        let first: Option<String> = codec.decode(&mut buf).expect("decodes");
+        assert_eq!(first.as_deref(), Some("hello"));
+        let mut reader = &buf[..];
+        let second: Option<String> = codec.read_frame(&mut reader).expect("reads");
+        assert_eq!(second.as_deref(), Some("again"));
+        let end: Option<String> = codec.read_frame(&mut reader).expect("reads");
+        assert_eq!(end, None);
+    }
+
+    #[test]
+    fn codec_waits_for_the_rest_of_a_partial_frame() {
+        let codec = RqMeshCodec::default();
+        let frame = codec.encode(&"hello".to_string()).expect("encodes");
+        for cut in [1, FRAME_HEADER_LEN, frame.len() - 1] {
+            let mut buf = frame[..cut].to_vec();
+            let decoded: Option<String> = codec.decode(&mut buf).expect("decodes");
+            assert_eq!(decoded, None);
+            assert_eq!(buf.len(), cut);
+        }
+    }
+
+    #[test]
+    fn codec_rejects_a_truncated_frame() {
+        let codec = RqMeshCodec::default();
+        let frame = codec.encode(&"hello".to_string()).expect("encodes");
+        let payload_len = frame.len() - FRAME_HEADER_LEN;
+        for (cut, expected, received) in [
+            (2, FRAME_HEADER_LEN, 2),
+            (frame.len() - 1, payload_len, payload_len - 1),
+        ] {
+            let mut reader = &frame[..cut];
+            match codec.read_frame::<_, String>(&mut reader) {
+                Err(RqMeshError::FrameError(FrameErrorKind::TruncatedFrame {
+                    expected: e,
+                    received: r,
+                })) => assert_eq!((e, r), (expected, received)),
+                other => panic!("expected TruncatedFrame, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn codec_rejects_an_oversized_frame() {
+        let codec = RqMeshCodec::new(8);
+        match codec.encode(&"more than eight bytes".to_string()) {
+            Err(RqMeshError::FrameError(FrameErrorKind::FrameTooLarge {
+                max_frame_size, ..
+            })) => assert_eq!(max_frame_size, 8),
+            other => panic!("expected FrameTooLarge, got {:?}", other),
+        }
+
+        // The header alone is enough to refuse a frame, before any payload
+        // arrives or is allocated.
+        let header = 9u32.to_be_bytes();
+        let mut buf = header.to_vec();
+        assert!(matches!(
+            codec.decode::<String>(&mut buf),
+            Err(RqMeshError::FrameError(FrameErrorKind::FrameTooLarge {
+                size: 9,
+                ..
+            }))
+        ));
+        let mut reader = &header[..];
+        assert!(matches!(
+            codec.read_frame::<_, String>(&mut reader),
+            Err(RqMeshError::FrameError(FrameErrorKind::FrameTooLarge {
+                size: 9,
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn broadcast_of_a_command_is_accepted() {
+        let broadcast = BroadcastRequest::new(
+            envelope(RunCommandRequest::new("echo", vec!["hi"])),
+            Vec::new(),
+        );
+        let decoded = RqMeshFrame::<BroadcastRequest>::from_envelope(envelope(broadcast));
+        assert!(decoded.is_ok());
+    }
+
+    #[test]
+    fn broadcast_of_a_broadcast_or_route_is_rejected() {
+        let inner = BroadcastRequest::new(
+            envelope(RunCommandRequest::new("echo", vec!["hi"])),
+            Vec::new(),
+        );
+        let route = RouteRequest::new(envelope(inner.clone()), Vec::new());
+        for nested in [envelope(inner), envelope(route)] {
+            let action = nested.action().to_string();
+            let outer = envelope(BroadcastRequest::new(nested, Vec::new()));
+            match RqMeshFrame::<BroadcastRequest>::from_envelope(outer) {
+                Err(RqMeshError::RoutingError(RoutingErrorKind::NestedFanOut { action: a })) => {
+                    assert_eq!(a, action)
+                }
+                other => panic!("expected NestedFanOut, got {:?}", other),
+            }
+        }
+    }
+}